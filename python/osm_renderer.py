@@ -0,0 +1,60 @@
+"""A thin ctypes wrapper around the C API of the renderer.
+
+Build the shared library with `cargo build --release --features ffi`, then:
+
+    renderer = Renderer("city.bin", "mapcss/osmosnimki-minimal.mapcss")
+    png_bytes = renderer.render_tile(16, 39614, 20486)
+"""
+
+import ctypes
+import os
+import sys
+
+
+def _default_library_path():
+    name = {"darwin": "librenderer.dylib", "win32": "renderer.dll"}.get(sys.platform, "librenderer.so")
+    return os.path.join(os.path.dirname(__file__), "..", "target", "release", name)
+
+
+class Renderer:
+    def __init__(self, geodata_file, stylesheet_file, library_path=None):
+        lib = ctypes.CDLL(library_path or _default_library_path())
+
+        lib.renderer_init.argtypes = [ctypes.c_char_p, ctypes.c_char_p]
+        lib.renderer_init.restype = ctypes.c_void_p
+        lib.renderer_render_tile.argtypes = [
+            ctypes.c_void_p,
+            ctypes.c_uint8,
+            ctypes.c_uint32,
+            ctypes.c_uint32,
+            ctypes.c_uint32,
+            ctypes.POINTER(ctypes.c_size_t),
+        ]
+        lib.renderer_render_tile.restype = ctypes.POINTER(ctypes.c_uint8)
+        lib.renderer_free_png.argtypes = [ctypes.POINTER(ctypes.c_uint8), ctypes.c_size_t]
+        lib.renderer_free_png.restype = None
+        lib.renderer_free.argtypes = [ctypes.c_void_p]
+        lib.renderer_free.restype = None
+
+        self._lib = lib
+        self._renderer = lib.renderer_init(os.fsencode(geodata_file), os.fsencode(stylesheet_file))
+        if not self._renderer:
+            raise RuntimeError("Failed to initialize the renderer, see stderr for details")
+
+    def render_tile(self, zoom, x, y, scale=1):
+        size = ctypes.c_size_t()
+        png = self._lib.renderer_render_tile(self._renderer, zoom, x, y, scale, ctypes.byref(size))
+        if not png:
+            raise RuntimeError("Failed to render tile {}/{}/{}, see stderr for details".format(zoom, x, y))
+        try:
+            return ctypes.string_at(png, size.value)
+        finally:
+            self._lib.renderer_free_png(png, size)
+
+    def close(self):
+        if self._renderer:
+            self._lib.renderer_free(self._renderer)
+            self._renderer = None
+
+    def __del__(self):
+        self.close()
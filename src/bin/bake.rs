@@ -0,0 +1,56 @@
+use anyhow::Result;
+use renderer::mapcss::styler::StyleType;
+use renderer::render::TileRenderer;
+use std::env;
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+fn bake(geodata_file: &str, stylesheet_file: &str, stylesheet_type: &StyleType, output: &str) -> Result<()> {
+    let tile_renderer = TileRenderer::new(geodata_file, stylesheet_file, stylesheet_type, None)?;
+    let zoom_masks = tile_renderer.bake_zoom_masks();
+
+    let count_skipped = |masks: &[u32]| masks.iter().filter(|mask| **mask == 0).count();
+    println!(
+        "{} of {} nodes and {} of {} ways are never drawn",
+        count_skipped(&zoom_masks.nodes),
+        zoom_masks.nodes.len(),
+        count_skipped(&zoom_masks.ways),
+        zoom_masks.ways.len()
+    );
+
+    zoom_masks.save(output)
+}
+
+fn main() {
+    let args: Vec<_> = env::args().collect();
+    let bin_name = args.first().map(String::as_str).unwrap_or("bake");
+    let usage = format!("Usage: {} [--mapsme] GEODATA STYLESHEET OUTPUT", bin_name);
+
+    let mut stylesheet_type = StyleType::Josm;
+    let mut positional_args = Vec::new();
+    for arg in args.iter().skip(1) {
+        match arg.as_str() {
+            "--mapsme" => stylesheet_type = StyleType::MapsMe,
+            _ if arg.starts_with("--") => fail(&usage),
+            _ => positional_args.push(arg.clone()),
+        }
+    }
+
+    if positional_args.len() != 3 {
+        fail(&usage);
+    }
+
+    let output = &positional_args[2];
+    match bake(&positional_args[0], &positional_args[1], &stylesheet_type, output) {
+        Ok(_) => println!("Successfully saved the zoom masks to {}", output),
+        Err(err) => {
+            for cause in err.chain() {
+                eprintln!("{}", cause);
+            }
+            std::process::exit(1);
+        }
+    }
+}
@@ -1,32 +1,147 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use renderer::geodata::expiry::expired_tiles;
+use renderer::geodata::importer::{ImportOptions, TruncatedInput};
+use renderer::geodata::reader::GeodataReader;
+use renderer::geodata::tag_filter::TagFilter;
+use renderer::tile::MAX_ZOOM;
 use std::env;
 use std::fs;
+use std::io::{BufWriter, Write};
+use std::ops::RangeInclusive;
 use std::path::{Path, PathBuf};
 
-fn import(input: &Path, tmp_output: &Path, output: &Path) -> Result<()> {
-    println!("Importing OSM data from {}", input.to_string_lossy());
-    renderer::geodata::importer::import(input, tmp_output)?;
+// Where to save the list of the tiles that change when the output file is replaced with the new one.
+struct ExpiryOptions {
+    file: PathBuf,
+    zooms: RangeInclusive<u8>,
+}
+
+fn import(
+    inputs: &[PathBuf],
+    tmp_output: &Path,
+    output: &Path,
+    options: &ImportOptions,
+    expiry: Option<&ExpiryOptions>,
+) -> Result<()> {
+    let input_names = inputs.iter().map(|input| input.to_string_lossy()).collect::<Vec<_>>();
+    println!("Importing OSM data from {}", input_names.join(", "));
+    renderer::geodata::importer::import_files(inputs, tmp_output, options)?;
+    if let Some(expiry) = expiry {
+        save_expired_tiles(output, tmp_output, expiry)?;
+    }
     fs::rename(tmp_output, output)?;
 
     Ok(())
 }
 
+// The tiles are saved as `z/x/y` lines, which is what most tile expiry scripts expect.
+fn save_expired_tiles(old_file: &Path, new_file: &Path, expiry: &ExpiryOptions) -> Result<()> {
+    let tiles = if old_file.exists() {
+        let old = GeodataReader::load(&old_file.to_string_lossy()).context("Failed to load the old geodata file")?;
+        let new = GeodataReader::load(&new_file.to_string_lossy())?;
+        expired_tiles(&old, &new, expiry.zooms.clone())
+    } else {
+        // Nothing could have been rendered from the data that didn't exist.
+        Vec::new()
+    };
+
+    let expiry_file_name = expiry.file.to_string_lossy();
+    let mut writer = BufWriter::new(
+        fs::File::create(&expiry.file).context(format!("Failed to open {} for writing", expiry_file_name))?,
+    );
+    for tile in &tiles {
+        writeln!(writer, "{}/{}/{}", tile.zoom, tile.x, tile.y)?;
+    }
+    writer
+        .flush()
+        .context(format!("Failed to write the expired tiles to {}", expiry_file_name))?;
+    println!("Saved {} expired tiles to {}", tiles.len(), expiry_file_name);
+
+    Ok(())
+}
+
+fn parse_zoom_range(range: &str) -> Option<RangeInclusive<u8>> {
+    let (min_zoom, max_zoom) = range.split_once('-').unwrap_or((range, range));
+    match (min_zoom.parse(), max_zoom.parse()) {
+        (Ok(min_zoom), Ok(max_zoom)) if min_zoom <= max_zoom && max_zoom <= MAX_ZOOM => Some(min_zoom..=max_zoom),
+        _ => None,
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
 fn main() {
     let args: Vec<_> = env::args().collect();
+    let bin_name = args.first().map(String::as_str).unwrap_or("importer");
+    let usage = format!(
+        "Usage: {} [--keep-tags TAG_LIST_OR_MAPCSS] [--keep-all-nodes] [--compress] [--tolerate-truncation] [--force] \
+         [--expire-tiles FILE [--expire-zooms MIN-MAX]] [--dropped-refs FILE] [--report FILE] INPUT... OUTPUT",
+        bin_name
+    );
 
-    if args.len() != 3 {
-        let bin_name = args.first().map(String::as_str).unwrap_or("importer");
-        eprintln!("Usage: {} INPUT OUTPUT", bin_name);
-        std::process::exit(1);
+    let mut options = ImportOptions::default();
+    let mut force = false;
+    let mut expiry_file = None;
+    let mut expiry_zooms = 0..=MAX_ZOOM;
+    let mut positional_args = Vec::new();
+    let mut arg_iter = args.iter().skip(1);
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_str() {
+            "--keep-tags" => {
+                let file_name = arg_iter.next().unwrap_or_else(|| fail(&usage));
+                match TagFilter::from_file(Path::new(file_name)) {
+                    Ok(filter) => options.tag_filter = Some(filter),
+                    Err(err) => fail(&format!("Failed to load the tag filter: {:#}", err)),
+                }
+            }
+            "--keep-all-nodes" => options.keep_all_nodes = true,
+            "--compress" => options.compress = true,
+            "--tolerate-truncation" => options.tolerate_truncation = true,
+            "--force" => force = true,
+            "--dropped-refs" => {
+                options.dropped_refs_file = Some(PathBuf::from(arg_iter.next().unwrap_or_else(|| fail(&usage))))
+            }
+            "--report" => options.report_file = Some(PathBuf::from(arg_iter.next().unwrap_or_else(|| fail(&usage)))),
+            "--expire-tiles" => expiry_file = Some(PathBuf::from(arg_iter.next().unwrap_or_else(|| fail(&usage)))),
+            "--expire-zooms" => {
+                let range = arg_iter.next().unwrap_or_else(|| fail(&usage));
+                expiry_zooms =
+                    parse_zoom_range(range).unwrap_or_else(|| fail(&format!("Invalid zoom range: {}", range)));
+            }
+            _ if arg.starts_with("--") => fail(&usage),
+            _ => positional_args.push(arg),
+        }
     }
 
-    let input = PathBuf::from(&args[1]);
-    let output = PathBuf::from(&args[2]);
+    if positional_args.len() < 2 {
+        fail(&usage);
+    }
+
+    let (output, inputs) = positional_args.split_last().unwrap();
+    let inputs = inputs.iter().map(PathBuf::from).collect::<Vec<_>>();
+    let output = PathBuf::from(output);
+    if output.exists() && !force {
+        fail(&format!(
+            "{} already exists, pass --force to overwrite it",
+            output.to_string_lossy()
+        ));
+    }
 
-    let mut tmp_output = output.clone();
-    tmp_output.set_extension("tmp");
+    let expiry = expiry_file.map(|file| ExpiryOptions {
+        file,
+        zooms: expiry_zooms,
+    });
 
-    match import(&input, &tmp_output, &output) {
+    // The data is written to a temporary file next to the output, which then replaces the output in one step,
+    // so that an interrupted import never leaves a truncated file behind.
+    let mut tmp_output = output.clone().into_os_string();
+    tmp_output.push(".tmp");
+    let tmp_output = PathBuf::from(tmp_output);
+
+    match import(&inputs, &tmp_output, &output, &options, expiry.as_ref()) {
         Ok(_) => println!("Successfully imported OSM data to {}", output.to_string_lossy()),
         Err(err) => {
             // Make a best-effort attempt to remove the unfinished mess
@@ -37,6 +152,9 @@ fn main() {
             for cause in err.chain() {
                 eprintln!("{}", cause);
             }
+            if err.chain().any(|cause| cause.is::<TruncatedInput>()) {
+                eprintln!("Pass --tolerate-truncation to import the complete elements anyway");
+            }
             std::process::exit(1);
         }
     }
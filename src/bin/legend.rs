@@ -0,0 +1,141 @@
+use anyhow::{anyhow, Context, Result};
+use renderer::geodata::importer::import;
+use renderer::legend::{escape_xml, Legend};
+use renderer::mapcss::parser::parse_file;
+use renderer::mapcss::styler::StyleType;
+use renderer::render::{EntityFilter, RenderOptions, TileRenderer};
+use std::env;
+use std::fs;
+use std::path::Path;
+
+struct LegendOptions {
+    stylesheet_file: String,
+    stylesheet_type: StyleType,
+    zoom: u8,
+    scale: usize,
+    output_dir: String,
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+fn render_legend(options: &LegendOptions) -> Result<usize> {
+    let stylesheet_path = Path::new(&options.stylesheet_file);
+    let base_path = stylesheet_path.parent().unwrap_or_else(|| Path::new(""));
+    let file_name = stylesheet_path
+        .file_name()
+        .and_then(|x| x.to_str())
+        .ok_or_else(|| anyhow!("Invalid stylesheet path: {}", options.stylesheet_file))?;
+    let rules = parse_file(base_path, file_name).context("Failed to parse the stylesheet file")?;
+    let legend = Legend::from_rules(&rules, options.zoom);
+
+    // The samples go through the importer just like real OSM data.
+    let temp_prefix = env::temp_dir().join(format!("osm-renderer-legend-{}", std::process::id()));
+    let (xml_file, geodata_file) = (temp_prefix.with_extension("osm"), temp_prefix.with_extension("bin"));
+    fs::write(&xml_file, legend.to_osm_xml()).context("Failed to save the legend samples")?;
+    let imported = import(&xml_file, &geodata_file);
+    let _ = fs::remove_file(&xml_file);
+    imported.context("Failed to import the legend samples")?;
+
+    let geodata_file_str = geodata_file.to_string_lossy().into_owned();
+    let tile_renderer = TileRenderer::new(
+        &geodata_file_str,
+        &options.stylesheet_file,
+        &options.stylesheet_type,
+        None,
+    );
+    let _ = fs::remove_file(&geodata_file);
+    let tile_renderer = tile_renderer?;
+
+    fs::create_dir_all(&options.output_dir).with_context(|| format!("Failed to create {}", options.output_dir))?;
+    let region = legend.swatch_region();
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Legend</title></head>\n<body>\n<table>\n",
+    );
+    for sample in &legend.samples {
+        let render_options = RenderOptions {
+            filter: EntityFilter {
+                ids: Some(Some(sample.id).into_iter().collect()),
+                ..EntityFilter::default()
+            },
+            ..RenderOptions::default()
+        };
+        let image = tile_renderer.render_region(&region, options.scale, &render_options)?;
+        let png_name = format!("{}.png", sample.id);
+        fs::write(Path::new(&options.output_dir).join(&png_name), image.to_png()?)
+            .with_context(|| format!("Failed to write {}", png_name))?;
+
+        let tags = sample
+            .tags
+            .iter()
+            .map(|(k, v)| escape_xml(&format!("{}={}", k, v)))
+            .collect::<Vec<_>>()
+            .join("<br>");
+        html.push_str(&format!(
+            "<tr><td><img src=\"{}\" width=\"{}\" height=\"{}\"></td><td><code>{}</code></td><td>{}</td></tr>\n",
+            png_name,
+            region.width,
+            region.height,
+            escape_xml(&sample.selector),
+            tags
+        ));
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    let html_file = Path::new(&options.output_dir).join("index.html");
+    fs::write(&html_file, html).with_context(|| format!("Failed to write {}", html_file.display()))?;
+
+    Ok(legend.samples.len())
+}
+
+fn main() {
+    let args: Vec<_> = env::args().collect();
+    let bin_name = args.first().map(String::as_str).unwrap_or("legend");
+    let usage = format!("Usage: {} [--scale N] [--mapsme] STYLESHEET ZOOM OUTPUT_DIR", bin_name);
+
+    let mut scale = 1;
+    let mut stylesheet_type = StyleType::Josm;
+    let mut positional_args = Vec::new();
+    let mut arg_iter = args.iter().skip(1);
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_str() {
+            "--scale" => {
+                scale = match arg_iter.next().map(|x| x.parse()) {
+                    Some(Ok(scale)) if (1..=4).contains(&scale) => scale,
+                    _ => fail("The scale should be an integer between 1 and 4"),
+                }
+            }
+            "--mapsme" => stylesheet_type = StyleType::MapsMe,
+            _ if arg.starts_with("--") => fail(&usage),
+            _ => positional_args.push(arg.clone()),
+        }
+    }
+
+    if positional_args.len() != 3 {
+        fail(&usage);
+    }
+
+    let zoom = match positional_args[1].parse() {
+        Ok(zoom) if zoom <= 20 => zoom,
+        _ => fail(&format!("Invalid zoom level: {}", positional_args[1])),
+    };
+
+    let options = LegendOptions {
+        stylesheet_file: positional_args[0].clone(),
+        stylesheet_type,
+        zoom,
+        scale,
+        output_dir: positional_args[2].clone(),
+    };
+
+    match render_legend(&options) {
+        Ok(count) => println!("Saved {} legend entries to {}", count, options.output_dir),
+        Err(err) => {
+            for cause in err.chain() {
+                eprintln!("{}", cause);
+            }
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use renderer::mapcss::lint::lint_file;
+use std::env;
+use std::path::Path;
+
+fn main() {
+    let args: Vec<_> = env::args().collect();
+    if args.len() != 2 {
+        let bin_name = args.first().map(String::as_str).unwrap_or("mapcss-check");
+        eprintln!("Usage: {} STYLESHEET", bin_name);
+        std::process::exit(1);
+    }
+
+    let stylesheet_path = Path::new(&args[1]);
+    let base_path = stylesheet_path.parent().unwrap_or_else(|| Path::new(""));
+    let file_name = match stylesheet_path.file_name().and_then(|x| x.to_str()) {
+        Some(file_name) => file_name,
+        None => {
+            eprintln!("Invalid stylesheet path: {}", args[1]);
+            std::process::exit(1);
+        }
+    };
+
+    match lint_file(base_path, file_name) {
+        Ok(diagnostics) => {
+            for diagnostic in &diagnostics {
+                println!("{}", diagnostic);
+            }
+            if !diagnostics.is_empty() {
+                eprintln!("Found {} problem(s) in {}", diagnostics.len(), args[1]);
+                std::process::exit(1);
+            }
+        }
+        Err(err) => {
+            for cause in err.chain() {
+                eprintln!("{}", cause);
+            }
+            std::process::exit(2);
+        }
+    }
+}
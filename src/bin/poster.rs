@@ -0,0 +1,152 @@
+use anyhow::{anyhow, Context, Result};
+use renderer::mapcss::styler::StyleType;
+use renderer::render::{PixelRegion, RenderOptions, TileRenderer};
+use std::env;
+use std::fs;
+
+struct PosterOptions {
+    geodata_file: String,
+    stylesheet_file: String,
+    stylesheet_type: StyleType,
+    bbox: (f64, f64, f64, f64),
+    zoom: u8,
+    scale: usize,
+    // The size of a PDF page and how much the neighboring pages overlap, in the pixels of the tiles.
+    page_size: (u32, u32),
+    overlap: u32,
+    output: String,
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+fn parse_bbox(bbox: &str) -> Result<(f64, f64, f64, f64)> {
+    let parts = bbox
+        .split(',')
+        .map(|x| x.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid bounding box: {}", bbox))?;
+    match parts.as_slice() {
+        &[min_lat, min_lon, max_lat, max_lon] => Ok((min_lat, min_lon, max_lat, max_lon)),
+        _ => Err(anyhow!(
+            "Expected 4 comma-separated numbers in the bounding box, got {}",
+            bbox
+        )),
+    }
+}
+
+fn parse_page_size(page_size: &str) -> Option<(u32, u32)> {
+    let (width, height) = page_size.split_once('x')?;
+    match (width.parse(), height.parse()) {
+        (Ok(width), Ok(height)) if width > 0 && height > 0 => Some((width, height)),
+        _ => None,
+    }
+}
+
+fn render_poster(options: &PosterOptions) -> Result<()> {
+    let (min_lat, min_lon, max_lat, max_lon) = options.bbox;
+    let region = PixelRegion::from_bbox(min_lat, min_lon, max_lat, max_lon, options.zoom)?;
+
+    let tile_renderer = TileRenderer::new(
+        &options.geodata_file,
+        &options.stylesheet_file,
+        &options.stylesheet_type,
+        None,
+    )?;
+
+    if options.output.ends_with(".pdf") {
+        let (page_width, page_height) = options.page_size;
+        let pages = region.split_into_pages(page_width, page_height, options.overlap)?;
+        println!("Rendering {} pages", pages.len());
+        let pdf = tile_renderer.render_pdf(&pages, options.scale, &RenderOptions::default())?;
+        fs::write(&options.output, pdf).with_context(|| format!("Failed to write {}", options.output))?;
+        return Ok(());
+    }
+
+    println!(
+        "Rendering a {}x{} image",
+        region.width as usize * options.scale,
+        region.height as usize * options.scale
+    );
+    let image = tile_renderer.render_region(&region, options.scale, &RenderOptions::default())?;
+    let png = image.to_png()?;
+    fs::write(&options.output, png).with_context(|| format!("Failed to write {}", options.output))?;
+
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<_> = env::args().collect();
+    let bin_name = args.first().map(String::as_str).unwrap_or("poster");
+    let usage = format!(
+        "Usage: {} [--scale N] [--mapsme] [--page WIDTHxHEIGHT] [--overlap N] GEODATA STYLESHEET \
+         MIN_LAT,MIN_LON,MAX_LAT,MAX_LON ZOOM OUTPUT",
+        bin_name
+    );
+
+    let mut scale = 1;
+    // An A4 page.
+    let mut page_size = (595, 842);
+    let mut overlap = 40;
+    let mut stylesheet_type = StyleType::Josm;
+    let mut positional_args = Vec::new();
+    let mut arg_iter = args.iter().skip(1);
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_str() {
+            "--scale" => {
+                scale = match arg_iter.next().map(|x| x.parse()) {
+                    Some(Ok(scale)) if (1..=4).contains(&scale) => scale,
+                    _ => fail("The scale should be an integer between 1 and 4"),
+                }
+            }
+            "--mapsme" => stylesheet_type = StyleType::MapsMe,
+            "--page" => {
+                page_size = match arg_iter.next().and_then(|x| parse_page_size(x)) {
+                    Some(page_size) => page_size,
+                    _ => fail("The page size should look like WIDTHxHEIGHT, e.g. 595x842"),
+                }
+            }
+            "--overlap" => {
+                overlap = match arg_iter.next().map(|x| x.parse()) {
+                    Some(Ok(overlap)) => overlap,
+                    _ => fail("The overlap should be a non-negative integer"),
+                }
+            }
+            _ if arg.starts_with("--") => fail(&usage),
+            _ => positional_args.push(arg.clone()),
+        }
+    }
+
+    if positional_args.len() != 5 {
+        fail(&usage);
+    }
+
+    let bbox = parse_bbox(&positional_args[2]).unwrap_or_else(|err| fail(&format!("{:#}", err)));
+    let zoom = positional_args[3]
+        .parse()
+        .unwrap_or_else(|_| fail(&format!("Invalid zoom level: {}", positional_args[3])));
+
+    let options = PosterOptions {
+        geodata_file: positional_args[0].clone(),
+        stylesheet_file: positional_args[1].clone(),
+        stylesheet_type,
+        bbox,
+        zoom,
+        scale,
+        page_size,
+        overlap,
+        output: positional_args[4].clone(),
+    };
+
+    match render_poster(&options) {
+        Ok(_) => println!("Successfully saved the poster to {}", options.output),
+        Err(err) => {
+            for cause in err.chain() {
+                eprintln!("{}", cause);
+            }
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use anyhow::{anyhow, Context, Result};
+use renderer::mapcss::color::Color;
+use renderer::mapcss::styler::StyleType;
+use renderer::render::{PixelRegion, TileRenderer};
+use renderer::tile::{Tile, MAX_ZOOM, TILE_SIZE};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::{env, thread};
+
+struct PrerenderOptions {
+    geodata_file: String,
+    stylesheet_file: String,
+    stylesheet_type: StyleType,
+    bbox: (f64, f64, f64, f64),
+    min_zoom: u8,
+    max_zoom: u8,
+    scale: usize,
+    threads: usize,
+    output_dir: String,
+}
+
+// The color and the image of the canvas.
+type Canvas = (Option<Color>, Option<String>);
+
+// The blank tiles look the same at every zoom level with the same canvas color and image, so the first one of every
+// canvas is saved and all others are hard links to it.
+#[derive(Default)]
+struct BlankTiles {
+    saved: Mutex<HashMap<Canvas, PathBuf>>,
+    count: AtomicUsize,
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+fn parse_bbox(bbox: &str) -> Result<(f64, f64, f64, f64)> {
+    let parts = bbox
+        .split(',')
+        .map(|x| x.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid bounding box: {}", bbox))?;
+    match parts.as_slice() {
+        &[min_lat, min_lon, max_lat, max_lon] => Ok((min_lat, min_lon, max_lat, max_lon)),
+        _ => Err(anyhow!(
+            "Expected 4 comma-separated numbers in the bounding box, got {}",
+            bbox
+        )),
+    }
+}
+
+// Either `Z` or `MIN_Z-MAX_Z`.
+fn parse_zoom_range(zooms: &str) -> Option<(u8, u8)> {
+    let (min_zoom, max_zoom) = zooms.split_once('-').unwrap_or((zooms, zooms));
+    match (min_zoom.parse(), max_zoom.parse()) {
+        (Ok(min_zoom), Ok(max_zoom)) if min_zoom <= max_zoom && max_zoom <= MAX_ZOOM => Some((min_zoom, max_zoom)),
+        _ => None,
+    }
+}
+
+// The same paths as the URLs of the tiles on the server, so that the directory can be served as is.
+fn tile_path(output_dir: &str, tile: &Tile, scale: usize) -> PathBuf {
+    let file_name = match scale {
+        1 => format!("{}.png", tile.y),
+        _ => format!("{}@{}x.png", tile.y, scale),
+    };
+    Path::new(output_dir)
+        .join(tile.zoom.to_string())
+        .join(tile.x.to_string())
+        .join(file_name)
+}
+
+fn save_tile(
+    tile_renderer: &TileRenderer,
+    tile: &Tile,
+    options: &PrerenderOptions,
+    blank_tiles: &BlankTiles,
+) -> Result<()> {
+    let path = tile_path(&options.output_dir, tile, options.scale);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+
+    if tile_renderer.is_blank(tile) {
+        blank_tiles.count.fetch_add(1, Ordering::Relaxed);
+        let styler = tile_renderer.styler();
+        let canvas = (
+            styler.canvas_fill_color(tile.zoom).cloned(),
+            styler.canvas_fill_image(tile.zoom).map(str::to_string),
+        );
+        let mut saved = blank_tiles.saved.lock().unwrap();
+        if let Some(saved_path) = saved.get(&canvas) {
+            // Linking fails if the file is already there (e.g. from an earlier run).
+            let _ = fs::remove_file(&path);
+            // Some file systems have no hard links, so the tile is copied there.
+            if fs::hard_link(saved_path, &path).is_err() {
+                fs::copy(saved_path, &path).with_context(|| format!("Failed to write {}", path.display()))?;
+            }
+            return Ok(());
+        }
+        write_tile(tile_renderer, tile, options.scale, &path)?;
+        saved.insert(canvas, path);
+        return Ok(());
+    }
+
+    write_tile(tile_renderer, tile, options.scale, &path)
+}
+
+fn write_tile(tile_renderer: &TileRenderer, tile: &Tile, scale: usize, path: &Path) -> Result<()> {
+    let png = tile_renderer
+        .render_png(tile, scale)
+        .with_context(|| format!("Failed to render {}/{}/{}", tile.zoom, tile.x, tile.y))?;
+    // A hard link to a blank tile must not be overwritten in place, since that changes all other links too.
+    let _ = fs::remove_file(path);
+    fs::write(path, png).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+fn prerender(options: &PrerenderOptions) -> Result<()> {
+    let tile_renderer = TileRenderer::new(
+        &options.geodata_file,
+        &options.stylesheet_file,
+        &options.stylesheet_type,
+        None,
+    )?;
+    let blank_tiles = BlankTiles::default();
+    let mut total_count = 0;
+
+    let (min_lat, min_lon, max_lat, max_lon) = options.bbox;
+    let tile_size = TILE_SIZE;
+    for zoom in options.min_zoom..=options.max_zoom {
+        let region = PixelRegion::from_bbox(min_lat, min_lon, max_lat, max_lon, zoom)?;
+        let (min_x, max_x) = (region.left / tile_size, (region.left + region.width - 1) / tile_size);
+        let (min_y, max_y) = (region.top / tile_size, (region.top + region.height - 1) / tile_size);
+        let columns = (max_x - min_x + 1) as usize;
+        let tile_count = columns * (max_y - min_y + 1) as usize;
+        println!("Rendering {} tiles of zoom level {}", tile_count, zoom);
+
+        let next_tile = AtomicUsize::new(0);
+        let results = thread::scope(|scope| {
+            let workers = (0..options.threads)
+                .map(|_| {
+                    scope.spawn(|| loop {
+                        let idx = next_tile.fetch_add(1, Ordering::Relaxed);
+                        if idx >= tile_count {
+                            return Ok(());
+                        }
+                        let tile = Tile {
+                            zoom,
+                            x: min_x + (idx % columns) as u32,
+                            y: min_y + (idx / columns) as u32,
+                        };
+                        if let Err(err) = save_tile(&tile_renderer, &tile, options, &blank_tiles) {
+                            // The other threads stop after their current tiles.
+                            next_tile.store(tile_count, Ordering::Relaxed);
+                            return Err(err);
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().unwrap())
+                .collect::<Vec<Result<()>>>()
+        });
+        results.into_iter().collect::<Result<()>>()?;
+        total_count += tile_count;
+    }
+
+    println!(
+        "{} of {} tiles are blank and link to {} saved ones",
+        blank_tiles.count.load(Ordering::Relaxed),
+        total_count,
+        blank_tiles.saved.lock().unwrap().len()
+    );
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<_> = env::args().collect();
+    let bin_name = args.first().map(String::as_str).unwrap_or("prerender");
+    let usage = format!(
+        "Usage: {} [--scale N] [--threads N] [--mapsme] GEODATA STYLESHEET MIN_LAT,MIN_LON,MAX_LAT,MAX_LON \
+         MIN_ZOOM-MAX_ZOOM OUTPUT_DIR",
+        bin_name
+    );
+
+    let mut scale = 1;
+    let mut threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let mut stylesheet_type = StyleType::Josm;
+    let mut positional_args = Vec::new();
+    let mut arg_iter = args.iter().skip(1);
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_str() {
+            "--scale" => {
+                scale = match arg_iter.next().map(|x| x.parse()) {
+                    Some(Ok(scale)) if (1..=4).contains(&scale) => scale,
+                    _ => fail("The scale should be an integer between 1 and 4"),
+                }
+            }
+            "--threads" => {
+                threads = match arg_iter.next().map(|x| x.parse()) {
+                    Some(Ok(threads)) if threads > 0 => threads,
+                    _ => fail("The number of threads should be a positive integer"),
+                }
+            }
+            "--mapsme" => stylesheet_type = StyleType::MapsMe,
+            _ if arg.starts_with("--") => fail(&usage),
+            _ => positional_args.push(arg.clone()),
+        }
+    }
+
+    if positional_args.len() != 5 {
+        fail(&usage);
+    }
+
+    let bbox = parse_bbox(&positional_args[2]).unwrap_or_else(|err| fail(&format!("{:#}", err)));
+    let (min_zoom, max_zoom) = parse_zoom_range(&positional_args[3])
+        .unwrap_or_else(|| fail(&format!("Invalid zoom levels: {}", positional_args[3])));
+
+    let options = PrerenderOptions {
+        geodata_file: positional_args[0].clone(),
+        stylesheet_file: positional_args[1].clone(),
+        stylesheet_type,
+        bbox,
+        min_zoom,
+        max_zoom,
+        scale,
+        threads,
+        output_dir: positional_args[4].clone(),
+    };
+
+    match prerender(&options) {
+        Ok(_) => println!("Successfully saved the tiles to {}", options.output_dir),
+        Err(err) => {
+            for cause in err.chain() {
+                eprintln!("{}", cause);
+            }
+            std::process::exit(1);
+        }
+    }
+}
@@ -1,6 +1,14 @@
-use renderer::http_server::run_server;
+use anyhow::{Context, Result};
+use renderer::draw::labeler::LabelLimits;
+use renderer::draw::png_writer::PngCompression;
+use renderer::geodata::prefilter::Prefilter;
+use renderer::geodata::zoom_masks::ZoomMasks;
+use renderer::http_server::{run_server_with_styles, ServerOptions};
 use renderer::mapcss::styler::StyleType;
+use renderer::overlay::{Overlay, OverlayStyle};
+use renderer::render::TileRenderer;
 use std::env;
+use std::time::Duration;
 use tini::Ini;
 
 fn fail() -> ! {
@@ -36,28 +44,188 @@ fn main() {
     };
 
     let server_address = get_value_from_config(&config, "http", "address");
-    let geodata_file = get_value_from_config(&config, "geodata", "file");
-
-    let style_section = "style";
-    let stylesheet_file = get_value_from_config(&config, style_section, "file");
-    let stylesheet_type = match get_value_from_config(&config, style_section, "type").as_str() {
-        "josm" => StyleType::Josm,
-        "mapsme" => StyleType::MapsMe,
-        unknown_style => {
-            eprintln!("Unknown stylesheet type: {}", unknown_style);
+    let mut server_options = ServerOptions::default();
+    if let Some(queue_size) = config.get::<String>("http", "queue-size") {
+        server_options.queue_size = match queue_size.parse() {
+            Ok(queue_size) if queue_size > 0 => queue_size,
+            _ => {
+                eprintln!("Invalid queue size: {}", queue_size);
+                fail();
+            }
+        };
+    }
+    if let Some(threads) = config.get::<String>("http", "threads") {
+        server_options.threads = match threads.parse() {
+            Ok(threads) if threads > 0 => Some(threads),
+            _ => {
+                eprintln!("Invalid number of threads: {}", threads);
+                fail();
+            }
+        };
+    }
+    server_options.pin_threads = match config.get::<String>("http", "pin-threads").as_deref() {
+        None | Some("false") => false,
+        Some("true") => true,
+        Some(other) => {
+            eprintln!("Invalid pin-threads value (expected true or false): {}", other);
+            fail();
+        }
+    };
+    if let Some(timeout_ms) = config.get::<String>("http", "timeout-ms") {
+        server_options.timeout = match timeout_ms.parse() {
+            Ok(timeout_ms) => Some(Duration::from_millis(timeout_ms)),
+            Err(_) => {
+                eprintln!("Invalid timeout: {}", timeout_ms);
+                fail();
+            }
+        };
+    }
+    server_options.render_time_header = match config.get::<String>("http", "render-time-header").as_deref() {
+        None | Some("false") => false,
+        Some("true") => true,
+        Some(other) => {
+            eprintln!("Invalid render-time-header value (expected true or false): {}", other);
             fail();
         }
     };
-    let font_size_multiplier =
+    server_options.request_log = match config.get::<String>("http", "request-log").as_deref() {
+        None | Some("true") => true,
+        Some("false") => false,
+        Some(other) => {
+            eprintln!("Invalid request-log value (expected true or false): {}", other);
+            fail();
+        }
+    };
+    if let Some(allow_origin) = config.get::<String>("http", "allow-origin") {
+        server_options.allow_origin = allow_origin;
+    }
+    server_options.not_found_outside_data = match config.get::<String>("http", "outside-data").as_deref() {
+        None | Some("blank") => false,
+        Some("404") => true,
+        Some(other) => {
+            eprintln!("Invalid outside-data value (expected blank or 404): {}", other);
+            fail();
+        }
+    };
+    server_options.public_url = config.get::<String>("http", "public-url");
+    if let Some(base_path) = config.get::<String>("http", "base-path") {
+        if !base_path.starts_with('/') {
+            eprintln!(
+                "Invalid base-path value (expected a path starting with /): {}",
+                base_path
+            );
+            fail();
+        }
+        server_options.base_path = base_path;
+    }
+    server_options.trust_forwarded_for = match config.get::<String>("http", "forwarded-for").as_deref() {
+        None | Some("false") => false,
+        Some("true") => true,
+        Some(other) => {
+            eprintln!("Invalid forwarded-for value (expected true or false): {}", other);
+            fail();
+        }
+    };
+    server_options.preview_page = match config.get::<String>("http", "preview").as_deref() {
+        None | Some("true") => true,
+        Some("false") => false,
+        Some(other) => {
+            eprintln!("Invalid preview value (expected true or false): {}", other);
+            fail();
+        }
+    };
+    server_options.search = match config.get::<String>("http", "search").as_deref() {
+        None | Some("false") => false,
+        Some("true") => true,
+        Some(other) => {
+            eprintln!("Invalid search value (expected true or false): {}", other);
+            fail();
+        }
+    };
+    if let Some(slow_tile_ms) = config.get::<String>("http", "slow-tile-ms") {
+        server_options.slow_tile_threshold = match slow_tile_ms.parse() {
+            Ok(slow_tile_ms) => Some(Duration::from_millis(slow_tile_ms)),
+            Err(_) => {
+                eprintln!("Invalid slow tile threshold: {}", slow_tile_ms);
+                fail();
+            }
+        };
+    }
+    let drawing_threads = config
+        .get::<String>("http", "drawing-threads")
+        .map(|threads_str| match threads_str.parse() {
+            Ok(threads) if threads > 0 => threads,
+            _ => {
+                eprintln!("Invalid number of drawing threads: {}", threads_str);
+                fail();
+            }
+        })
+        .unwrap_or(1);
+    if server_options.pin_threads && drawing_threads > 1 {
+        // The drawing threads of a tile would all run on the CPU of its rendering thread.
+        eprintln!("pin-threads can't be used together with drawing-threads");
+        fail();
+    }
+    let png_compression = match config.get::<String>("http", "png-compression").as_deref() {
+        None | Some("default") => PngCompression::Default,
+        Some("fast") => PngCompression::Fast,
+        Some("best") => PngCompression::Best,
+        Some(other) => {
+            eprintln!(
+                "Invalid png-compression value (expected fast, default or best): {}",
+                other
+            );
+            fail();
+        }
+    };
+
+    let load_zoom_masks = |section: &str| {
         config
-            .get::<String>(style_section, "font-mul")
-            .map(|multiplier_str| match multiplier_str.parse() {
-                Ok(multiplier) => multiplier,
-                Err(_) => {
-                    eprintln!("Invalid font size multiplier: {}", multiplier_str);
+            .get::<String>(section, "zoom-masks")
+            .map(|file| match ZoomMasks::load(&file) {
+                Ok(zoom_masks) => zoom_masks,
+                Err(err) => {
+                    eprintln!("{:#}", err);
                     fail();
                 }
-            });
+            })
+    };
+    let geodata_file = get_value_from_config(&config, "geodata", "file");
+    let zoom_masks = load_zoom_masks("geodata");
+    // Every [geodata.NAME] section adds another geodata file (e.g. for an adjacent region).
+    let extra_geodata = config
+        .iter()
+        .filter(|(section_name, _)| section_name.starts_with("geodata."))
+        .map(|(section_name, _)| {
+            (
+                get_value_from_config(&config, section_name, "file"),
+                load_zoom_masks(section_name),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    // Every [overlay.NAME] section describes a GeoJSON file and the style to draw it with.
+    let mut overlays = Vec::new();
+    for (section_name, section) in config.iter() {
+        if section_name != "overlay" && !section_name.starts_with("overlay.") {
+            continue;
+        }
+        let mut style = OverlayStyle::default();
+        for (name, value) in section.iter().filter(|(name, _)| name.as_str() != "file") {
+            if let Err(err) = style.set_property(name, value) {
+                eprintln!("Invalid overlay style in section [{}]: {}", section_name, err);
+                fail();
+            }
+        }
+        let file = get_value_from_config(&config, section_name, "file");
+        match Overlay::load(&file, style) {
+            Ok(overlay) => overlays.push(overlay),
+            Err(err) => {
+                eprintln!("Failed to load the overlay from section [{}]: {:#}", section_name, err);
+                fail();
+            }
+        }
+    }
 
     let osm_ids = if args.len() >= 3 {
         Some(
@@ -70,14 +238,147 @@ fn main() {
         None
     };
 
-    let res = run_server(
-        &server_address,
-        &geodata_file,
-        &stylesheet_file,
-        &stylesheet_type,
-        font_size_multiplier,
-        osm_ids,
-    );
+    // Every [style.NAME] section adds another stylesheet to compare with the main one, with the same settings as
+    // [style] unless the section overrides them.
+    let get_style_value = |section: &str, name: &str| {
+        config
+            .get::<String>(section, name)
+            .or_else(|| config.get::<String>("style", name))
+    };
+    let build_renderer = |style_section: &str| -> Result<TileRenderer<'static>> {
+        let stylesheet_file = get_value_from_config(&config, style_section, "file");
+        let stylesheet_type = match get_style_value(style_section, "type")
+            .unwrap_or_else(|| get_value_from_config(&config, style_section, "type"))
+            .as_str()
+        {
+            "josm" => StyleType::Josm,
+            "mapsme" => StyleType::MapsMe,
+            unknown_style => {
+                eprintln!("Unknown stylesheet type: {}", unknown_style);
+                fail();
+            }
+        };
+        let font_size_multiplier =
+            get_style_value(style_section, "font-mul").map(|multiplier_str| match multiplier_str.parse() {
+                Ok(multiplier) => multiplier,
+                Err(_) => {
+                    eprintln!("Invalid font size multiplier: {}", multiplier_str);
+                    fail();
+                }
+            });
+        let style_cache_capacity =
+            get_style_value(style_section, "cache-size").map(|capacity_str| match capacity_str.parse() {
+                Ok(capacity) if capacity > 0 => capacity,
+                _ => {
+                    eprintln!("Invalid style cache size: {}", capacity_str);
+                    fail();
+                }
+            });
+        let label_languages = get_style_value(style_section, "label-languages")
+            .map(|languages| {
+                languages
+                    .split(',')
+                    .map(|language| language.trim().to_string())
+                    .filter(|language| !language.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let label_limits = get_style_value(style_section, "max-labels")
+            .map(|limits_str| match LabelLimits::parse(&limits_str) {
+                Ok(label_limits) => label_limits,
+                Err(err) => {
+                    eprintln!("Invalid label limits: {:#}", err);
+                    fail();
+                }
+            })
+            .unwrap_or_default();
+        let mut prefilter = Prefilter::default();
+        if let Some(lengths_str) = get_style_value(style_section, "prefilter-min-length") {
+            prefilter = match prefilter.with_min_lengths(&lengths_str) {
+                Ok(prefilter) => prefilter,
+                Err(err) => {
+                    eprintln!("Invalid prefilter-min-length value: {:#}", err);
+                    fail();
+                }
+            };
+        }
+        if let Some(tags_str) = get_style_value(style_section, "prefilter-tags") {
+            prefilter = match prefilter.with_kept_tags(&tags_str) {
+                Ok(prefilter) => prefilter,
+                Err(err) => {
+                    eprintln!("Invalid prefilter-tags value: {:#}", err);
+                    fail();
+                }
+            };
+        }
+        let debug_entity_id =
+            get_style_value(style_section, "debug-entity").map(|id_str| match id_str.trim().parse::<u64>() {
+                Ok(id) => id,
+                Err(_) => {
+                    eprintln!("Invalid debug entity id: {}", id_str);
+                    fail();
+                }
+            });
+        let strict_stylesheet = match get_style_value(style_section, "strict").as_deref() {
+            None | Some("true") => true,
+            Some("false") => false,
+            Some(other) => {
+                eprintln!("Invalid strict value (expected true or false): {}", other);
+                fail();
+            }
+        };
+        let gamma_correction = match get_style_value(style_section, "gamma-correction").as_deref() {
+            None | Some("false") => false,
+            Some("true") => true,
+            Some(other) => {
+                eprintln!("Invalid gamma-correction value (expected true or false): {}", other);
+                fail();
+            }
+        };
+
+        let mut tile_renderer = TileRenderer::new_with_strictness(
+            &geodata_file,
+            &stylesheet_file,
+            &stylesheet_type,
+            font_size_multiplier,
+            strict_stylesheet,
+        )?
+        .with_osm_ids(osm_ids.clone())
+        .with_overlays(overlays.clone())
+        .with_gamma_correction(gamma_correction)
+        .with_label_languages(label_languages)
+        .with_label_limits(label_limits)
+        .with_prefilter(prefilter)
+        .with_debug_entity_id(debug_entity_id)
+        .with_drawing_threads(drawing_threads)
+        .with_png_compression(png_compression);
+        if let Some(capacity) = style_cache_capacity {
+            tile_renderer = tile_renderer.with_style_cache_capacity(capacity);
+        }
+        // The zoom masks are computed for the main stylesheet, so the other ones are rendered without them.
+        let is_main_style = style_section == "style";
+        if let Some(zoom_masks) = zoom_masks.as_ref().filter(|_| is_main_style) {
+            tile_renderer = tile_renderer.with_zoom_masks(zoom_masks.clone())?;
+        }
+        for (file, zoom_masks) in &extra_geodata {
+            let zoom_masks = zoom_masks.as_ref().filter(|_| is_main_style).cloned();
+            tile_renderer = tile_renderer.with_geodata_file(file, zoom_masks)?;
+        }
+        Ok(tile_renderer)
+    };
+
+    let res = build_renderer("style").and_then(|tile_renderer| {
+        let styles = config
+            .iter()
+            .filter_map(|(section_name, _)| section_name.strip_prefix("style."))
+            .map(|name| {
+                let style_renderer = build_renderer(&format!("style.{}", name))
+                    .with_context(|| format!("Failed to load the stylesheet from section [style.{}]", name))?;
+                Ok((name.to_string(), style_renderer))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        run_server_with_styles(&server_address, tile_renderer, styles, &server_options)
+    });
 
     if let Err(e) = res {
         for cause in e.chain() {
@@ -1,22 +1,94 @@
+use crate::coords::Coords;
 use crate::draw::fill::{fill_contour, Filler};
 use crate::draw::icon_cache::IconCache;
-use crate::draw::labeler::Labeler;
-use crate::draw::line::draw_lines;
-use crate::draw::png_writer::rgb_triples_to_png;
-use crate::draw::point_pairs::PointPairCollection;
-use crate::draw::tile_pixels::{RgbTriples, TilePixels};
-use crate::geodata::reader::{Node, OsmEntities, OsmEntity};
-use crate::mapcss::styler::{Style, StyledArea, Styler, TextPosition};
-use crate::tile::Tile;
+use crate::draw::labelable::Labelable;
+use crate::draw::labeler::{LabelCandidate, LabelLimits, Labeler};
+use crate::draw::line::{draw_lines, LineOptions, LineShape};
+use crate::draw::png_writer::{rgb_bytes_to_png, PngCompression};
+use crate::draw::point::Point;
+use crate::draw::point_pairs::{PointPairCollection, PointPairIter};
+use crate::draw::tile_pixels::{BoundingBox, Canvas, RecordedPixels, RgbTriples, RgbaColor, TilePixels};
+use crate::draw::vector::{VectorCanvas, VectorStroke};
+use crate::geodata::reader::{Multipolygon, Node, OsmArea, OsmEntities, OsmEntity, Relation, Way};
+use crate::mapcss::color::Color;
+use crate::mapcss::styler::{BlendMode, LineCap, LineJoin, Style, StyledArea, Styler};
+use crate::overlay::{Geometry, Overlay};
+use crate::tile::{coords_to_xy_tile_relative, Tile};
 use anyhow::Result;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
 use std::path::Path;
 use std::sync::Arc;
+use std::time::Instant;
 
 pub struct Drawer {
     icon_cache: IconCache,
     labeler: Labeler,
+    drawing_threads: usize,
 }
 
+// An entity that is drawn as a polygon or a line.
+#[derive(Clone, Copy)]
+enum Figure<'a, 'e> {
+    Way(&'a Way<'e>),
+    Multipolygon(&'a Multipolygon<'e>),
+    Relation(&'a Relation<'e>),
+    // The casings of the ways that continue each other (see `CasingChains`).
+    WayChain(&'a WayChain<'a, 'e>),
+}
+
+// The ways in the order they are drawn, with whether each of them is drawn from its last node to its first one.
+type WayChain<'a, 'e> = [(&'a Way<'e>, bool)];
+
+// Every way starts where the previous one ends, so the shared nodes are taken once.
+fn chain_nodes<'e>(chain: &WayChain<'_, 'e>) -> Vec<Node<'e>> {
+    let mut nodes = Vec::new();
+    for (idx, (way, reversed)) in chain.iter().enumerate() {
+        let node_count = way.node_count();
+        let skipped = if idx == 0 { 0 } else { 1 };
+        let node_at = |pos: usize| way.get_node(if *reversed { node_count - 1 - pos } else { pos });
+        nodes.extend((skipped..node_count).map(node_at));
+    }
+    nodes
+}
+
+impl Figure<'_, '_> {
+    // The lines of the figure (or the rings of a multipolygon) in the pixels of the tile, without rounding.
+    fn to_lines(self, tile: &Tile, scale: f64) -> Vec<Vec<(f64, f64)>> {
+        let to_line = |nodes: &mut dyn Iterator<Item = Node<'_>>| {
+            nodes
+                .map(|node| {
+                    let (x, y) = coords_to_xy_tile_relative(&node, tile);
+                    (x * scale, y * scale)
+                })
+                .collect()
+        };
+        match self {
+            Figure::Way(way) => vec![to_line(&mut (0..way.node_count()).map(|idx| way.get_node(idx)))],
+            Figure::Multipolygon(rel) => (0..rel.polygon_count())
+                .map(|idx| {
+                    let polygon = rel.get_polygon(idx);
+                    to_line(&mut (0..polygon.node_count()).map(|idx| polygon.get_node(idx)))
+                })
+                .collect(),
+            Figure::Relation(rel) => (0..rel.line_count())
+                .map(|idx| {
+                    let line = rel.get_line(idx);
+                    to_line(&mut (0..line.node_count()).map(|idx| line.get_node(idx)))
+                })
+                .collect(),
+            Figure::WayChain(chain) => vec![to_line(&mut chain_nodes(chain).into_iter())],
+        }
+    }
+}
+
+// With several drawing threads, the figures are drawn in batches of this size (per thread)
+// so that the recorded pixels don't take too much memory.
+const FIGURES_PER_THREAD: usize = 32;
+
+const DEBUG_FONT_SIZE: f64 = 10.0;
+
 #[derive(Clone, Eq, PartialEq, Hash)]
 enum DrawType {
     Fill,
@@ -29,14 +101,373 @@ pub struct TileRenderedPixels {
     pub dimension: usize,
 }
 
+// Diagnostic drawings on top of a tile, e.g. to see where the tiles end or why a label is missing.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DebugOverlays {
+    // The boundaries and the id of the tile.
+    pub grid: bool,
+    // The bounding boxes of the labels: blue for the placed ones, red for the ones that collided with others.
+    pub labels: bool,
+    // The ids of the drawn entities, at their label positions.
+    pub ids: bool,
+}
+
+impl DebugOverlays {
+    fn is_empty(&self) -> bool {
+        !self.grid && !self.labels && !self.ids
+    }
+}
+
+#[derive(Default)]
+pub struct DrawOptions {
+    // If drawing takes longer than this, it's aborted with `DeadlineExceeded`.
+    pub deadline: Option<Instant>,
+    pub debug: DebugOverlays,
+}
+
+// Returned (wrapped in anyhow::Error) when a tile couldn't be drawn before its deadline.
+#[derive(Debug)]
+pub struct DeadlineExceeded;
+
+impl fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("rendering deadline exceeded")
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+// The areas are filled and the lines are drawn level by level. The level of a figure is determined by its major
+// z-index first (1 for the fills and 3 for the lines by default, just like in JOSM), and then by its layer. Within a
+// level, all areas are filled first, then all casings are drawn, and then all strokes. This way, the fills are
+// covered by all lines unless a style says otherwise, the roads of the same layer look connected at junctions,
+// and the bridges cover the roads below them, while the tunnels are covered by the roads above them.
+struct DrawStep<'a, 'e> {
+    figure: Figure<'a, 'e>,
+    style: &'a Style,
+    is_fill: bool,
+    major_z_index: f64,
+    layer: i64,
+}
+
+const DEFAULT_FILL_MAJOR_Z_INDEX: f64 = 1.0;
+const DEFAULT_LINE_MAJOR_Z_INDEX: f64 = 3.0;
+
+impl<'a, 'e> DrawStep<'a, 'e> {
+    fn new(figure: Figure<'a, 'e>, style: &'a Style, is_fill: bool) -> DrawStep<'a, 'e> {
+        let default_major_z_index = if is_fill {
+            DEFAULT_FILL_MAJOR_Z_INDEX
+        } else {
+            DEFAULT_LINE_MAJOR_Z_INDEX
+        };
+        DrawStep {
+            figure,
+            style,
+            is_fill,
+            major_z_index: style.major_z_index.unwrap_or(default_major_z_index),
+            layer: style.layer.unwrap_or(0),
+        }
+    }
+
+    fn compare_levels(&self, other: &DrawStep<'_, '_>) -> Ordering {
+        self.major_z_index
+            .total_cmp(&other.major_z_index)
+            .then_with(|| self.layer.cmp(&other.layer))
+    }
+}
+
+// Every area is filled, and the lines of everything except the multipolygons are drawn, in the order of the levels.
+fn to_draw_steps<'a, 'e>(
+    styled_areas: &'a [(StyledArea<'e, 'a>, Arc<Style>)],
+    styled_relations: &'a [(&'a Relation<'e>, Arc<Style>)],
+) -> Vec<DrawStep<'a, 'e>> {
+    let areas = styled_areas.iter().map(|(area, style)| match area {
+        StyledArea::Way(way) => (Figure::Way(way), style.as_ref()),
+        StyledArea::Multipolygon(rel) => (Figure::Multipolygon(rel), style.as_ref()),
+    });
+    let relations = styled_relations
+        .iter()
+        .map(|(relation, style)| (Figure::Relation(relation), style.as_ref()));
+    let mut steps = areas
+        .clone()
+        .map(|(figure, style)| DrawStep::new(figure, style, true))
+        .chain(
+            areas
+                .filter(|(figure, _)| !matches!(figure, Figure::Multipolygon(_)))
+                .chain(relations)
+                .map(|(figure, style)| DrawStep::new(figure, style, false)),
+        )
+        .collect::<Vec<_>>();
+    // The sort is stable, so the figures of a level are still sorted by z-index, and the fills go first.
+    steps.sort_by(DrawStep::compare_levels);
+    steps
+}
+
+fn select_figures<'a, 'e>(level_steps: &[DrawStep<'a, 'e>], is_fill: bool) -> Vec<(Figure<'a, 'e>, &'a Style)> {
+    level_steps
+        .iter()
+        .filter(|step| step.is_fill == is_fill)
+        .map(|step| (step.figure, step.style))
+        .collect()
+}
+
+// Two ways continue each other if they end at the same node, no other way with the same casing ends there, and their
+// casings look the same and are drawn at the same z-index. The casings of such ways (usually the parts of the same
+// street) are drawn as one line, so that they are joined at the shared node just like at the inner nodes of a way,
+// instead of leaving a gap between the butt ends, and their dashes go on across the node.
+struct CasingChains<'a, 'e> {
+    chains: Vec<Vec<(&'a Way<'e>, bool)>>,
+    positions: Vec<CasingPosition>,
+}
+
+#[derive(Clone, Copy)]
+enum CasingPosition {
+    Single,
+    // The chain with this index is drawn in place of the line.
+    Chain(usize),
+    // The line is drawn as a part of a chain in another place.
+    Merged,
+}
+
+// The casing properties of a style that must be equal for the casings to be merged.
+#[derive(Eq, Hash, PartialEq)]
+struct CasingKey<'s> {
+    color: &'s Color,
+    width: u64,
+    dashes: Option<Vec<u64>>,
+    line_cap: &'s Option<LineCap>,
+    line_join: &'s Option<LineJoin>,
+    blend_mode: Option<BlendMode>,
+    z_index: u64,
+}
+
+impl<'a, 'e> CasingChains<'a, 'e> {
+    fn new(lines: &[(Figure<'a, 'e>, &'a Style)]) -> CasingChains<'a, 'e> {
+        // The ends of the candidate ways are identified by the casing of the way and by their coordinates.
+        let mut casing_ids = HashMap::new();
+        let mut ends = HashMap::<_, Vec<(usize, bool)>>::new();
+        let mut ways = vec![None; lines.len()];
+        for (idx, (figure, style)) in lines.iter().enumerate() {
+            let Figure::Way(way) = figure else {
+                continue;
+            };
+            let (Some(color), Some(width)) = (&style.casing_color, style.casing_width) else {
+                continue;
+            };
+            if way.node_count() < 2 || way.is_closed() {
+                continue;
+            }
+            let key = CasingKey {
+                color,
+                width: width.to_bits(),
+                dashes: style
+                    .casing_dashes
+                    .as_ref()
+                    .map(|dashes| dashes.iter().map(|dash| dash.to_bits()).collect()),
+                line_cap: &style.casing_line_cap,
+                line_join: &style.casing_line_join,
+                blend_mode: style.blend_mode,
+                z_index: style.z_index.to_bits(),
+            };
+            let next_casing_id = casing_ids.len();
+            let casing_id = *casing_ids.entry(key).or_insert(next_casing_id);
+            for at_start in [true, false] {
+                ends.entry((casing_id, end_coords(way, at_start)))
+                    .or_default()
+                    .push((idx, at_start));
+            }
+            ways[idx] = Some((*way, casing_id));
+        }
+
+        // The other way that ends at the given end of a way, if it's the only one.
+        let neighbor = |idx: usize, at_start: bool| {
+            let (way, casing_id) = ways[idx]?;
+            match ends[&(casing_id, end_coords(way, at_start))].as_slice() {
+                // The same way may have several styles with the same casing, which don't continue each other.
+                [first, second] if ways[first.0]?.0.global_id() != ways[second.0]?.0.global_id() => {
+                    Some(if first.0 == idx { *second } else { *first })
+                }
+                _ => None,
+            }
+        };
+
+        let mut chains = Vec::new();
+        let mut positions = vec![CasingPosition::Single; lines.len()];
+        let mut is_chained = vec![false; lines.len()];
+        for idx in 0..lines.len() {
+            if ways[idx].is_none() || is_chained[idx] {
+                continue;
+            }
+            // Go back to the first way of the chain (or around the ring of ways back to this one).
+            let (mut first, mut first_reversed) = (idx, false);
+            while let Some((prev, prev_at_start)) = neighbor(first, !first_reversed) {
+                if prev == idx {
+                    break;
+                }
+                // The previous way is drawn towards the shared node, so it is reversed if it starts there.
+                (first, first_reversed) = (prev, prev_at_start);
+            }
+
+            let mut chain = vec![(first, first_reversed)];
+            is_chained[first] = true;
+            let (mut last, mut last_reversed) = (first, first_reversed);
+            while let Some((next, next_at_start)) = neighbor(last, last_reversed) {
+                if is_chained[next] {
+                    break;
+                }
+                (last, last_reversed) = (next, !next_at_start);
+                chain.push((last, last_reversed));
+                is_chained[last] = true;
+            }
+
+            if chain.len() > 1 {
+                // The chain is drawn in place of the way that comes first in the drawing order.
+                let position = chain.iter().map(|(idx, _)| *idx).min().unwrap();
+                for (idx, _) in &chain {
+                    positions[*idx] = CasingPosition::Merged;
+                }
+                positions[position] = CasingPosition::Chain(chains.len());
+                chains.push(
+                    chain
+                        .into_iter()
+                        .map(|(idx, reversed)| (ways[idx].unwrap().0, reversed))
+                        .collect(),
+                );
+            }
+        }
+
+        CasingChains { chains, positions }
+    }
+
+    fn figures<'c>(&'c self, lines: &[(Figure<'c, 'e>, &'c Style)]) -> Vec<(Figure<'c, 'e>, &'c Style)> {
+        lines
+            .iter()
+            .zip(&self.positions)
+            .filter_map(|(&(figure, style), position)| match position {
+                CasingPosition::Single => Some((figure, style)),
+                CasingPosition::Chain(chain_idx) => Some((Figure::WayChain(&self.chains[*chain_idx]), style)),
+                CasingPosition::Merged => None,
+            })
+            .collect()
+    }
+}
+
+// The coordinates are compared exactly, since the ways that share a node use the same node.
+fn end_coords(way: &Way<'_>, at_start: bool) -> (u64, u64) {
+    let node = way.get_node(if at_start { 0 } else { way.node_count() - 1 });
+    (node.lat().to_bits(), node.lon().to_bits())
+}
+
+fn style_areas<'e, 'r>(
+    styler: &Styler,
+    entities: &'r OsmEntities<'e>,
+    tile: &Tile,
+    for_labels: bool,
+) -> Vec<(StyledArea<'e, 'r>, Arc<Style>)> {
+    let mut styled_areas = styler.style_areas(
+        entities.ways.iter(),
+        entities.multipolygons.iter(),
+        tile.zoom,
+        for_labels,
+    );
+    styled_areas.retain(|(area, style)| !is_too_small(area, style, tile));
+    styled_areas
+}
+
+fn check_deadline(deadline: Option<Instant>) -> Result<()> {
+    match deadline {
+        Some(deadline) if Instant::now() > deadline => Err(DeadlineExceeded.into()),
+        _ => Ok(()),
+    }
+}
+
+// `min-area` is measured at the normal scale, just like the widths, so the `@2x` tiles drop the same areas.
+// The unclosed ways are lines, so they are never dropped.
+fn is_too_small(area: &StyledArea<'_, '_>, style: &Style, tile: &Tile) -> bool {
+    let Some(min_area) = style.min_area else {
+        return false;
+    };
+    match area {
+        StyledArea::Way(way) => way.is_closed() && way.get_area(tile, 1.0) < min_area,
+        StyledArea::Multipolygon(multipolygon) => multipolygon.get_area(tile, 1.0) < min_area,
+    }
+}
+
+// Does the same as `Drawer::draw_one_area()`, except for filling the areas with images.
+fn draw_vector_figure(
+    canvas: &mut dyn VectorCanvas,
+    tile: &Tile,
+    scale: f64,
+    figure: Figure<'_, '_>,
+    style: &Style,
+    draw_type: &DrawType,
+) {
+    let blend_mode = style.blend_mode.unwrap_or_default();
+    let scale_dashes = |dashes: &Option<Vec<f64>>| {
+        dashes
+            .as_ref()
+            .map(|nums| nums.iter().map(|x| x * scale).collect::<Vec<_>>())
+    };
+    match *draw_type {
+        DrawType::Fill => {
+            if let Some(color) = &style.fill_color {
+                let opacity = style.fill_opacity.unwrap_or(1.0);
+                canvas.add_fill(&figure.to_lines(tile, scale), color, opacity, blend_mode);
+            }
+        }
+        DrawType::Casing => {
+            if let (Some(color), Some(casing_width)) = (&style.casing_color, style.casing_width) {
+                let dashes = scale_dashes(&style.casing_dashes);
+                let stroke = VectorStroke {
+                    color,
+                    width: casing_width * scale,
+                    opacity: 1.0,
+                    dashes: dashes.as_deref(),
+                    line_cap: style.casing_line_cap.as_ref(),
+                    line_join: style.casing_line_join.as_ref(),
+                    blend_mode,
+                };
+                canvas.add_lines(&figure.to_lines(tile, scale), &stroke);
+            }
+        }
+        DrawType::Stroke => {
+            if let Some(color) = &style.color {
+                let dashes = scale_dashes(&style.dashes);
+                let stroke = VectorStroke {
+                    color,
+                    width: scale * style.width.unwrap_or(1.0),
+                    opacity: style.opacity.unwrap_or(1.0),
+                    dashes: dashes.as_deref(),
+                    line_cap: style.line_cap.as_ref(),
+                    line_join: style.line_join.as_ref(),
+                    blend_mode,
+                };
+                canvas.add_lines(&figure.to_lines(tile, scale), &stroke);
+            }
+        }
+    }
+}
+
 impl Drawer {
     pub fn new(base_path: &Path) -> Drawer {
         Drawer {
             icon_cache: IconCache::new(base_path),
             labeler: Labeler::default(),
+            drawing_threads: 1,
         }
     }
 
+    // Figures of one tile can be rasterized on several threads. They are composited in the same order anyway,
+    // so the result doesn't depend on the number of threads. The same threads reset and blend the pixels in bands
+    // of rows (see `TilePixels::set_threads()`).
+    pub fn set_drawing_threads(&mut self, threads: usize) {
+        self.drawing_threads = threads.max(1);
+    }
+
+    pub fn set_label_limits(&mut self, label_limits: LabelLimits) {
+        self.labeler.set_label_limits(label_limits);
+    }
+
     pub fn draw_tile(
         &self,
         entities: &OsmEntities<'_>,
@@ -44,17 +475,13 @@ impl Drawer {
         pixels: &mut TilePixels,
         scale: usize,
         styler: &Styler,
+        overlays: &[Overlay],
     ) -> Result<Vec<u8>> {
-        let rendered_pixels = self.draw_to_pixels(entities, tile, pixels, scale, styler);
+        self.draw_into_pixels(entities, tile, pixels, scale, styler, overlays, &DrawOptions::default())?;
 
-        {
-            let _m = crate::perf_stats::measure("RGB triples to PNG");
-            rgb_triples_to_png(
-                &rendered_pixels.triples,
-                rendered_pixels.dimension,
-                rendered_pixels.dimension,
-            )
-        }
+        let _m = crate::perf_stats::measure("RGB bytes to PNG");
+        let dimension = pixels.dimension();
+        rgb_bytes_to_png(pixels.to_rgb_bytes(), dimension, dimension, PngCompression::Default)
     }
 
     pub fn draw_to_pixels(
@@ -64,39 +491,80 @@ impl Drawer {
         pixels: &mut TilePixels,
         scale: usize,
         styler: &Styler,
-    ) -> TileRenderedPixels {
+        overlays: &[Overlay],
+        options: &DrawOptions,
+    ) -> Result<TileRenderedPixels> {
+        self.draw_into_pixels(entities, tile, pixels, scale, styler, overlays, options)?;
+        Ok(TileRenderedPixels {
+            triples: pixels.to_rgb_triples(),
+            dimension: pixels.dimension(),
+        })
+    }
+
+    // Leaves the drawn tile in `pixels`, to be read with `TilePixels::to_rgb_bytes()` or `to_rgb_triples()`.
+    pub fn draw_into_pixels(
+        &self,
+        entities: &OsmEntities<'_>,
+        tile: &Tile,
+        pixels: &mut TilePixels,
+        scale: usize,
+        styler: &Styler,
+        overlays: &[Overlay],
+        options: &DrawOptions,
+    ) -> Result<()> {
+        let deadline = options.deadline;
+        pixels.set_threads(self.drawing_threads);
         {
             let _m = crate::perf_stats::measure("Resetting TilePixels");
-            pixels.reset(&styler.canvas_fill_color);
+            let canvas_color = styler.canvas_fill_color(tile.zoom);
+            match styler.canvas_fill_image(tile.zoom) {
+                Some(image_name) => {
+                    let read_icon_cache = self.icon_cache.open_read_session(image_name);
+                    pixels.reset(canvas_color, read_icon_cache.get(image_name).and_then(Option::as_ref));
+                }
+                None => pixels.reset(canvas_color, None),
+            }
         }
 
         let styled_areas = {
             let _m = crate::perf_stats::measure("Style areas");
-            styler.style_areas(entities.ways.iter(), entities.multipolygons.iter(), tile.zoom, false)
+            style_areas(styler, entities, tile, false)
         };
 
         let float_scale = scale as f64;
+        let line_options = LineOptions {
+            use_caps_for_dashes: styler.use_caps_for_dashes,
+            antialiasing: styler.canvas_antialiasing(tile.zoom),
+        };
 
-        let draw_areas_with_type = |pixels: &mut TilePixels, draw_type, use_multipolygons| {
-            self.draw_areas(
-                pixels,
-                &styled_areas,
-                tile,
-                float_scale,
-                draw_type,
-                use_multipolygons,
-                styler.use_caps_for_dashes,
-            );
+        check_deadline(deadline)?;
+        let styled_relations = {
+            let _m = crate::perf_stats::measure("Style relations");
+            styler.style_entities(entities.relations.iter(), tile.zoom, false)
         };
 
-        {
-            let _m = crate::perf_stats::measure("Fill areas");
-            draw_areas_with_type(pixels, &DrawType::Fill, true);
-        }
-        {
-            let _m = crate::perf_stats::measure("Draw areas");
-            draw_areas_with_type(pixels, &DrawType::Casing, false);
-            draw_areas_with_type(pixels, &DrawType::Stroke, false);
+        let steps = to_draw_steps(&styled_areas, &styled_relations);
+        for level_steps in steps.chunk_by(|a, b| a.compare_levels(b) == Ordering::Equal) {
+            check_deadline(deadline)?;
+            {
+                let _m = crate::perf_stats::measure("Fill areas");
+                self.draw_figures(
+                    pixels,
+                    &select_figures(level_steps, true),
+                    tile,
+                    float_scale,
+                    &DrawType::Fill,
+                    line_options,
+                );
+            }
+            {
+                let _m = crate::perf_stats::measure("Draw lines");
+                let lines = select_figures(level_steps, false);
+                let casing_chains = CasingChains::new(&lines);
+                let casings = casing_chains.figures(&lines);
+                self.draw_figures(pixels, &casings, tile, float_scale, &DrawType::Casing, line_options);
+                self.draw_figures(pixels, &lines, tile, float_scale, &DrawType::Stroke, line_options);
+            }
         }
 
         {
@@ -104,9 +572,10 @@ impl Drawer {
             pixels.blend_unfinished_pixels(false);
         }
 
+        check_deadline(deadline)?;
         let styled_areas_for_labels = {
             let _m = crate::perf_stats::measure("Style area for labels");
-            styler.style_areas(entities.ways.iter(), entities.multipolygons.iter(), tile.zoom, true)
+            style_areas(styler, entities, tile, true)
         };
 
         let styled_nodes = {
@@ -114,6 +583,7 @@ impl Drawer {
             styler.style_entities(entities.nodes.iter(), tile.zoom, true)
         };
 
+        check_deadline(deadline)?;
         {
             let _m = crate::perf_stats::measure("Draw labels");
             self.draw_labels(pixels, tile, float_scale, &styled_areas_for_labels, &styled_nodes);
@@ -124,48 +594,168 @@ impl Drawer {
             pixels.blend_unfinished_pixels(true);
         }
 
-        TileRenderedPixels {
-            triples: pixels.to_rgb_triples(),
-            dimension: pixels.dimension(),
+        if !overlays.is_empty() {
+            let _m = crate::perf_stats::measure("Draw overlays");
+            for overlay in overlays {
+                self.draw_overlay(pixels, overlay, tile, float_scale);
+            }
+            pixels.blend_unfinished_pixels(false);
         }
+
+        if !options.debug.is_empty() {
+            let _m = crate::perf_stats::measure("Draw debug overlays");
+            self.draw_debug_overlays(pixels, tile, float_scale, &options.debug, &styled_areas, &styled_nodes);
+        }
+
+        Ok(())
     }
 
-    fn draw_areas(
+    // Draws the same figures and labels as `draw_into_pixels()`, but as vectors on `canvas` (without the background).
+    // The labels are still placed on `pixels` to find out which of them collide, and their glyphs are drawn as
+    // outlines, so they look just like on the PNG tiles. The icons, the fill images (including the one of the canvas) and
+    // the overlays are not drawn.
+    pub fn draw_vector(
         &self,
+        entities: &OsmEntities<'_>,
+        tile: &Tile,
         pixels: &mut TilePixels,
-        areas: &[(StyledArea<'_, '_>, Arc<Style>)],
+        scale: usize,
+        styler: &Styler,
+        options: &DrawOptions,
+        canvas: &mut dyn VectorCanvas,
+    ) -> Result<()> {
+        let deadline = options.deadline;
+        pixels.set_threads(self.drawing_threads);
+        pixels.reset(styler.canvas_fill_color(tile.zoom), None);
+        pixels.record_label_outlines();
+        let float_scale = scale as f64;
+
+        let styled_areas = style_areas(styler, entities, tile, false);
+        check_deadline(deadline)?;
+        let styled_relations = styler.style_entities(entities.relations.iter(), tile.zoom, false);
+
+        let steps = to_draw_steps(&styled_areas, &styled_relations);
+        for level_steps in steps.chunk_by(|a, b| a.compare_levels(b) == Ordering::Equal) {
+            check_deadline(deadline)?;
+            for (figure, style) in select_figures(level_steps, true) {
+                draw_vector_figure(canvas, tile, float_scale, figure, style, &DrawType::Fill);
+            }
+            let lines = select_figures(level_steps, false);
+            let casing_chains = CasingChains::new(&lines);
+            for (figure, style) in casing_chains.figures(&lines) {
+                draw_vector_figure(canvas, tile, float_scale, figure, style, &DrawType::Casing);
+            }
+            for (figure, style) in &lines {
+                draw_vector_figure(canvas, tile, float_scale, *figure, style, &DrawType::Stroke);
+            }
+        }
+
+        check_deadline(deadline)?;
+        let styled_areas_for_labels = style_areas(styler, entities, tile, true);
+        let styled_nodes = styler.style_entities(entities.nodes.iter(), tile.zoom, true);
+        check_deadline(deadline)?;
+        {
+            let _m = crate::perf_stats::measure("Draw labels");
+            self.draw_labels(pixels, tile, float_scale, &styled_areas_for_labels, &styled_nodes);
+        }
+        for outline in pixels.take_label_outlines() {
+            canvas.add_label_outline(&outline);
+        }
+
+        Ok(())
+    }
+
+    fn draw_figures(
+        &self,
+        pixels: &mut TilePixels,
+        figures: &[(Figure<'_, '_>, &Style)],
         tile: &Tile,
         scale: f64,
         draw_type: &DrawType,
-        use_multipolygons: bool,
-        use_caps_for_dashes: bool,
+        line_options: LineOptions,
     ) {
-        for (area, style) in areas {
-            match area {
-                StyledArea::Way(way) => {
-                    self.draw_one_area(pixels, tile, scale, *way, style, draw_type, use_caps_for_dashes);
-                }
-                StyledArea::Multipolygon(rel) if use_multipolygons => {
-                    self.draw_one_area(pixels, tile, scale, *rel, style, draw_type, use_caps_for_dashes);
-                }
-                _ => {}
+        if self.drawing_threads == 1 {
+            for (figure, style) in figures {
+                pixels.set_blend_mode(style.blend_mode.unwrap_or_default());
+                self.draw_figure(pixels, tile, scale, figure, style, draw_type, line_options);
+                pixels.bump_generation();
+            }
+            return;
+        }
+
+        let bb = pixels.bb().clone();
+        for batch in figures.chunks(self.drawing_threads * FIGURES_PER_THREAD) {
+            let chunk_size = batch.len().div_ceil(self.drawing_threads);
+            let recorded = std::thread::scope(|s| {
+                let handles = batch
+                    .chunks(chunk_size)
+                    .map(|chunk| {
+                        s.spawn(|| {
+                            chunk
+                                .iter()
+                                .map(|(figure, style)| {
+                                    let mut recorded = RecordedPixels::new(&bb);
+                                    self.draw_figure(
+                                        &mut recorded,
+                                        tile,
+                                        scale,
+                                        figure,
+                                        style,
+                                        draw_type,
+                                        line_options,
+                                    );
+                                    recorded
+                                })
+                                .collect::<Vec<_>>()
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                handles
+                    .into_iter()
+                    .flat_map(|handle| handle.join().unwrap())
+                    .collect::<Vec<_>>()
+            });
+            for ((_, style), figure_pixels) in batch.iter().zip(&recorded) {
+                pixels.set_blend_mode(style.blend_mode.unwrap_or_default());
+                figure_pixels.replay(pixels);
             }
         }
     }
 
-    fn draw_one_area<'e, A>(
+    fn draw_figure<C: Canvas>(
         &self,
-        pixels: &mut TilePixels,
-        tile: &'e Tile,
+        canvas: &mut C,
+        tile: &Tile,
+        scale: f64,
+        figure: &Figure<'_, '_>,
+        style: &Style,
+        draw_type: &DrawType,
+        line_options: LineOptions,
+    ) {
+        let points = match *figure {
+            Figure::Way(way) => way.to_point_pairs(tile, scale),
+            Figure::Multipolygon(rel) => rel.to_point_pairs(tile, scale),
+            Figure::Relation(rel) => rel.to_point_pairs(tile, scale),
+            Figure::WayChain(chain) => {
+                let points = chain_nodes(chain)
+                    .iter()
+                    .map(|node| Point::from_node(node, tile, scale))
+                    .collect::<Vec<_>>();
+                Box::new((1..points.len()).map(move |idx| (points[idx - 1].clone(), points[idx].clone())))
+            }
+        };
+        self.draw_one_area(canvas, points, scale, style, draw_type, line_options)
+    }
+
+    fn draw_one_area<C: Canvas>(
+        &self,
+        pixels: &mut C,
+        points: PointPairIter<'_>,
         scale: f64,
-        area: &'e A,
         style: &Style,
         draw_type: &DrawType,
-        use_caps_for_dashes: bool,
-    ) where
-        A: OsmEntity<'e> + PointPairCollection<'e>,
-    {
-        let points = area.to_point_pairs(tile, scale);
+        line_options: LineOptions,
+    ) {
         let float_or_one = |num: &Option<f64>| num.unwrap_or(1.0);
 
         let scale_dashes =
@@ -192,8 +782,11 @@ impl Drawer {
                             color,
                             1.0,
                             &scale_dashes(&style.casing_dashes),
-                            &style.casing_line_cap,
-                            use_caps_for_dashes,
+                            &LineShape {
+                                cap: &style.casing_line_cap,
+                                join: &style.casing_line_join,
+                            },
+                            line_options,
                             pixels,
                         );
                     }
@@ -207,57 +800,172 @@ impl Drawer {
                         color,
                         float_or_one(&style.opacity),
                         &scale_dashes(&style.dashes),
-                        &style.line_cap,
-                        use_caps_for_dashes,
+                        &LineShape {
+                            cap: &style.line_cap,
+                            join: &style.line_join,
+                        },
+                        line_options,
                         pixels,
                     );
                 }
             }
         }
+    }
+
+    fn draw_overlay(&self, pixels: &mut TilePixels, overlay: &Overlay, tile: &Tile, scale: f64) {
+        let style = &overlay.style;
+        let margin = style.width.max(style.point_radius);
+        let to_point_pairs = |points: &[(f64, f64)]| -> PointPairIter<'_> {
+            let points = points
+                .iter()
+                .map(|p| Point::from_coords(p, tile, scale))
+                .collect::<Vec<_>>();
+            Box::new((1..points.len()).map(move |idx| (points[idx - 1].clone(), points[idx].clone())))
+        };
+        let stroke = |pixels: &mut TilePixels, points| {
+            draw_lines(
+                points,
+                style.width * scale,
+                &style.color,
+                style.opacity,
+                &None,
+                &LineShape {
+                    cap: &Some(LineCap::Round),
+                    join: &Some(LineJoin::Round),
+                },
+                LineOptions {
+                    use_caps_for_dashes: false,
+                    antialiasing: true,
+                },
+                pixels,
+            );
+            pixels.bump_generation();
+        };
 
-        pixels.bump_generation();
+        for shape in overlay.shapes.iter().filter(|s| s.intersects(tile, margin)) {
+            match &shape.geometry {
+                Geometry::Point(point) => {
+                    let center = Point::from_coords(point, tile, scale);
+                    let radius = style.point_radius * scale;
+                    let circle = (0..=16)
+                        .map(|idx| {
+                            let angle = f64::from(idx) * std::f64::consts::PI / 8.0;
+                            Point {
+                                x: center.x + (radius * angle.cos()).round() as i32,
+                                y: center.y + (radius * angle.sin()).round() as i32,
+                            }
+                        })
+                        .collect::<Vec<_>>();
+                    let pairs = Box::new(circle.windows(2).map(|w| (w[0].clone(), w[1].clone())));
+                    fill_contour(pairs, &Filler::Color(&style.color), style.opacity, pixels);
+                    pixels.bump_generation();
+                }
+                Geometry::Line(points) => stroke(pixels, to_point_pairs(points)),
+                Geometry::Polygon(rings) => {
+                    if let Some(fill_color) = &style.fill_color {
+                        let pairs = Box::new(rings.iter().flat_map(|ring| to_point_pairs(ring)));
+                        fill_contour(pairs, &Filler::Color(fill_color), style.fill_opacity, pixels);
+                        pixels.bump_generation();
+                    }
+                    for ring in rings {
+                        stroke(pixels, to_point_pairs(ring));
+                    }
+                }
+            }
+        }
     }
 
-    fn draw_labels(
+    fn draw_debug_overlays(
         &self,
         pixels: &mut TilePixels,
         tile: &Tile,
         scale: f64,
+        debug: &DebugOverlays,
         areas: &[(StyledArea<'_, '_>, Arc<Style>)],
         nodes: &[(&Node<'_>, Arc<Style>)],
     ) {
-        {
-            let _m = crate::perf_stats::measure("Label areas");
-            for (area, style) in areas {
-                match area {
-                    StyledArea::Way(way) => self.labeler.label_entity(
-                        *way,
-                        style,
-                        tile,
-                        scale,
-                        &self.icon_cache,
-                        TextPosition::Line,
-                        pixels,
-                    ),
-                    StyledArea::Multipolygon(rel) => self.labeler.label_entity(
-                        *rel,
-                        style,
-                        tile,
-                        scale,
-                        &self.icon_cache,
-                        TextPosition::Center,
-                        pixels,
-                    ),
-                }
+        let outline = |pixels: &mut TilePixels, bb: &BoundingBox, color: &RgbaColor| {
+            for x in bb.min_x..=bb.max_x {
+                pixels.set_pixel(x, bb.min_y, color);
+                pixels.set_pixel(x, bb.max_y, color);
+            }
+            for y in bb.min_y..=bb.max_y {
+                pixels.set_pixel(bb.min_x, y, color);
+                pixels.set_pixel(bb.max_x, y, color);
+            }
+            pixels.bump_generation();
+        };
+
+        // Label boxes go first, because the ids below are placed as labels, too.
+        if debug.labels {
+            let (placed_color, rejected_color) = (
+                RgbaColor::from_components(0x20, 0x60, 0xff, 0xff),
+                RgbaColor::from_components(0xff, 0x20, 0x20, 0xff),
+            );
+            for (bb, placed) in pixels.label_boxes().to_vec() {
+                outline(pixels, &bb, if placed { &placed_color } else { &rejected_color });
             }
         }
 
-        {
-            let _m = crate::perf_stats::measure("Label nodes");
-            for &(node, ref style) in nodes {
-                self.labeler
-                    .label_entity(node, style, tile, scale, &self.icon_cache, TextPosition::Center, pixels);
+        let font_size = DEBUG_FONT_SIZE * scale;
+        let text_color = Color { r: 0xc0, g: 0, b: 0xc0 };
+        if debug.grid {
+            let tile_bb = pixels.bb().clone();
+            outline(pixels, &tile_bb, &RgbaColor::from_color(&text_color, 1.0));
+            let tile_id = format!("{}/{}/{}", tile.zoom, tile.x, tile.y);
+            let origin = (f64::from(pixels.bb().max_x) / 2.0, 2.0 * scale);
+            let placed = self
+                .labeler
+                .place_plain(&tile_id, origin, font_size, &text_color, pixels);
+            pixels.bump_label_generation(placed);
+        }
+
+        if debug.ids {
+            let id_color = Color {
+                r: 0x20,
+                g: 0x20,
+                b: 0x20,
+            };
+            let mut place_id = |entity: &dyn Labelable, id: u64| {
+                if let Some((x, y)) = entity.get_label_position(tile, scale) {
+                    let placed = self.labeler.place_plain(
+                        &id.to_string(),
+                        (x, y - font_size / 2.0),
+                        font_size,
+                        &id_color,
+                        pixels,
+                    );
+                    pixels.bump_label_generation(placed);
+                }
+            };
+            for (node, _) in nodes {
+                place_id(*node, node.global_id());
+            }
+            for (area, _) in areas {
+                match area {
+                    StyledArea::Way(way) => place_id(*way, way.global_id()),
+                    StyledArea::Multipolygon(rel) => place_id(*rel, rel.global_id()),
+                }
             }
         }
+
+        pixels.blend_unfinished_pixels(true);
+    }
+
+    fn draw_labels(
+        &self,
+        pixels: &mut TilePixels,
+        tile: &Tile,
+        scale: f64,
+        areas: &[(StyledArea<'_, '_>, Arc<Style>)],
+        nodes: &[(&Node<'_>, Arc<Style>)],
+    ) {
+        let areas = areas.iter().map(|(area, style)| match area {
+            StyledArea::Way(way) => LabelCandidate::Way(way, style),
+            StyledArea::Multipolygon(rel) => LabelCandidate::Multipolygon(rel, style),
+        });
+        let nodes = nodes.iter().map(|(node, style)| LabelCandidate::Node(node, style));
+        self.labeler
+            .label_entities(areas.chain(nodes).collect(), tile, scale, &self.icon_cache, pixels);
     }
 }
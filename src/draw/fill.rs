@@ -4,7 +4,7 @@ use crate::draw::point_pairs::PointPairIter;
 use crate::draw::tile_pixels::RgbaColor;
 use crate::mapcss::color::Color;
 
-use crate::draw::tile_pixels::TilePixels;
+use crate::draw::tile_pixels::Canvas;
 use indexmap::IndexMap;
 use std::cmp::{max, min};
 
@@ -13,7 +13,7 @@ pub enum Filler<'a> {
     Image(&'a Icon),
 }
 
-pub fn fill_contour(points: PointPairIter<'_>, filler: &Filler<'_>, opacity: f64, pixels: &mut TilePixels) {
+pub fn fill_contour<C: Canvas>(points: PointPairIter<'_>, filler: &Filler<'_>, opacity: f64, pixels: &mut C) {
     let mut y_to_edges = EdgesByY::default();
 
     for (idx, (p1, p2)) in points.enumerate() {
@@ -74,7 +74,7 @@ fn draw_line(edge_idx: usize, p1: &Point, p2: &Point, y_to_edges: &mut EdgesByY,
         if cur_point.y >= min_y && cur_point.y <= max_y {
             let edge = y_to_edges
                 .entry(cur_point.y)
-                .or_insert_with(Default::default)
+                .or_default()
                 .entry(edge_idx)
                 .or_insert_with(|| Edge {
                     x_min: cur_point.x,
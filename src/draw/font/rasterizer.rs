@@ -1,6 +1,7 @@
 use std::collections::BTreeMap;
 
 use crate::draw::tile_pixels::{RgbaColor, TilePixels};
+use crate::draw::vector::{LabelOutline, PathSegment};
 use crate::mapcss::color::Color;
 
 #[derive(Default)]
@@ -14,6 +15,8 @@ type Stripes = BTreeMap<i32, Stripe>;
 pub struct Rasterizer {
     stripes: Stripes,
     color: Color,
+    // The contours of what is drawn, if the labels are drawn for a vector tile.
+    outline: Option<Vec<PathSegment>>,
 }
 
 impl Rasterizer {
@@ -21,6 +24,20 @@ impl Rasterizer {
         Rasterizer {
             stripes: Stripes::default(),
             color: color.clone(),
+            outline: None,
+        }
+    }
+
+    pub fn with_outline(mut self, enabled: bool) -> Rasterizer {
+        self.outline = enabled.then(Vec::new);
+        self
+    }
+
+    // Unlike `draw_line()` and `draw_quad()`, the outline commands follow the contours of the glyphs, so that they
+    // can be filled by an SVG or PDF viewer.
+    pub fn add_to_outline(&mut self, segment: PathSegment) {
+        if let Some(outline) = &mut self.outline {
+            outline.push(segment);
         }
     }
 
@@ -41,7 +58,7 @@ impl Rasterizer {
         let y_max = y0.max(y1);
 
         for y in (y_min.floor() as i32)..=(y_max.floor() as i32) {
-            let current_stripes = self.stripes.entry(y).or_insert_with(Default::default);
+            let current_stripes = self.stripes.entry(y).or_default();
 
             let y_bottom = f64::from(y).max(y_min);
             let y_top = f64::from(y + 1).min(y_max);
@@ -124,8 +141,8 @@ impl Rasterizer {
                 }
             };
 
-            let mut x_min = i32::max_value();
-            let mut x_max = i32::min_value();
+            let mut x_min = i32::MAX;
+            let mut x_max = i32::MIN;
             if !stripe.a.is_empty() {
                 x_min = x_min.min(*stripe.a.keys().min().unwrap());
                 x_max = x_max.max(*stripe.a.keys().max().unwrap());
@@ -144,6 +161,12 @@ impl Rasterizer {
             }
         }
 
+        if let Some(outline) = self.outline.as_ref().filter(|outline| !outline.is_empty()) {
+            pixels.add_label_outline(LabelOutline {
+                color: self.color.clone(),
+                segments: outline.clone(),
+            });
+        }
         true
     }
 }
@@ -2,11 +2,13 @@ use crate::draw::font::rasterizer::Rasterizer;
 use crate::draw::labelable::Labelable;
 use crate::draw::point::Point;
 use crate::draw::tile_pixels::TilePixels;
+use crate::draw::vector::PathSegment;
 use crate::geodata::reader::OsmEntity;
 use crate::mapcss::color::Color;
-use crate::mapcss::styler::{TextPosition, TextStyle};
+use crate::mapcss::styler::{TextPosition, TextStyle, TextTransform};
 use crate::tile::{Tile, TILE_SIZE};
 use stb_truetype::{FontInfo, Vertex, VertexType};
+use std::borrow::Cow;
 
 pub struct TextPlacer {
     font: FontInfo<&'static [u8]>,
@@ -21,43 +23,41 @@ impl Default for TextPlacer {
 }
 
 impl TextPlacer {
+    // Centered text is placed at the label position of `on`, unless `text_origin` sets the middle of its top edge.
     pub fn place<'e, E>(
         &self,
         on: &E,
         text_style: &TextStyle,
         tile: &Tile,
         global_scale: f64,
-        y_offset: usize,
+        text_origin: Option<(f64, f64)>,
         default_text_position: TextPosition,
         pixels: &mut TilePixels,
     ) -> bool
     where
         E: Labelable + OsmEntity<'e>,
     {
-        let font_size = match text_style.font_size {
-            Some(font_size) => font_size * global_scale,
-            _ => return true,
-        };
-
-        let text_to_draw = match on.tags().get_by_key(&text_style.text) {
-            Some(text_to_draw) => text_to_draw,
+        let label_text = match self.prepare_text(on, text_style, global_scale) {
+            Some(label_text) => label_text,
             _ => return true,
         };
+        let lines = split_into_lines(&label_text.text, &text_style.line_break_chars);
 
         let text_pos = text_style.text_position.as_ref().unwrap_or(&default_text_position);
 
-        let scale = f64::from(self.font.scale_for_pixel_height(font_size as f32));
-        let glyphs = self.text_to_glyphs(text_to_draw, scale);
+        let scale = label_text.scale;
+        let letter_spacing = label_text.letter_spacing;
 
         let text_color = match text_style.text_color {
             Some(ref color) => color,
             _ => &Color { r: 0, g: 0, b: 0 },
         };
-        let mut rasterizer = Rasterizer::new(text_color);
+        let mut rasterizer = Rasterizer::new(text_color).with_outline(pixels.records_label_outlines());
         let vm = self.get_v_metrics(scale);
 
         match text_pos {
             TextPosition::Line => {
+                let glyphs = self.text_to_glyphs(&lines.join(" "), scale, letter_spacing);
                 if let Some(mut points) = on.get_waypoints(tile, global_scale) {
                     if points.len() < 2 {
                         return true;
@@ -79,87 +79,54 @@ impl TextPlacer {
 
                     let mut cur_dist = (total_way_length - glyphs.total_width) / 2.0;
 
-                    let glyph_center_y = (vm.descent + vm.ascent) / 2.0;
                     for glyph in &glyphs.glyphs {
-                        let glyph_center_x = glyph.width / 2.0;
-                        let way_pos = compute_way_position(&points, cur_dist + glyph_center_x);
-
-                        let tr = |point: &(f64, f64)| {
-                            let (original_x, original_y) = point;
-
-                            let translated_x = original_x - glyph_center_x;
-                            let translated_y = original_y - glyph_center_y;
-
-                            let (angle_sin, angle_cos) = (-way_pos.angle).sin_cos();
-
-                            let rotated_x = translated_x * angle_cos - translated_y * angle_sin;
-                            let rotated_y = translated_y * angle_cos + translated_x * angle_sin;
-
-                            let back_translated_x = way_pos.x + rotated_x;
-                            let back_translated_y = way_pos.y - rotated_y;
-                            (back_translated_x, back_translated_y)
-                        };
-
+                        let way_pos = compute_way_position(&points, cur_dist + glyph.width / 2.0);
                         {
                             let _m = crate::perf_stats::measure("Rasterize glyph (line)");
-                            glyph.rasterize(&mut rasterizer, scale, tr);
+                            glyph.rasterize_rotated(&mut rasterizer, scale, &vm, &way_pos);
                         }
-
-                        cur_dist += glyph.width;
+                        cur_dist += glyph.advance;
                     }
                 }
             }
-            TextPosition::Center => {
+            TextPosition::Curved => {
+                let glyphs = self.text_to_glyphs(&lines.join(" "), scale, letter_spacing);
                 if let Some((center_x, center_y)) = on.get_label_position(tile, global_scale) {
-                    let mut glyph_rows = Vec::new();
-                    let mut current_row = Vec::new();
-                    let mut current_row_width = 0.0;
-                    let mut max_row_width = 0.0;
-
-                    for (idx, glyph) in glyphs.glyphs.iter().enumerate() {
-                        current_row.push(glyph);
-                        current_row_width += glyph.width;
-                        let is_last_glyph = idx + 1 == glyphs.glyphs.len();
-                        let should_break =
-                            glyph.ch.is_whitespace() && (current_row_width + glyph.width > MAX_TEXT_WIDTH);
-                        if !current_row.is_empty() && (should_break || is_last_glyph) {
-                            glyph_rows.push((current_row.clone(), current_row_width));
-                            if current_row_width > max_row_width {
-                                max_row_width = current_row_width;
-                            }
-                            current_row.clear();
-                            current_row_width = 0.0;
+                    // The text goes along an arc of a circle that lies below the label position, so that
+                    // the middle of the text is the highest point of it.
+                    let radius = CURVED_TEXT_RADIUS * glyphs.total_width;
+                    let mut cur_angle = -glyphs.total_width / radius / 2.0;
+                    for glyph in &glyphs.glyphs {
+                        let angle = cur_angle + glyph.width / 2.0 / radius;
+                        let way_pos = WayPosition {
+                            x: center_x + radius * angle.sin(),
+                            y: center_y + radius * (1.0 - angle.cos()),
+                            angle,
+                        };
+                        {
+                            let _m = crate::perf_stats::measure("Rasterize glyph (curved)");
+                            glyph.rasterize_rotated(&mut rasterizer, scale, &vm, &way_pos);
                         }
+                        cur_angle += glyph.advance / radius;
                     }
+                }
+            }
+            TextPosition::Center => {
+                let line_glyphs = self.lines_to_glyphs(&lines, &label_text);
+                let glyph_rows = wrap_lines(&line_glyphs, text_style, &label_text);
 
-                    let row_height = vm.ascent - vm.descent + vm.line_gap;
-                    let total_height = row_height * glyph_rows.len() as f64;
+                let row_height = vm.ascent - vm.descent + vm.line_gap;
+                let total_height = row_height * glyph_rows.len() as f64;
 
-                    let mut cur_y = center_y;
-                    if y_offset > 0 {
-                        cur_y += y_offset as f64;
-                    } else {
-                        cur_y -= total_height / 2.0;
-                    }
+                let (center_x, top_y) = match text_origin {
+                    Some(text_origin) => text_origin,
+                    None => match on.get_label_position(tile, global_scale) {
+                        Some((center_x, center_y)) => (center_x, center_y - total_height / 2.0),
+                        None => return true,
+                    },
+                };
 
-                    for (row, row_width) in &glyph_rows {
-                        let mut cur_x = center_x - row_width / 2.0;
-                        for glyph in row.iter() {
-                            let baseline = cur_y + vm.ascent;
-                            let x_offset = cur_x;
-                            let tr = |point: &(f64, f64)| {
-                                let (x, y) = point;
-                                (x_offset + x, baseline - y)
-                            };
-                            {
-                                let _m = crate::perf_stats::measure("Rasterize glyph (center)");
-                                glyph.rasterize(&mut rasterizer, scale, tr);
-                            }
-                            cur_x += glyph.width;
-                        }
-                        cur_y += row_height;
-                    }
-                }
+                rasterize_rows(&mut rasterizer, &glyph_rows, (center_x, top_y), scale, &vm);
             }
         }
 
@@ -167,7 +134,62 @@ impl TextPlacer {
         rasterizer.save_to_figure(pixels)
     }
 
-    fn text_to_glyphs(&self, text: &str, scale: f64) -> Glyphs {
+    // Draws a single line of text that isn't attached to any entity, e.g. for debugging.
+    pub fn place_plain(
+        &self,
+        text: &str,
+        text_origin: (f64, f64),
+        font_size: f64,
+        color: &Color,
+        pixels: &mut TilePixels,
+    ) -> bool {
+        let scale = f64::from(self.font.scale_for_pixel_height(font_size as f32));
+        let glyphs = self.text_to_glyphs(text, scale, 0.0);
+        let row = (glyphs.glyphs.iter().collect(), glyphs.total_width);
+        let mut rasterizer = Rasterizer::new(color);
+        rasterize_rows(&mut rasterizer, &[row], text_origin, scale, &self.get_v_metrics(scale));
+        rasterizer.save_to_figure(pixels)
+    }
+
+    // The size of the text of `on` if it's placed at the center, or `None` if there's nothing to draw.
+    pub fn measure_centered<'e, E>(&self, on: &E, text_style: &TextStyle, global_scale: f64) -> Option<(f64, f64)>
+    where
+        E: OsmEntity<'e>,
+    {
+        let label_text = self.prepare_text(on, text_style, global_scale)?;
+        let lines = split_into_lines(&label_text.text, &text_style.line_break_chars);
+        let line_glyphs = self.lines_to_glyphs(&lines, &label_text);
+        let glyph_rows = wrap_lines(&line_glyphs, text_style, &label_text);
+
+        let vm = self.get_v_metrics(label_text.scale);
+        let width = glyph_rows.iter().map(|(_, row_width)| *row_width).fold(0.0, f64::max);
+        let height = (vm.ascent - vm.descent + vm.line_gap) * glyph_rows.len() as f64;
+        Some((width, height))
+    }
+
+    fn prepare_text<'e, E>(&self, on: &E, text_style: &TextStyle, global_scale: f64) -> Option<LabelText<'e>>
+    where
+        E: OsmEntity<'e>,
+    {
+        let font_size = text_style.font_size * global_scale;
+        let text = transform_text(text_style.resolve_text(&on.tags())?, &text_style.text_transform);
+        Some(LabelText {
+            text,
+            font_size,
+            scale: f64::from(self.font.scale_for_pixel_height(font_size as f32)),
+            letter_spacing: text_style.letter_spacing.unwrap_or_default() * global_scale,
+        })
+    }
+
+    fn lines_to_glyphs(&self, lines: &[&str], label_text: &LabelText<'_>) -> Vec<Glyphs> {
+        lines
+            .iter()
+            .map(|line| self.text_to_glyphs(line, label_text.scale, label_text.letter_spacing))
+            .collect()
+    }
+
+    // Every glyph except the last one is followed by `letter_spacing` pixels.
+    fn text_to_glyphs(&self, text: &str, scale: f64, letter_spacing: f64) -> Glyphs {
         let mut result = Glyphs {
             glyphs: Vec::<Glyph>::default(),
             total_width: 0.0,
@@ -180,6 +202,7 @@ impl TextPlacer {
             let mut glyph = Glyph {
                 ch,
                 width: advance_width * scale,
+                advance: 0.0,
                 shape: self.font.get_glyph_shape(glyph_id),
             };
 
@@ -188,11 +211,15 @@ impl TextPlacer {
                 glyph.width += kern_advance * scale;
             }
 
-            result.total_width += glyph.width;
             prev_glyph_id = Some(glyph_id);
 
             result.glyphs.push(glyph);
         }
+        let glyph_count = result.glyphs.len();
+        for (idx, glyph) in result.glyphs.iter_mut().enumerate() {
+            glyph.advance = glyph.width + if idx + 1 < glyph_count { letter_spacing } else { 0.0 };
+            result.total_width += glyph.advance;
+        }
         result
     }
 
@@ -207,6 +234,13 @@ impl TextPlacer {
     }
 }
 
+struct LabelText<'t> {
+    text: Cow<'t, str>,
+    font_size: f64,
+    scale: f64,
+    letter_spacing: f64,
+}
+
 struct VMetrics {
     descent: f64,
     ascent: f64,
@@ -216,10 +250,31 @@ struct VMetrics {
 struct Glyph {
     ch: char,
     width: f64,
+    // The distance to the next glyph, which is larger than the width with letter spacing.
+    advance: f64,
     shape: Option<Vec<Vertex>>,
 }
 
 impl Glyph {
+    // Draws the glyph centered at `pos` and rotated by its angle.
+    fn rasterize_rotated(&self, rasterizer: &mut Rasterizer, scale: f64, vm: &VMetrics, pos: &WayPosition) {
+        let glyph_center_x = self.width / 2.0;
+        let glyph_center_y = (vm.descent + vm.ascent) / 2.0;
+        let (angle_sin, angle_cos) = (-pos.angle).sin_cos();
+        let tr = |point: &(f64, f64)| {
+            let (original_x, original_y) = point;
+
+            let translated_x = original_x - glyph_center_x;
+            let translated_y = original_y - glyph_center_y;
+
+            let rotated_x = translated_x * angle_cos - translated_y * angle_sin;
+            let rotated_y = translated_y * angle_cos + translated_x * angle_sin;
+
+            (pos.x + rotated_x, pos.y - rotated_y)
+        };
+        self.rasterize(rasterizer, scale, tr);
+    }
+
     fn rasterize<F>(&self, rasterizer: &mut Rasterizer, scale: f64, tr: F)
     where
         F: Fn(&(f64, f64)) -> (f64, f64),
@@ -231,15 +286,20 @@ impl Glyph {
             for v in vertices {
                 let to = convert(v.x, v.y);
                 match v.vertex_type() {
-                    VertexType::MoveTo => {}
+                    VertexType::MoveTo => {
+                        let p0 = tr(&to);
+                        rasterizer.add_to_outline(PathSegment::MoveTo(p0.0, p0.1));
+                    }
                     VertexType::LineTo => {
                         let (p1, p0) = (tr(&from), tr(&to));
                         rasterizer.draw_line(p0.0, p0.1, p1.0, p1.1);
+                        rasterizer.add_to_outline(PathSegment::LineTo(p0.0, p0.1));
                     }
                     VertexType::CurveTo => {
                         let midpoint = convert(v.cx, v.cy);
                         let (p2, p1, p0) = (tr(&from), tr(&midpoint), tr(&to));
                         rasterizer.draw_quad(p0.0, p0.1, p1.0, p1.1, p2.0, p2.1);
+                        rasterizer.add_to_outline(PathSegment::QuadTo(p1.0, p1.1, p0.0, p0.1));
                     }
                 }
                 from = to;
@@ -253,6 +313,102 @@ struct Glyphs {
     total_width: f64,
 }
 
+// Draws centered rows of glyphs starting from `top_y`.
+fn rasterize_rows(
+    rasterizer: &mut Rasterizer,
+    rows: &[(Vec<&Glyph>, f64)],
+    (center_x, top_y): (f64, f64),
+    scale: f64,
+    vm: &VMetrics,
+) {
+    let row_height = vm.ascent - vm.descent + vm.line_gap;
+    let mut cur_y = top_y;
+    for (row, row_width) in rows {
+        let mut cur_x = center_x - row_width / 2.0;
+        for glyph in row.iter() {
+            let baseline = cur_y + vm.ascent;
+            let x_offset = cur_x;
+            let tr = |point: &(f64, f64)| {
+                let (x, y) = point;
+                (x_offset + x, baseline - y)
+            };
+            {
+                let _m = crate::perf_stats::measure("Rasterize glyph (center)");
+                glyph.rasterize(rasterizer, scale, tr);
+            }
+            cur_x += glyph.advance;
+        }
+        cur_y += row_height;
+    }
+}
+
+fn wrap_lines<'g>(
+    line_glyphs: &'g [Glyphs],
+    text_style: &TextStyle,
+    label_text: &LabelText<'_>,
+) -> Vec<(Vec<&'g Glyph>, f64)> {
+    let max_width = match text_style.max_width {
+        Some(ems) if ems > 0.0 => ems * label_text.font_size,
+        Some(_) => f64::INFINITY,
+        None => MAX_TEXT_WIDTH,
+    };
+    line_glyphs
+        .iter()
+        .flat_map(|glyphs| wrap_glyphs(&glyphs.glyphs, max_width))
+        .collect()
+}
+
+// Splits a line into rows, each of which ends with a whitespace after which the row becomes wider than `max_width`.
+fn wrap_glyphs(glyphs: &[Glyph], max_width: f64) -> Vec<(Vec<&Glyph>, f64)> {
+    let mut glyph_rows = Vec::new();
+    let mut current_row = Vec::new();
+    let mut current_row_width = 0.0;
+
+    for (idx, glyph) in glyphs.iter().enumerate() {
+        current_row.push(glyph);
+        current_row_width += glyph.advance;
+        let is_last_glyph = idx + 1 == glyphs.len();
+        let should_break = glyph.ch.is_whitespace() && (current_row_width + glyph.width > max_width);
+        if should_break || is_last_glyph {
+            glyph_rows.push((std::mem::take(&mut current_row), current_row_width));
+            current_row_width = 0.0;
+        }
+    }
+    glyph_rows
+}
+
+fn transform_text<'t>(text: &'t str, text_transform: &Option<TextTransform>) -> Cow<'t, str> {
+    match text_transform {
+        None => Cow::Borrowed(text),
+        Some(TextTransform::Uppercase) => Cow::Owned(text.to_uppercase()),
+        Some(TextTransform::Lowercase) => Cow::Owned(text.to_lowercase()),
+        Some(TextTransform::Capitalize) => {
+            let mut result = String::with_capacity(text.len());
+            let mut at_word_start = true;
+            for ch in text.chars() {
+                if at_word_start {
+                    result.extend(ch.to_uppercase());
+                } else {
+                    result.push(ch);
+                }
+                at_word_start = ch.is_whitespace();
+            }
+            Cow::Owned(result)
+        }
+    }
+}
+
+// Empty lines (e.g. after a trailing `/`) are dropped.
+fn split_into_lines<'t>(text: &'t str, line_break_chars: &[char]) -> Vec<&'t str> {
+    if line_break_chars.is_empty() {
+        return vec![text];
+    }
+    text.split(line_break_chars)
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
 fn get_angle(points: &[Point], start_idx: usize) -> f64 {
     let from = &points[start_idx];
     let to = &points[start_idx + 1];
@@ -276,7 +432,7 @@ fn compute_way_position(points: &[Point], advance_by: f64) -> WayPosition {
             let from = &points[point_idx];
             let to = &points[point_idx + 1];
             let ratio = to_travel / from.dist(to);
-            let coord_dist = |from_c, to_c| (f64::from(from_c) + (f64::from(to_c - from_c) * ratio));
+            let coord_dist = |from_c, to_c| f64::from(from_c) + (f64::from(to_c - from_c) * ratio);
             return WayPosition {
                 x: coord_dist(from.x, to.x),
                 y: coord_dist(from.y, to.y),
@@ -296,4 +452,28 @@ fn compute_way_position(points: &[Point], advance_by: f64) -> WayPosition {
 }
 
 const MAX_TEXT_WIDTH: f64 = TILE_SIZE as f64 / 8.0;
+// The radius of the arc of a curved label, relative to the width of the text. The arc of a wider text is
+// longer, but bends the same.
+const CURVED_TEXT_RADIUS: f64 = 1.5;
 const FONT_DATA: &[u8] = include_bytes!("NotoSans-Regular.ttf");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transform_text() {
+        let transform = |text_transform| transform_text("улица  de la Paix", &text_transform).into_owned();
+        assert_eq!(transform(None), "улица  de la Paix");
+        assert_eq!(transform(Some(TextTransform::Uppercase)), "УЛИЦА  DE LA PAIX");
+        assert_eq!(transform(Some(TextTransform::Lowercase)), "улица  de la paix");
+        assert_eq!(transform(Some(TextTransform::Capitalize)), "Улица  De La Paix");
+    }
+
+    #[test]
+    fn test_split_into_lines() {
+        assert_eq!(split_into_lines("Moscow / Москва", &[]), vec!["Moscow / Москва"]);
+        assert_eq!(split_into_lines("Moscow / Москва", &['/']), vec!["Moscow", "Москва"]);
+        assert_eq!(split_into_lines("a;b/c/", &['/', ';']), vec!["a", "b", "c"]);
+    }
+}
@@ -1,5 +1,5 @@
 use crate::draw::point::Point;
-use crate::geodata::reader::{Multipolygon, Node, Way};
+use crate::geodata::reader::{Multipolygon, Node, OsmArea, Way};
 use crate::tile::{coords_to_xy_tile_relative, Tile};
 use std::cmp::Ordering;
 use std::collections::binary_heap::BinaryHeap;
@@ -10,6 +10,8 @@ type LabelPosition = Option<PointF>;
 pub trait Labelable {
     fn get_label_position(&self, tile: &Tile, scale: f64) -> LabelPosition;
     fn get_waypoints(&self, tile: &Tile, scale: f64) -> Option<Vec<Point>>;
+    // In square pixels, without the holes. The nodes and the unclosed ways have no area.
+    fn get_area(&self, tile: &Tile, scale: f64) -> f64;
 }
 
 impl<'n> Labelable for Node<'n> {
@@ -21,12 +23,15 @@ impl<'n> Labelable for Node<'n> {
     fn get_waypoints(&self, _: &Tile, _: f64) -> Option<Vec<Point>> {
         None
     }
+
+    fn get_area(&self, _: &Tile, _: f64) -> f64 {
+        0.0
+    }
 }
 
 impl<'w> Labelable for Way<'w> {
     fn get_label_position(&self, tile: &Tile, scale: f64) -> LabelPosition {
-        let polygon = nodes_to_points((0..self.node_count()).map(|idx| self.get_node(idx)), tile, scale);
-        get_label_position(vec![polygon], scale)
+        get_label_position(vec![self.to_points(tile, scale)], scale)
     }
 
     fn get_waypoints(&self, tile: &Tile, scale: f64) -> Option<Vec<Point>> {
@@ -36,11 +41,60 @@ impl<'w> Labelable for Way<'w> {
                 .collect(),
         )
     }
+
+    fn get_area(&self, tile: &Tile, scale: f64) -> f64 {
+        if !self.is_closed() {
+            return 0.0;
+        }
+        get_polygon_area(&self.to_points(tile, scale)) / 2.0
+    }
+}
+
+impl<'w> Way<'w> {
+    fn to_points(&self, tile: &Tile, scale: f64) -> Vec<PointF> {
+        nodes_to_points((0..self.node_count()).map(|idx| self.get_node(idx)), tile, scale)
+    }
 }
 
 impl<'r> Labelable for Multipolygon<'r> {
     fn get_label_position(&self, tile: &Tile, scale: f64) -> LabelPosition {
-        let polygons = (0..self.polygon_count())
+        get_label_position(self.to_polygons(tile, scale), scale)
+    }
+
+    fn get_waypoints(&self, _: &Tile, _: f64) -> Option<Vec<Point>> {
+        None
+    }
+
+    // The polygons are filled with the even-odd rule, so the ones inside an odd number of other polygons are holes.
+    fn get_area(&self, tile: &Tile, scale: f64) -> f64 {
+        let polygons = self.to_polygons(tile, scale);
+        let area = polygons
+            .iter()
+            .enumerate()
+            .filter(|(_, poly)| !poly.is_empty())
+            .map(|(idx, poly)| {
+                let depth = polygons
+                    .iter()
+                    .enumerate()
+                    .filter(|&(other_idx, other)| {
+                        other_idx != idx && point_to_polygon_dist(&poly[0], std::slice::from_ref(other)) > 0.0
+                    })
+                    .count();
+                let area = get_polygon_area(poly) / 2.0;
+                if depth % 2 == 0 {
+                    area
+                } else {
+                    -area
+                }
+            })
+            .sum::<f64>();
+        area.max(0.0)
+    }
+}
+
+impl<'r> Multipolygon<'r> {
+    fn to_polygons(&self, tile: &Tile, scale: f64) -> Polygons {
+        (0..self.polygon_count())
             .map(|poly_idx| {
                 let poly = self.get_polygon(poly_idx);
                 nodes_to_points(
@@ -49,12 +103,7 @@ impl<'r> Labelable for Multipolygon<'r> {
                     scale,
                 )
             })
-            .collect::<Vec<_>>();
-        get_label_position(polygons, scale)
-    }
-
-    fn get_waypoints(&self, _: &Tile, _: f64) -> Option<Vec<Point>> {
-        None
+            .collect()
     }
 }
 
@@ -98,21 +147,26 @@ impl Cell {
 
 impl PartialEq for Cell {
     fn eq(&self, other: &Self) -> bool {
-        self.max_fitness.eq(&other.max_fitness)
+        self.cmp(other) == Ordering::Equal
     }
 }
 
 impl Eq for Cell {}
 
+// Cells with the same fitness are ordered by their centers, so that they are popped from the heap
+// in the same order no matter in which order they were pushed.
 impl Ord for Cell {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap()
+        self.max_fitness
+            .total_cmp(&other.max_fitness)
+            .then_with(|| other.center.0.total_cmp(&self.center.0))
+            .then_with(|| other.center.1.total_cmp(&self.center.1))
     }
 }
 
 impl PartialOrd for Cell {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.max_fitness.partial_cmp(&other.max_fitness)
+        Some(self.cmp(other))
     }
 }
 
@@ -247,10 +301,10 @@ impl BoundingBox {
 }
 
 fn get_bounding_box(polygon: &[PointF]) -> BoundingBox {
-    let mut min_x = std::f64::INFINITY;
-    let mut max_x = std::f64::NEG_INFINITY;
-    let mut min_y = std::f64::INFINITY;
-    let mut max_y = std::f64::NEG_INFINITY;
+    let mut min_x = f64::INFINITY;
+    let mut max_x = f64::NEG_INFINITY;
+    let mut min_y = f64::INFINITY;
+    let mut max_y = f64::NEG_INFINITY;
 
     for point in polygon {
         min_x = min_x.min(point.0);
@@ -293,7 +347,7 @@ fn segment_dist_sq(point: &PointF, seg_start: &PointF, seg_end: &PointF) -> f64
 
 fn point_to_polygon_dist(point: &PointF, polygons: &[Vec<PointF>]) -> f64 {
     let mut inside = false;
-    let mut min_dist_sq = std::f64::INFINITY;
+    let mut min_dist_sq = f64::INFINITY;
 
     for poly in polygons {
         for (a, b) in iterate_polygon(poly) {
@@ -2,97 +2,271 @@ use crate::draw::font::text_placer::TextPlacer;
 use crate::draw::icon::Icon;
 use crate::draw::icon_cache::IconCache;
 use crate::draw::labelable::Labelable;
-use crate::draw::tile_pixels::TilePixels;
-use crate::geodata::reader::OsmEntity;
-use crate::mapcss::styler::{Style, TextPosition};
+use crate::draw::tile_pixels::{BoundingBox, RgbaColor, TilePixels};
+use crate::geodata::reader::{Multipolygon, Node, OsmEntity, Way};
+use crate::mapcss::color::Color;
+use crate::mapcss::styler::{IconAnchor, Style, TextAnchor, TextPosition, TextStyle};
 use crate::tile::Tile;
+use anyhow::{bail, Context, Result};
 
 #[derive(Default)]
 pub struct Labeler {
     text_placer: TextPlacer,
+    label_limits: LabelLimits,
+}
+
+// The maximum number of labels (with or without icons) placed on one tile, depending on the zoom level.
+// Written as e.g. `40, 80@16`: 40 labels per tile up to zoom level 15, and 80 labels from zoom level 16 on.
+// The zoom levels below the first limit (if it has a zoom level) don't have a limit.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LabelLimits {
+    // Sorted by zoom level.
+    limits: Vec<(u8, usize)>,
+}
+
+impl LabelLimits {
+    pub fn parse(limits_str: &str) -> Result<LabelLimits> {
+        let mut limits = Vec::new();
+        for limit_str in limits_str.split(',').map(str::trim) {
+            let (count_str, zoom_str) = limit_str.split_once('@').unwrap_or((limit_str, "0"));
+            let count = count_str
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid number of labels: {}", limit_str))?;
+            let zoom = zoom_str
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid zoom level: {}", limit_str))?;
+            if limits.last().is_some_and(|&(prev_zoom, _)| prev_zoom >= zoom) {
+                bail!("The label limits should be sorted by zoom level: {}", limits_str);
+            }
+            limits.push((zoom, count));
+        }
+        Ok(LabelLimits { limits })
+    }
+
+    pub fn for_zoom(&self, zoom: u8) -> Option<usize> {
+        self.limits
+            .iter()
+            .rev()
+            .find(|(min_zoom, _)| *min_zoom <= zoom)
+            .map(|(_, count)| *count)
+    }
+}
+
+// An entity to be labeled and its style. The text goes along the ways unless the style says otherwise.
+pub enum LabelCandidate<'a, 'e> {
+    Way(&'a Way<'e>, &'a Style),
+    Multipolygon(&'a Multipolygon<'e>, &'a Style),
+    Node(&'a Node<'e>, &'a Style),
+}
+
+impl LabelCandidate<'_, '_> {
+    fn style(&self) -> &Style {
+        match *self {
+            LabelCandidate::Way(_, style) | LabelCandidate::Multipolygon(_, style) | LabelCandidate::Node(_, style) => {
+                style
+            }
+        }
+    }
 }
 
 impl Labeler {
-    pub fn label_entity<'e, E>(
+    pub fn set_label_limits(&mut self, label_limits: LabelLimits) {
+        self.label_limits = label_limits;
+    }
+
+    // The candidates are placed in the given order, so the earlier ones win if the labels collide. If the number
+    // of labels is limited at this zoom level, the candidates with higher layers and z-indices go first instead,
+    // and the ones that are left when the limit is reached are dropped.
+    pub fn label_entities(
         &self,
-        entity: &E,
-        style: &Style,
+        mut candidates: Vec<LabelCandidate<'_, '_>>,
         tile: &Tile,
         scale: f64,
         icon_cache: &IconCache,
-        default_text_position: TextPosition,
         pixels: &mut TilePixels,
-    ) where
-        E: Labelable + OsmEntity<'e>,
-    {
-        let succeeded = {
-            if let Some(y_offset) = self.label_with_icon(entity, style, tile, scale, icon_cache, pixels) {
-                self.label_with_text(entity, style, tile, scale, y_offset, default_text_position, pixels)
-            } else {
-                false
+    ) {
+        let max_labels = self.label_limits.for_zoom(tile.zoom);
+        if max_labels.is_some() {
+            // The sort is stable, so the usual order still decides between the labels with equal priorities.
+            candidates.sort_by(|a, b| {
+                let (a_style, b_style) = (a.style(), b.style());
+                b_style
+                    .layer
+                    .unwrap_or(0)
+                    .cmp(&a_style.layer.unwrap_or(0))
+                    .then_with(|| b_style.z_index.total_cmp(&a_style.z_index))
+            });
+        }
+
+        let mut placed_on_tile = 0;
+        let mut dropped = 0;
+        for candidate in &candidates {
+            if max_labels.is_some_and(|max_labels| placed_on_tile >= max_labels) {
+                dropped += 1;
+                continue;
             }
-        };
 
-        pixels.bump_label_generation(succeeded);
+            let label_count = pixels.label_boxes().len();
+            let placed = match *candidate {
+                LabelCandidate::Way(way, style) => {
+                    self.label_entity(way, style, tile, scale, icon_cache, TextPosition::Line, pixels)
+                }
+                LabelCandidate::Multipolygon(rel, style) => {
+                    self.label_entity(rel, style, tile, scale, icon_cache, TextPosition::Center, pixels)
+                }
+                LabelCandidate::Node(node, style) => {
+                    self.label_entity(node, style, tile, scale, icon_cache, TextPosition::Center, pixels)
+                }
+            };
+
+            // The labels of the neighboring tiles are placed, too (so that the labels crossing the tile borders
+            // look the same on both sides), but only the ones that are visible on this tile count.
+            let label_boxes = pixels.label_boxes();
+            if placed && label_boxes.len() > label_count && overlaps(&label_boxes[label_count].0, pixels.bb()) {
+                placed_on_tile += 1;
+            }
+        }
+
+        if dropped > 0 {
+            crate::perf_stats::count("Dropped labels", dropped);
+        }
     }
 
-    fn label_with_icon(
+    fn label_entity<'e, E>(
         &self,
-        entity: &impl Labelable,
+        entity: &E,
         style: &Style,
         tile: &Tile,
         scale: f64,
         icon_cache: &IconCache,
+        default_text_position: TextPosition,
         pixels: &mut TilePixels,
-    ) -> Option<usize> {
-        let icon_name = match style.icon_image {
-            Some(ref icon_name) => icon_name,
-            _ => return Some(0),
-        };
-
-        let read_icon_cache = icon_cache.open_read_session(icon_name);
+    ) -> bool
+    where
+        E: Labelable + OsmEntity<'e>,
+    {
+        let read_icon_cache = style
+            .icon_image
+            .as_ref()
+            .map(|icon_name| (icon_name, icon_cache.open_read_session(icon_name)));
+        let icon = read_icon_cache
+            .as_ref()
+            .and_then(|(icon_name, cache)| cache.get(icon_name.as_str()))
+            .and_then(Option::as_ref);
 
-        if let Some(Some(icon)) = read_icon_cache.get(icon_name) {
-            let (center_x, center_y) = match entity.get_label_position(tile, scale) {
-                Some(center) => center,
-                _ => return Some(0),
-            };
-            if self.draw_icon(icon, center_x, center_y, pixels) {
-                Some(icon.height / 2)
-            } else {
-                None
-            }
-        } else {
-            Some(0)
+        if style
+            .text_style
+            .as_ref()
+            .is_some_and(|text_style| text_style.allow_overlap)
+        {
+            pixels.allow_label_overlap();
         }
+        let succeeded = self.place_label(entity, style, icon, tile, scale, default_text_position, pixels);
+
+        pixels.bump_label_generation(succeeded);
+        succeeded
     }
 
-    fn label_with_text<'e, E>(
+    pub fn place_plain(
+        &self,
+        text: &str,
+        text_origin: (f64, f64),
+        font_size: f64,
+        color: &Color,
+        pixels: &mut TilePixels,
+    ) -> bool {
+        self.text_placer
+            .place_plain(text, text_origin, font_size, color, pixels)
+    }
+
+    fn place_label<'e, E>(
         &self,
         entity: &E,
         style: &Style,
+        icon: Option<&Icon>,
         tile: &Tile,
         scale: f64,
-        y_offset: usize,
         default_text_position: TextPosition,
         pixels: &mut TilePixels,
     ) -> bool
     where
         E: Labelable + OsmEntity<'e>,
     {
-        if let Some(ref text_style) = style.text_style {
-            self.text_placer
-                .place(entity, text_style, tile, scale, y_offset, default_text_position, pixels)
-        } else {
-            true
+        let label_position = entity.get_label_position(tile, scale);
+        let icon_box = match (icon, label_position) {
+            (Some(icon), Some(position)) => Some(get_icon_box(icon, position, &style.icon_anchor)),
+            _ => None,
+        };
+
+        let text_style = match style.text_style {
+            Some(ref text_style) => text_style,
+            _ => {
+                return icon
+                    .zip(icon_box)
+                    .is_none_or(|(icon, icon_box)| self.draw_icon(icon, &icon_box, pixels))
+            }
+        };
+        let text_position = text_style.text_position.as_ref().unwrap_or(&default_text_position);
+
+        let is_anchored = icon_box.is_some()
+            || text_style
+                .text_anchor
+                .as_ref()
+                .is_some_and(|anchor| *anchor != TextAnchor::Center);
+        let text_box = match label_position {
+            Some(position) if is_anchored && *text_position == TextPosition::Center => self
+                .text_placer
+                .measure_centered(entity, text_style, scale)
+                .map(|text_size| get_text_box(text_size, text_style, icon_box.as_ref(), position, scale)),
+            _ => None,
+        };
+
+        let text_box = match text_box {
+            Some(text_box) => text_box,
+            _ => {
+                // Text along a way or centered at the label position doesn't depend on the icon, so they are simply
+                // placed one after another.
+                let icon_placed = icon
+                    .zip(icon_box)
+                    .is_none_or(|(icon, icon_box)| self.draw_icon(icon, &icon_box, pixels));
+                return icon_placed
+                    && self
+                        .text_placer
+                        .place(entity, text_style, tile, scale, None, default_text_position, pixels);
+            }
+        };
+
+        // Both boxes are reserved before drawing anything, so that the other labels can't be squeezed into the gaps
+        // between the letters, and the icon is never drawn without its text (or vice versa).
+        if let Some(ref icon_box) = icon_box {
+            if !reserve_box(icon_box, pixels) || !reserve_box(&text_box, pixels) {
+                return false;
+            }
+        }
+
+        if let (Some(icon), Some(icon_box)) = (icon, icon_box) {
+            if !self.draw_icon(icon, &icon_box, pixels) {
+                return false;
+            }
         }
-    }
 
-    fn draw_icon(&self, icon: &Icon, center_x: f64, center_y: f64, pixels: &mut TilePixels) -> bool {
-        let get_start_coord = |coord, dimension| (coord - (dimension as f64 / 2.0)) as i32;
+        let text_origin = ((text_box.min_x + text_box.max_x) / 2.0, text_box.min_y);
+        self.text_placer.place(
+            entity,
+            text_style,
+            tile,
+            scale,
+            Some(text_origin),
+            default_text_position,
+            pixels,
+        )
+    }
 
-        let start_x = get_start_coord(center_x, icon.width);
-        let start_y = get_start_coord(center_y, icon.height);
+    fn draw_icon(&self, icon: &Icon, icon_box: &LabelBox, pixels: &mut TilePixels) -> bool {
+        let start_x = icon_box.min_x as i32;
+        let start_y = icon_box.min_y as i32;
 
         for x in 0..icon.width {
             for y in 0..icon.height {
@@ -105,3 +279,79 @@ impl Labeler {
         true
     }
 }
+
+struct LabelBox {
+    min_x: f64,
+    min_y: f64,
+    max_x: f64,
+    max_y: f64,
+}
+
+impl LabelBox {
+    fn around(center_x: f64, center_y: f64, width: f64, height: f64) -> LabelBox {
+        LabelBox {
+            min_x: center_x - width / 2.0,
+            min_y: center_y - height / 2.0,
+            max_x: center_x + width / 2.0,
+            max_y: center_y + height / 2.0,
+        }
+    }
+
+    fn center(&self) -> (f64, f64) {
+        ((self.min_x + self.max_x) / 2.0, (self.min_y + self.max_y) / 2.0)
+    }
+}
+
+fn get_icon_box(icon: &Icon, (x, y): (f64, f64), icon_anchor: &Option<IconAnchor>) -> LabelBox {
+    let (width, height) = (icon.width as f64, icon.height as f64);
+    let center_y = match icon_anchor {
+        None | Some(IconAnchor::Center) => y,
+        Some(IconAnchor::Top) => y + height / 2.0,
+        Some(IconAnchor::Bottom) => y - height / 2.0,
+    };
+    LabelBox::around(x, center_y, width, height)
+}
+
+// The text goes below the icon by default. Without an icon, it's anchored to the label position itself.
+fn get_text_box(
+    (width, height): (f64, f64),
+    text_style: &TextStyle,
+    icon_box: Option<&LabelBox>,
+    (x, y): (f64, f64),
+    scale: f64,
+) -> LabelBox {
+    let point_box = LabelBox::around(x, y, 0.0, 0.0);
+    let anchor_box = icon_box.unwrap_or(&point_box);
+    let default_anchor = if icon_box.is_some() {
+        TextAnchor::Below
+    } else {
+        TextAnchor::Center
+    };
+    let offset = text_style.text_offset.unwrap_or_default() * scale;
+    let (center_x, center_y) = anchor_box.center();
+    match text_style.text_anchor.as_ref().unwrap_or(&default_anchor) {
+        TextAnchor::Center => LabelBox::around(center_x, center_y, width, height),
+        TextAnchor::Above => LabelBox::around(center_x, anchor_box.min_y - offset - height / 2.0, width, height),
+        TextAnchor::Below => LabelBox::around(center_x, anchor_box.max_y + offset + height / 2.0, width, height),
+        TextAnchor::Left => LabelBox::around(anchor_box.min_x - offset - width / 2.0, center_y, width, height),
+        TextAnchor::Right => LabelBox::around(anchor_box.max_x + offset + width / 2.0, center_y, width, height),
+    }
+}
+
+fn overlaps(a: &BoundingBox, b: &BoundingBox) -> bool {
+    a.min_x <= b.max_x && b.min_x <= a.max_x && a.min_y <= b.max_y && b.min_y <= a.max_y
+}
+
+// Fills the box with transparent pixels, which doesn't change the way the tile looks, but makes the box collide
+// with the labels that are placed later.
+fn reserve_box(label_box: &LabelBox, pixels: &mut TilePixels) -> bool {
+    let transparent = RgbaColor::from_components(0, 0, 0, 0);
+    for y in (label_box.min_y as i32)..=(label_box.max_y.ceil() as i32) {
+        for x in (label_box.min_x as i32)..=(label_box.max_x.ceil() as i32) {
+            if !pixels.set_label_pixel(x, y, &transparent) {
+                return false;
+            }
+        }
+    }
+    true
+}
@@ -1,28 +1,104 @@
+use crate::draw::fill::{fill_contour, Filler};
 use crate::draw::opacity_calculator::OpacityCalculator;
 use crate::draw::point::Point;
 use crate::draw::point_pairs::PointPairIter;
+use crate::draw::tile_pixels::Canvas;
 use crate::draw::tile_pixels::RgbaColor;
-use crate::draw::tile_pixels::TilePixels;
 use crate::mapcss::color::Color;
-use crate::mapcss::styler::{is_non_trivial_cap, LineCap};
+use crate::mapcss::styler::{is_non_trivial_cap, LineCap, LineJoin};
 
-pub fn draw_lines(
+// How all lines of a tile are drawn, unlike their widths or colors, which come from the styles of the lines.
+#[derive(Clone, Copy)]
+pub struct LineOptions {
+    pub use_caps_for_dashes: bool,
+    pub antialiasing: bool,
+}
+
+// The caps at the ends of a line and the joins between its segments.
+pub struct LineShape<'s> {
+    pub cap: &'s Option<LineCap>,
+    pub join: &'s Option<LineJoin>,
+}
+
+// Longer miters are cut off like bevels, just like in SVG.
+const MITER_LIMIT: f64 = 4.0;
+
+pub fn draw_lines<C: Canvas>(
     points: PointPairIter<'_>,
     width: f64,
     color: &Color,
     opacity: f64,
     dashes: &Option<Vec<f64>>,
-    line_cap: &Option<LineCap>,
-    use_caps_for_dashes: bool,
-    pixels: &mut TilePixels,
+    shape: &LineShape<'_>,
+    options: LineOptions,
+    pixels: &mut C,
 ) {
+    let line_cap = shape.cap;
     let half_width = width / 2.0;
-    let line_cap_for_dashes = if use_caps_for_dashes { line_cap } else { &None };
-    let mut opacity_calculator = OpacityCalculator::new(half_width, dashes, line_cap_for_dashes);
-    let opacity_calculator_for_outer_caps = OpacityCalculator::new(half_width, &Some(vec![0.0]), line_cap);
+    let line_cap_for_dashes = if options.use_caps_for_dashes { line_cap } else { &None };
+    let antialiasing = options.antialiasing;
+    let mut opacity_calculator = OpacityCalculator::new(half_width, dashes, line_cap_for_dashes, antialiasing);
+    let opacity_calculator_for_outer_caps =
+        OpacityCalculator::new(half_width, &Some(vec![0.0]), line_cap, antialiasing);
+
+    let round_join_calculator =
+        OpacityCalculator::new(half_width, &Some(vec![0.0]), &Some(LineCap::Round), antialiasing);
 
     let has_caps = is_non_trivial_cap(line_cap);
 
+    // Without a join, the segments meet with a notch on the outer side of the turn.
+    let draw_join = |prev: &Point, node: &Point, next: &Point, line_join: &LineJoin, pixels: &mut C| {
+        if prev == node || node == next {
+            return;
+        }
+        if *line_join == LineJoin::Round {
+            // The caps of both segments make a circle around the node.
+            for other in [prev, next] {
+                let cap_end = node.push_away_from(other, half_width);
+                draw_line(node, &cap_end, color, opacity, &round_join_calculator, pixels);
+            }
+            return;
+        }
+
+        let cross = i64::from(node.x - prev.x) * i64::from(next.y - node.y)
+            - i64::from(node.y - prev.y) * i64::from(next.x - node.x);
+        if cross == 0 {
+            return;
+        }
+        let outer_side = if cross > 0 { -1.0 } else { 1.0 };
+        let normal = |from: &Point, to: &Point| {
+            let dist = from.dist(to);
+            (
+                outer_side * f64::from(from.y - to.y) / dist,
+                outer_side * f64::from(to.x - from.x) / dist,
+            )
+        };
+        let offset = |(dx, dy): (f64, f64), by: f64| Point {
+            x: node.x + (dx * by).round() as i32,
+            y: node.y + (dy * by).round() as i32,
+        };
+        let (n1, n2) = (normal(prev, node), normal(node, next));
+
+        // The wedge between the outer edges of the segments, which is filled without antialiasing, like the areas.
+        let mut corners = vec![node.clone(), offset(n1, half_width)];
+        if *line_join == LineJoin::Miter {
+            let (mx, my) = (n1.0 + n2.0, n1.1 + n2.1);
+            let miter_len = (mx * mx + my * my).sqrt();
+            // The distance from the node to the tip of the miter, in half widths.
+            let miter_ratio = miter_len / (1.0 + n1.0 * n2.0 + n1.1 * n2.1);
+            if miter_ratio.is_finite() && miter_ratio <= MITER_LIMIT {
+                corners.push(offset((mx / miter_len, my / miter_len), half_width * miter_ratio));
+            }
+        }
+        corners.push(offset(n2, half_width));
+
+        let corner_count = corners.len();
+        let edges = Box::new(
+            (0..corner_count).map(move |idx| (corners[idx].clone(), corners[(idx + 1) % corner_count].clone())),
+        );
+        fill_contour(edges, &Filler::Color(color), opacity, pixels);
+    };
+
     let mut peekable_points = points.peekable();
     let mut first = true;
 
@@ -56,19 +132,26 @@ pub fn draw_lines(
             }
         }
 
+        if let (Some(line_join), Some((next_p1, next_p2))) = (shape.join, peekable_points.peek()) {
+            // A dashed line is joined only where a dash goes through the node.
+            if *next_p1 == p2 && opacity_calculator.calculate(0.0, 0.0).opacity > 0.0 {
+                draw_join(&p1, &p2, next_p2, line_join, pixels);
+            }
+        }
+
         first = false;
     }
 }
 
 // Full-blown Bresenham with anti-aliasing and thick line support.
 // Mostly inspired by http://kt8216.unixcab.org/murphy/index.html
-fn draw_line(
+fn draw_line<C: Canvas>(
     p1: &Point,
     p2: &Point,
     color: &Color,
     initial_opacity: f64,
     opacity_calculator: &OpacityCalculator,
-    pixels: &mut TilePixels,
+    pixels: &mut C,
 ) {
     if p1 == p2 {
         return;
@@ -164,3 +247,50 @@ fn swap_x_y_if_needed<T>(a: T, b: T, should_swap: bool) -> (T, T) {
         (a, b)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::draw::tile_pixels::TilePixels;
+
+    #[test]
+    fn test_line_joins() {
+        let white = Color { r: 255, g: 255, b: 255 };
+        let black = Color { r: 0, g: 0, b: 0 };
+        let mut pixels = TilePixels::new(1);
+        // A thick line that goes right and then down, i.e. turns clockwise on the screen.
+        let draw = |pixels: &mut TilePixels, line_join: Option<LineJoin>| {
+            pixels.reset(Some(&white), None);
+            let points = [Point { x: 10, y: 10 }, Point { x: 30, y: 10 }, Point { x: 30, y: 30 }];
+            let pairs = Box::new(points.windows(2).map(|w| (w[0].clone(), w[1].clone())));
+            draw_lines(
+                pairs,
+                10.0,
+                &black,
+                1.0,
+                &None,
+                &LineShape {
+                    cap: &None,
+                    join: &line_join,
+                },
+                LineOptions {
+                    use_caps_for_dashes: false,
+                    antialiasing: true,
+                },
+                pixels,
+            );
+            pixels.blend_unfinished_pixels(false);
+            let triples = pixels.to_rgb_triples();
+            let dimension = pixels.dimension();
+            let is_black = |x: usize, y: usize| triples[y * dimension + x] == (0, 0, 0);
+            // The inside of the turn is covered by the segments themselves, and the pixels outside of it are covered by
+            // the miter only, by the miter and the round join, and by all joins.
+            assert!(is_black(27, 13));
+            (is_black(34, 6), is_black(33, 7), is_black(32, 8))
+        };
+        assert_eq!(draw(&mut pixels, None), (false, false, false));
+        assert_eq!(draw(&mut pixels, Some(LineJoin::Round)), (false, true, true));
+        assert_eq!(draw(&mut pixels, Some(LineJoin::Bevel)), (false, false, true));
+        assert_eq!(draw(&mut pixels, Some(LineJoin::Miter)), (true, true, true));
+    }
+}
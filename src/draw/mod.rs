@@ -9,7 +9,10 @@ pub mod labelable;
 pub mod labeler;
 pub mod line;
 pub mod opacity_calculator;
+pub mod pdf;
 pub mod png_writer;
 pub mod point;
 pub mod point_pairs;
+pub mod svg;
 pub mod tile_pixels;
+pub mod vector;
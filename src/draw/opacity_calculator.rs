@@ -5,6 +5,7 @@ pub struct OpacityCalculator {
     dashes: Vec<DashSegment>,
     total_dash_len: f64,
     traveled_distance: f64,
+    antialiasing: bool,
 }
 
 pub struct OpacityData {
@@ -13,7 +14,12 @@ pub struct OpacityData {
 }
 
 impl OpacityCalculator {
-    pub fn new(half_line_width: f64, dashes: &Option<Vec<f64>>, line_cap: &Option<LineCap>) -> Self {
+    pub fn new(
+        half_line_width: f64,
+        dashes: &Option<Vec<f64>>,
+        line_cap: &Option<LineCap>,
+        antialiasing: bool,
+    ) -> Self {
         let mut dash_segments = Vec::new();
         let mut len_before = 0.0;
 
@@ -26,6 +32,7 @@ impl OpacityCalculator {
             dashes: dash_segments,
             total_dash_len: len_before,
             traveled_distance: 0.0,
+            antialiasing,
         }
     }
 
@@ -36,8 +43,20 @@ impl OpacityCalculator {
         let half_line_width = (self.half_line_width.powi(2) - cap_dist.powi(2)).sqrt();
 
         let cd = get_opacity_by_center_distance(center_distance, half_line_width);
+        let opacity = if self.antialiasing {
+            sd.opacity.min(cd)
+        } else {
+            // Without antialiasing, a pixel is either in the line or not, and even the thinnest lines are a pixel wide.
+            let is_covered = |opacity: f64| opacity >= 0.5;
+            let in_center = center_distance < half_line_width.max(0.5);
+            if in_center && is_covered(sd.opacity) {
+                1.0
+            } else {
+                0.0
+            }
+        };
         OpacityData {
-            opacity: sd.opacity.min(cd),
+            opacity,
             is_in_line: cd > 0.0,
         }
     }
@@ -0,0 +1,349 @@
+use crate::draw::vector::{format_coord, to_segments, LabelOutline, PathSegment, VectorCanvas, VectorStroke};
+use crate::mapcss::color::Color;
+use crate::mapcss::styler::{BlendMode, LineCap, LineJoin};
+use anyhow::Result;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::fmt::Write as _;
+use std::io::Write;
+
+// A PDF document made of vector pages (see `TileRenderer::render_pdf()`).
+#[derive(Default)]
+pub struct PdfDocument {
+    pages: Vec<PdfPage>,
+}
+
+// One pixel of the map is one PDF point (1/72 of an inch), so an A4 page is 595x842 pixels. The coordinates go from
+// the top left corner of the page, like in the tiles.
+pub struct PdfPage {
+    width: f64,
+    height: f64,
+    content: String,
+    // The dictionaries of the graphics states with the opacities and the blend modes, named `/GS0`, `/GS1` and so on.
+    graphics_states: Vec<String>,
+}
+
+impl PdfDocument {
+    pub fn add_page(&mut self, page: PdfPage) {
+        self.pages.push(page);
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut objects = vec![
+            "<< /Type /Catalog /Pages 2 0 R >>".as_bytes().to_vec(),
+            format!(
+                "<< /Type /Pages /Kids [{}] /Count {} >>",
+                (0..self.pages.len())
+                    .map(|idx| format!("{} 0 R", page_object_id(idx)))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                self.pages.len()
+            )
+            .into_bytes(),
+        ];
+        for (idx, page) in self.pages.iter().enumerate() {
+            let graphics_states = page
+                .graphics_states
+                .iter()
+                .enumerate()
+                .map(|(idx, state)| format!("/GS{} {}", idx, state))
+                .collect::<Vec<_>>();
+            objects.push(
+                format!(
+                    "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] /Resources << /ExtGState << {} >> >> \
+                     /Contents {} 0 R >>",
+                    format_coord(page.width),
+                    format_coord(page.height),
+                    graphics_states.join(" "),
+                    page_object_id(idx) + 1
+                )
+                .into_bytes(),
+            );
+
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(page.content.as_bytes())?;
+            let compressed = encoder.finish()?;
+            let mut content = format!("<< /Length {} /Filter /FlateDecode >>\nstream\n", compressed.len()).into_bytes();
+            content.extend(compressed);
+            content.extend(b"\nendstream");
+            objects.push(content);
+        }
+
+        // The binary comment tells the tools that the file is not plain text.
+        let mut result = b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n".to_vec();
+        let mut offsets = Vec::new();
+        for (idx, object) in objects.iter().enumerate() {
+            offsets.push(result.len());
+            result.extend(format!("{} 0 obj\n", idx + 1).as_bytes());
+            result.extend(object);
+            result.extend(b"\nendobj\n");
+        }
+        let xref_offset = result.len();
+        let mut xref = format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1);
+        for offset in offsets {
+            let _ = writeln!(xref, "{:010} 00000 n ", offset);
+        }
+        let _ = write!(
+            xref,
+            "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+            objects.len() + 1,
+            xref_offset
+        );
+        result.extend(xref.as_bytes());
+        Ok(result)
+    }
+}
+
+impl PdfPage {
+    pub fn new(width: f64, height: f64, background: Option<&Color>) -> PdfPage {
+        // PDF counts the coordinates from the bottom left corner, so the page is flipped vertically.
+        let mut content = format!("1 0 0 -1 0 {} cm\n", format_coord(height));
+        if let Some(color) = background {
+            let _ = writeln!(
+                content,
+                "{} rg 0 0 {} {} re f",
+                to_rgb(color),
+                format_coord(width),
+                format_coord(height)
+            );
+        }
+        PdfPage {
+            width,
+            height,
+            content,
+            graphics_states: Vec::new(),
+        }
+    }
+
+    // Everything up to `end_tile()` is drawn in the coordinates of a tile of `dimension` pixels whose top left corner
+    // is at `(left, top)`, and is clipped to the tile.
+    pub fn begin_tile(&mut self, left: f64, top: f64, dimension: f64) {
+        let (left, top, dimension) = (format_coord(left), format_coord(top), format_coord(dimension));
+        let _ = writeln!(
+            self.content,
+            "q {0} {1} {2} {2} re W n 1 0 0 1 {0} {1} cm",
+            left, top, dimension
+        );
+    }
+
+    pub fn end_tile(&mut self) {
+        self.content.push_str("Q\n");
+    }
+
+    // Sets the opacity (for both the fills and the strokes) and the blend mode, if they are not the default ones.
+    fn set_graphics_state(&mut self, opacity: f64, blend_mode: BlendMode) {
+        let blend_mode = match blend_mode {
+            BlendMode::Normal => None,
+            BlendMode::Multiply => Some("/Multiply"),
+            BlendMode::Screen => Some("/Screen"),
+        };
+        if opacity >= 1.0 && blend_mode.is_none() {
+            return;
+        }
+        let mut state = String::from("<<");
+        if opacity < 1.0 {
+            let _ = write!(state, " /ca {0} /CA {0}", opacity);
+        }
+        if let Some(blend_mode) = blend_mode {
+            let _ = write!(state, " /BM {}", blend_mode);
+        }
+        state.push_str(" >>");
+        let idx = match self.graphics_states.iter().position(|existing| *existing == state) {
+            Some(idx) => idx,
+            None => {
+                self.graphics_states.push(state);
+                self.graphics_states.len() - 1
+            }
+        };
+        let _ = write!(self.content, "/GS{} gs ", idx);
+    }
+
+    fn add_path(&mut self, paths: &[Vec<PathSegment>], closed: bool) {
+        // A quadratic curve is turned into a cubic one, which needs the point where the curve starts.
+        let mut current = (0.0, 0.0);
+        for path in paths {
+            for segment in path {
+                let _ = match *segment {
+                    PathSegment::MoveTo(x, y) => {
+                        current = (x, y);
+                        write!(self.content, "{} {} m ", format_coord(x), format_coord(y))
+                    }
+                    PathSegment::LineTo(x, y) => {
+                        current = (x, y);
+                        write!(self.content, "{} {} l ", format_coord(x), format_coord(y))
+                    }
+                    PathSegment::QuadTo(cx, cy, x, y) => {
+                        let from = current;
+                        current = (x, y);
+                        let towards_control =
+                            |(px, py): (f64, f64)| (px + 2.0 / 3.0 * (cx - px), py + 2.0 / 3.0 * (cy - py));
+                        let ((x1, y1), (x2, y2)) = (towards_control(from), towards_control((x, y)));
+                        write!(
+                            self.content,
+                            "{} {} {} {} {} {} c ",
+                            format_coord(x1),
+                            format_coord(y1),
+                            format_coord(x2),
+                            format_coord(y2),
+                            format_coord(x),
+                            format_coord(y)
+                        )
+                    }
+                };
+            }
+            if closed {
+                self.content.push_str("h ");
+            }
+        }
+    }
+}
+
+impl VectorCanvas for PdfPage {
+    fn add_fill(&mut self, rings: &[Vec<(f64, f64)>], color: &Color, opacity: f64, blend_mode: BlendMode) {
+        let rings = to_segments(rings);
+        if rings.is_empty() {
+            return;
+        }
+        self.content.push_str("q ");
+        self.set_graphics_state(opacity, blend_mode);
+        let _ = write!(self.content, "{} rg ", to_rgb(color));
+        self.add_path(&rings, true);
+        self.content.push_str("f* Q\n");
+    }
+
+    fn add_lines(&mut self, lines: &[Vec<(f64, f64)>], stroke: &VectorStroke<'_>) {
+        let lines = to_segments(lines);
+        if lines.is_empty() {
+            return;
+        }
+        let line_cap = match stroke.line_cap {
+            None | Some(LineCap::Butt) => 0,
+            Some(LineCap::Round) => 1,
+            Some(LineCap::Square) => 2,
+        };
+        let line_join = match stroke.line_join {
+            Some(LineJoin::Miter) => 0,
+            None | Some(LineJoin::Round) => 1,
+            Some(LineJoin::Bevel) => 2,
+        };
+        self.content.push_str("q ");
+        self.set_graphics_state(stroke.opacity, stroke.blend_mode);
+        let _ = write!(
+            self.content,
+            "{} RG {} w {} J {} j ",
+            to_rgb(stroke.color),
+            format_coord(stroke.width),
+            line_cap,
+            line_join
+        );
+        if let Some(dashes) = stroke.dashes.filter(|dashes| !dashes.is_empty()) {
+            let dashes = dashes.iter().map(|dash| format_coord(*dash)).collect::<Vec<_>>();
+            let _ = write!(self.content, "[{}] 0 d ", dashes.join(" "));
+        }
+        self.add_path(&lines, false);
+        self.content.push_str("S Q\n");
+    }
+
+    fn add_label_outline(&mut self, outline: &LabelOutline) {
+        let _ = write!(self.content, "{} rg ", to_rgb(&outline.color));
+        self.add_path(std::slice::from_ref(&outline.segments), false);
+        // The glyphs are filled with the nonzero rule, like when they are rasterized.
+        self.content.push_str("f\n");
+    }
+}
+
+// Page `idx` is followed by its content.
+fn page_object_id(idx: usize) -> usize {
+    3 + 2 * idx
+}
+
+fn to_rgb(color: &Color) -> String {
+    let component = |c: u8| format!("{}", (f64::from(c) / 255.0 * 1000.0).round() / 1000.0);
+    format!("{} {} {}", component(color.r), component(color.g), component(color.b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(haystack: &[u8], needle: &str) -> usize {
+        haystack
+            .windows(needle.len())
+            .position(|window| window == needle.as_bytes())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_pdf_document() {
+        let mut page = PdfPage::new(100.0, 200.0, Some(&Color { r: 255, g: 0, b: 0 }));
+        page.begin_tile(-10.0, 20.0, 256.0);
+        let color = Color { r: 0, g: 0, b: 51 };
+        page.add_fill(
+            &[vec![(0.0, 0.0), (1.0, 0.0), (1.0, 1.0)]],
+            &color,
+            0.5,
+            BlendMode::Normal,
+        );
+        page.add_fill(
+            &[vec![(0.0, 0.0), (1.0, 0.0), (1.0, 1.0)]],
+            &color,
+            0.5,
+            BlendMode::Multiply,
+        );
+        page.add_lines(
+            &[vec![(0.0, 0.0), (3.0, 4.0)]],
+            &VectorStroke {
+                color: &color,
+                width: 2.0,
+                opacity: 0.5,
+                dashes: Some(&[4.0, 2.0]),
+                line_cap: Some(&LineCap::Round),
+                line_join: Some(&LineJoin::Miter),
+                blend_mode: BlendMode::Normal,
+            },
+        );
+        page.add_label_outline(&LabelOutline {
+            color: color.clone(),
+            segments: vec![PathSegment::MoveTo(0.0, 0.0), PathSegment::QuadTo(3.0, 3.0, 6.0, 0.0)],
+        });
+        page.end_tile();
+        assert_eq!(
+            page.content,
+            "1 0 0 -1 0 200 cm\n\
+             1 0 0 rg 0 0 100 200 re f\n\
+             q -10 20 256 256 re W n 1 0 0 1 -10 20 cm\n\
+             q /GS0 gs 0 0 0.2 rg 0 0 m 1 0 l 1 1 l h f* Q\n\
+             q /GS1 gs 0 0 0.2 rg 0 0 m 1 0 l 1 1 l h f* Q\n\
+             q /GS0 gs 0 0 0.2 RG 2 w 1 J 0 j [4 2] 0 d 0 0 m 3 4 l S Q\n\
+             0 0 0.2 rg 0 0 m 2 2 4 2 6 0 c f\n\
+             Q\n"
+        );
+        assert_eq!(
+            page.graphics_states,
+            vec!["<< /ca 0.5 /CA 0.5 >>", "<< /ca 0.5 /CA 0.5 /BM /Multiply >>"]
+        );
+
+        let mut document = PdfDocument::default();
+        document.add_page(page);
+        document.add_page(PdfPage::new(100.0, 200.0, None));
+        let bytes = document.to_bytes().unwrap();
+        assert!(bytes.starts_with(b"%PDF-1.4\n"));
+        assert!(bytes.ends_with(b"%%EOF\n"));
+        find(&bytes, "<< /Type /Pages /Kids [3 0 R 5 0 R] /Count 2 >>");
+        // The cross-reference table points at the objects.
+        let xref = find(&bytes, "xref\n");
+        let xref_lines = String::from_utf8_lossy(&bytes[xref..])
+            .lines()
+            .take(9)
+            .map(String::from)
+            .collect::<Vec<_>>();
+        assert_eq!(xref_lines[1], "0 7");
+        for (idx, line) in xref_lines[3..].iter().enumerate() {
+            let offset = line[..10].parse::<usize>().unwrap();
+            assert!(bytes[offset..].starts_with(format!("{} 0 obj\n", idx + 1).as_bytes()));
+        }
+        let startxref = find(&bytes, "startxref\n") + "startxref\n".len();
+        let startxref_value = String::from_utf8_lossy(&bytes[startxref..]);
+        assert_eq!(startxref_value.lines().next().unwrap().parse::<usize>().unwrap(), xref);
+    }
+}
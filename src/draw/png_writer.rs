@@ -1,21 +1,184 @@
 use anyhow::{Context, Result};
-use png::{ColorType, Encoder};
+use png::{ColorType, Compression, Encoder, FilterType};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// Enough for the land and the sea tiles at every scale, plus the other canvas and area colors.
+const PNG_CACHE_CAPACITY: usize = 64;
+
+// How hard to try to make the PNGs smaller.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum PngCompression {
+    // Ten times faster to encode, but the PNGs are larger by a third.
+    Fast,
+    #[default]
+    Default,
+    // About three times slower than `Default` for a few percent smaller PNGs.
+    Best,
+}
 
 pub fn rgb_triples_to_png(triples: &[(u8, u8, u8)], width: usize, height: usize) -> Result<Vec<u8>> {
+    let bytes = triples.iter().flat_map(|&(r, g, b)| [r, g, b]).collect::<Vec<_>>();
+    rgb_bytes_to_png(&bytes, width, height, PngCompression::Default)
+}
+
+// `bytes` are the row-major RGB values of the pixels.
+pub fn rgb_bytes_to_png(bytes: &[u8], width: usize, height: usize, compression: PngCompression) -> Result<Vec<u8>> {
     let mut buf = Vec::new();
     {
         let mut png_encoder = Encoder::new(&mut buf, width as u32, height as u32);
         png_encoder.set_color(ColorType::Rgb);
+        // Tiles mostly consist of runs of identical pixels, which deflate compresses well as they are. Filtering
+        // them only helps the fast compression, which doesn't look far enough back for these runs.
+        let (compression, filter) = match compression {
+            PngCompression::Fast => (Compression::Fast, FilterType::Paeth),
+            PngCompression::Default => (Compression::Default, FilterType::NoFilter),
+            PngCompression::Best => (Compression::Best, FilterType::NoFilter),
+        };
+        png_encoder.set_compression(compression);
+        png_encoder.set_filter(filter);
         let mut png_writer = png_encoder.write_header().context("Failed to write PNG header")?;
+        png_writer.write_image_data(bytes).context("Failed to write PNG data")?;
+    }
+    Ok(buf)
+}
+
+// Lots of tiles look exactly the same (e.g. the empty land or sea tiles at low zoom levels), so their PNGs are kept
+// and reused instead of being encoded again. Only the tiles of a single color are cached: they are recognized exactly
+// by their color and size, while the other tiles could only be recognized by a hash of all pixels, and a collision
+// would serve a wrong tile.
+#[derive(Default)]
+pub struct PngCache {
+    state: Mutex<PngCacheState>,
+}
 
-        let mut image_bytes = Vec::new();
-        for &(r, g, b) in triples {
-            image_bytes.extend([r, g, b].iter());
+#[derive(Default)]
+struct PngCacheState {
+    entries: HashMap<PngCacheKey, CachedPng>,
+    clock: u64,
+}
+
+// The color of all pixels, the width and the height.
+type PngCacheKey = ((u8, u8, u8), usize, usize);
+
+struct CachedPng {
+    png: Vec<u8>,
+    // The value of `PngCacheState::clock` when this PNG was last used.
+    last_used: u64,
+}
+
+impl PngCache {
+    pub fn rgb_bytes_to_png(
+        &self,
+        bytes: &[u8],
+        width: usize,
+        height: usize,
+        compression: PngCompression,
+    ) -> Result<Vec<u8>> {
+        let key = match bytes {
+            [r, g, b, ..] if bytes.chunks_exact(3).all(|rgb| rgb == [*r, *g, *b]) => ((*r, *g, *b), width, height),
+            _ => return rgb_bytes_to_png(bytes, width, height, compression),
+        };
+
+        if let Some(png) = self.state.lock().unwrap().get(&key) {
+            crate::perf_stats::count("PNG cache hits", 1);
+            return Ok(png);
         }
+        // Encode without holding the lock, so that the other tiles are not blocked.
+        let png = rgb_bytes_to_png(bytes, width, height, compression)?;
+        self.state.lock().unwrap().insert(key, png.clone());
+        Ok(png)
+    }
 
-        png_writer
-            .write_image_data(image_bytes.as_slice())
-            .context("Failed to write PNG data")?;
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl PngCacheState {
+    fn get(&mut self, key: &PngCacheKey) -> Option<Vec<u8>> {
+        self.clock += 1;
+        let cached = self.entries.get_mut(key)?;
+        cached.last_used = self.clock;
+        Some(cached.png.clone())
+    }
+
+    fn insert(&mut self, key: PngCacheKey, png: Vec<u8>) {
+        if self.entries.len() >= PNG_CACHE_CAPACITY && !self.entries.contains_key(&key) {
+            let evicted_key = self
+                .entries
+                .iter()
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(key, _)| *key);
+            if let Some(evicted_key) = evicted_key {
+                self.entries.remove(&evicted_key);
+            }
+        }
+        self.clock += 1;
+        let last_used = self.clock;
+        self.entries.insert(key, CachedPng { png, last_used });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_bytes(triples: &[(u8, u8, u8)]) -> Vec<u8> {
+        triples.iter().flat_map(|&(r, g, b)| [r, g, b]).collect()
+    }
+
+    #[test]
+    fn test_compression_levels() {
+        let triples = (0..64 * 64)
+            .map(|idx| ((idx % 7) as u8, (idx / 64) as u8, 0))
+            .collect::<Vec<_>>();
+        for compression in [PngCompression::Fast, PngCompression::Default, PngCompression::Best] {
+            let png = rgb_bytes_to_png(&to_bytes(&triples), 64, 64, compression).unwrap();
+            let mut reader = png::Decoder::new(png.as_slice()).read_info().unwrap();
+            let mut decoded = vec![0; reader.output_buffer_size()];
+            reader.next_frame(&mut decoded).unwrap();
+            assert_eq!(decoded, to_bytes(&triples), "{:?}", compression);
+        }
+    }
+
+    #[test]
+    fn test_png_cache() {
+        let cache = PngCache::default();
+        let png = |triples: &[(u8, u8, u8)], width, height| {
+            cache
+                .rgb_bytes_to_png(&to_bytes(triples), width, height, PngCompression::Default)
+                .unwrap()
+        };
+        let sea = vec![(0xb5, 0xd0, 0xd0); 16];
+        let sea_png = png(&sea, 4, 4);
+        assert_eq!(sea_png, rgb_triples_to_png(&sea, 4, 4).unwrap());
+        assert_eq!(png(&sea, 4, 4), sea_png);
+        // The same pixels in a different shape are a different image.
+        assert_ne!(png(&sea, 2, 8), sea_png);
+        assert_eq!(cache.len(), 2);
+
+        // The tiles of several colors are always encoded again.
+        let mut pixels = sea.clone();
+        pixels[0] = (0, 0, 0);
+        assert_eq!(png(&pixels, 4, 4), rgb_triples_to_png(&pixels, 4, 4).unwrap());
+        assert_eq!(cache.len(), 2);
+
+        for idx in 0..2 * PNG_CACHE_CAPACITY {
+            // Keep using the sea tile, so that it's never the least recently used one.
+            assert_eq!(png(&sea, 4, 4), sea_png);
+            let pixels = vec![(idx as u8, 0, 0); 16];
+            let pixels_png = png(&pixels, 4, 4);
+            assert_eq!(pixels_png, rgb_triples_to_png(&pixels, 4, 4).unwrap());
+            assert_eq!(png(&pixels, 4, 4), pixels_png);
+        }
+        assert_eq!(cache.len(), PNG_CACHE_CAPACITY);
+        let state = cache.state.lock().unwrap();
+        assert!(state.entries.contains_key(&(sea[0], 4, 4)));
+        assert!(!state.entries.contains_key(&(sea[0], 2, 8)));
     }
-    Ok(buf)
 }
@@ -1,3 +1,4 @@
+use crate::coords::Coords;
 use crate::geodata::reader::Node;
 use crate::tile as t;
 
@@ -9,7 +10,11 @@ pub struct Point {
 
 impl Point {
     pub fn from_node(node: &Node<'_>, tile: &t::Tile, scale: f64) -> Point {
-        let (x, y) = t::coords_to_xy_tile_relative(node, tile);
+        Point::from_coords(node, tile, scale)
+    }
+
+    pub fn from_coords<C: Coords>(coords: &C, tile: &t::Tile, scale: f64) -> Point {
+        let (x, y) = t::coords_to_xy_tile_relative(coords, tile);
 
         let to_coord = |c: f64| (c * scale).round() as i32;
         Point {
@@ -1,5 +1,5 @@
 use crate::draw::point::Point;
-use crate::geodata::reader::{Multipolygon, Polygon, Way};
+use crate::geodata::reader::{Multipolygon, Polygon, Relation, Way};
 use crate::tile::Tile;
 
 pub type PointPairIter<'a> = Box<dyn Iterator<Item = (Point, Point)> + 'a>;
@@ -39,3 +39,10 @@ impl<'r> PointPairCollection<'r> for Multipolygon<'r> {
         Box::new((0..polygon_count).flat_map(move |idx| self.get_polygon(idx).into_point_pairs(tile, scale)))
     }
 }
+
+impl<'r> PointPairCollection<'r> for Relation<'r> {
+    fn to_point_pairs(&'r self, tile: &'r Tile, scale: f64) -> PointPairIter<'r> {
+        let line_count = self.line_count();
+        Box::new((0..line_count).flat_map(move |idx| self.get_line(idx).into_point_pairs(tile, scale)))
+    }
+}
@@ -0,0 +1,188 @@
+use crate::draw::vector::{format_coord, to_segments, LabelOutline, PathSegment, VectorCanvas, VectorStroke};
+use crate::mapcss::color::Color;
+use crate::mapcss::styler::{BlendMode, LineCap, LineJoin};
+use std::fmt::Write;
+
+// A tile drawn as vectors instead of pixels (see `TileRenderer::render_svg()`). Everything outside of the tile is
+// clipped when the document is shown.
+pub struct SvgDocument {
+    content: String,
+}
+
+impl SvgDocument {
+    pub fn new(dimension: usize, canvas_color: Option<&Color>) -> SvgDocument {
+        let mut content = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{0}\" viewBox=\"0 0 {0} {0}\">\n",
+            dimension
+        );
+        if let Some(color) = canvas_color {
+            let _ = writeln!(
+                content,
+                "<rect width=\"100%\" height=\"100%\" fill=\"{}\"/>",
+                to_hex(color)
+            );
+        }
+        SvgDocument { content }
+    }
+
+    pub fn finish(mut self) -> String {
+        self.content.push_str("</svg>\n");
+        self.content
+    }
+
+    fn finish_element(&mut self, blend_mode: BlendMode) {
+        match blend_mode {
+            BlendMode::Normal => {}
+            BlendMode::Multiply => self.content.push_str(" style=\"mix-blend-mode:multiply\""),
+            BlendMode::Screen => self.content.push_str(" style=\"mix-blend-mode:screen\""),
+        }
+        self.content.push_str("/>\n");
+    }
+}
+
+impl VectorCanvas for SvgDocument {
+    fn add_fill(&mut self, rings: &[Vec<(f64, f64)>], color: &Color, opacity: f64, blend_mode: BlendMode) {
+        let path = to_path(&to_segments(rings), true);
+        if path.is_empty() {
+            return;
+        }
+        let _ = write!(
+            self.content,
+            "<path d=\"{}\" fill=\"{}\" fill-rule=\"evenodd\"",
+            path,
+            to_hex(color)
+        );
+        if opacity < 1.0 {
+            let _ = write!(self.content, " fill-opacity=\"{}\"", opacity);
+        }
+        self.finish_element(blend_mode);
+    }
+
+    fn add_lines(&mut self, lines: &[Vec<(f64, f64)>], stroke: &VectorStroke<'_>) {
+        let path = to_path(&to_segments(lines), false);
+        if path.is_empty() {
+            return;
+        }
+        let line_cap = match stroke.line_cap {
+            None | Some(LineCap::Butt) => "butt",
+            Some(LineCap::Round) => "round",
+            Some(LineCap::Square) => "square",
+        };
+        let line_join = match stroke.line_join {
+            None | Some(LineJoin::Round) => "round",
+            Some(LineJoin::Miter) => "miter",
+            Some(LineJoin::Bevel) => "bevel",
+        };
+        let _ = write!(
+            self.content,
+            "<path d=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"{}\" stroke-linecap=\"{}\" \
+             stroke-linejoin=\"{}\"",
+            path,
+            to_hex(stroke.color),
+            format_coord(stroke.width),
+            line_cap,
+            line_join
+        );
+        if stroke.opacity < 1.0 {
+            let _ = write!(self.content, " stroke-opacity=\"{}\"", stroke.opacity);
+        }
+        if let Some(dashes) = stroke.dashes.filter(|dashes| !dashes.is_empty()) {
+            let dashes = dashes.iter().map(|dash| format_coord(*dash)).collect::<Vec<_>>();
+            let _ = write!(self.content, " stroke-dasharray=\"{}\"", dashes.join(" "));
+        }
+        self.finish_element(stroke.blend_mode);
+    }
+
+    fn add_label_outline(&mut self, outline: &LabelOutline) {
+        let _ = writeln!(
+            self.content,
+            "<path d=\"{}\" fill=\"{}\"/>",
+            to_path(std::slice::from_ref(&outline.segments), false),
+            to_hex(&outline.color)
+        );
+    }
+}
+
+fn to_path(paths: &[Vec<PathSegment>], closed: bool) -> String {
+    let mut result = String::new();
+    for path in paths {
+        for segment in path {
+            let _ = match *segment {
+                PathSegment::MoveTo(x, y) => write!(result, "M{} {}", format_coord(x), format_coord(y)),
+                PathSegment::LineTo(x, y) => write!(result, "L{} {}", format_coord(x), format_coord(y)),
+                PathSegment::QuadTo(cx, cy, x, y) => write!(
+                    result,
+                    "Q{} {} {} {}",
+                    format_coord(cx),
+                    format_coord(cy),
+                    format_coord(x),
+                    format_coord(y)
+                ),
+            };
+        }
+        if closed {
+            result.push('Z');
+        }
+    }
+    result
+}
+
+fn to_hex(color: &Color) -> String {
+    format!("#{:02x}{:02x}{:02x}", color.r, color.g, color.b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_svg_document() {
+        let color = Color { r: 0xff, g: 0x80, b: 0 };
+        let mut svg = SvgDocument::new(256, Some(&Color { r: 0, g: 0, b: 0 }));
+        svg.add_fill(
+            &[vec![(0.0, 0.0), (10.0, 0.0), (10.0, 10.0)], vec![(1.0, 1.0)]],
+            &color,
+            0.5,
+            BlendMode::Multiply,
+        );
+        svg.add_lines(
+            &[vec![(0.125, -0.001), (1.0 / 3.0, 2.0)]],
+            &VectorStroke {
+                color: &color,
+                width: 1.5,
+                opacity: 1.0,
+                dashes: Some(&[4.0, 2.0]),
+                line_cap: Some(&LineCap::Round),
+                line_join: Some(&LineJoin::Bevel),
+                blend_mode: BlendMode::Normal,
+            },
+        );
+        svg.add_lines(
+            &[vec![(0.0, 0.0)]],
+            &VectorStroke {
+                color: &color,
+                width: 1.0,
+                opacity: 1.0,
+                dashes: None,
+                line_cap: None,
+                line_join: None,
+                blend_mode: BlendMode::Normal,
+            },
+        );
+        svg.add_label_outline(&LabelOutline {
+            color: color.clone(),
+            segments: vec![PathSegment::MoveTo(1.0, 1.0), PathSegment::QuadTo(2.0, 0.0, 3.0, 1.0)],
+        });
+        assert_eq!(
+            svg.finish(),
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"256\" height=\"256\" viewBox=\"0 0 256 256\">\n\
+             <rect width=\"100%\" height=\"100%\" fill=\"#000000\"/>\n\
+             <path d=\"M0 0L10 0L10 10Z\" fill=\"#ff8000\" fill-rule=\"evenodd\" fill-opacity=\"0.5\" \
+             style=\"mix-blend-mode:multiply\"/>\n\
+             <path d=\"M0.13 0L0.33 2\" fill=\"none\" stroke=\"#ff8000\" stroke-width=\"1.5\" stroke-linecap=\"round\" \
+             stroke-linejoin=\"bevel\" stroke-dasharray=\"4 2\"/>\n\
+             <path d=\"M1 1Q2 0 3 1\" fill=\"#ff8000\"/>\n\
+             </svg>\n"
+        );
+    }
+}
@@ -0,0 +1,67 @@
+use crate::mapcss::color::Color;
+use crate::mapcss::styler::{BlendMode, LineCap, LineJoin};
+
+// Where `Drawer::draw_vector()` puts the styled figures and labels of a tile instead of rasterizing them, e.g. an
+// SVG document or a page of a PDF. The coordinates are in the pixels of the tile, just like for the PNG tiles.
+pub trait VectorCanvas {
+    // The rings of the multipolygons with holes are filled with the even-odd rule, just like on the PNG tiles.
+    fn add_fill(&mut self, rings: &[Vec<(f64, f64)>], color: &Color, opacity: f64, blend_mode: BlendMode);
+
+    fn add_lines(&mut self, lines: &[Vec<(f64, f64)>], stroke: &VectorStroke<'_>);
+
+    fn add_label_outline(&mut self, outline: &LabelOutline);
+}
+
+// How a line is drawn, with the width and the dashes already scaled.
+pub struct VectorStroke<'s> {
+    pub color: &'s Color,
+    pub width: f64,
+    pub opacity: f64,
+    pub dashes: Option<&'s [f64]>,
+    pub line_cap: Option<&'s LineCap>,
+    pub line_join: Option<&'s LineJoin>,
+    pub blend_mode: BlendMode,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PathSegment {
+    MoveTo(f64, f64),
+    LineTo(f64, f64),
+    // The control point goes first.
+    QuadTo(f64, f64, f64, f64),
+}
+
+// The glyphs of a placed label as a path to fill (see `TilePixels::record_label_outlines()`).
+pub struct LabelOutline {
+    pub color: Color,
+    pub segments: Vec<PathSegment>,
+}
+
+// The lines (or the rings) as a path that starts anew at the first point of every line. The lines with less than
+// two points are skipped.
+pub fn to_segments(lines: &[Vec<(f64, f64)>]) -> Vec<Vec<PathSegment>> {
+    lines
+        .iter()
+        .filter(|line| line.len() >= 2)
+        .map(|line| {
+            line.iter()
+                .enumerate()
+                .map(|(idx, &(x, y))| {
+                    if idx == 0 {
+                        PathSegment::MoveTo(x, y)
+                    } else {
+                        PathSegment::LineTo(x, y)
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// A hundredth of a pixel is more than enough, and the documents for the dense tiles are much smaller without
+// the rest of the digits.
+pub fn format_coord(coord: f64) -> String {
+    let rounded = (coord * 100.0).round() / 100.0;
+    // Avoids `-0`.
+    format!("{}", rounded + 0.0)
+}
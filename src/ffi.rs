@@ -0,0 +1,111 @@
+// A C ABI for embedding the renderer into other programs (see python/osm_renderer.py for an example).
+// All functions report errors to stderr and return NULL, since there's no good way to pass
+// an anyhow::Error through the ABI.
+
+use crate::mapcss::styler::StyleType;
+use crate::render::TileRenderer;
+use crate::tile::Tile;
+use anyhow::{bail, Context, Result};
+use std::ffi::{c_char, CStr};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+
+pub struct Renderer {
+    tile_renderer: TileRenderer<'static>,
+}
+
+/// # Safety
+///
+/// `geodata_file` and `stylesheet_file` must be valid NUL-terminated strings.
+/// The result must be freed with `renderer_free()`.
+#[no_mangle]
+pub unsafe extern "C" fn renderer_init(geodata_file: *const c_char, stylesheet_file: *const c_char) -> *mut Renderer {
+    let init = || -> Result<Renderer> {
+        let geodata_file = to_str(geodata_file).context("Invalid geodata file name")?;
+        let stylesheet_file = to_str(stylesheet_file).context("Invalid stylesheet file name")?;
+        let tile_renderer = TileRenderer::new(geodata_file, stylesheet_file, &StyleType::Josm, None)?;
+        Ok(Renderer { tile_renderer })
+    };
+    match run_reporting_errors(init) {
+        Some(renderer) => Box::into_raw(Box::new(renderer)),
+        None => ptr::null_mut(),
+    }
+}
+
+/// # Safety
+///
+/// `renderer` must come from `renderer_init()`, and `png_size` must point to writable memory.
+/// The result must be freed with `renderer_free_png()`. Can be called from several threads at once.
+#[no_mangle]
+pub unsafe extern "C" fn renderer_render_tile(
+    renderer: *const Renderer,
+    zoom: u8,
+    x: u32,
+    y: u32,
+    scale: u32,
+    png_size: *mut usize,
+) -> *mut u8 {
+    let render = || -> Result<Vec<u8>> {
+        if renderer.is_null() || png_size.is_null() {
+            bail!("NULL passed to renderer_render_tile()");
+        }
+        // The tile itself is checked by the renderer.
+        if !(1..=4).contains(&scale) {
+            bail!("Invalid scale: {}", scale);
+        }
+        let tile = Tile { zoom, x, y };
+        (*renderer).tile_renderer.render_png(&tile, scale as usize)
+    };
+    match run_reporting_errors(render) {
+        Some(png) => {
+            let png = png.into_boxed_slice();
+            *png_size = png.len();
+            Box::into_raw(png) as *mut u8
+        }
+        None => ptr::null_mut(),
+    }
+}
+
+/// # Safety
+///
+/// `png` and `png_size` must come from a single `renderer_render_tile()` call.
+#[no_mangle]
+pub unsafe extern "C" fn renderer_free_png(png: *mut u8, png_size: usize) {
+    if !png.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(png, png_size)));
+    }
+}
+
+/// # Safety
+///
+/// `renderer` must come from `renderer_init()` and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn renderer_free(renderer: *mut Renderer) {
+    if !renderer.is_null() {
+        drop(Box::from_raw(renderer));
+    }
+}
+
+unsafe fn to_str<'a>(s: *const c_char) -> Result<&'a str> {
+    if s.is_null() {
+        bail!("NULL string");
+    }
+    Ok(CStr::from_ptr(s).to_str()?)
+}
+
+// Panicking across the ABI boundary is undefined behavior, so we treat panics as errors.
+fn run_reporting_errors<T>(f: impl FnOnce() -> Result<T>) -> Option<T> {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(result)) => Some(result),
+        Ok(Err(err)) => {
+            for cause in err.chain() {
+                eprintln!("{}", cause);
+            }
+            None
+        }
+        Err(_) => {
+            eprintln!("The renderer panicked");
+            None
+        }
+    }
+}
@@ -0,0 +1,542 @@
+use crate::geodata::importer::{Bounds, EntityStorages, Multipolygon, Polygon, RawNode, RawTags};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashSet};
+
+// Synthetic entities get ids counting down from u64::MAX, so they never clash with real OSM ids.
+const FIRST_SYNTHETIC_ID: u64 = u64::MAX;
+
+// Every file numbers its synthetic entities from the same id, so the same id in two files doesn't mean the same
+// entity. The real OSM ids are nowhere near the upper half of the range.
+pub(super) fn is_synthetic_id(id: u64) -> bool {
+    id > u64::MAX / 2
+}
+
+// OSM coastlines are split into many open ways that are oriented so that land is on the left.
+// We stitch them into chains, clip everything to the bounds of the extract, close the chains
+// that leave the extract by walking along its boundary, and save the results as synthetic
+// multipolygons tagged with `natural=land` (one per ring) and `natural=sea` (the whole extract
+// with land rings cut out) so that stylesheets can fill them like any other area.
+pub(super) fn add_coastline_polygons(entity_storages: &mut EntityStorages) {
+    let chains = merge_chains(collect_coastline_chains(entity_storages));
+    if chains.is_empty() {
+        return;
+    }
+
+    let bounds = match entity_storages
+        .bounds
+        .clone()
+        .or_else(|| get_node_extent(entity_storages))
+    {
+        Some(bounds) => bounds,
+        None => return,
+    };
+
+    let mut builder = CoastlineBuilder {
+        entity_storages,
+        bounds,
+        next_synthetic_id: FIRST_SYNTHETIC_ID,
+    };
+
+    let mut land_rings = Vec::new();
+    let mut pieces = Vec::new();
+    let mut dropped_chain_count = 0;
+
+    for chain in chains {
+        let points = chain.iter().map(|id| builder.to_clip_point(*id)).collect::<Vec<_>>();
+        if chain.len() > 3 && chain.first() == chain.last() {
+            land_rings.extend(builder.clip_ring(&points));
+        } else {
+            let (good_pieces, bad_piece_count) = builder.clip_chain(&points);
+            pieces.extend(good_pieces);
+            dropped_chain_count += bad_piece_count;
+        }
+    }
+
+    land_rings.extend(builder.close_pieces(&pieces));
+
+    if dropped_chain_count > 0 {
+        eprintln!(
+            "{} coastline fragment(s) end inside the extract and can't be closed; ignoring them",
+            dropped_chain_count
+        );
+    }
+
+    if land_rings.is_empty() {
+        return;
+    }
+
+    println!("Assembled {} land polygon(s) from coastlines", land_rings.len());
+
+    let mut sea_polygons = vec![builder.bounds_ring()];
+    for ring in land_rings {
+        let polygon = builder.materialize_ring(&ring);
+        sea_polygons.push(polygon.clone());
+        builder.add_multipolygon(vec![polygon], "land");
+    }
+    builder.add_multipolygon(sea_polygons, "sea");
+}
+
+#[derive(Clone)]
+struct ClipPoint {
+    node_id: Option<usize>,
+    x: f64,
+    y: f64,
+}
+
+struct Piece {
+    points: Vec<ClipPoint>,
+    entry: f64,
+    exit: f64,
+}
+
+#[derive(Clone, Copy)]
+enum Edge {
+    Left,
+    Right,
+    Bottom,
+    Top,
+}
+
+const ALL_EDGES: [Edge; 4] = [Edge::Left, Edge::Right, Edge::Bottom, Edge::Top];
+const BOUNDARY_EPSILON: f64 = 1e-9;
+
+struct CoastlineBuilder<'a> {
+    entity_storages: &'a mut EntityStorages,
+    bounds: Bounds,
+    next_synthetic_id: u64,
+}
+
+impl<'a> CoastlineBuilder<'a> {
+    fn to_clip_point(&self, node_id: usize) -> ClipPoint {
+        let node = &self.entity_storages.node_storage.get_entities()[node_id];
+        ClipPoint {
+            node_id: Some(node_id),
+            x: node.lon,
+            y: node.lat,
+        }
+    }
+
+    fn is_inside(&self, edge: Edge, p: &ClipPoint) -> bool {
+        let b = &self.bounds;
+        match edge {
+            Edge::Left => p.x >= b.min_lon,
+            Edge::Right => p.x <= b.max_lon,
+            Edge::Bottom => p.y >= b.min_lat,
+            Edge::Top => p.y <= b.max_lat,
+        }
+    }
+
+    fn intersect(&self, edge: Edge, a: &ClipPoint, b: &ClipPoint) -> ClipPoint {
+        let at_x = |x: f64| {
+            let t = (x - a.x) / (b.x - a.x);
+            ClipPoint {
+                node_id: None,
+                x,
+                y: a.y + t * (b.y - a.y),
+            }
+        };
+        let at_y = |y: f64| {
+            let t = (y - a.y) / (b.y - a.y);
+            ClipPoint {
+                node_id: None,
+                x: a.x + t * (b.x - a.x),
+                y,
+            }
+        };
+        match edge {
+            Edge::Left => at_x(self.bounds.min_lon),
+            Edge::Right => at_x(self.bounds.max_lon),
+            Edge::Bottom => at_y(self.bounds.min_lat),
+            Edge::Top => at_y(self.bounds.max_lat),
+        }
+    }
+
+    // Sutherland-Hodgman. The result may contain degenerate edges running along the boundary,
+    // but those are harmless for filling.
+    fn clip_ring(&self, ring: &[ClipPoint]) -> Option<Vec<ClipPoint>> {
+        let mut current = ring[..ring.len() - 1].to_vec();
+        for edge in ALL_EDGES {
+            if current.is_empty() {
+                break;
+            }
+            let mut next = Vec::new();
+            for idx in 0..current.len() {
+                let prev = &current[(idx + current.len() - 1) % current.len()];
+                let cur = &current[idx];
+                match (self.is_inside(edge, prev), self.is_inside(edge, cur)) {
+                    (true, true) => next.push(cur.clone()),
+                    (true, false) => next.push(self.intersect(edge, prev, cur)),
+                    (false, true) => {
+                        next.push(self.intersect(edge, prev, cur));
+                        next.push(cur.clone());
+                    }
+                    (false, false) => {}
+                }
+            }
+            current = next;
+        }
+
+        if current.len() < 3 {
+            return None;
+        }
+        current.push(current[0].clone());
+        Some(current)
+    }
+
+    // Splits a polyline into pieces lying inside the bounds. Returns the pieces that start and end
+    // on the boundary, along with the number of pieces that don't (and thus can't be closed).
+    fn clip_chain(&self, chain: &[ClipPoint]) -> (Vec<Piece>, usize) {
+        let mut raw_pieces = Vec::new();
+        let mut current: Vec<ClipPoint> = Vec::new();
+
+        for idx in 1..chain.len() {
+            let (a, b) = (&chain[idx - 1], &chain[idx]);
+            let (t0, t1) = match self.clip_segment(a, b) {
+                Some(range) => range,
+                None => continue,
+            };
+            let point_at = |t: f64, original: &ClipPoint| {
+                if t == 0.0 || t == 1.0 {
+                    original.clone()
+                } else {
+                    ClipPoint {
+                        node_id: None,
+                        x: a.x + t * (b.x - a.x),
+                        y: a.y + t * (b.y - a.y),
+                    }
+                }
+            };
+            if current.is_empty() || t0 > 0.0 {
+                if !current.is_empty() {
+                    raw_pieces.push(std::mem::take(&mut current));
+                }
+                current.push(point_at(t0, a));
+            }
+            current.push(point_at(t1, b));
+            if t1 < 1.0 {
+                raw_pieces.push(std::mem::take(&mut current));
+            }
+        }
+        if !current.is_empty() {
+            raw_pieces.push(current);
+        }
+
+        let mut pieces = Vec::new();
+        let mut bad_piece_count = 0;
+        for points in raw_pieces {
+            let entry = self.perimeter_position(&points[0]);
+            let exit = self.perimeter_position(&points[points.len() - 1]);
+            match (entry, exit) {
+                (Some(entry), Some(exit)) if points.len() >= 2 => pieces.push(Piece { points, entry, exit }),
+                _ => bad_piece_count += 1,
+            }
+        }
+        (pieces, bad_piece_count)
+    }
+
+    // Liang-Barsky: returns the parameter range of the segment that lies inside the bounds.
+    fn clip_segment(&self, a: &ClipPoint, b: &ClipPoint) -> Option<(f64, f64)> {
+        let (dx, dy) = (b.x - a.x, b.y - a.y);
+        let mut t0 = 0.0f64;
+        let mut t1 = 1.0f64;
+        let checks = [
+            (-dx, a.x - self.bounds.min_lon),
+            (dx, self.bounds.max_lon - a.x),
+            (-dy, a.y - self.bounds.min_lat),
+            (dy, self.bounds.max_lat - a.y),
+        ];
+        for (p, q) in checks {
+            if p == 0.0 {
+                if q < 0.0 {
+                    return None;
+                }
+            } else {
+                let r = q / p;
+                if p < 0.0 {
+                    t0 = t0.max(r);
+                } else {
+                    t1 = t1.min(r);
+                }
+            }
+        }
+        if t0 < t1 {
+            Some((t0, t1))
+        } else {
+            None
+        }
+    }
+
+    // Maps a point on the boundary to [0; 4), going counterclockwise from the south-west corner.
+    fn perimeter_position(&self, p: &ClipPoint) -> Option<f64> {
+        let b = &self.bounds;
+        let (width, height) = (b.max_lon - b.min_lon, b.max_lat - b.min_lat);
+        let near = |c1: f64, c2: f64| (c1 - c2).abs() <= BOUNDARY_EPSILON;
+        if near(p.y, b.min_lat) {
+            Some((p.x - b.min_lon) / width)
+        } else if near(p.x, b.max_lon) {
+            Some(1.0 + (p.y - b.min_lat) / height)
+        } else if near(p.y, b.max_lat) {
+            Some(2.0 + (b.max_lon - p.x) / width)
+        } else if near(p.x, b.min_lon) {
+            Some((3.0 + (b.max_lat - p.y) / height) % 4.0)
+        } else {
+            None
+        }
+    }
+
+    fn corner(&self, idx: usize) -> ClipPoint {
+        let b = &self.bounds;
+        let (x, y) = match idx {
+            0 => (b.min_lon, b.min_lat),
+            1 => (b.max_lon, b.min_lat),
+            2 => (b.max_lon, b.max_lat),
+            _ => (b.min_lon, b.max_lat),
+        };
+        ClipPoint { node_id: None, x, y }
+    }
+
+    // Since land is always on the left, we leave the bounds and walk counterclockwise along
+    // the boundary until we meet the next piece entering the bounds.
+    fn close_pieces(&self, pieces: &[Piece]) -> Vec<Vec<ClipPoint>> {
+        let ccw_distance = |from: f64, to: f64| (to - from).rem_euclid(4.0);
+
+        let mut rings = Vec::new();
+        let mut used = vec![false; pieces.len()];
+        for start in 0..pieces.len() {
+            if used[start] {
+                continue;
+            }
+            let mut ring = Vec::new();
+            let mut current = start;
+            loop {
+                used[current] = true;
+                ring.extend(pieces[current].points.iter().cloned());
+
+                let exit = pieces[current].exit;
+                let next = (0..pieces.len())
+                    .filter(|&idx| idx == start || !used[idx])
+                    .min_by(|&a, &b| {
+                        ccw_distance(exit, pieces[a].entry).total_cmp(&ccw_distance(exit, pieces[b].entry))
+                    })
+                    .unwrap_or(start);
+                let distance = ccw_distance(exit, pieces[next].entry);
+
+                let mut corners = (0..4)
+                    .map(|idx| (ccw_distance(exit, idx as f64), idx))
+                    .filter(|&(d, _)| d > 0.0 && d < distance)
+                    .collect::<Vec<_>>();
+                corners.sort_by(|a, b| a.0.total_cmp(&b.0));
+                ring.extend(corners.into_iter().map(|(_, idx)| self.corner(idx)));
+
+                if next == start {
+                    break;
+                }
+                current = next;
+            }
+            if ring.len() >= 3 {
+                ring.push(ring[0].clone());
+                rings.push(ring);
+            }
+        }
+        rings
+    }
+
+    fn bounds_ring(&mut self) -> Polygon {
+        let corners = (0..4).chain(0..1).map(|idx| self.corner(idx)).collect::<Vec<_>>();
+        self.materialize_ring(&corners)
+    }
+
+    fn materialize_ring(&mut self, ring: &[ClipPoint]) -> Polygon {
+        let mut polygon = Polygon::default();
+        let mut first_synthetic_node = None;
+        for (idx, p) in ring.iter().enumerate() {
+            let node_id = match p.node_id {
+                Some(id) => id,
+                // Reuse the first node for the closing point to get a properly closed ring.
+                None if idx + 1 == ring.len() && first_synthetic_node.is_some() => first_synthetic_node.unwrap(),
+                None => {
+                    let id = self.add_synthetic_node(p);
+                    if idx == 0 {
+                        first_synthetic_node = Some(id);
+                    }
+                    id
+                }
+            };
+            polygon.push(node_id);
+        }
+        polygon
+    }
+
+    fn add_synthetic_node(&mut self, p: &ClipPoint) -> usize {
+        let node = RawNode {
+            global_id: self.next_synthetic_id(),
+            lat: p.y,
+            lon: p.x,
+            tags: RawTags::default(),
+        };
+        self.entity_storages.node_storage.add_synthetic(node)
+    }
+
+    fn add_multipolygon(&mut self, polygons: Vec<Polygon>, natural: &str) {
+        let mut multipolygon = Multipolygon {
+            global_id: self.next_synthetic_id(),
+            polygon_ids: Vec::new(),
+            tags: RawTags::default(),
+        };
+        let strings = &mut self.entity_storages.strings;
+        multipolygon.tags.insert_str(strings, "natural", natural);
+        for poly in polygons {
+            multipolygon
+                .polygon_ids
+                .push(self.entity_storages.polygon_storage.len());
+            self.entity_storages.polygon_storage.push(poly);
+        }
+        self.entity_storages.multipolygon_storage.add_synthetic(multipolygon);
+    }
+
+    fn next_synthetic_id(&mut self) -> u64 {
+        let id = self.next_synthetic_id;
+        self.next_synthetic_id -= 1;
+        id
+    }
+}
+
+fn collect_coastline_chains(entity_storages: &EntityStorages) -> Vec<Vec<usize>> {
+    entity_storages
+        .way_storage
+        .get_entities()
+        .iter()
+        .filter(|way| way.node_ids.len() >= 2 && way.tags.has_tag(&entity_storages.strings, "natural", "coastline"))
+        .map(|way| way.node_ids.clone())
+        .collect()
+}
+
+fn merge_chains(chains: Vec<Vec<usize>>) -> Vec<Vec<usize>> {
+    let mut result = Vec::new();
+    let last_nodes = chains.iter().map(|c| c[c.len() - 1]).collect::<HashSet<_>>();
+
+    let mut by_first_node = BTreeMap::new();
+    for chain in chains {
+        match by_first_node.entry(chain[0]) {
+            // Two coastlines starting at the same node mean broken data; don't try to be smart.
+            Entry::Occupied(_) => result.push(chain),
+            Entry::Vacant(e) => {
+                e.insert(chain);
+            }
+        }
+    }
+
+    let extend_chain = |mut chain: Vec<usize>, by_first_node: &mut BTreeMap<usize, Vec<usize>>| {
+        while chain.first() != chain.last() {
+            match by_first_node.remove(&chain[chain.len() - 1]) {
+                Some(next) => chain.extend_from_slice(&next[1..]),
+                None => break,
+            }
+        }
+        chain
+    };
+
+    // Start with the chains that aren't continuations of other chains, so that
+    // we don't end up with a chain split in the middle.
+    let start_nodes = by_first_node
+        .keys()
+        .filter(|node| !last_nodes.contains(node))
+        .cloned()
+        .collect::<Vec<_>>();
+    for node in start_nodes {
+        if let Some(chain) = by_first_node.remove(&node) {
+            result.push(extend_chain(chain, &mut by_first_node));
+        }
+    }
+    while let Some((_, chain)) = by_first_node.pop_first() {
+        result.push(extend_chain(chain, &mut by_first_node));
+    }
+
+    result
+}
+
+fn get_node_extent(entity_storages: &EntityStorages) -> Option<Bounds> {
+    let nodes = entity_storages.node_storage.get_entities();
+    let first = nodes.first()?;
+    let mut bounds = Bounds {
+        min_lat: first.lat,
+        max_lat: first.lat,
+        min_lon: first.lon,
+        max_lon: first.lon,
+    };
+    for node in nodes.iter().skip(1) {
+        bounds.min_lat = bounds.min_lat.min(node.lat);
+        bounds.max_lat = bounds.max_lat.max(node.lat);
+        bounds.min_lon = bounds.min_lon.min(node.lon);
+        bounds.max_lon = bounds.max_lon.max(node.lon);
+    }
+    Some(bounds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geodata::importer::RawWay;
+
+    fn get_ring_area(entity_storages: &EntityStorages, polygon: &Polygon) -> f64 {
+        let nodes = entity_storages.node_storage.get_entities();
+        let doubled_area: f64 = (1..polygon.len())
+            .map(|idx| {
+                let (n1, n2) = (&nodes[polygon[idx - 1]], &nodes[polygon[idx]]);
+                n1.lon * n2.lat - n2.lon * n1.lat
+            })
+            .sum();
+        doubled_area / 2.0
+    }
+
+    #[test]
+    fn test_coastline_crossing_bounds() {
+        let mut entity_storages = EntityStorages::new();
+        entity_storages.bounds = Some(Bounds {
+            min_lat: 0.0,
+            max_lat: 10.0,
+            min_lon: 0.0,
+            max_lon: 10.0,
+        });
+
+        // Two ways going from west to east, so the land is to the north.
+        for (id, (lat, lon)) in [(5.0, -1.0), (5.0, 5.0), (5.0, 11.0)].into_iter().enumerate() {
+            let node = RawNode {
+                global_id: id as u64,
+                lat,
+                lon,
+                tags: RawTags::default(),
+            };
+            entity_storages.node_storage.add(node.global_id, node);
+        }
+        for (id, node_ids) in [vec![1, 2], vec![0, 1]].into_iter().enumerate() {
+            let mut way = RawWay {
+                global_id: id as u64,
+                node_ids,
+                tags: RawTags::default(),
+            };
+            way.tags
+                .insert_str(&mut entity_storages.strings, "natural", "coastline");
+            entity_storages.way_storage.add(way.global_id, way);
+        }
+
+        add_coastline_polygons(&mut entity_storages);
+
+        let multipolygons = entity_storages.multipolygon_storage.get_entities();
+        assert_eq!(multipolygons.len(), 2);
+
+        let land = &multipolygons[0];
+        assert_eq!(land.tags.get_str(&entity_storages.strings, "natural"), Some("land"));
+        assert_eq!(land.polygon_ids.len(), 1);
+        let land_ring = &entity_storages.polygon_storage[land.polygon_ids[0]];
+        assert_eq!(land_ring.first(), land_ring.last());
+        assert!(land_ring.contains(&1));
+        assert!((get_ring_area(&entity_storages, land_ring) - 50.0).abs() < 1e-9);
+
+        let sea = &multipolygons[1];
+        assert_eq!(sea.tags.get_str(&entity_storages.strings, "natural"), Some("sea"));
+        assert_eq!(sea.polygon_ids.len(), 2);
+        let sea_ring = &entity_storages.polygon_storage[sea.polygon_ids[0]];
+        assert!((get_ring_area(&entity_storages, sea_ring) - 100.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,148 @@
+use crate::coords::Coords;
+use crate::geodata::reader::{GeodataReader, Node, OsmEntities, OsmEntity};
+use crate::tile::{coords_to_xy, Tile, TILE_SIZE};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+use std::ops::RangeInclusive;
+
+// Returns the tiles at the given zoom levels that may look different when they are rendered from `new` instead
+// of `old` (e.g. after the data was imported again), so that the caches can drop only these tiles.
+//
+// An entity has changed if its tags or the coordinates of its nodes are different, or if it's present only in
+// one of the files. The tiles around a changed entity are expired, too, since every tile is rendered with the
+// entities of its neighbors (e.g. for the labels that cross the tile border).
+pub fn expired_tiles<'a>(
+    old: &'a GeodataReader<'a>,
+    new: &'a GeodataReader<'a>,
+    zooms: RangeInclusive<u8>,
+) -> Vec<Tile> {
+    let old_footprints = collect_footprints(&old.all_entities());
+    let new_footprints = collect_footprints(&new.all_entities());
+
+    let mut changed_bboxes = Vec::new();
+    for (id, new_footprint) in &new_footprints {
+        match old_footprints.get(id) {
+            Some(old_footprint) if old_footprint.hash == new_footprint.hash => {}
+            Some(old_footprint) => changed_bboxes.extend([&old_footprint.bbox, &new_footprint.bbox]),
+            None => changed_bboxes.push(&new_footprint.bbox),
+        }
+    }
+    for (id, old_footprint) in &old_footprints {
+        if !new_footprints.contains_key(id) {
+            changed_bboxes.push(&old_footprint.bbox);
+        }
+    }
+
+    let mut tiles = BTreeSet::new();
+    for zoom in zooms {
+        let max_index = (1u32 << zoom) - 1;
+        let to_tile_index = |pixel: f64| ((pixel / f64::from(TILE_SIZE)).max(0.0) as u32).min(max_index);
+        for bbox in changed_bboxes.iter().filter_map(|bbox| bbox.as_ref()) {
+            // Tile y coordinates grow from north to south.
+            let (left, top) = coords_to_xy(&(bbox.max_lat, bbox.min_lon), zoom);
+            let (right, bottom) = coords_to_xy(&(bbox.min_lat, bbox.max_lon), zoom);
+            let (min_x, max_x) = (to_tile_index(left), to_tile_index(right));
+            let (min_y, max_y) = (to_tile_index(top), to_tile_index(bottom));
+            for x in min_x.saturating_sub(1)..=(max_x + 1).min(max_index) {
+                for y in min_y.saturating_sub(1)..=(max_y + 1).min(max_index) {
+                    tiles.insert((zoom, x, y));
+                }
+            }
+        }
+    }
+
+    tiles.into_iter().map(|(zoom, x, y)| Tile { zoom, x, y }).collect()
+}
+
+// The entity kinds are numbered, since e.g. a node and a way can have the same global id.
+type EntityKey = (u8, u64);
+
+// Everything about an entity that affects how it's drawn, boiled down to a hash, and the area it's drawn in.
+struct Footprint {
+    hash: u64,
+    // None for the entities without nodes (e.g. multipolygons without valid polygons), which are never drawn.
+    bbox: Option<BoundingBox>,
+}
+
+struct BoundingBox {
+    min_lat: f64,
+    min_lon: f64,
+    max_lat: f64,
+    max_lon: f64,
+}
+
+fn collect_footprints<'a>(entities: &OsmEntities<'a>) -> HashMap<EntityKey, Footprint> {
+    fn add<'a, E: OsmEntity<'a>>(
+        footprints: &mut HashMap<EntityKey, Footprint>,
+        kind: u8,
+        entities: &[E],
+        get_parts: impl Fn(&E) -> Vec<Vec<Node<'a>>>,
+    ) {
+        for entity in entities {
+            footprints.insert(
+                (kind, entity.global_id()),
+                compute_footprint(entity, &get_parts(entity)),
+            );
+        }
+    }
+
+    let mut footprints = HashMap::new();
+    add(&mut footprints, 0, &entities.nodes, |node| vec![vec![node.clone()]]);
+    add(&mut footprints, 1, &entities.ways, |way| {
+        vec![(0..way.node_count()).map(|idx| way.get_node(idx)).collect()]
+    });
+    add(&mut footprints, 2, &entities.multipolygons, |multipolygon| {
+        (0..multipolygon.polygon_count())
+            .map(|idx| {
+                let polygon = multipolygon.get_polygon(idx);
+                (0..polygon.node_count()).map(|idx| polygon.get_node(idx)).collect()
+            })
+            .collect()
+    });
+    add(&mut footprints, 3, &entities.relations, |relation| {
+        (0..relation.line_count())
+            .map(|idx| {
+                let line = relation.get_line(idx);
+                (0..line.node_count()).map(|idx| line.get_node(idx)).collect()
+            })
+            .collect()
+    });
+    footprints
+}
+
+fn compute_footprint<'a, E: OsmEntity<'a>>(entity: &E, parts: &[Vec<Node<'a>>]) -> Footprint {
+    let mut hasher = DefaultHasher::new();
+    let tags = entity.tags();
+    for (k, v) in &tags {
+        (k, v).hash(&mut hasher);
+    }
+
+    let mut bbox: Option<BoundingBox> = None;
+    for part in parts {
+        part.len().hash(&mut hasher);
+        for node in part {
+            let (lat, lon) = (node.lat(), node.lon());
+            (lat.to_bits(), lon.to_bits()).hash(&mut hasher);
+            bbox = Some(match bbox {
+                Some(bbox) => BoundingBox {
+                    min_lat: bbox.min_lat.min(lat),
+                    min_lon: bbox.min_lon.min(lon),
+                    max_lat: bbox.max_lat.max(lat),
+                    max_lon: bbox.max_lon.max(lon),
+                },
+                None => BoundingBox {
+                    min_lat: lat,
+                    min_lon: lon,
+                    max_lat: lat,
+                    max_lon: lon,
+                },
+            });
+        }
+    }
+
+    Footprint {
+        hash: hasher.finish(),
+        bbox,
+    }
+}
@@ -0,0 +1,61 @@
+use crate::geodata::importer::RawRefs;
+use std::collections::BTreeMap;
+
+// Joins member ways of a relation into as few continuous lines as possible, so that e.g. dashes
+// don't restart at every way boundary. Unlike multipolygons, the lines don't have to be closed,
+// and ways may go in any direction.
+pub(super) fn find_lines_in_relation(ways: &[&RawRefs]) -> Vec<RawRefs> {
+    let mut endpoint_to_ways = BTreeMap::<usize, Vec<usize>>::new();
+    for (idx, way) in ways.iter().enumerate() {
+        endpoint_to_ways.entry(way[0]).or_default().push(idx);
+        endpoint_to_ways.entry(way[way.len() - 1]).or_default().push(idx);
+    }
+
+    let mut used = vec![false; ways.len()];
+    let take_way_ending_at = |node_id: usize, used: &mut [bool]| -> Option<RawRefs> {
+        let candidates = endpoint_to_ways.get(&node_id)?;
+        let idx = candidates.iter().cloned().find(|idx| !used[*idx])?;
+        used[idx] = true;
+        let mut way = ways[idx].clone();
+        if way[0] != node_id {
+            way.reverse();
+        }
+        Some(way)
+    };
+
+    let mut lines = Vec::new();
+    for idx in 0..ways.len() {
+        if used[idx] {
+            continue;
+        }
+        used[idx] = true;
+        let mut line = ways[idx].clone();
+
+        for _ in 0..2 {
+            while line.first() != line.last() {
+                match take_way_ending_at(line[line.len() - 1], &mut used) {
+                    Some(next) => line.extend_from_slice(&next[1..]),
+                    None => break,
+                }
+            }
+            // Now try to extend the line in the opposite direction.
+            line.reverse();
+        }
+
+        lines.push(line);
+    }
+
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ways_in_different_directions() {
+        let ways = [vec![3, 4, 5], vec![3, 2, 1], vec![5, 6], vec![10, 11]];
+        let lines = find_lines_in_relation(&ways.iter().collect::<Vec<_>>());
+        assert_eq!(lines, vec![vec![1, 2, 3, 4, 5, 6], vec![10, 11]]);
+    }
+}
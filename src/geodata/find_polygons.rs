@@ -1,8 +1,14 @@
 use crate::geodata::importer::Polygon;
+use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
 
 type NodePos = (u64, u64);
 
+// Ways that should share a node often end at two different nodes a few centimeters apart (e.g. after being
+// drawn separately by different mappers). The ends of such ways are connected if they are not farther apart
+// than this (in degrees, so about a meter).
+const SNAP_DISTANCE: f64 = 1e-5;
+
 pub(super) struct NodeDesc {
     id: usize,
     pos: NodePos,
@@ -29,41 +35,131 @@ impl NodeDescPair {
     }
 }
 
+pub(super) struct MultipolygonRings {
+    pub(super) polygons: Vec<Polygon>,
+    // The number of gaps between the ends of the ways that were closed with SNAP_DISTANCE.
+    pub(super) closed_gaps: usize,
+    // The number of segments that didn't end up in a complete ring and were dropped.
+    pub(super) unmatched_segments: usize,
+    // The number of complete rings that were dropped because they cross themselves.
+    pub(super) self_intersecting_rings: usize,
+}
+
+// Counts the multipolygons that could be imported only partially or not at all, to be reported once after
+// the import instead of making the user look for them in the log.
+#[derive(Default)]
+pub(super) struct AssemblyReport {
+    repaired: Vec<u64>,
+    incomplete: Vec<u64>,
+    dropped: Vec<u64>,
+}
+
+impl AssemblyReport {
+    const MAX_LISTED_IDS: usize = 10;
+
+    pub(super) fn add(&mut self, relation_id: u64, rings: Option<&MultipolygonRings>) {
+        match rings {
+            None => self.dropped.push(relation_id),
+            Some(rings) if rings.unmatched_segments > 0 || rings.self_intersecting_rings > 0 => {
+                self.incomplete.push(relation_id)
+            }
+            Some(rings) if rings.closed_gaps > 0 => self.repaired.push(relation_id),
+            Some(_) => {}
+        }
+    }
+
+    // The numbers of the repaired, incomplete and dropped multipolygons.
+    pub(super) fn counts(&self) -> (usize, usize, usize) {
+        (self.repaired.len(), self.incomplete.len(), self.dropped.len())
+    }
+
+    pub(super) fn summary(&self) -> Option<String> {
+        let groups = [
+            ("Multipolygons with closed gaps", &self.repaired),
+            ("Multipolygons with dropped rings", &self.incomplete),
+            ("Dropped multipolygons", &self.dropped),
+        ];
+        let lines = groups
+            .iter()
+            .filter(|(_, ids)| !ids.is_empty())
+            .map(|(description, ids)| {
+                let mut listed_ids = ids
+                    .iter()
+                    .take(Self::MAX_LISTED_IDS)
+                    .map(|id| format!("#{}", id))
+                    .collect::<Vec<_>>();
+                if ids.len() > Self::MAX_LISTED_IDS {
+                    listed_ids.push(format!("and {} more", ids.len() - Self::MAX_LISTED_IDS));
+                }
+                format!("{} ({}): {}", description, ids.len(), listed_ids.join(", "))
+            })
+            .collect::<Vec<_>>();
+        if lines.is_empty() {
+            None
+        } else {
+            Some(lines.join("\n"))
+        }
+    }
+}
+
+// Returns None only if there are no complete rings at all. The segments that can't be made into a ring are
+// dropped, so that one broken way doesn't make a big lake or forest disappear from the map.
 pub(super) fn find_polygons_in_multipolygon(
     relation_id: u64,
     relation_segments: &[NodeDescPair],
-) -> Option<Vec<Polygon>> {
+) -> Option<MultipolygonRings> {
     let connections = get_connections(relation_segments);
     let mut available_segments = vec![true; relation_segments.len()];
-    find_rings(relation_id, relation_segments, &connections, &mut available_segments).map(|all_rings| {
-        let mut polygons = Vec::new();
-        for ring in all_rings {
-            let mut polygon = Polygon::default();
-            for idx in 0..ring.len() {
-                let seg = &relation_segments[ring[idx]];
-                if idx == 0 {
-                    polygon.push(seg.node1.id);
-                }
-                let last_node = polygon[polygon.len() - 1];
-                polygon.push(if last_node == seg.node1.id {
-                    seg.node2.id
-                } else {
-                    seg.node1.id
-                });
-            }
-            polygons.push(polygon);
-        }
-        polygons
-    })
+    let rings = find_rings(relation_segments, &connections, &mut available_segments);
+
+    if rings.self_intersecting_rings > 0 {
+        eprintln!(
+            "Relation #{} is not a valid multipolygon ({} of its rings are self-intersecting and are dropped)",
+            relation_id, rings.self_intersecting_rings,
+        );
+    }
+    if relation_segments.is_empty() {
+        eprintln!(
+            "Relation #{} is not a valid multipolygon (none of its member ways are in the input file)",
+            relation_id
+        );
+        return None;
+    }
+    if rings.polygons.is_empty() {
+        eprintln!(
+            "Relation #{} is not a valid multipolygon (none of its {} segments form a valid ring)",
+            relation_id,
+            relation_segments.len(),
+        );
+        return None;
+    }
+    if rings.unmatched_segments > 0 {
+        eprintln!(
+            "Relation #{} is not a valid multipolygon (built {} complete rings, but {} segments are unmatched), \
+             only the complete rings are imported",
+            relation_id,
+            rings.polygons.len(),
+            rings.unmatched_segments,
+        );
+    }
+    if rings.closed_gaps > 0 {
+        eprintln!(
+            "Relation #{} is not a valid multipolygon (closed {} gaps between the ends of its ways)",
+            relation_id, rings.closed_gaps,
+        );
+    }
+    Some(rings)
 }
 
 struct SearchParams {
     first_pos: NodePos,
+    first_node_id: usize,
     is_inner: bool,
 }
 
 struct ConnectedSegment {
     other_side: NodePos,
+    other_side_id: usize,
     segment_index: usize,
     is_inner: bool,
 }
@@ -74,8 +170,8 @@ fn get_connections(relation_segments: &[NodeDescPair]) -> SegmentConnections {
     let mut connections = SegmentConnections::new();
 
     for (idx, seg) in relation_segments.iter().enumerate() {
-        add_to_connections(&mut connections, seg.node1.pos, seg.node2.pos, idx, seg.is_inner);
-        add_to_connections(&mut connections, seg.node2.pos, seg.node1.pos, idx, seg.is_inner);
+        add_to_connections(&mut connections, seg.node1.pos, &seg.node2, idx, seg.is_inner);
+        add_to_connections(&mut connections, seg.node2.pos, &seg.node1, idx, seg.is_inner);
     }
 
     connections
@@ -83,13 +179,14 @@ fn get_connections(relation_segments: &[NodeDescPair]) -> SegmentConnections {
 
 fn add_to_connections(
     connections: &mut SegmentConnections,
-    pos1: NodePos,
-    pos2: NodePos,
+    pos: NodePos,
+    other_side: &NodeDesc,
     segment_index: usize,
     is_inner: bool,
 ) {
-    connections.entry(pos1).or_default().push(ConnectedSegment {
-        other_side: pos2,
+    connections.entry(pos).or_default().push(ConnectedSegment {
+        other_side: other_side.pos,
+        other_side_id: other_side.id,
         segment_index,
         is_inner,
     });
@@ -99,6 +196,10 @@ struct CurrentRing<'a> {
     available_segments: &'a mut Vec<bool>,
     used_segments: Vec<usize>,
     used_vertices: HashSet<NodePos>,
+    node_ids: Polygon,
+    // The positions of `node_ids`, for checking the ring for self-intersections.
+    node_positions: Vec<NodePos>,
+    closed_gaps: usize,
 }
 
 impl<'a> CurrentRing<'a> {
@@ -106,17 +207,22 @@ impl<'a> CurrentRing<'a> {
         self.available_segments[seg.segment_index] = false;
         self.used_segments.push(seg.segment_index);
         self.used_vertices.insert(seg.other_side);
+        self.node_ids.push(seg.other_side_id);
+        self.node_positions.push(seg.other_side);
     }
 }
 
 fn find_rings(
-    relation_id: u64,
     relation_segments: &[NodeDescPair],
     connections: &SegmentConnections,
     available_segments: &mut Vec<bool>,
-) -> Option<Vec<Vec<usize>>> {
-    let mut res = Vec::new();
-    let mut unmatched_count = relation_segments.len();
+) -> MultipolygonRings {
+    let mut res = MultipolygonRings {
+        polygons: Vec::new(),
+        closed_gaps: 0,
+        unmatched_segments: 0,
+        self_intersecting_rings: 0,
+    };
     for start_idx in 0..available_segments.len() {
         if !available_segments[start_idx] {
             continue;
@@ -132,27 +238,38 @@ fn find_rings(
                 .iter()
                 .cloned()
                 .collect(),
+            node_ids: vec![start_segment.node1.id, start_segment.node2.id],
+            node_positions: vec![start_segment.node1.pos, start_segment.node2.pos],
+            closed_gaps: 0,
         };
         let search_params = SearchParams {
             first_pos: start_segment.node1.pos,
+            first_node_id: start_segment.node1.id,
             is_inner: start_segment.is_inner,
         };
 
-        if !find_ring_from(start_segment.node2.pos, &search_params, connections, &mut ring) {
-            eprintln!(
-                "Relation #{} is not a valid multipolygon (built {} complete rings, but {} segments are unmatched)",
-                relation_id,
-                res.len(),
-                unmatched_count,
-            );
-            return None;
+        // The segments of a ring that can't be closed are not returned to the available ones: otherwise,
+        // every one of them would start another attempt that fails the same way.
+        if find_ring_from(
+            start_segment.node2.pos,
+            &search_params,
+            relation_segments,
+            connections,
+            &mut ring,
+        ) {
+            if is_self_intersecting(&ring.node_positions) {
+                // A bow-tie ring can't be filled correctly with any fill rule, so it's better not to draw it.
+                res.self_intersecting_rings += 1;
+            } else {
+                res.closed_gaps += ring.closed_gaps;
+                res.polygons.push(ring.node_ids);
+            }
+        } else {
+            res.unmatched_segments += ring.used_segments.len();
         }
-
-        unmatched_count -= ring.used_segments.len();
-        res.push(ring.used_segments);
     }
 
-    Some(res)
+    res
 }
 
 fn find_next_segment<'a>(
@@ -175,9 +292,43 @@ fn find_next_segment<'a>(
     None
 }
 
+fn distance(pos1: NodePos, pos2: NodePos) -> f64 {
+    let d_lat = f64::from_bits(pos1.0) - f64::from_bits(pos2.0);
+    let d_lon = f64::from_bits(pos1.1) - f64::from_bits(pos2.1);
+    d_lat.hypot(d_lon)
+}
+
+// Finds the closest available segment that has an end within SNAP_DISTANCE of `from_pos`, and returns
+// the near end of it together with the segment itself as seen from this end.
+fn find_snapped_segment<'a>(
+    from_pos: NodePos,
+    search_params: &SearchParams,
+    relation_segments: &'a [NodeDescPair],
+    ring: &CurrentRing<'_>,
+) -> Option<(&'a NodeDesc, &'a NodeDesc, usize)> {
+    let mut best: Option<(f64, &NodeDesc, &NodeDesc, usize)> = None;
+    for (idx, seg) in relation_segments.iter().enumerate() {
+        if seg.is_inner != search_params.is_inner || !ring.available_segments[idx] {
+            continue;
+        }
+        for (near, far) in [(&seg.node1, &seg.node2), (&seg.node2, &seg.node1)] {
+            let far_is_used = far.pos != search_params.first_pos && ring.used_vertices.contains(&far.pos);
+            if ring.used_vertices.contains(&near.pos) || far_is_used {
+                continue;
+            }
+            let dist = distance(from_pos, near.pos);
+            if dist <= SNAP_DISTANCE && best.is_none_or(|(best_dist, ..)| dist < best_dist) {
+                best = Some((dist, near, far, idx));
+            }
+        }
+    }
+    best.map(|(_, near, far, idx)| (near, far, idx))
+}
+
 fn find_ring_from(
     mut start_pos: NodePos,
     search_params: &SearchParams,
+    relation_segments: &[NodeDescPair],
     connections: &SegmentConnections,
     ring: &mut CurrentRing<'_>,
 ) -> bool {
@@ -190,7 +341,195 @@ fn find_ring_from(
                 }
                 start_pos = seg.other_side;
             }
-            None => return false,
+            None => {
+                // A dead end: either the ring almost returns to where it started, or there is
+                // another way that starts almost where this one ends. Either way, the two nodes
+                // on the sides of the gap are merged into one instead of being connected by a tiny
+                // edge, which could touch the edges around it.
+                if ring.used_segments.len() >= 3 && distance(start_pos, search_params.first_pos) <= SNAP_DISTANCE {
+                    *ring.node_ids.last_mut().unwrap() = search_params.first_node_id;
+                    *ring.node_positions.last_mut().unwrap() = search_params.first_pos;
+                    ring.closed_gaps += 1;
+                    return true;
+                }
+                match find_snapped_segment(start_pos, search_params, relation_segments, ring) {
+                    Some((near, far, segment_index)) => {
+                        ring.used_vertices.insert(near.pos);
+                        ring.include_segment(&ConnectedSegment {
+                            other_side: far.pos,
+                            other_side_id: far.id,
+                            segment_index,
+                            is_inner: search_params.is_inner,
+                        });
+                        ring.closed_gaps += 1;
+                        if search_params.first_pos == far.pos {
+                            return ring.used_segments.len() >= 3;
+                        }
+                        start_pos = far.pos;
+                    }
+                    None => return false,
+                }
+            }
         }
     }
 }
+
+// Checks whether any two edges of a closed ring that aren't next to each other cross or touch. The edges
+// are swept from west to east, so that only the edges whose longitude ranges overlap are compared.
+fn is_self_intersecting(ring: &[NodePos]) -> bool {
+    let points = ring
+        .iter()
+        .map(|pos| (f64::from_bits(pos.1), f64::from_bits(pos.0)))
+        .collect::<Vec<_>>();
+    let edge_count = points.len() - 1;
+    let min_x = |edge: usize| points[edge].0.min(points[edge + 1].0);
+    let max_x = |edge: usize| points[edge].0.max(points[edge + 1].0);
+
+    let mut edges = (0..edge_count).collect::<Vec<_>>();
+    edges.sort_by(|a, b| min_x(*a).total_cmp(&min_x(*b)));
+
+    let mut active_edges: Vec<usize> = Vec::new();
+    for edge in edges {
+        let x = min_x(edge);
+        active_edges.retain(|other| max_x(*other) >= x);
+        for &other in &active_edges {
+            let distance = edge.abs_diff(other);
+            let are_adjacent = distance == 1 || distance == edge_count - 1;
+            if !are_adjacent && edges_intersect(points[edge], points[edge + 1], points[other], points[other + 1]) {
+                return true;
+            }
+        }
+        active_edges.push(edge);
+    }
+    false
+}
+
+type Point = (f64, f64);
+
+fn edges_intersect(p1: Point, p2: Point, q1: Point, q2: Point) -> bool {
+    let orientation = |a: Point, b: Point, c: Point| {
+        let cross = (b.0 - a.0) * (c.1 - a.1) - (b.1 - a.1) * (c.0 - a.0);
+        cross.partial_cmp(&0.0).unwrap_or(Ordering::Equal)
+    };
+    // Checks whether `c`, which is collinear with the edge from `a` to `b`, lies on it.
+    let is_on_edge = |a: Point, b: Point, c: Point| {
+        c.0 >= a.0.min(b.0) && c.0 <= a.0.max(b.0) && c.1 >= a.1.min(b.1) && c.1 <= a.1.max(b.1)
+    };
+
+    let o1 = orientation(p1, p2, q1);
+    let o2 = orientation(p1, p2, q2);
+    let o3 = orientation(q1, q2, p1);
+    let o4 = orientation(q1, q2, p2);
+
+    (o1 != o2 && o3 != o4)
+        || (o1 == Ordering::Equal && is_on_edge(p1, p2, q1))
+        || (o2 == Ordering::Equal && is_on_edge(p1, p2, q2))
+        || (o3 == Ordering::Equal && is_on_edge(q1, q2, p1))
+        || (o4 == Ordering::Equal && is_on_edge(q1, q2, p2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds the segments of a way from its node coordinates, numbering the nodes from `first_id`.
+    fn way_segments(first_id: usize, coords: &[(f64, f64)], is_inner: bool) -> Vec<NodeDescPair> {
+        (1..coords.len())
+            .map(|idx| {
+                let (lat1, lon1) = coords[idx - 1];
+                let (lat2, lon2) = coords[idx];
+                NodeDescPair::new(
+                    NodeDesc::new(first_id + idx - 1, lat1, lon1),
+                    NodeDesc::new(first_id + idx, lat2, lon2),
+                    is_inner,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_exact_rings() {
+        let mut segments = way_segments(0, &[(0.0, 0.0), (0.0, 1.0), (1.0, 1.0), (1.0, 0.0)], false);
+        segments.push(NodeDescPair::new(
+            NodeDesc::new(3, 1.0, 0.0),
+            NodeDesc::new(0, 0.0, 0.0),
+            false,
+        ));
+        let rings = find_polygons_in_multipolygon(1, &segments).unwrap();
+        assert_eq!(rings.polygons, vec![vec![0, 1, 2, 3, 0]]);
+        assert_eq!(rings.closed_gaps, 0);
+        assert_eq!(rings.unmatched_segments, 0);
+    }
+
+    #[test]
+    fn test_snapped_rings() {
+        // Two ways that form a square, but whose ends are a few centimeters apart in both corners.
+        let mut segments = way_segments(0, &[(0.0, 0.0), (0.0, 1.0), (1.0, 1.0)], false);
+        segments.extend(way_segments(
+            10,
+            &[(1.0, 1.000_000_3), (1.0, 0.0), (0.000_000_2, 0.0)],
+            false,
+        ));
+        let rings = find_polygons_in_multipolygon(1, &segments).unwrap();
+        assert_eq!(rings.polygons, vec![vec![0, 1, 2, 11, 0]]);
+        assert_eq!(rings.closed_gaps, 2);
+        assert_eq!(rings.unmatched_segments, 0);
+
+        // The ends of the second way are too far away from the first one.
+        let mut segments = way_segments(0, &[(0.0, 0.0), (0.0, 1.0), (1.0, 1.0)], false);
+        segments.extend(way_segments(10, &[(1.0, 1.001), (1.0, 0.0), (0.001, 0.0)], false));
+        assert!(find_polygons_in_multipolygon(1, &segments).is_none());
+    }
+
+    #[test]
+    fn test_incomplete_rings() {
+        let mut segments = way_segments(0, &[(0.0, 0.0), (0.0, 1.0), (1.0, 1.0), (1.0, 0.0), (0.0, 0.0)], false);
+        segments.extend(way_segments(10, &[(0.2, 0.2), (0.2, 0.8), (0.8, 0.8)], true));
+        let rings = find_polygons_in_multipolygon(1, &segments).unwrap();
+        assert_eq!(rings.polygons, vec![vec![0, 1, 2, 3, 4]]);
+        assert_eq!(rings.closed_gaps, 0);
+        assert_eq!(rings.unmatched_segments, 2);
+
+        let mut report = AssemblyReport::default();
+        report.add(1, Some(&rings));
+        report.add(2, None);
+        report.add(3, None);
+        assert_eq!(
+            report.summary().unwrap(),
+            "Multipolygons with dropped rings (1): #1\nDropped multipolygons (2): #2, #3"
+        );
+        assert!(AssemblyReport::default().summary().is_none());
+    }
+
+    #[test]
+    fn test_self_intersecting_rings() {
+        let bow_tie = way_segments(0, &[(0.0, 0.0), (0.0, 1.0), (1.0, 0.0), (1.0, 1.0), (0.0, 0.0)], false);
+        assert!(find_polygons_in_multipolygon(1, &bow_tie).is_none());
+
+        // A ring that is a bow tie only because of a snapped gap is dropped as well, the valid one is kept.
+        let mut segments = way_segments(0, &[(0.0, 0.0), (0.0, 1.0), (1.0, 1.0), (1.0, 0.0), (0.0, 0.0)], false);
+        segments.extend(way_segments(
+            10,
+            &[(2.0, 2.0), (2.0, 3.0), (3.0, 2.0), (3.0, 3.0), (2.000_000_1, 2.0)],
+            false,
+        ));
+        let rings = find_polygons_in_multipolygon(1, &segments).unwrap();
+        assert_eq!(rings.polygons, vec![vec![0, 1, 2, 3, 4]]);
+        assert_eq!(rings.self_intersecting_rings, 1);
+        assert_eq!(rings.closed_gaps, 0);
+
+        // Touching the ring at a vertex is an intersection, too.
+        let touching = way_segments(
+            0,
+            &[(0.0, 0.0), (0.0, 2.0), (2.0, 2.0), (0.0, 1.0), (2.0, 0.0), (0.0, 0.0)],
+            false,
+        );
+        assert!(is_self_intersecting(
+            &touching
+                .iter()
+                .map(|seg| seg.node1.pos)
+                .chain([touching[0].node1.pos])
+                .collect::<Vec<_>>()
+        ));
+    }
+}
@@ -0,0 +1,123 @@
+use crate::geodata::importer::{EntityStorages, Multipolygon, RawNode, RawTags, RawWay};
+use crate::geodata::saver::save_to_internal_format;
+use crate::geodata::string_table::StringTable;
+use anyhow::{anyhow, Context, Result};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+// Builds small geodata files with exact geometries for the tests, without writing OSM XML first. The entities
+// reference each other by their OSM ids and are saved just like the imported ones, except that the untagged nodes
+// are never pruned and the rings of the multipolygons are taken as is instead of being assembled from ways.
+#[derive(Clone, Default)]
+pub struct GeodataBuilder {
+    nodes: Vec<(u64, f64, f64, Tags)>,
+    ways: Vec<(u64, Vec<u64>, Tags)>,
+    multipolygons: Vec<(u64, Vec<Vec<u64>>, Tags)>,
+    compress: bool,
+}
+
+impl GeodataBuilder {
+    pub fn with_node(mut self, id: u64, lat: f64, lon: f64, tags: &[(&str, &str)]) -> GeodataBuilder {
+        self.nodes.push((id, lat, lon, to_tags(tags)));
+        self
+    }
+
+    pub fn with_way(mut self, id: u64, node_ids: &[u64], tags: &[(&str, &str)]) -> GeodataBuilder {
+        self.ways.push((id, node_ids.to_vec(), to_tags(tags)));
+        self
+    }
+
+    // The rings are filled with the even-odd rule, so the inner ones don't need a role. The rings that are not
+    // closed are closed automatically.
+    pub fn with_multipolygon(mut self, id: u64, rings: &[&[u64]], tags: &[(&str, &str)]) -> GeodataBuilder {
+        let rings = rings.iter().map(|ring| ring.to_vec()).collect();
+        self.multipolygons.push((id, rings, to_tags(tags)));
+        self
+    }
+
+    pub fn with_compression(mut self, compress: bool) -> GeodataBuilder {
+        self.compress = compress;
+        self
+    }
+
+    // Fails if a way or a multipolygon references a node that wasn't added.
+    pub fn save<P: AsRef<Path>>(&self, output: P) -> Result<()> {
+        let storages = self.to_entity_storages()?;
+        let output_file = File::create(output.as_ref()).context(format!(
+            "Failed to open {} for writing",
+            output.as_ref().to_string_lossy()
+        ))?;
+        let mut writer = BufWriter::new(output_file);
+        save_to_internal_format(&mut writer, &storages, self.compress)?;
+        writer.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+        Ok(())
+    }
+
+    fn to_entity_storages(&self) -> Result<EntityStorages> {
+        let mut storages = EntityStorages::new();
+        for (id, lat, lon, tags) in &self.nodes {
+            let node = RawNode {
+                global_id: *id,
+                lat: *lat,
+                lon: *lon,
+                tags: to_raw_tags(&mut storages.strings, tags),
+            };
+            storages.node_storage.add(*id, node);
+        }
+
+        let translate_node_ids = |storages: &EntityStorages, entity_id, node_ids: &[u64]| {
+            node_ids
+                .iter()
+                .map(|node_id| {
+                    storages
+                        .node_storage
+                        .translate_id(*node_id)
+                        .ok_or_else(|| anyhow!("Entity #{} references an unknown node #{}", entity_id, node_id))
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+        for (id, node_ids, tags) in &self.ways {
+            let way = RawWay {
+                global_id: *id,
+                node_ids: translate_node_ids(&storages, *id, node_ids)?,
+                tags: to_raw_tags(&mut storages.strings, tags),
+            };
+            storages.way_storage.add(*id, way);
+        }
+        for (id, rings, tags) in &self.multipolygons {
+            let mut multipolygon = Multipolygon {
+                global_id: *id,
+                polygon_ids: Vec::new(),
+                tags: to_raw_tags(&mut storages.strings, tags),
+            };
+            for ring in rings {
+                let mut polygon = translate_node_ids(&storages, *id, ring)?;
+                if let (Some(&first), Some(&last)) = (polygon.first(), polygon.last()) {
+                    if first != last {
+                        polygon.push(first);
+                    }
+                }
+                multipolygon.polygon_ids.push(storages.polygon_storage.len());
+                storages.polygon_storage.push(polygon);
+            }
+            storages.multipolygon_storage.add(*id, multipolygon);
+        }
+        Ok(storages)
+    }
+}
+
+// The strings are interned only when the entities are saved, since every save starts with a new string table.
+type Tags = Vec<(String, String)>;
+
+fn to_tags(tags: &[(&str, &str)]) -> Tags {
+    tags.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+fn to_raw_tags(strings: &mut StringTable, tags: &Tags) -> RawTags {
+    let mut raw_tags = RawTags::default();
+    for (k, v) in tags {
+        raw_tags.insert_str(strings, k, v);
+    }
+    raw_tags
+}
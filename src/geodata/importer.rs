@@ -1,45 +1,167 @@
 use crate::coords;
-use crate::geodata::find_polygons::{find_polygons_in_multipolygon, NodeDesc, NodeDescPair};
+use crate::geodata::coastline::add_coastline_polygons;
+use crate::geodata::find_lines::find_lines_in_relation;
+use crate::geodata::find_polygons::{find_polygons_in_multipolygon, AssemblyReport, NodeDesc, NodeDescPair};
+use crate::geodata::progress::{format_duration, format_storage_stats, Progress, ProgressUnit};
+use crate::geodata::report::{count_duplicate_node_coords, ImportReport};
 use crate::geodata::saver::save_to_internal_format;
+use crate::geodata::string_table::{StringId, StringTable};
+use crate::geodata::tag_filter::TagFilter;
 use anyhow::{anyhow, bail, Context, Result};
 #[cfg(feature = "pbf")]
-use osmpbf::{Element, ElementReader, RelMemberType};
+use osmpbf::{BlobDecode, BlobReader, Element, RelMemberType};
 use quick_xml::events::attributes::Attributes;
 use quick_xml::events::{BytesStart, Event};
 use quick_xml::reader::Reader;
 use std::borrow::Cow;
 use std::collections::HashSet;
 use std::collections::{BTreeMap, HashMap};
-use std::ffi::OsStr;
+use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
-use std::io::{BufReader, BufWriter};
-use std::path::Path;
+use std::io::{BufReader, BufWriter, SeekFrom};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+pub struct ImportOptions {
+    // If set, only tags kept by the filter are saved to the output file.
+    pub tag_filter: Option<TagFilter>,
+    // Makes the output file smaller, but the reader has to decompress
+    // the ints and strings into memory instead of just mapping them.
+    pub compress: bool,
+    // Import the elements that could be parsed from a truncated input file instead of failing.
+    pub tolerate_truncation: bool,
+    // Save the untagged nodes that are not a part of any way too, e.g. for the stylesheets that draw all nodes.
+    pub keep_all_nodes: bool,
+    // If set, the ways and relations with references to objects missing from the input are saved there as CSV.
+    pub dropped_refs_file: Option<PathBuf>,
+    // If set, the import report is saved there as JSON.
+    pub report_file: Option<PathBuf>,
+}
+
+// Returned (wrapped in anyhow::Error) when the input file ends in the middle of the data.
+#[derive(Debug)]
+pub struct TruncatedInput;
+
+impl fmt::Display for TruncatedInput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("the input file is truncated")
+    }
+}
+
+impl std::error::Error for TruncatedInput {}
+
+pub fn import<P: AsRef<Path>>(input: P, output: P) -> Result<ImportReport> {
+    import_with_options(input, output, &ImportOptions::default())
+}
+
+pub fn import_with_options<P: AsRef<Path>>(input: P, output: P, options: &ImportOptions) -> Result<ImportReport> {
+    import_files(&[input], output, options)
+}
+
+// Merges several input files (e.g. extracts of adjacent regions) into one output file. The objects present in
+// more than one file are imported only once, and the members of a relation are collected from all files.
+pub fn import_files<I: AsRef<Path>, P: AsRef<Path>>(
+    inputs: &[I],
+    output: P,
+    options: &ImportOptions,
+) -> Result<ImportReport> {
+    if inputs.is_empty() {
+        bail!("No input files to import");
+    }
+
+    // All inputs are checked before parsing anything, so that a bad last file doesn't fail the import only after
+    // the other ones have been parsed.
+    let mut input_files = Vec::new();
+    for input in inputs {
+        input_files.push(open_input_file(input.as_ref(), options)?);
+    }
 
-pub fn import<P: AsRef<Path>>(input: P, output: P) -> Result<()> {
     let output_file = File::create(output.as_ref()).context(format!(
         "Failed to open {} for writing",
         output.as_ref().to_string_lossy()
     ))?;
     let mut writer = BufWriter::new(output_file);
 
-    let parsed = match input.as_ref().extension().and_then(OsStr::to_str) {
-        Some("osm") | Some("xml") => {
-            let input_file = File::open(input.as_ref()).context(format!(
-                "Failed to open {} for reading",
-                input.as_ref().to_string_lossy()
-            ))?;
-            let parser = Reader::from_reader(BufReader::new(input_file));
-            parse_osm_xml(parser)?
+    let parsing_start = Instant::now();
+    let mut parsed = EntityStorages::new();
+    for (idx, (input, (input_file, input_format))) in inputs.iter().zip(input_files).enumerate() {
+        if inputs.len() > 1 {
+            println!("[{}/{}] {}", idx + 1, inputs.len(), input.as_ref().to_string_lossy());
         }
-        #[cfg(feature = "pbf")]
-        Some("pbf") => parse_pbf(input)?,
-        _ => bail!("Extension not supported"),
-    };
+        match input_format {
+            InputFormat::Xml => {
+                let input_size = input_file.metadata().map(|m| m.len()).unwrap_or_default();
+                let parser = Reader::from_reader(BufReader::new(input_file));
+                parse_osm_xml(parser, input_size, options.tolerate_truncation, &mut parsed)?;
+            }
+            #[cfg(feature = "pbf")]
+            InputFormat::Pbf => parse_pbf(input.as_ref(), options.tolerate_truncation, &mut parsed)?,
+            #[cfg(not(feature = "pbf"))]
+            InputFormat::Pbf => unreachable!(),
+        }
+    }
+    resolve_forward_refs(&mut parsed);
+    assemble_relations(&mut parsed);
+    if parsed.duplicate_count > 0 {
+        println!(
+            "Merged {} objects that were present in more than one input file",
+            parsed.duplicate_count
+        );
+    }
+
+    let coastline_start = Instant::now();
+    add_coastline_polygons(&mut parsed);
+    parsed.polygon_assembly_time += coastline_start.elapsed();
+    let parsing_time = parsing_start.elapsed() - parsed.polygon_assembly_time;
+
+    if let Some(ref tag_filter) = options.tag_filter {
+        filter_tags(&mut parsed, tag_filter);
+    }
+    let duplicate_node_coords = count_duplicate_node_coords(&parsed);
+    if !options.keep_all_nodes {
+        prune_nodes(&mut parsed);
+    }
 
     println!("Converting geodata to internal format");
-    save_to_internal_format(&mut writer, &parsed).context("Failed to write the imported data to the output file")?;
-    Ok(())
+    let saving_start = Instant::now();
+    // Dropping a BufWriter silently ignores the errors, and the file has to be on the disk before
+    // the caller replaces the old file with it.
+    save_to_internal_format(&mut writer, &parsed, options.compress)
+        .and_then(|()| Ok(writer.into_inner().map_err(|err| err.into_error())?.sync_all()?))
+        .context("Failed to write the imported data to the output file")?;
+
+    println!(
+        "Imported {} in {} (parsing: {}, polygon assembly: {}, saving: {})",
+        format_storage_stats(&parsed),
+        format_duration(parsing_start.elapsed()),
+        format_duration(parsing_time),
+        format_duration(parsed.polygon_assembly_time),
+        format_duration(saving_start.elapsed())
+    );
+    if let Some(summary) = parsed.assembly_report.summary() {
+        println!("{}", summary);
+    }
+    if let Some(summary) = parsed.dropped_refs.summary() {
+        eprintln!(
+            "WARNING: some references point to objects missing from the input, they are skipped\n{}",
+            summary
+        );
+    }
+    if let Some(ref file) = options.dropped_refs_file {
+        parsed.dropped_refs.save_csv(file)?;
+    }
+
+    let report = ImportReport::new(&parsed, duplicate_node_coords);
+    if let Some(summary) = report.summary() {
+        println!("{}", summary);
+    }
+    if let Some(ref file) = options.report_file {
+        report.save(file)?;
+    }
+    Ok(report)
 }
 
 pub(super) struct OsmEntityStorage<E: Default> {
@@ -48,20 +170,26 @@ pub(super) struct OsmEntityStorage<E: Default> {
 }
 
 impl<E: Default> OsmEntityStorage<E> {
-    fn new() -> OsmEntityStorage<E> {
+    pub(super) fn new() -> OsmEntityStorage<E> {
         OsmEntityStorage {
             global_id_to_local_id: HashMap::new(),
             entities: Vec::new(),
         }
     }
 
-    fn add(&mut self, global_id: u64, entity: E) {
+    pub(super) fn add(&mut self, global_id: u64, entity: E) {
         let old_size = self.entities.len();
         self.global_id_to_local_id.insert(global_id, old_size);
         self.entities.push(entity);
     }
 
-    fn translate_id(&self, global_id: u64) -> Option<usize> {
+    // For entities that don't come from the input file and thus can't be referenced by global id.
+    pub(super) fn add_synthetic(&mut self, entity: E) -> usize {
+        self.entities.push(entity);
+        self.entities.len() - 1
+    }
+
+    pub(super) fn translate_id(&self, global_id: u64) -> Option<usize> {
         self.global_id_to_local_id.get(&global_id).cloned()
     }
 
@@ -75,32 +203,274 @@ pub(super) struct EntityStorages {
     pub(super) way_storage: OsmEntityStorage<RawWay>,
     pub(super) polygon_storage: Vec<Polygon>,
     pub(super) multipolygon_storage: OsmEntityStorage<Multipolygon>,
+    pub(super) relation_storage: OsmEntityStorage<Relation>,
+    // The keys and values of the tags of all entities.
+    pub(super) strings: StringTable,
+    pub(super) bounds: Option<Bounds>,
+    // The time spent on turning multipolygon and boundary relations (and coastlines) into polygons and lines.
+    pub(super) polygon_assembly_time: Duration,
+    // The multipolygons that were repaired or dropped because of the problems with their rings.
+    pub(super) assembly_report: AssemblyReport,
+    // The ways and relations that reference objects missing from all input files.
+    pub(super) dropped_refs: DroppedRefs,
+    // The number of objects that were present in more than one input file.
+    duplicate_count: usize,
+    // The relations that are assembled after all input files are parsed, since their members may come later
+    // in the file or even in another file, and their indices in this list by global id.
+    pending_relations: Vec<RawRelation>,
+    pending_relation_ids: HashMap<u64, usize>,
+    // The ways that reference the nodes which weren't parsed yet when the way was added (e.g. in unsorted PBF files
+    // or in the later input files), by local id, with the OSM ids of all their nodes.
+    unresolved_ways: BTreeMap<usize, Vec<u64>>,
+}
+
+impl EntityStorages {
+    pub(super) fn new() -> EntityStorages {
+        EntityStorages {
+            node_storage: OsmEntityStorage::new(),
+            way_storage: OsmEntityStorage::new(),
+            polygon_storage: Vec::new(),
+            multipolygon_storage: OsmEntityStorage::new(),
+            relation_storage: OsmEntityStorage::new(),
+            strings: StringTable::default(),
+            bounds: None,
+            polygon_assembly_time: Duration::default(),
+            assembly_report: AssemblyReport::default(),
+            dropped_refs: DroppedRefs::default(),
+            duplicate_count: 0,
+            pending_relations: Vec::new(),
+            pending_relation_ids: HashMap::new(),
+            unresolved_ways: BTreeMap::new(),
+        }
+    }
+
+    pub(super) fn pending_relation_count(&self) -> usize {
+        self.pending_relations.len()
+    }
+}
+
+#[derive(Clone)]
+pub(super) struct Bounds {
+    pub(super) min_lat: f64,
+    pub(super) max_lat: f64,
+    pub(super) min_lon: f64,
+    pub(super) max_lon: f64,
+}
+
+fn filter_tags(entity_storages: &mut EntityStorages, tag_filter: &TagFilter) {
+    let mut removed_count = 0;
+    let strings = &entity_storages.strings;
+    let mut filter = |tags: &mut RawTags| {
+        let old_len = tags.len();
+        tags.retain(|k, _| tag_filter.keeps(strings.get(k)));
+        removed_count += old_len - tags.len();
+    };
+
+    entity_storages
+        .node_storage
+        .entities
+        .iter_mut()
+        .for_each(|x| filter(&mut x.tags));
+    entity_storages
+        .way_storage
+        .entities
+        .iter_mut()
+        .for_each(|x| filter(&mut x.tags));
+    entity_storages
+        .multipolygon_storage
+        .entities
+        .iter_mut()
+        .for_each(|x| filter(&mut x.tags));
+    entity_storages
+        .relation_storage
+        .entities
+        .iter_mut()
+        .for_each(|x| filter(&mut x.tags));
+
+    println!("Removed {} tags not needed for rendering", removed_count);
 }
 
-fn print_storage_stats(entity_storages: &EntityStorages) {
+// Drops the untagged nodes that are not a part of any way or polygon, since they are never drawn. This has to happen
+// after the tags are filtered, which leaves more nodes without tags. The remaining nodes keep their order, so that the
+// output still depends only on the input.
+fn prune_nodes(entity_storages: &mut EntityStorages) {
+    let node_count = entity_storages.node_storage.entities.len();
+    let mut referenced = vec![false; node_count];
+    let way_refs = entity_storages.way_storage.entities.iter().map(|way| &way.node_ids);
+    for node_id in way_refs.chain(&entity_storages.polygon_storage).flatten() {
+        referenced[*node_id] = true;
+    }
+
+    // `usize::MAX` for the dropped nodes.
+    let mut new_ids = vec![usize::MAX; node_count];
+    let mut kept_nodes = Vec::new();
+    let mut unused_count = 0;
+    for (old_id, node) in mem::take(&mut entity_storages.node_storage.entities)
+        .into_iter()
+        .enumerate()
+    {
+        if node.tags.is_empty() && !referenced[old_id] {
+            unused_count += 1;
+            continue;
+        }
+        new_ids[old_id] = kept_nodes.len();
+        kept_nodes.push(node);
+    }
+
+    let node_storage = &mut entity_storages.node_storage;
+    node_storage.entities = kept_nodes;
+    node_storage.global_id_to_local_id.retain(|_, id| {
+        *id = new_ids[*id];
+        *id != usize::MAX
+    });
+    let way_refs = entity_storages
+        .way_storage
+        .entities
+        .iter_mut()
+        .map(|way| &mut way.node_ids);
+    for node_id in way_refs.chain(&mut entity_storages.polygon_storage).flatten() {
+        *node_id = new_ids[*node_id];
+    }
+
     println!(
-        "Got {} nodes, {} ways and {} multipolygon relations so far",
-        entity_storages.node_storage.entities.len(),
-        entity_storages.way_storage.entities.len(),
-        entity_storages.multipolygon_storage.entities.len()
+        "Removed {} untagged nodes that are not a part of any way, kept {} nodes",
+        unused_count,
+        entity_storages.node_storage.entities.len()
     );
 }
 
-#[cfg(feature = "pbf")]
-fn parse_pbf<P: AsRef<Path>>(input: P) -> Result<EntityStorages> {
-    let mut entity_storages = EntityStorages {
-        node_storage: OsmEntityStorage::new(),
-        way_storage: OsmEntityStorage::new(),
-        polygon_storage: Vec::new(),
-        multipolygon_storage: OsmEntityStorage::new(),
-    };
+enum InputFormat {
+    Xml,
+    Pbf,
+}
+
+fn open_input_file(input: &Path, options: &ImportOptions) -> Result<(File, InputFormat)> {
+    let input_name = input.to_string_lossy();
+    let mut input_file = File::open(input).context(format!("Failed to open {} for reading", input_name))?;
+    let input_format =
+        detect_input_format(&mut input_file).context(format!("{} doesn't look like OSM data", input_name))?;
+
+    match input_format {
+        InputFormat::Xml => {
+            // Check the end of the file first, so that a truncated download doesn't fail only after it's parsed.
+            if !ends_with_root_element(&mut input_file)? {
+                let message = format!("{} doesn't end with </osm>", input_name);
+                if !options.tolerate_truncation {
+                    return Err(anyhow!(TruncatedInput).context(message));
+                }
+                eprintln!("WARNING: {}, only the complete elements will be imported", message);
+            }
+            input_file.seek(SeekFrom::Start(0))?;
+        }
+        #[cfg(feature = "pbf")]
+        InputFormat::Pbf => {}
+        #[cfg(not(feature = "pbf"))]
+        InputFormat::Pbf => bail!(
+            "{} looks like a PBF file, but the importer was built without PBF support (rebuild it with --features pbf)",
+            input_name
+        ),
+    }
+    Ok((input_file, input_format))
+}
+
+// The format is determined by the contents of the file rather than by its extension, so that we can
+// explain what's wrong with it instead of failing somewhere in the middle of parsing.
+fn detect_input_format(input_file: &mut File) -> Result<InputFormat> {
+    let mut head = Vec::new();
+    Read::by_ref(input_file).take(4096).read_to_end(&mut head)?;
+    input_file.seek(SeekFrom::Start(0))?;
+
+    if head.is_empty() {
+        bail!("The file is empty");
+    }
+    if head.starts_with(&[0x1f, 0x8b]) {
+        bail!("The file is compressed with gzip, decompress it first");
+    }
+    if head.starts_with(b"BZh") {
+        bail!("The file is compressed with bzip2, decompress it first");
+    }
+    if head.starts_with(b"PK\x03\x04") {
+        bail!("The file is a ZIP archive, extract the OSM data from it first");
+    }
+    // A PBF file starts with the (big endian, and small) size of the first blob header, followed by the header
+    // itself, which contains the type of the blob.
+    if head.starts_with(&[0, 0]) && head.windows(9).any(|w| w == b"OSMHeader") {
+        return Ok(InputFormat::Pbf);
+    }
+
+    let text = head.strip_prefix(b"\xef\xbb\xbf").unwrap_or(&head);
+    if text.iter().find(|b| !b.is_ascii_whitespace()) != Some(&b'<') {
+        bail!("The file is neither XML nor PBF");
+    }
+
+    // Skip the XML declaration, comments and so on. There's nothing to check if the root element doesn't
+    // start in the first few kilobytes.
+    let mut parser = Reader::from_reader(text);
+    let mut buf = Vec::new();
+    while let Ok(event) = parser.read_event_into(&mut buf) {
+        match event {
+            Event::Start(start) | Event::Empty(start) => {
+                let name = start.local_name();
+                if name.as_ref() != b"osm" {
+                    bail!(
+                        "The root element is <{}> instead of <osm>, so this is XML, but not OSM data",
+                        ascii_name_as_str(name.as_ref())
+                    );
+                }
+                break;
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+    Ok(InputFormat::Xml)
+}
+
+// Looks for the closing tag of the root element (or an empty root element) at the end of the file.
+fn ends_with_root_element(input_file: &mut File) -> Result<bool> {
+    const TAIL_SIZE: u64 = 1024;
+    let file_size = input_file.metadata()?.len();
+    input_file.seek(SeekFrom::Start(file_size.saturating_sub(TAIL_SIZE)))?;
+    let mut tail = Vec::new();
+    input_file.read_to_end(&mut tail)?;
+
+    let tail = String::from_utf8_lossy(&tail);
+    let last_tag = tail.trim_end().rsplit('<').next().unwrap_or_default();
+    Ok(last_tag == "/osm>" || (last_tag.starts_with("osm") && last_tag.ends_with("/>")))
+}
 
-    let mut elem_count = 0;
+#[cfg(feature = "pbf")]
+fn parse_pbf(input: &Path, tolerate_truncation: bool, entity_storages: &mut EntityStorages) -> Result<()> {
     println!("Parsing PBF");
 
-    let reader = ElementReader::from_path(input)?;
-    reader.for_each(|element| {
-        match element {
+    // The blobs are neither decompressed nor decoded here, so counting them is much faster than parsing them.
+    // This also finds a truncated last blob before the heavy work starts.
+    let mut blob_count = 0;
+    for blob in BlobReader::from_path(input)? {
+        if let Err(e) = blob {
+            let message = format!(
+                "Failed to read blob #{} of {}: {}",
+                blob_count,
+                input.to_string_lossy(),
+                e
+            );
+            if !tolerate_truncation {
+                return Err(anyhow!(TruncatedInput).context(message));
+            }
+            eprintln!("WARNING: {}, only the blobs before it will be imported", message);
+            break;
+        }
+        blob_count += 1;
+    }
+    let mut progress = Progress::new(blob_count as u64, ProgressUnit::Blobs);
+
+    for (blob_idx, blob) in BlobReader::from_path(input)?.take(blob_count).enumerate() {
+        let block = match blob?.decode()? {
+            BlobDecode::OsmData(block) => block,
+            _ => continue,
+        };
+        block.for_each_element(|element| match element {
             Element::DenseNode(el_node) => {
                 let mut node = RawNode {
                     global_id: el_node.id() as u64,
@@ -109,89 +479,58 @@ fn parse_pbf<P: AsRef<Path>>(input: P) -> Result<EntityStorages> {
                     tags: RawTags::default(),
                 };
                 for (key, value) in el_node.tags() {
-                    node.tags.insert(key.to_string(), value.to_string());
+                    node.tags.insert_str(&mut entity_storages.strings, key, value);
                 }
-                elem_count += 1;
-                entity_storages.node_storage.add(node.global_id, node);
+                add_node(node, entity_storages);
             }
             Element::Way(el_way) => {
-                let mut way = RawWay {
-                    global_id: el_way.id() as u64,
-                    node_ids: RawRefs::default(),
-                    tags: RawTags::default(),
-                };
+                let mut way = ParsedWay::default();
+                way.way.global_id = el_way.id() as u64;
                 for (key, value) in el_way.tags() {
-                    way.tags.insert(key.to_string(), value.to_string());
+                    way.way.tags.insert_str(&mut entity_storages.strings, key, value);
                 }
-                for r in el_way.refs() {
-                    if let Some(local_id) = entity_storages.node_storage.translate_id(r as u64) {
-                        way.node_ids.push(local_id);
-                    }
-                }
-                postprocess_node_refs(&mut way.node_ids);
-                elem_count += 1;
-                entity_storages.way_storage.add(way.global_id, way);
+                way.node_refs.extend(el_way.refs().map(|r| r as u64));
+                add_way(way, entity_storages);
             }
             Element::Relation(el_rel) => {
                 let mut relation = RawRelation {
                     global_id: el_rel.id() as u64,
                     way_refs: Vec::<RelationWayRef>::default(),
+                    relation_refs: Vec::new(),
+                    unresolved_way_refs: Vec::new(),
                     tags: RawTags::default(),
                 };
                 for (key, value) in el_rel.tags() {
-                    relation.tags.insert(key.to_string(), value.to_string());
-                }
-                for way in el_rel.members() {
-                    if way.member_type == RelMemberType::Way {
-                        if let Some(local_id) = entity_storages.way_storage.translate_id(way.member_id as u64) {
-                            let is_inner = way.role().unwrap() == "inner";
-                            relation.way_refs.push(RelationWayRef {
-                                way_id: local_id,
-                                is_inner,
-                            });
-                        }
-                    }
+                    relation.tags.insert_str(&mut entity_storages.strings, key, value);
                 }
-                if relation.tags.iter().any(|(k, v)| k == "type" && v == "multipolygon") {
-                    let segments = relation.to_segments(&entity_storages);
-                    if let Some(polygons) = find_polygons_in_multipolygon(relation.global_id, &segments) {
-                        let mut multipolygon = Multipolygon {
-                            global_id: relation.global_id,
-                            polygon_ids: Vec::new(),
-                            tags: relation.tags,
-                        };
-                        for poly in polygons {
-                            multipolygon.polygon_ids.push(entity_storages.polygon_storage.len());
-                            entity_storages.polygon_storage.push(poly);
-                        }
-                        elem_count += 1;
-                        entity_storages
-                            .multipolygon_storage
-                            .add(relation.global_id, multipolygon);
+                for member in el_rel.members() {
+                    let is_inner = member.role().unwrap() == "inner";
+                    match member.member_type {
+                        RelMemberType::Way => relation.add_way_ref(member.member_id as u64, is_inner, entity_storages),
+                        RelMemberType::Relation => relation.relation_refs.push((member.member_id as u64, is_inner)),
+                        RelMemberType::Node => {}
                     }
                 }
+                add_relation(relation, entity_storages);
             }
             Element::Node(_) => panic!(),
-        }
-        if elem_count % 100_000 == 0 {
-            print_storage_stats(&entity_storages);
-        }
-    })?;
+        });
+        progress.update(blob_idx as u64 + 1, entity_storages);
+    }
 
-    print_storage_stats(&entity_storages);
+    progress.finish(entity_storages);
 
-    Ok(entity_storages)
+    Ok(())
 }
 
-fn parse_osm_xml<R: BufRead>(mut parser: Reader<R>) -> Result<EntityStorages> {
-    let mut entity_storages = EntityStorages {
-        node_storage: OsmEntityStorage::new(),
-        way_storage: OsmEntityStorage::new(),
-        polygon_storage: Vec::new(),
-        multipolygon_storage: OsmEntityStorage::new(),
-    };
-
-    let mut elem_count = 0;
+fn parse_osm_xml<R: BufRead>(
+    mut parser: Reader<R>,
+    input_size: u64,
+    tolerate_truncation: bool,
+    entity_storages: &mut EntityStorages,
+) -> Result<()> {
+    let mut progress = Progress::new(input_size, ProgressUnit::Bytes);
+    let mut inside_root = false;
 
     println!("Parsing XML");
     let mut buf = Vec::new();
@@ -204,28 +543,43 @@ fn parse_osm_xml<R: BufRead>(mut parser: Reader<R>) -> Result<EntityStorages> {
                 &mut parser,
                 start.local_name().as_ref(),
                 &mut start.attributes(),
-                &mut entity_storages,
+                entity_storages,
                 have_subelements,
             )?;
-            elem_count += 1;
-            if elem_count % 100_000 == 0 {
-                print_storage_stats(&entity_storages);
-            }
+            progress.update(parser.buffer_position() as u64, entity_storages);
             Ok(())
         };
-        match e {
+        let result = match e {
+            // Unlike newer versions, quick-xml doesn't complain about the unclosed root element by itself.
+            Event::Eof if inside_root => Err(anyhow!(TruncatedInput)),
             Event::Eof => break,
-            Event::Start(start) => on_elem(start, true)?,
-            Event::Empty(start) => on_elem(start, false)?,
-            _ => {}
+            Event::Start(start) if start.local_name().as_ref() == b"osm" => {
+                inside_root = true;
+                Ok(())
+            }
+            Event::End(end) if end.local_name().as_ref() == b"osm" => {
+                inside_root = false;
+                Ok(())
+            }
+            Event::Start(start) => on_elem(start, true),
+            Event::Empty(start) => on_elem(start, false),
+            _ => Ok(()),
+        };
+        match result {
+            // The element that was being parsed when the input ended is incomplete, so it's dropped.
+            Err(e) if tolerate_truncation && e.is::<TruncatedInput>() => {
+                eprintln!("WARNING: the input file ends unexpectedly, only the complete elements are imported");
+                break;
+            }
+            result => result?,
         }
         // The official `quick-xml` examples suggests we do this to save memory.
         buf.clear();
     }
 
-    print_storage_stats(&entity_storages);
+    progress.finish(entity_storages);
 
-    Ok(entity_storages)
+    Ok(())
 }
 
 fn process_element<R: BufRead>(
@@ -236,6 +590,24 @@ fn process_element<R: BufRead>(
     have_subelements: bool,
 ) -> Result<()> {
     match name {
+        b"bounds" => {
+            let bounds = Bounds {
+                min_lat: parse_required_attr(parser, name, attrs, b"minlat")?,
+                min_lon: parse_required_attr(parser, name, attrs, b"minlon")?,
+                max_lat: parse_required_attr(parser, name, attrs, b"maxlat")?,
+                max_lon: parse_required_attr(parser, name, attrs, b"maxlon")?,
+            };
+            // The bounds of several input files are merged into one rectangle that covers all of them.
+            entity_storages.bounds = Some(match entity_storages.bounds.take() {
+                Some(old) => Bounds {
+                    min_lat: old.min_lat.min(bounds.min_lat),
+                    min_lon: old.min_lon.min(bounds.min_lon),
+                    max_lat: old.max_lat.max(bounds.max_lat),
+                    max_lon: old.max_lon.max(bounds.max_lon),
+                },
+                None => bounds,
+            });
+        }
         b"node" => {
             let mut node = RawNode {
                 global_id: get_id(parser, name, attrs)?,
@@ -246,24 +618,22 @@ fn process_element<R: BufRead>(
             if have_subelements {
                 process_subelements(name, &mut node, entity_storages, process_node_subelement, parser)?;
             }
-            entity_storages.node_storage.add(node.global_id, node);
+            add_node(node, entity_storages);
         }
         b"way" => {
-            let mut way = RawWay {
-                global_id: get_id(parser, name, attrs)?,
-                node_ids: RawRefs::default(),
-                tags: RawTags::default(),
-            };
+            let mut way = ParsedWay::default();
+            way.way.global_id = get_id(parser, name, attrs)?;
             if have_subelements {
                 process_subelements(name, &mut way, entity_storages, process_way_subelement, parser)?;
             }
-            postprocess_node_refs(&mut way.node_ids);
-            entity_storages.way_storage.add(way.global_id, way);
+            add_way(way, entity_storages);
         }
         b"relation" => {
             let mut relation = RawRelation {
                 global_id: get_id(parser, name, attrs)?,
                 way_refs: Vec::<RelationWayRef>::default(),
+                relation_refs: Vec::new(),
+                unresolved_way_refs: Vec::new(),
                 tags: RawTags::default(),
             };
             if have_subelements {
@@ -275,38 +645,307 @@ fn process_element<R: BufRead>(
                     parser,
                 )?;
             }
-            if relation.tags.iter().any(|(k, v)| k == "type" && v == "multipolygon") {
-                let segments = relation.to_segments(entity_storages);
-                if let Some(polygons) = find_polygons_in_multipolygon(relation.global_id, &segments) {
-                    let mut multipolygon = Multipolygon {
-                        global_id: relation.global_id,
-                        polygon_ids: Vec::new(),
-                        tags: relation.tags,
-                    };
-                    for poly in polygons {
-                        multipolygon.polygon_ids.push(entity_storages.polygon_storage.len());
-                        entity_storages.polygon_storage.push(poly);
-                    }
-                    entity_storages
-                        .multipolygon_storage
-                        .add(relation.global_id, multipolygon);
-                }
-            }
+            add_relation(relation, entity_storages);
         }
         _ => {}
     }
     Ok(())
 }
 
+// When several input files are imported, the same objects may come from more than one of them. The first copy
+// of a node is kept, since extracts of the same data don't disagree about its position.
+fn add_node(node: RawNode, entity_storages: &mut EntityStorages) {
+    if entity_storages.node_storage.translate_id(node.global_id).is_some() {
+        entity_storages.duplicate_count += 1;
+        return;
+    }
+    entity_storages.node_storage.add(node.global_id, node);
+}
+
+// A way that crosses the border of an extract may be cut at the border, so the copy with more nodes wins.
+// The local id of the way stays the same, since the relations parsed earlier may already refer to it.
+fn add_way(parsed: ParsedWay, entity_storages: &mut EntityStorages) {
+    let ParsedWay { mut way, node_refs } = parsed;
+    let node_storage = &entity_storages.node_storage;
+    way.node_ids
+        .extend(node_refs.iter().filter_map(|r| node_storage.translate_id(*r)));
+    let is_resolved = way.node_ids.len() == node_refs.len();
+    postprocess_node_refs(&mut way.node_ids);
+
+    let local_id = match entity_storages.way_storage.translate_id(way.global_id) {
+        Some(local_id) => {
+            entity_storages.duplicate_count += 1;
+            let old_way = &mut entity_storages.way_storage.entities[local_id];
+            if way.node_ids.len() <= old_way.node_ids.len() {
+                return;
+            }
+            *old_way = way;
+            local_id
+        }
+        None => {
+            entity_storages.way_storage.add(way.global_id, way);
+            entity_storages.way_storage.entities.len() - 1
+        }
+    };
+    if is_resolved {
+        entity_storages.unresolved_ways.remove(&local_id);
+    } else {
+        entity_storages.unresolved_ways.insert(local_id, node_refs);
+    }
+}
+
+// Relations are assembled only after all input files are parsed, since every file may contain only a part
+// of the members of a relation. The members of all copies of a relation are merged.
+fn add_relation(relation: RawRelation, entity_storages: &mut EntityStorages) {
+    let pending_idx = match entity_storages.pending_relation_ids.get(&relation.global_id) {
+        Some(&idx) => idx,
+        None => {
+            let idx = entity_storages.pending_relations.len();
+            entity_storages.pending_relation_ids.insert(relation.global_id, idx);
+            entity_storages.pending_relations.push(relation);
+            return;
+        }
+    };
+
+    entity_storages.duplicate_count += 1;
+    let old_relation = &mut entity_storages.pending_relations[pending_idx];
+    for way_ref in relation.way_refs {
+        if !old_relation.way_refs.iter().any(|r| r.way_id == way_ref.way_id) {
+            old_relation.way_refs.push(way_ref);
+        }
+    }
+    for relation_ref in relation.relation_refs {
+        if !old_relation.relation_refs.contains(&relation_ref) {
+            old_relation.relation_refs.push(relation_ref);
+        }
+    }
+    for way_ref in relation.unresolved_way_refs {
+        if !old_relation.unresolved_way_refs.iter().any(|r| r.0 == way_ref.0) {
+            old_relation.unresolved_way_refs.push(way_ref);
+        }
+    }
+}
+
+// The nodes and ways that were referenced before they were parsed are looked up again when all input files are
+// parsed. The references that are still unresolved point to the objects missing in the input (e.g. outside of an
+// extract) and are skipped.
+fn resolve_forward_refs(entity_storages: &mut EntityStorages) {
+    let mut resolved_count = 0;
+    for (local_id, node_refs) in mem::take(&mut entity_storages.unresolved_ways) {
+        let node_storage = &entity_storages.node_storage;
+        let mut node_ids = node_refs
+            .iter()
+            .filter_map(|r| node_storage.translate_id(*r))
+            .collect::<RawRefs>();
+        let way = &mut entity_storages.way_storage.entities[local_id];
+        if node_ids.len() == node_refs.len() {
+            resolved_count += 1;
+        } else {
+            let missing_count = node_refs.len() - node_ids.len();
+            entity_storages.dropped_refs.ways.push((way.global_id, missing_count));
+        }
+        postprocess_node_refs(&mut node_ids);
+        way.node_ids = node_ids;
+    }
+
+    for relation in &mut entity_storages.pending_relations {
+        if relation.unresolved_way_refs.is_empty() {
+            continue;
+        }
+        let mut missing_count = 0;
+        for (way_id, is_inner) in mem::take(&mut relation.unresolved_way_refs) {
+            match entity_storages.way_storage.translate_id(way_id) {
+                Some(way_id) if !relation.way_refs.iter().any(|r| r.way_id == way_id) => {
+                    relation.way_refs.push(RelationWayRef { way_id, is_inner });
+                }
+                Some(_) => {}
+                None => missing_count += 1,
+            }
+        }
+        if missing_count == 0 {
+            resolved_count += 1;
+        } else {
+            let dropped_refs = &mut entity_storages.dropped_refs;
+            dropped_refs.relations.push((relation.global_id, missing_count));
+        }
+    }
+
+    if resolved_count > 0 {
+        println!(
+            "Resolved the references of {} ways and relations to objects that come later in the input",
+            resolved_count
+        );
+    }
+}
+
+// The global ids of the ways with missing nodes and the relations with missing member ways, in the parsing order,
+// with the number of the missing references. The missing nodes and member relations of relations are not counted,
+// since they are never used anyway.
+#[derive(Default)]
+pub(super) struct DroppedRefs {
+    ways: Vec<(u64, usize)>,
+    relations: Vec<(u64, usize)>,
+}
+
+impl DroppedRefs {
+    const MAX_LISTED_IDS: usize = 10;
+
+    // The numbers of the ways with missing nodes and of the relations with missing member ways.
+    pub(super) fn counts(&self) -> (usize, usize) {
+        (self.ways.len(), self.relations.len())
+    }
+
+    fn summary(&self) -> Option<String> {
+        let groups = [
+            ("Ways with missing nodes", &self.ways),
+            ("Relations with missing member ways", &self.relations),
+        ];
+        let lines = groups
+            .iter()
+            .filter(|(_, ids)| !ids.is_empty())
+            .map(|(description, ids)| {
+                let ref_count = ids.iter().map(|(_, count)| count).sum::<usize>();
+                let mut listed_ids = ids
+                    .iter()
+                    .take(Self::MAX_LISTED_IDS)
+                    .map(|(id, _)| format!("#{}", id))
+                    .collect::<Vec<_>>();
+                if ids.len() > Self::MAX_LISTED_IDS {
+                    listed_ids.push(format!("and {} more", ids.len() - Self::MAX_LISTED_IDS));
+                }
+                format!(
+                    "{} ({}, {} references in total): {}",
+                    description,
+                    ids.len(),
+                    ref_count,
+                    listed_ids.join(", ")
+                )
+            })
+            .collect::<Vec<_>>();
+        if lines.is_empty() {
+            None
+        } else {
+            Some(lines.join("\n"))
+        }
+    }
+
+    // The file is written even if nothing was dropped, so that a stale file from an earlier import doesn't remain.
+    fn save_csv(&self, file: &Path) -> Result<()> {
+        let file_name = file.to_string_lossy();
+        let mut writer =
+            BufWriter::new(File::create(file).context(format!("Failed to open {} for writing", file_name))?);
+        writeln!(writer, "type,id,missing_refs")?;
+        for (entity_type, ids) in [("way", &self.ways), ("relation", &self.relations)] {
+            for (id, count) in ids {
+                writeln!(writer, "{},{},{}", entity_type, id, count)?;
+            }
+        }
+        writer
+            .flush()
+            .context(format!("Failed to write the dropped references to {}", file_name))?;
+        println!(
+            "Saved {} objects with dropped references to {}",
+            self.ways.len() + self.relations.len(),
+            file_name
+        );
+        Ok(())
+    }
+}
+
+// Multipolygons and boundaries without relation members are assembled first, in the order they were parsed.
+// Only one level of nesting is supported: the member ways of member relations are added to the relation,
+// but the members of their member relations are not. A member relation with the `inner` role makes all
+// of its ways inner.
+fn assemble_relations(entity_storages: &mut EntityStorages) {
+    let relations = std::mem::take(&mut entity_storages.pending_relations);
+    entity_storages.pending_relation_ids = HashMap::new();
+
+    let relation_way_refs = relations
+        .iter()
+        .map(|relation| (relation.global_id, relation.way_refs.clone()))
+        .collect::<HashMap<_, _>>();
+    let (simple_relations, nested_relations): (Vec<_>, Vec<_>) = relations
+        .into_iter()
+        .partition(|relation| relation.relation_refs.is_empty());
+
+    for relation in simple_relations {
+        assemble_relation(relation, entity_storages);
+    }
+    for mut relation in nested_relations {
+        for &(member_id, is_inner) in &relation.relation_refs {
+            if member_id == relation.global_id {
+                continue;
+            }
+            if let Some(way_refs) = relation_way_refs.get(&member_id) {
+                relation.way_refs.extend(way_refs.iter().map(|way_ref| RelationWayRef {
+                    way_id: way_ref.way_id,
+                    is_inner: way_ref.is_inner || is_inner,
+                }));
+            }
+        }
+        assemble_relation(relation, entity_storages);
+    }
+}
+
+fn assemble_relation(relation: RawRelation, entity_storages: &mut EntityStorages) {
+    let assembly_start = Instant::now();
+    if relation.tags.has_tag(&entity_storages.strings, "type", "multipolygon") {
+        let segments = relation.to_segments(entity_storages);
+        let rings = find_polygons_in_multipolygon(relation.global_id, &segments);
+        entity_storages.assembly_report.add(relation.global_id, rings.as_ref());
+        if let Some(rings) = rings {
+            let mut multipolygon = Multipolygon {
+                global_id: relation.global_id,
+                polygon_ids: Vec::new(),
+                tags: relation.tags,
+            };
+            for poly in rings.polygons {
+                multipolygon.polygon_ids.push(entity_storages.polygon_storage.len());
+                entity_storages.polygon_storage.push(poly);
+            }
+            entity_storages
+                .multipolygon_storage
+                .add(relation.global_id, multipolygon);
+        }
+    } else if relation.tags.has_tag(&entity_storages.strings, "type", "boundary") {
+        add_boundary_relation(relation, entity_storages);
+    }
+    entity_storages.polygon_assembly_time += assembly_start.elapsed();
+}
+
+fn add_boundary_relation(relation: RawRelation, entity_storages: &mut EntityStorages) {
+    let member_ways = relation
+        .way_refs
+        .iter()
+        .map(|way_ref| &entity_storages.way_storage.entities[way_ref.way_id].node_ids)
+        .filter(|node_ids| node_ids.len() >= 2)
+        .collect::<Vec<_>>();
+    let lines = find_lines_in_relation(&member_ways);
+    if lines.is_empty() {
+        return;
+    }
+
+    let mut boundary = Relation {
+        global_id: relation.global_id,
+        line_ids: Vec::new(),
+        tags: relation.tags,
+    };
+    for line in lines {
+        boundary.line_ids.push(entity_storages.polygon_storage.len());
+        entity_storages.polygon_storage.push(line);
+    }
+    entity_storages.relation_storage.add(relation.global_id, boundary);
+}
+
 fn process_subelements<E: Default, R: BufRead, F>(
     entity_name: &[u8],
     entity: &mut E,
-    entity_storages: &EntityStorages,
+    entity_storages: &mut EntityStorages,
     subelement_processor: F,
     parser: &mut Reader<R>,
 ) -> Result<()>
 where
-    F: Fn(&mut Reader<R>, &mut E, &EntityStorages, &[u8], &mut Attributes) -> Result<()>,
+    F: Fn(&mut Reader<R>, &mut E, &mut EntityStorages, &[u8], &mut Attributes) -> Result<()>,
 {
     let mut buf = Vec::new();
     loop {
@@ -315,7 +954,7 @@ where
             ascii_name_as_str(entity_name)
         ))?;
         match e {
-            Event::Eof => break,
+            Event::Eof => bail!(TruncatedInput),
             Event::End(end) if end.local_name().as_ref() == entity_name => break,
             Event::Start(start) | Event::Empty(start) => subelement_processor(
                 parser,
@@ -355,27 +994,39 @@ fn postprocess_node_refs(refs: &mut RawRefs) {
 fn process_node_subelement<R: BufRead>(
     parser: &mut Reader<R>,
     node: &mut RawNode,
-    _: &EntityStorages,
+    entity_storages: &mut EntityStorages,
     sub_name: &[u8],
     sub_attrs: &mut Attributes,
 ) -> Result<()> {
-    try_add_tag(parser, sub_name, sub_attrs, &mut node.tags).map(|_| ())
+    try_add_tag(
+        parser,
+        sub_name,
+        sub_attrs,
+        &mut node.tags,
+        &mut entity_storages.strings,
+    )
+    .map(|_| ())
 }
 
 fn process_way_subelement<R: BufRead>(
     parser: &mut Reader<R>,
-    way: &mut RawWay,
-    entity_storages: &EntityStorages,
+    way: &mut ParsedWay,
+    entity_storages: &mut EntityStorages,
     sub_name: &[u8],
     sub_attrs: &mut Attributes,
 ) -> Result<()> {
-    if try_add_tag(parser, sub_name, sub_attrs, &mut way.tags)? {
+    if try_add_tag(
+        parser,
+        sub_name,
+        sub_attrs,
+        &mut way.way.tags,
+        &mut entity_storages.strings,
+    )? {
         return Ok(());
     }
     if sub_name == b"nd" {
-        if let Some(r) = get_ref(parser, sub_name, sub_attrs, &entity_storages.node_storage)? {
-            way.node_ids.push(r);
-        }
+        way.node_refs
+            .push(parse_required_attr(parser, sub_name, sub_attrs, b"ref")?);
     }
     Ok(())
 }
@@ -383,18 +1034,34 @@ fn process_way_subelement<R: BufRead>(
 fn process_relation_subelement<R: BufRead>(
     parser: &mut Reader<R>,
     relation: &mut RawRelation,
-    entity_storages: &EntityStorages,
+    entity_storages: &mut EntityStorages,
     sub_name: &[u8],
     sub_attrs: &mut Attributes,
 ) -> Result<()> {
-    if try_add_tag(parser, sub_name, sub_attrs, &mut relation.tags)? {
+    if try_add_tag(
+        parser,
+        sub_name,
+        sub_attrs,
+        &mut relation.tags,
+        &mut entity_storages.strings,
+    )? {
+        return Ok(());
+    }
+    if sub_name != b"member" {
         return Ok(());
     }
-    if sub_name == b"member" && get_required_attr(parser, sub_name, sub_attrs, b"type")? == "way" {
-        if let Some(r) = get_ref(parser, sub_name, sub_attrs, &entity_storages.way_storage)? {
+    match get_required_attr(parser, sub_name, sub_attrs, b"type")?.as_ref() {
+        "way" => {
+            let global_id = parse_required_attr(parser, sub_name, sub_attrs, b"ref")?;
+            let is_inner = get_required_attr(parser, sub_name, sub_attrs, b"role")? == "inner";
+            relation.add_way_ref(global_id, is_inner, entity_storages);
+        }
+        "relation" => {
+            let global_id = parse_required_attr(parser, sub_name, sub_attrs, b"ref")?;
             let is_inner = get_required_attr(parser, sub_name, sub_attrs, b"role")? == "inner";
-            relation.way_refs.push(RelationWayRef { way_id: r, is_inner });
+            relation.relation_refs.push((global_id, is_inner));
         }
+        _ => {}
     }
     Ok(())
 }
@@ -444,28 +1111,19 @@ where
     Ok(parsed_value)
 }
 
-fn get_ref<E: Default, R: BufRead>(
-    parser: &mut Reader<R>,
-    elem_name: &[u8],
-    attrs: &mut Attributes,
-    storage: &OsmEntityStorage<E>,
-) -> Result<Option<usize>> {
-    let reference = parse_required_attr(parser, elem_name, attrs, b"ref")?;
-    Ok(storage.translate_id(reference))
-}
-
 fn try_add_tag<R: BufRead>(
     parser: &mut Reader<R>,
     elem_name: &[u8],
     attrs: &mut Attributes,
     tags: &mut RawTags,
+    strings: &mut StringTable,
 ) -> Result<bool> {
     if elem_name != b"tag" {
         return Ok(false);
     }
     let key = get_required_attr(parser, elem_name, attrs, b"k")?;
     let value = get_required_attr(parser, elem_name, attrs, b"v")?;
-    tags.insert(key.to_string(), value.to_string());
+    tags.insert_str(strings, &key, &value);
     Ok(true)
 }
 
@@ -474,7 +1132,55 @@ fn get_id<R: BufRead>(parser: &mut Reader<R>, elem_name: &[u8], attrs: &mut Attr
 }
 
 pub(super) type RawRefs = Vec<usize>;
-pub(super) type RawTags = BTreeMap<String, String>;
+
+// The keys and the values are interned in `EntityStorages::strings`. Like in OSM, there is only one value per key.
+#[derive(Clone, Default)]
+pub(super) struct RawTags {
+    kvs: Vec<(StringId, StringId)>,
+}
+
+impl RawTags {
+    // Replaces the old value of the key, if there is one.
+    pub(super) fn insert(&mut self, key: StringId, value: StringId) {
+        match self.kvs.iter_mut().find(|(k, _)| *k == key) {
+            Some(kv) => kv.1 = value,
+            None => self.kvs.push((key, value)),
+        }
+    }
+
+    pub(super) fn insert_str(&mut self, strings: &mut StringTable, key: &str, value: &str) {
+        self.insert(strings.intern(key), strings.intern(value));
+    }
+
+    pub(super) fn get(&self, key: StringId) -> Option<StringId> {
+        self.kvs.iter().find(|(k, _)| *k == key).map(|(_, v)| *v)
+    }
+
+    pub(super) fn get_str<'s>(&self, strings: &'s StringTable, key: &str) -> Option<&'s str> {
+        self.get(strings.find(key)?).map(|value| strings.get(value))
+    }
+
+    pub(super) fn has_tag(&self, strings: &StringTable, key: &str, value: &str) -> bool {
+        self.get_str(strings, key) == Some(value)
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.kvs.is_empty()
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.kvs.len()
+    }
+
+    // In the order in which the tags were added, not sorted by key.
+    pub(super) fn iter(&self) -> impl Iterator<Item = (StringId, StringId)> + '_ {
+        self.kvs.iter().copied()
+    }
+
+    pub(super) fn retain(&mut self, mut f: impl FnMut(StringId, StringId) -> bool) {
+        self.kvs.retain(|&(k, v)| f(k, v));
+    }
+}
 
 #[derive(Default)]
 pub(super) struct RawNode {
@@ -501,6 +1207,14 @@ pub(super) struct RawWay {
     pub(super) tags: RawTags,
 }
 
+// A way with the OSM ids of its nodes, which are translated to the local ids only when the way is added.
+#[derive(Default)]
+struct ParsedWay {
+    way: RawWay,
+    node_refs: Vec<u64>,
+}
+
+#[derive(Clone)]
 pub struct RelationWayRef {
     way_id: usize,
     is_inner: bool,
@@ -510,10 +1224,21 @@ pub struct RelationWayRef {
 struct RawRelation {
     global_id: u64,
     way_refs: Vec<RelationWayRef>,
+    // The global ids of the member relations, with `true` for the inner ones.
+    relation_refs: Vec<(u64, bool)>,
+    // The global ids of the member ways that weren't parsed yet, resolved by `resolve_forward_refs()`.
+    unresolved_way_refs: Vec<(u64, bool)>,
     tags: RawTags,
 }
 
 impl RawRelation {
+    fn add_way_ref(&mut self, global_id: u64, is_inner: bool, entity_storages: &EntityStorages) {
+        match entity_storages.way_storage.translate_id(global_id) {
+            Some(way_id) => self.way_refs.push(RelationWayRef { way_id, is_inner }),
+            None => self.unresolved_way_refs.push((global_id, is_inner)),
+        }
+    }
+
     fn to_segments(&self, entity_storages: &EntityStorages) -> Vec<NodeDescPair> {
         let create_node_desc = |way: &RawWay, node_idx_in_way| {
             let node_id = way.node_ids[node_idx_in_way];
@@ -544,3 +1269,12 @@ pub(super) struct Multipolygon {
     pub(super) polygon_ids: RawRefs,
     pub(super) tags: RawTags,
 }
+
+// Only boundary relations are imported for now. Their member ways are joined into lines,
+// which are stored together with polygons since both are just sequences of nodes.
+#[derive(Default)]
+pub(super) struct Relation {
+    pub(super) global_id: u64,
+    pub(super) line_ids: RawRefs,
+    pub(super) tags: RawTags,
+}
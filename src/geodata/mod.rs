@@ -1,4 +1,16 @@
+mod coastline;
+pub mod expiry;
+mod find_lines;
 mod find_polygons;
+#[cfg(feature = "test-support")]
+pub mod fixture;
 pub mod importer;
+pub mod prefilter;
+mod progress;
 pub mod reader;
+pub mod report;
 mod saver;
+pub mod search;
+mod string_table;
+pub mod tag_filter;
+pub mod zoom_masks;
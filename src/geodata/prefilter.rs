@@ -0,0 +1,188 @@
+use crate::coords::Coords;
+use crate::geodata::reader::{Node, OsmEntities, OsmEntity};
+use crate::tile::coords_to_xy;
+use anyhow::{bail, Context, Result};
+
+// Drops the entities that are unlikely to be drawn at low zoom levels before they are styled, which bounds the work
+// for the dense tiles (e.g. a zoom level 10 tile over a big city has hundreds of thousands of entities). Both
+// settings are written like `LabelLimits`, e.g. `4, 0@13`: the value before `@` applies from that zoom level
+// (0 by default) up to the next one, and nothing is dropped below the first zoom level.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Prefilter {
+    // The ways and multipolygons shorter than this many pixels (along all their lines) are dropped.
+    min_lengths: Vec<(u8, f64)>,
+    // Only the entities with at least one of these tags are kept, unless it's None (written as `*`).
+    kept_tags: Vec<(u8, Option<Vec<TagPattern>>)>,
+}
+
+// `key` matches any value of the key, `key=value` only this value.
+#[derive(Clone, Debug, PartialEq)]
+struct TagPattern {
+    key: String,
+    value: Option<String>,
+}
+
+impl Prefilter {
+    // E.g. `4, 0@13` to drop the ways shorter than 4 pixels up to zoom level 12.
+    pub fn with_min_lengths(mut self, lengths_str: &str) -> Result<Prefilter> {
+        self.min_lengths = parse_by_zoom(lengths_str, |length_str| match length_str.parse::<f64>() {
+            Ok(length) if length >= 0.0 => Ok(length),
+            _ => bail!("Invalid length in pixels: {}", length_str),
+        })?;
+        Ok(self)
+    }
+
+    // E.g. `place highway=motorway natural=water, *@12` to keep only the places, the motorways and the water
+    // up to zoom level 11.
+    pub fn with_kept_tags(mut self, tags_str: &str) -> Result<Prefilter> {
+        self.kept_tags = parse_by_zoom(tags_str, |patterns_str| {
+            if patterns_str == "*" {
+                return Ok(None);
+            }
+            let patterns = patterns_str
+                .split_whitespace()
+                .map(|pattern| {
+                    let (key, value) = match pattern.split_once('=') {
+                        Some((key, value)) => (key, Some(value.to_string())),
+                        None => (pattern, None),
+                    };
+                    TagPattern {
+                        key: key.to_string(),
+                        value,
+                    }
+                })
+                .collect::<Vec<_>>();
+            if patterns.is_empty() {
+                bail!("Expected a list of tags or *");
+            }
+            Ok(Some(patterns))
+        })?;
+        Ok(self)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min_lengths.is_empty() && self.kept_tags.is_empty()
+    }
+
+    pub fn apply(&self, entities: &mut OsmEntities<'_>, zoom: u8) {
+        let min_length = for_zoom(&self.min_lengths, zoom)
+            .copied()
+            .filter(|length| *length > 0.0);
+        let kept_tags = for_zoom(&self.kept_tags, zoom).and_then(Option::as_ref);
+        if min_length.is_none() && kept_tags.is_none() {
+            return;
+        }
+
+        let has_kept_tag = |entity: &dyn OsmEntity<'_>| {
+            kept_tags.is_none_or(|patterns| {
+                let tags = entity.tags();
+                patterns.iter().any(|pattern| match &pattern.value {
+                    Some(value) => tags.has_tag(&pattern.key, value),
+                    None => tags.contains_key(&pattern.key),
+                })
+            })
+        };
+        let is_long_enough = |lines: &mut dyn Iterator<Item = Vec<Node<'_>>>| {
+            min_length.is_none_or(|min_length| is_longer_than(lines, min_length, zoom))
+        };
+
+        let count_before = entity_count(entities);
+        entities.nodes.retain(|node| has_kept_tag(node));
+        entities.ways.retain(|way| {
+            let mut lines = std::iter::once_with(|| (0..way.node_count()).map(|idx| way.get_node(idx)).collect());
+            has_kept_tag(way) && is_long_enough(&mut lines)
+        });
+        entities.multipolygons.retain(|multipolygon| {
+            let mut lines = (0..multipolygon.polygon_count()).map(|idx| {
+                let polygon = multipolygon.get_polygon(idx);
+                (0..polygon.node_count()).map(|idx| polygon.get_node(idx)).collect()
+            });
+            has_kept_tag(multipolygon) && is_long_enough(&mut lines)
+        });
+        entities.relations.retain(|relation| has_kept_tag(relation));
+        crate::perf_stats::count("Prefiltered entities", (count_before - entity_count(entities)) as u64);
+    }
+}
+
+fn entity_count(entities: &OsmEntities<'_>) -> usize {
+    entities.nodes.len() + entities.ways.len() + entities.multipolygons.len() + entities.relations.len()
+}
+
+// Stops as soon as the lines are long enough, since most entities that are kept are much longer.
+fn is_longer_than(lines: &mut dyn Iterator<Item = Vec<Node<'_>>>, min_length: f64, zoom: u8) -> bool {
+    let mut length = 0.0;
+    for line in lines {
+        let points = line.iter().map(|node| coords_to_xy(&(node.lat(), node.lon()), zoom));
+        let points = points.collect::<Vec<_>>();
+        for segment in points.windows(2) {
+            let ((x1, y1), (x2, y2)) = (segment[0], segment[1]);
+            length += (x2 - x1).hypot(y2 - y1);
+            if length >= min_length {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+fn parse_by_zoom<T>(values_str: &str, parse_value: impl Fn(&str) -> Result<T>) -> Result<Vec<(u8, T)>> {
+    let mut values = Vec::new();
+    for value_str in values_str.split(',').map(str::trim) {
+        let (value_str, zoom_str) = value_str.split_once('@').unwrap_or((value_str, "0"));
+        let value = parse_value(value_str.trim()).with_context(|| format!("Invalid value: {}", value_str))?;
+        let zoom = zoom_str
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid zoom level: {}", zoom_str))?;
+        if values.last().is_some_and(|&(prev_zoom, _)| prev_zoom >= zoom) {
+            bail!("The values should be sorted by zoom level: {}", values_str);
+        }
+        values.push((zoom, value));
+    }
+    Ok(values)
+}
+
+fn for_zoom<T>(values: &[(u8, T)], zoom: u8) -> Option<&T> {
+    values
+        .iter()
+        .rev()
+        .find(|(min_zoom, _)| *min_zoom <= zoom)
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_prefilter() {
+        let prefilter = Prefilter::default()
+            .with_min_lengths("4, 0@13")
+            .unwrap()
+            .with_kept_tags("place  natural=water@4, *@12")
+            .unwrap();
+        assert_eq!(for_zoom(&prefilter.min_lengths, 12), Some(&4.0));
+        assert_eq!(for_zoom(&prefilter.min_lengths, 13), Some(&0.0));
+        assert_eq!(for_zoom(&prefilter.kept_tags, 3), None);
+        let patterns = for_zoom(&prefilter.kept_tags, 11).unwrap().as_ref().unwrap();
+        assert_eq!(
+            patterns,
+            &[
+                TagPattern {
+                    key: "place".to_string(),
+                    value: None
+                },
+                TagPattern {
+                    key: "natural".to_string(),
+                    value: Some("water".to_string())
+                },
+            ]
+        );
+        assert_eq!(for_zoom(&prefilter.kept_tags, 12), Some(&None));
+
+        assert!(Prefilter::default().with_min_lengths("-1").is_err());
+        assert!(Prefilter::default().with_min_lengths("4@12, 2@10").is_err());
+        assert!(Prefilter::default().with_kept_tags("place, @12").is_err());
+        assert!(Prefilter::default().is_empty());
+    }
+}
@@ -0,0 +1,161 @@
+use crate::geodata::importer::EntityStorages;
+use std::io::{IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+const BAR_WIDTH: usize = 30;
+
+#[derive(Clone, Copy)]
+pub(super) enum ProgressUnit {
+    Bytes,
+    #[cfg_attr(not(feature = "pbf"), allow(dead_code))]
+    Blobs,
+}
+
+// Shows how much of the input file has been parsed so far. On a terminal, a single line is updated in place
+// a few times per second; otherwise (e.g. when the output goes to a log file), a new line is printed every
+// few seconds.
+pub(super) struct Progress {
+    unit: ProgressUnit,
+    total: u64,
+    done: u64,
+    start_time: Instant,
+    last_report_time: Instant,
+    is_terminal: bool,
+}
+
+impl Progress {
+    pub(super) fn new(total: u64, unit: ProgressUnit) -> Progress {
+        let now = Instant::now();
+        Progress {
+            unit,
+            total,
+            done: 0,
+            start_time: now,
+            last_report_time: now,
+            is_terminal: std::io::stdout().is_terminal(),
+        }
+    }
+
+    pub(super) fn update(&mut self, done: u64, entity_storages: &EntityStorages) {
+        self.done = done.min(self.total);
+        let report_interval = if self.is_terminal {
+            Duration::from_millis(250)
+        } else {
+            Duration::from_secs(10)
+        };
+        if self.last_report_time.elapsed() >= report_interval {
+            self.report(entity_storages);
+            self.last_report_time = Instant::now();
+        }
+    }
+
+    pub(super) fn finish(&mut self, entity_storages: &EntityStorages) {
+        self.done = self.total;
+        self.report(entity_storages);
+        if self.is_terminal {
+            println!();
+        }
+    }
+
+    fn report(&self, entity_storages: &EntityStorages) {
+        let line = format!(
+            "{} | {}",
+            self.format_progress(self.start_time.elapsed()),
+            format_parsing_stats(entity_storages)
+        );
+        if self.is_terminal {
+            // Pad with spaces to erase the remains of a longer previous line.
+            print!("\r{:<120}", line);
+            let _ = std::io::stdout().flush();
+        } else {
+            println!("{}", line);
+        }
+    }
+
+    fn format_progress(&self, elapsed: Duration) -> String {
+        let fraction = if self.total == 0 {
+            1.0
+        } else {
+            self.done as f64 / self.total as f64
+        };
+        let filled = ((fraction * BAR_WIDTH as f64) as usize).min(BAR_WIDTH);
+        let bar = format!("[{}{}]", "#".repeat(filled), ".".repeat(BAR_WIDTH - filled));
+
+        let seconds = elapsed.as_secs_f64();
+        let throughput = if seconds > 0.0 { self.done as f64 / seconds } else { 0.0 };
+        let throughput = match self.unit {
+            ProgressUnit::Bytes => format!("{:.1} MB/s", throughput / 1e6),
+            ProgressUnit::Blobs => format!("{:.1} blobs/s", throughput),
+        };
+
+        let eta = if self.done >= self.total {
+            "done".to_string()
+        } else if self.done > 0 && seconds > 0.0 {
+            let remaining = (self.total - self.done) as f64 * seconds / self.done as f64;
+            format!("ETA {}", format_duration(Duration::from_secs_f64(remaining)))
+        } else {
+            "ETA unknown".to_string()
+        };
+
+        format!("{} {:5.1}% | {} | {}", bar, 100.0 * fraction, throughput, eta)
+    }
+}
+
+pub(super) fn format_storage_stats(entity_storages: &EntityStorages) -> String {
+    format!(
+        "{} nodes, {} ways, {} multipolygons, {} boundaries with {} distinct tag strings",
+        entity_storages.node_storage.get_entities().len(),
+        entity_storages.way_storage.get_entities().len(),
+        entity_storages.multipolygon_storage.get_entities().len(),
+        entity_storages.relation_storage.get_entities().len(),
+        entity_storages.strings.len()
+    )
+}
+
+// Multipolygons and boundaries are assembled only after all input files are parsed, so only the number
+// of relations is known while parsing.
+fn format_parsing_stats(entity_storages: &EntityStorages) -> String {
+    format!(
+        "{} nodes, {} ways, {} relations",
+        entity_storages.node_storage.get_entities().len(),
+        entity_storages.way_storage.get_entities().len(),
+        entity_storages.pending_relation_count()
+    )
+}
+
+pub(super) fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    if seconds >= 60 {
+        format!("{}m {:02}s", seconds / 60, seconds % 60)
+    } else {
+        format!("{:.1}s", duration.as_secs_f64())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_progress() {
+        let mut progress = Progress::new(200_000_000, ProgressUnit::Bytes);
+        progress.done = 50_000_000;
+        assert_eq!(
+            progress.format_progress(Duration::from_secs(10)),
+            "[#######.......................]  25.0% | 5.0 MB/s | ETA 30.0s"
+        );
+
+        progress.unit = ProgressUnit::Blobs;
+        progress.total = 1000;
+        progress.done = 10;
+        assert_eq!(
+            progress.format_progress(Duration::from_secs(20)),
+            "[..............................]   1.0% | 0.5 blobs/s | ETA 33m 00s"
+        );
+
+        progress.done = 0;
+        assert!(progress.format_progress(Duration::ZERO).ends_with("| ETA unknown"));
+        progress.done = 1000;
+        assert!(progress.format_progress(Duration::from_secs(20)).ends_with("| done"));
+    }
+}
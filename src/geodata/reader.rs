@@ -1,17 +1,23 @@
 use crate::coords::Coords;
+use crate::geodata::coastline::is_synthetic_id;
+use crate::geodata::saver::{COMPRESSED_DATA_FLAG, DATA_EXTENT_FLAG, GEODATA_MAGIC, GEODATA_VERSION};
+use crate::geodata::zoom_masks::ZoomMasks;
 use crate::tile;
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
+use flate2::read::ZlibDecoder;
 use memmap2::{Mmap, MmapOptions};
 use std::cmp::Ordering;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::fs::File;
 use std::hash::{Hash, Hasher};
-use std::io::Cursor;
+use std::io::{Cursor, Read};
 use std::mem;
 use std::ops::Deref;
+use std::ptr;
 use std::slice;
 use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
 
 pub trait OsmEntity<'a> {
     fn global_id(&self) -> u64;
@@ -22,6 +28,32 @@ pub struct OsmEntities<'a> {
     pub nodes: Vec<Node<'a>>,
     pub ways: Vec<Way<'a>>,
     pub multipolygons: Vec<Multipolygon<'a>>,
+    pub relations: Vec<Relation<'a>>,
+}
+
+impl<'a> OsmEntities<'a> {
+    pub fn exclude_ids(&mut self, ids: &HashSet<u64>) {
+        self.nodes.retain(|e| !ids.contains(&e.global_id()));
+        self.ways.retain(|e| !ids.contains(&e.global_id()));
+        self.multipolygons.retain(|e| !ids.contains(&e.global_id()));
+        self.relations.retain(|e| !ids.contains(&e.global_id()));
+    }
+
+    // Adds the entities from another geodata file, except for the ones that are already present (e.g. because
+    // the files cover overlapping regions). The synthetic entities are always added, since their ids are unique
+    // only within a file (see `is_synthetic_id()`).
+    pub fn merge(&mut self, other: OsmEntities<'a>) {
+        fn merge_unique<'a, E: OsmEntity<'a>>(entities: &mut Vec<E>, other: Vec<E>) {
+            let ids = entities.iter().map(|e| e.global_id()).collect::<HashSet<_>>();
+            let is_new = |e: &E| is_synthetic_id(e.global_id()) || !ids.contains(&e.global_id());
+            entities.extend(other.into_iter().filter(is_new));
+        }
+
+        merge_unique(&mut self.nodes, other.nodes);
+        merge_unique(&mut self.ways, other.ways);
+        merge_unique(&mut self.multipolygons, other.multipolygons);
+        merge_unique(&mut self.relations, other.relations);
+    }
 }
 
 #[derive(Default)]
@@ -29,14 +61,23 @@ pub(super) struct OsmEntityIds {
     pub(super) nodes: Vec<u32>,
     pub(super) ways: Vec<u32>,
     pub(super) multipolygons: Vec<u32>,
+    pub(super) relations: Vec<u32>,
 }
 
+static NEXT_DATA_ID: AtomicUsize = AtomicUsize::new(0);
+
 pub trait OsmArea {
     fn is_closed(&self) -> bool;
 }
 
 pub struct GeodataReader<'a> {
     storages: ObjectStorages<'a>,
+    // Unique for every loaded file (see `Tags::data_id()`).
+    data_id: usize,
+    zoom_masks: Option<ZoomMasks>,
+    extent: Option<tile::LatLonBounds>,
+    tile_range: Option<tile::TileRange>,
+    _decompressed_data: Option<Vec<u32>>,
     _mmap: Mmap,
 }
 
@@ -53,58 +94,201 @@ impl<'a> GeodataReader<'a> {
         // `raw_mmap_bytes` points to bytes that are destroyed when `mmap` is dropped.
         // The bytes are only ever accessed from `storages`, which is bundled together with `mmap`
         // in `GeodataReader`. Therefore, `mmap` is still not dropped whenever we access the bytes.
-        let storages = ObjectStorages::from_bytes(unsafe { &*raw_mmap_bytes });
-        Ok(GeodataReader { storages, _mmap: mmap })
+        let (storages, extent, decompressed_data) = ObjectStorages::from_bytes(unsafe { &*raw_mmap_bytes })
+            .context(format!("Failed to load geodata from {}", file_name))?;
+        let mut reader = GeodataReader {
+            storages,
+            data_id: NEXT_DATA_ID.fetch_add(1, AtomicOrdering::Relaxed),
+            zoom_masks: None,
+            extent,
+            tile_range: None,
+            _decompressed_data: decompressed_data,
+            _mmap: mmap,
+        };
+        reader.tile_range = reader.compute_tile_range();
+        Ok(reader)
+    }
+
+    // From now on, the entities that aren't drawn at the zoom level of a tile are not returned for it.
+    pub fn set_zoom_masks(&mut self, zoom_masks: ZoomMasks) -> Result<()> {
+        let storages = self.storages();
+        let expected_counts = [
+            storages.node_storage.object_count,
+            storages.way_storage.object_count,
+            storages.multipolygon_storage.object_count,
+            storages.relation_storage.object_count,
+        ];
+        let actual_counts = [
+            zoom_masks.nodes.len(),
+            zoom_masks.ways.len(),
+            zoom_masks.multipolygons.len(),
+            zoom_masks.relations.len(),
+        ];
+        if expected_counts != actual_counts {
+            bail!("The zoom masks were computed for a different geodata file");
+        }
+        self.zoom_masks = Some(zoom_masks);
+        Ok(())
+    }
+
+    pub fn has_zoom_masks(&self) -> bool {
+        self.zoom_masks.is_some()
+    }
+
+    // The bounding box of all nodes, if it's stored in the file (see `DATA_EXTENT_FLAG`).
+    pub fn extent(&self) -> Option<tile::LatLonBounds> {
+        self.extent
+    }
+
+    // The smallest range of the tiles at the maximum zoom level that contains all entities (None if there are none).
+    pub fn tile_range(&self) -> Option<&tile::TileRange> {
+        self.tile_range.as_ref()
+    }
+
+    // All entities in the order in which they are stored, including the multipolygons without polygons,
+    // so that the position of an entity in its list can be used as its index (e.g. in `ZoomMasks`).
+    pub fn all_entities(&'a self) -> OsmEntities<'a> {
+        OsmEntities {
+            nodes: self.nodes().collect(),
+            ways: self.ways().collect(),
+            multipolygons: self.multipolygons().collect(),
+            relations: self.relations().collect(),
+        }
+    }
+
+    // Like `all_entities()`, but for one kind of entities at a time and without collecting them, e.g. to build a
+    // search index or statistics over a large file.
+    pub fn nodes(&'a self) -> impl ExactSizeIterator<Item = Node<'a>> {
+        (0..self.storages().node_storage.object_count).map(|idx| self.get_node(idx, 0.0))
+    }
+
+    pub fn ways(&'a self) -> impl ExactSizeIterator<Item = Way<'a>> {
+        (0..self.storages().way_storage.object_count).map(|idx| self.get_way(idx, 0.0))
+    }
+
+    pub fn multipolygons(&'a self) -> impl ExactSizeIterator<Item = Multipolygon<'a>> {
+        (0..self.storages().multipolygon_storage.object_count).map(|idx| self.get_multipolygon(idx, 0.0))
+    }
+
+    pub fn relations(&'a self) -> impl ExactSizeIterator<Item = Relation<'a>> {
+        (0..self.storages().relation_storage.object_count).map(|idx| self.get_relation(idx, 0.0))
     }
 
     pub fn get_entities_in_tile_with_neighbors(
         &'a self,
         t: &tile::Tile,
         osm_ids: &Option<HashSet<u64>>,
-    ) -> OsmEntities {
+    ) -> OsmEntities<'a> {
         let mut entity_ids = OsmEntityIds::default();
-
-        let deltas = [-1, 0, 1];
-        for dx in &deltas {
-            for dy in &deltas {
-                let adjacent_tile = tile::Tile {
-                    x: (t.x as i32 + dx) as u32,
-                    y: (t.y as i32 + dy) as u32,
-                    zoom: t.zoom,
-                };
+        let mut wrapped_ids = Vec::new();
+        for (adjacent_tile, world_offset) in tile::neighbor_tiles(t) {
+            if world_offset == 0 {
                 self.get_entities_in_tile(&adjacent_tile, &mut entity_ids);
+            } else {
+                let mut ids = OsmEntityIds::default();
+                self.get_entities_in_tile(&adjacent_tile, &mut ids);
+                wrapped_ids.push((ids, world_offset));
             }
         }
 
-        let uniq = |ids: &mut Vec<u32>| {
+        self.uniq_ids(&mut entity_ids, Some(t.zoom));
+        let mut entities = self.ids_to_entities(&entity_ids, osm_ids, 0.0);
+        // The entities across the antimeridian are moved by the width of the world to be next to the tile, unless
+        // they are close enough to be drawn where they are.
+        for (mut ids, world_offset) in wrapped_ids {
+            self.uniq_ids(&mut ids, Some(t.zoom));
+            let drop_found =
+                |wrapped: &mut Vec<u32>, found: &Vec<u32>| wrapped.retain(|id| found.binary_search(id).is_err());
+            drop_found(&mut ids.nodes, &entity_ids.nodes);
+            drop_found(&mut ids.ways, &entity_ids.ways);
+            drop_found(&mut ids.multipolygons, &entity_ids.multipolygons);
+            drop_found(&mut ids.relations, &entity_ids.relations);
+            entities.merge(self.ids_to_entities(&ids, osm_ids, 360.0 * f64::from(world_offset)));
+        }
+        entities
+    }
+
+    // Returns all entities that might intersect the given bounding box. Much like with tiles,
+    // the check is approximate, so some of the returned entities can lie slightly outside.
+    pub fn get_entities_in_bbox(
+        &'a self,
+        min_lat: f64,
+        min_lon: f64,
+        max_lat: f64,
+        max_lon: f64,
+        osm_ids: &Option<HashSet<u64>>,
+    ) -> OsmEntities<'a> {
+        // Tile y coordinates grow from north to south.
+        let top_left = tile::coords_to_max_zoom_tile(&(max_lat, min_lon));
+        let bottom_right = tile::coords_to_max_zoom_tile(&(min_lat, max_lon));
+        let bounds = tile::TileRange {
+            min_x: top_left.x,
+            max_x: bottom_right.x,
+            min_y: top_left.y,
+            max_y: bottom_right.y,
+        };
+
+        let mut entity_ids = OsmEntityIds::default();
+        self.get_entities_in_tile_range(bounds, &mut entity_ids);
+        self.uniq_ids(&mut entity_ids, None);
+        self.ids_to_entities(&entity_ids, osm_ids, 0.0)
+    }
+
+    // Sorts the ids and removes the duplicates, as well as the entities that are not drawn at the given zoom level.
+    fn uniq_ids(&self, entity_ids: &mut OsmEntityIds, zoom: Option<u8>) {
+        let zoom_masks = zoom.and_then(|zoom| self.zoom_masks.as_ref().map(|masks| (masks, zoom)));
+        let uniq = |ids: &mut Vec<u32>, masks: fn(&ZoomMasks) -> &Vec<u32>| {
             ids.sort_unstable();
             ids.dedup();
+            if let Some((zoom_masks, zoom)) = zoom_masks {
+                ids.retain(|id| ZoomMasks::is_drawn(masks(zoom_masks), *id as usize, zoom));
+            }
         };
 
-        uniq(&mut entity_ids.nodes);
-        uniq(&mut entity_ids.ways);
-        uniq(&mut entity_ids.multipolygons);
+        uniq(&mut entity_ids.nodes, |m| &m.nodes);
+        uniq(&mut entity_ids.ways, |m| &m.ways);
+        uniq(&mut entity_ids.multipolygons, |m| &m.multipolygons);
+        uniq(&mut entity_ids.relations, |m| &m.relations);
+    }
 
-        let nodes = entity_ids.nodes.iter().map(|id| self.get_node(*id as usize));
-        let ways = entity_ids.ways.iter().map(|id| self.get_way(*id as usize));
+    // The longitudes of all nodes of the entities are shifted by `lon_offset`.
+    fn ids_to_entities(
+        &'a self,
+        entity_ids: &OsmEntityIds,
+        osm_ids: &Option<HashSet<u64>>,
+        lon_offset: f64,
+    ) -> OsmEntities<'a> {
+        let nodes = entity_ids
+            .nodes
+            .iter()
+            .map(|id| self.get_node(*id as usize, lon_offset));
+        let ways = entity_ids.ways.iter().map(|id| self.get_way(*id as usize, lon_offset));
         let multipolygons = entity_ids.multipolygons.iter().filter_map(|id| {
-            let mp = self.get_multipolygon(*id as usize);
+            let mp = self.get_multipolygon(*id as usize, lon_offset);
             if mp.polygon_count() > 0 {
                 Some(mp)
             } else {
                 None
             }
         });
+        let relations = entity_ids
+            .relations
+            .iter()
+            .map(|id| self.get_relation(*id as usize, lon_offset));
 
         OsmEntities {
             nodes: filter_entities_by_ids(nodes, osm_ids),
             ways: filter_entities_by_ids(ways, osm_ids),
             multipolygons: filter_entities_by_ids(multipolygons, osm_ids),
+            relations: filter_entities_by_ids(relations, osm_ids),
         }
     }
 
     pub(super) fn get_entities_in_tile(&'a self, t: &tile::Tile, entity_ids: &mut OsmEntityIds) {
-        let mut bounds = tile::tile_to_max_zoom_tile_range(t);
+        self.get_entities_in_tile_range(tile::tile_to_max_zoom_tile_range(t), entity_ids);
+    }
+
+    fn get_entities_in_tile_range(&'a self, mut bounds: tile::TileRange, entity_ids: &mut OsmEntityIds) {
         let mut start_from_index = 0;
 
         let tile_count = self.tile_count();
@@ -116,9 +300,7 @@ impl<'a> GeodataReader<'a> {
                     let current_x = tile_x;
 
                     while (tile_x == current_x) && (tile_y <= bounds.max_y) {
-                        entity_ids.nodes.extend(self.tile_local_ids(current_index, 0));
-                        entity_ids.ways.extend(self.tile_local_ids(current_index, 1));
-                        entity_ids.multipolygons.extend(self.tile_local_ids(current_index, 2));
+                        self.decode_tile_local_ids(current_index, entity_ids);
 
                         current_index += 1;
                         if current_index >= tile_count {
@@ -183,41 +365,68 @@ impl<'a> GeodataReader<'a> {
         None
     }
 
-    fn get_node(&'a self, idx: usize) -> Node<'a> {
+    fn get_node(&'a self, idx: usize, lon_offset: f64) -> Node<'a> {
         Node {
             entity: BaseOsmEntity {
                 bytes: self.storages().node_storage.get_object(idx),
                 reader: self,
+                lon_offset,
             },
         }
     }
 
-    fn get_way(&'a self, idx: usize) -> Way<'a> {
+    fn get_way(&'a self, idx: usize, lon_offset: f64) -> Way<'a> {
         let bytes = self.storages().way_storage.get_object(idx);
         let node_ids_start_pos = mem::size_of::<u64>();
         let node_ids = self.get_ints_by_ref(&bytes[node_ids_start_pos..]);
         Way {
-            entity: BaseOsmEntity { bytes, reader: self },
+            entity: BaseOsmEntity {
+                bytes,
+                reader: self,
+                lon_offset,
+            },
             node_ids,
         }
     }
 
-    fn get_polygon(&'a self, idx: usize) -> Polygon<'a> {
+    fn get_polygon(&'a self, idx: usize, lon_offset: f64) -> Polygon<'a> {
         let bytes = self.storages().polygon_storage.get_object(idx);
         let node_ids = self.get_ints_by_ref(bytes);
-        Polygon { reader: self, node_ids }
+        Polygon {
+            reader: self,
+            node_ids,
+            lon_offset,
+        }
     }
 
-    fn get_multipolygon(&'a self, idx: usize) -> Multipolygon<'a> {
+    fn get_multipolygon(&'a self, idx: usize, lon_offset: f64) -> Multipolygon<'a> {
         let bytes = self.storages().multipolygon_storage.get_object(idx);
         let way_ids_start_pos = mem::size_of::<u64>();
         let way_ids = self.get_ints_by_ref(&bytes[way_ids_start_pos..]);
         Multipolygon {
-            entity: BaseOsmEntity { bytes, reader: self },
+            entity: BaseOsmEntity {
+                bytes,
+                reader: self,
+                lon_offset,
+            },
             polygon_ids: way_ids,
         }
     }
 
+    fn get_relation(&'a self, idx: usize, lon_offset: f64) -> Relation<'a> {
+        let bytes = self.storages().relation_storage.get_object(idx);
+        let line_ids_start_pos = mem::size_of::<u64>();
+        let line_ids = self.get_ints_by_ref(&bytes[line_ids_start_pos..]);
+        Relation {
+            entity: BaseOsmEntity {
+                bytes,
+                reader: self,
+                lon_offset,
+            },
+            line_ids,
+        }
+    }
+
     fn tile_xy(&self, idx: usize) -> (u32, u32) {
         let tile = self.storages().tile_storage.get_object(idx);
         let mut cursor = Cursor::new(tile);
@@ -226,27 +435,64 @@ impl<'a> GeodataReader<'a> {
         (x, y)
     }
 
-    fn tile_local_ids(&self, idx: usize, local_ids_idx: usize) -> &'a [u32] {
-        let tile = self.storages().tile_storage.get_object(idx);
-        let offset = 2 * mem::size_of::<u32>() * (local_ids_idx + 1);
-        self.get_ints_by_ref(&tile[offset..])
+    // The tile ids were checked on load, so they are always decoded successfully.
+    fn decode_tile_local_ids(&self, idx: usize, entity_ids: &mut OsmEntityIds) {
+        let mut lists = [
+            &mut entity_ids.nodes,
+            &mut entity_ids.ways,
+            &mut entity_ids.multipolygons,
+            &mut entity_ids.relations,
+        ];
+        self.storages()
+            .decode_tile_ids(idx, |kind, id| {
+                lists[kind].push(id);
+                Ok(())
+            })
+            .unwrap();
     }
 
     fn tile_count(&self) -> usize {
         self.storages().tile_storage.object_count
     }
 
+    fn compute_tile_range(&self) -> Option<tile::TileRange> {
+        if let Some(extent) = &self.extent {
+            // Tile y coordinates grow from north to south.
+            let top_left = tile::coords_to_max_zoom_tile(&(extent.max_lat, extent.min_lon));
+            let bottom_right = tile::coords_to_max_zoom_tile(&(extent.min_lat, extent.max_lon));
+            return Some(tile::TileRange {
+                min_x: top_left.x,
+                max_x: bottom_right.x,
+                min_y: top_left.y,
+                max_y: bottom_right.y,
+            });
+        }
+
+        // The tiles are sorted by x, so only y has to be looked for.
+        let last_idx = self.tile_count().checked_sub(1)?;
+        let ((min_x, _), (max_x, _)) = (self.tile_xy(0), self.tile_xy(last_idx));
+        let (min_y, max_y) = (0..self.tile_count())
+            .map(|idx| self.tile_xy(idx).1)
+            .fold((u32::MAX, 0), |(min_y, max_y), y| (min_y.min(y), max_y.max(y)));
+        Some(tile::TileRange {
+            min_x,
+            max_x,
+            min_y,
+            max_y,
+        })
+    }
+
     fn tags(&self, ref_bytes: &'a [u8]) -> Tags<'a> {
         Tags {
             kv_refs: self.get_ints_by_ref(ref_bytes),
             strings: self.storages().strings,
+            data_id: self.data_id,
         }
     }
 
+    // The refs were checked against the ints on load.
     fn get_ints_by_ref(&self, ref_bytes: &'a [u8]) -> &'a [u32] {
-        let mut cursor = Cursor::new(ref_bytes);
-        let offset = cursor.read_u32::<LittleEndian>().unwrap() as usize;
-        let length = cursor.read_u32::<LittleEndian>().unwrap() as usize;
+        let (offset, length) = read_int_ref(ref_bytes);
         &self.storages().ints[offset..offset + length]
     }
 
@@ -272,17 +518,14 @@ struct ObjectStorage<'a> {
 }
 
 impl<'a> ObjectStorage<'a> {
-    fn from_bytes(bytes: &[u8], object_size: usize) -> (ObjectStorage<'_>, &[u8]) {
-        let object_count = LittleEndian::read_u32(bytes) as usize;
-        let object_start_pos = mem::size_of::<u32>();
-        let object_end_pos = object_start_pos + object_size * object_count;
-        let storage = ObjectStorage {
+    fn from_sections(sections: &mut Sections<'a>, object_size: usize, name: &str) -> Result<ObjectStorage<'a>> {
+        let object_count = sections.read_u32(name)? as usize;
+        let objects = sections.take(object_count.saturating_mul(object_size), name)?;
+        Ok(ObjectStorage {
             object_count,
             object_size,
-            objects: &bytes[object_start_pos..object_end_pos],
-        };
-        let rest = &bytes[object_end_pos..];
-        (storage, rest)
+            objects,
+        })
     }
 
     fn get_object(&self, idx: usize) -> &'a [u8] {
@@ -297,51 +540,315 @@ struct ObjectStorages<'a> {
     way_storage: ObjectStorage<'a>,
     polygon_storage: ObjectStorage<'a>,
     multipolygon_storage: ObjectStorage<'a>,
+    relation_storage: ObjectStorage<'a>,
     tile_storage: ObjectStorage<'a>,
+    tile_ids: &'a [u8],
     ints: &'a [u32],
     strings: &'a [u8],
 }
 
+const KNOWN_FLAGS: u32 = COMPRESSED_DATA_FLAG | DATA_EXTENT_FLAG;
+const MAX_DEFLATE_RATIO: usize = 1032;
 const INT_REF_SIZE: usize = 2 * mem::size_of::<u32>();
 const NODE_SIZE: usize = mem::size_of::<u64>() + 2 * mem::size_of::<f64>() + INT_REF_SIZE;
 const POLYGON_SIZE: usize = INT_REF_SIZE;
-const WAY_OR_MULTIPOLYGON_SIZE: usize = mem::size_of::<u64>() + 2 * INT_REF_SIZE;
-const TILE_SIZE: usize = 2 * mem::size_of::<u32>() + 3 * INT_REF_SIZE;
+const WAY_OR_RELATION_SIZE: usize = mem::size_of::<u64>() + 2 * INT_REF_SIZE;
+const TILE_SIZE: usize = 2 * mem::size_of::<u32>() + INT_REF_SIZE;
 
 impl<'a> ObjectStorages<'a> {
     // All geodata members have sizes divisible by 4, so the u8* -> u32* cast should be safe,
     // provided that `bytes` is aligned to 4 bytes (if it's not, we're in trouble anyway).
-    #[cfg_attr(feature = "cargo-clippy", allow(clippy::cast_ptr_alignment))]
-    fn from_bytes(bytes: &[u8]) -> ObjectStorages<'_> {
-        let (node_storage, rest) = ObjectStorage::from_bytes(bytes, NODE_SIZE);
-        let (way_storage, rest) = ObjectStorage::from_bytes(rest, WAY_OR_MULTIPOLYGON_SIZE);
-        let (polygon_storage, rest) = ObjectStorage::from_bytes(rest, POLYGON_SIZE);
-        let (multipolygon_storage, rest) = ObjectStorage::from_bytes(rest, WAY_OR_MULTIPOLYGON_SIZE);
-        let (tile_storage, rest) = ObjectStorage::from_bytes(rest, TILE_SIZE);
-
-        let int_count = LittleEndian::read_u32(rest) as usize;
-        let start_pos = mem::size_of::<u32>();
-        let end_pos = start_pos + mem::size_of::<u32>() * int_count;
-        let byte_seq = &rest[start_pos..end_pos];
+    #[allow(clippy::cast_ptr_alignment)]
+    fn from_bytes(bytes: &[u8]) -> Result<(ObjectStorages<'_>, Option<tile::LatLonBounds>, Option<Vec<u32>>)> {
+        let mut sections = Sections { rest: bytes, offset: 0 };
+        if sections.take(GEODATA_MAGIC.len(), "header")? != GEODATA_MAGIC {
+            bail!(
+                "This is not a geodata file (or it was imported by a version of the renderer without format versions)"
+            );
+        }
+        let version = sections.read_u32("header")?;
+        if version != GEODATA_VERSION {
+            bail!(
+                "The geodata format version is {}, but this version of the renderer only reads version {} \
+                 (import the data again)",
+                version,
+                GEODATA_VERSION
+            );
+        }
+        let flags = sections.read_u32("header")?;
+        if flags & !KNOWN_FLAGS != 0 {
+            bail!(
+                "Unknown header flags {:#x} (the file was probably imported by a different version of the renderer)",
+                flags
+            );
+        }
+        let mut extent = None;
+        if flags & DATA_EXTENT_FLAG != 0 {
+            let extent_bytes = sections.take(4 * mem::size_of::<f64>(), "data extent")?;
+            let coord = |idx| LittleEndian::read_f64(&extent_bytes[idx * mem::size_of::<f64>()..]);
+            extent = Some(tile::LatLonBounds {
+                min_lat: coord(0),
+                min_lon: coord(1),
+                max_lat: coord(2),
+                max_lon: coord(3),
+            });
+        }
+        let node_storage = ObjectStorage::from_sections(&mut sections, NODE_SIZE, "node storage")?;
+        let way_storage = ObjectStorage::from_sections(&mut sections, WAY_OR_RELATION_SIZE, "way storage")?;
+        let polygon_storage = ObjectStorage::from_sections(&mut sections, POLYGON_SIZE, "polygon storage")?;
+        let multipolygon_storage =
+            ObjectStorage::from_sections(&mut sections, WAY_OR_RELATION_SIZE, "multipolygon storage")?;
+        let relation_storage = ObjectStorage::from_sections(&mut sections, WAY_OR_RELATION_SIZE, "relation storage")?;
+        let tile_storage = ObjectStorage::from_sections(&mut sections, TILE_SIZE, "tile storage")?;
+
+        let tile_ids_size = sections.read_u32("tile ids")? as usize;
+        let tile_ids = sections.take(tile_ids_size, "tile ids")?;
+        sections.take(
+            tile_ids_size.next_multiple_of(mem::size_of::<u32>()) - tile_ids_size,
+            "tile ids",
+        )?;
+
+        let mut decompressed_data = None;
+        if flags & COMPRESSED_DATA_FLAG != 0 {
+            let (buffer, byte_count) = decompress_data(&mut sections)?;
+            let raw_bytes = ptr::slice_from_raw_parts(buffer.as_ptr() as *const u8, byte_count);
+            decompressed_data = Some(buffer);
+            // Much like with the memory mapped bytes, the decompressed buffer is bundled together
+            // with `storages` in `GeodataReader`, and its heap allocation never moves.
+            sections = Sections {
+                rest: unsafe { &*raw_bytes },
+                offset: 0,
+            };
+        }
+
+        let int_count = sections.read_u32("ints")? as usize;
+        let byte_seq = sections.take(int_count.saturating_mul(mem::size_of::<u32>()), "ints")?;
         let int_ptr = byte_seq.as_ptr() as *const u32;
         let ints = unsafe { slice::from_raw_parts(int_ptr, int_count) };
-        let strings = &rest[end_pos..];
+        let strings = sections.rest;
 
-        ObjectStorages {
+        let storages = ObjectStorages {
             node_storage,
             way_storage,
             polygon_storage,
             multipolygon_storage,
+            relation_storage,
             tile_storage,
+            tile_ids,
             ints,
             strings,
+        };
+        storages.check_refs()?;
+        Ok((storages, extent, decompressed_data))
+    }
+
+    // Checks every ref of the entities and the tiles once, so that the entities can be read without any checks later.
+    fn check_refs(&self) -> Result<()> {
+        // The strings of the tags are read without checking them again (see `Tags::get_str()`).
+        let strings = str::from_utf8(self.strings).context("The strings are not valid UTF-8")?;
+        let (nodes, polygons) = (self.node_storage.object_count, self.polygon_storage.object_count);
+        for (storage, targets, name) in [
+            (&self.node_storage, &[RefTarget::Tags][..], "node"),
+            (
+                &self.way_storage,
+                &[RefTarget::Entities("node", nodes), RefTarget::Tags],
+                "way",
+            ),
+            (&self.polygon_storage, &[RefTarget::Entities("node", nodes)], "polygon"),
+            (
+                &self.multipolygon_storage,
+                &[RefTarget::Entities("polygon", polygons), RefTarget::Tags],
+                "multipolygon",
+            ),
+            (
+                &self.relation_storage,
+                &[RefTarget::Entities("polygon", polygons), RefTarget::Tags],
+                "relation",
+            ),
+        ] {
+            for idx in 0..storage.object_count {
+                // The refs are always at the end of an object.
+                let object = storage.get_object(idx);
+                let ref_bytes = object[object.len() - targets.len() * INT_REF_SIZE..].chunks(INT_REF_SIZE);
+                for (ref_bytes, target) in ref_bytes.zip(targets) {
+                    let (offset, length) = read_int_ref(ref_bytes);
+                    let Some(ints) = offset.checked_add(length).and_then(|end| self.ints.get(offset..end)) else {
+                        bail!(
+                            "The {} #{} refers to {} ints at offset {}, but there are only {} ints",
+                            name,
+                            idx,
+                            length,
+                            offset,
+                            self.ints.len()
+                        );
+                    };
+                    match *target {
+                        RefTarget::Entities(target_name, count) => {
+                            if let Some(id) = ints.iter().find(|&&id| id as usize >= count) {
+                                bail!("The {} #{} refers to {} #{} of {}", name, idx, target_name, id, count);
+                            }
+                        }
+                        RefTarget::Tags => {
+                            for kv_ref in ints.chunks_exact(2) {
+                                let (start, length) = (kv_ref[0] as usize, kv_ref[1] as usize);
+                                // `get()` also fails if the string doesn't start or end at a character boundary.
+                                if start
+                                    .checked_add(length)
+                                    .and_then(|end| strings.get(start..end))
+                                    .is_none()
+                                {
+                                    bail!(
+                                        "The tags of the {} #{} refer to {} bytes at offset {}, but there are \
+                                         only {} bytes of strings",
+                                        name,
+                                        idx,
+                                        length,
+                                        start,
+                                        strings.len()
+                                    );
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        let entity_counts = [
+            self.node_storage.object_count,
+            self.way_storage.object_count,
+            self.multipolygon_storage.object_count,
+            self.relation_storage.object_count,
+        ];
+        for idx in 0..self.tile_storage.object_count {
+            self.decode_tile_ids(idx, |kind, id| {
+                if id as usize >= entity_counts[kind] {
+                    bail!("The tile #{} refers to entity #{} of {}", idx, id, entity_counts[kind]);
+                }
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    // Each tile has sorted lists of node, way, multipolygon and relation ids. Every list is stored as a
+    // varint-encoded length followed by varint-encoded differences between adjacent ids. Calls `on_id` with the
+    // index of the list and the id for every id.
+    fn decode_tile_ids(&self, idx: usize, mut on_id: impl FnMut(usize, u32) -> Result<()>) -> Result<()> {
+        let tile = self.tile_storage.get_object(idx);
+        let (offset, length) = read_int_ref(&tile[2 * mem::size_of::<u32>()..]);
+        let Some(mut bytes) = offset
+            .checked_add(length)
+            .and_then(|end| self.tile_ids.get(offset..end))
+        else {
+            bail!(
+                "The tile #{} refers to {} bytes at offset {}, but there are only {} bytes of tile ids",
+                idx,
+                length,
+                offset,
+                self.tile_ids.len()
+            );
+        };
+        let read =
+            |bytes: &mut &[u8]| read_varint(bytes).with_context(|| format!("The ids of the tile #{} are broken", idx));
+
+        for kind in 0..4 {
+            let count = read(&mut bytes)?;
+            let mut current_id = 0u32;
+            for _ in 0..count {
+                current_id = current_id
+                    .checked_add(read(&mut bytes)?)
+                    .with_context(|| format!("The ids of the tile #{} are broken", idx))?;
+                on_id(kind, current_id)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// The rest of the file after the sections that were already read. The offsets are in the decompressed data after
+// the tile ids if the file is compressed.
+struct Sections<'a> {
+    rest: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Sections<'a> {
+    fn take(&mut self, size: usize, name: &str) -> Result<&'a [u8]> {
+        if size > self.rest.len() {
+            bail!(
+                "The {} is truncated at offset {}: expected {} bytes, but only {} are left \
+                 (the file may have been imported by a different version of the renderer)",
+                name,
+                self.offset,
+                size,
+                self.rest.len()
+            );
         }
+        let (section, rest) = self.rest.split_at(size);
+        self.rest = rest;
+        self.offset += size;
+        Ok(section)
+    }
+
+    fn read_u32(&mut self, name: &str) -> Result<u32> {
+        Ok(LittleEndian::read_u32(self.take(mem::size_of::<u32>(), name)?))
     }
 }
 
+// What the ints of an entity ref are: the refs to the strings of its tags, or the indices of the entities with the
+// given name in a storage with the given number of them.
+enum RefTarget {
+    Tags,
+    Entities(&'static str, usize),
+}
+
+// `(offset, length)` of a slice of ints.
+fn read_int_ref(ref_bytes: &[u8]) -> (usize, usize) {
+    let offset = LittleEndian::read_u32(ref_bytes) as usize;
+    let length = LittleEndian::read_u32(&ref_bytes[mem::size_of::<u32>()..]) as usize;
+    (offset, length)
+}
+
+// None if the bytes end in the middle of the varint or it doesn't fit into u32.
+fn read_varint(bytes: &mut &[u8]) -> Option<u32> {
+    let mut result = 0;
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+        result |= u32::from(byte & 0x7F).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            return Some(result);
+        }
+        shift += 7;
+    }
+}
+
+// Returns a buffer of u32 to make sure the ints are properly aligned, and the number of bytes in it.
+fn decompress_data(sections: &mut Sections<'_>) -> Result<(Vec<u32>, usize)> {
+    let byte_count = LittleEndian::read_u64(sections.take(mem::size_of::<u64>(), "compressed data")?) as usize;
+    let bytes = sections.rest;
+    // Deflate can't compress better than this, so a bigger size means that the file is broken (and it's better not
+    // to allocate a buffer for it).
+    if byte_count / MAX_DEFLATE_RATIO > bytes.len() {
+        bail!(
+            "The compressed data is truncated: expected {} bytes after decompressing",
+            byte_count
+        );
+    }
+    let mut buffer = vec![0u32; byte_count.div_ceil(mem::size_of::<u32>())];
+    let buffer_bytes = unsafe { slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, byte_count) };
+    ZlibDecoder::new(bytes)
+        .read_exact(buffer_bytes)
+        .context("Failed to decompress ints and strings")?;
+    Ok((buffer, byte_count))
+}
+
 pub struct Tags<'a> {
     kv_refs: &'a [u32],
     strings: &'a [u8],
+    data_id: usize,
 }
 
 const KV_REF_SIZE: usize = 4;
@@ -352,6 +859,12 @@ pub struct StringWithOffset<'a> {
 }
 
 impl<'a> Tags<'a> {
+    // The offsets of the strings are only comparable between the tags with the same data id, i.e. the ones
+    // that come from the same loaded geodata file.
+    pub fn data_id(&self) -> usize {
+        self.data_id
+    }
+
     pub fn get_by_key(&self, key: &str) -> Option<&'a str> {
         let kv_count = self.get_kv_count();
         if kv_count == 0 {
@@ -376,6 +889,42 @@ impl<'a> Tags<'a> {
         }
     }
 
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.get_by_key(key).is_some()
+    }
+
+    pub fn has_tag(&self, key: &str, value: &str) -> bool {
+        self.get_by_key(key) == Some(value)
+    }
+
+    // None if there is no such tag or if its value is not a number.
+    pub fn get_f64(&self, key: &str) -> Option<f64> {
+        self.get_by_key(key)?.parse().ok()
+    }
+
+    // "yes", "true" and "1" are true, "no", "false" and "0" are false, and anything else is None.
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        match self.get_by_key(key)? {
+            "yes" | "true" | "1" => Some(true),
+            "no" | "false" | "0" => Some(false),
+            _ => None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.get_kv_count() == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.get_kv_count()
+    }
+
+    // The tags are stored sorted by key, so this doesn't need to sort anything.
+    pub fn to_map(&self) -> BTreeMap<&'a str, &'a str> {
+        self.into_iter().collect()
+    }
+
+    // Also provides the offsets of the strings, which identify them in the loaded file (see `data_id()`).
     pub fn iter(&'a self) -> impl Iterator<Item = (StringWithOffset<'a>, StringWithOffset<'a>)> {
         (0..self.get_kv_count()).map(move |idx| self.get_kv(idx))
     }
@@ -393,6 +942,7 @@ impl<'a> Tags<'a> {
         (get_str_with_offset(0), get_str_with_offset(2))
     }
 
+    // The strings were checked on load.
     fn get_str(&self, start_pos: usize, length: usize) -> &'a str {
         unsafe { str::from_utf8_unchecked(&self.strings[start_pos..start_pos + length]) }
     }
@@ -402,10 +952,47 @@ impl<'a> Tags<'a> {
     }
 }
 
+impl<'t, 'a> IntoIterator for &'t Tags<'a> {
+    type Item = (&'a str, &'a str);
+    type IntoIter = TagsIter<'t, 'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        TagsIter { tags: self, idx: 0 }
+    }
+}
+
+// Iterates over the keys and values of the tags in the order of the keys.
+pub struct TagsIter<'t, 'a> {
+    tags: &'t Tags<'a>,
+    idx: usize,
+}
+
+impl<'a> Iterator for TagsIter<'_, 'a> {
+    type Item = (&'a str, &'a str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.idx == self.tags.get_kv_count() {
+            return None;
+        }
+        let (k, v) = self.tags.get_kv(self.idx);
+        self.idx += 1;
+        Some((k.str, v.str))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.tags.get_kv_count() - self.idx;
+        (remaining, Some(remaining))
+    }
+}
+
+impl ExactSizeIterator for TagsIter<'_, '_> {}
+
 #[derive(Clone)]
 struct BaseOsmEntity<'a> {
     bytes: &'a [u8],
     reader: &'a GeodataReader<'a>,
+    // Added to the longitudes of the nodes, to draw the entities from across the antimeridian next to a tile.
+    lon_offset: f64,
 }
 
 macro_rules! implement_osm_entity {
@@ -453,7 +1040,7 @@ impl<'a> Coords for Node<'a> {
 
     fn lon(&self) -> f64 {
         let start_pos = mem::size_of::<u64>() + mem::size_of::<f64>();
-        LittleEndian::read_f64(&self.entity.bytes[start_pos..])
+        LittleEndian::read_f64(&self.entity.bytes[start_pos..]) + self.entity.lon_offset
     }
 }
 
@@ -471,7 +1058,7 @@ impl<'a> Way<'a> {
 
     pub fn get_node(&self, idx: usize) -> Node<'a> {
         let node_id = self.node_ids[idx];
-        self.entity.reader.get_node(node_id as usize)
+        self.entity.reader.get_node(node_id as usize, self.entity.lon_offset)
     }
 }
 
@@ -489,6 +1076,7 @@ impl<'a> OsmArea for Way<'a> {
 pub struct Polygon<'a> {
     reader: &'a GeodataReader<'a>,
     node_ids: &'a [u32],
+    lon_offset: f64,
 }
 
 impl<'a> Polygon<'a> {
@@ -498,7 +1086,7 @@ impl<'a> Polygon<'a> {
 
     pub fn get_node(&self, idx: usize) -> Node<'a> {
         let node_id = self.node_ids[idx];
-        self.reader.get_node(node_id as usize)
+        self.reader.get_node(node_id as usize, self.lon_offset)
     }
 }
 
@@ -516,7 +1104,9 @@ impl<'a> Multipolygon<'a> {
 
     pub fn get_polygon(&self, idx: usize) -> Polygon<'a> {
         let polygon_id = self.polygon_ids[idx];
-        self.entity.reader.get_polygon(polygon_id as usize)
+        self.entity
+            .reader
+            .get_polygon(polygon_id as usize, self.entity.lon_offset)
     }
 }
 
@@ -525,3 +1115,21 @@ impl<'a> OsmArea for Multipolygon<'a> {
         true
     }
 }
+
+pub struct Relation<'a> {
+    entity: BaseOsmEntity<'a>,
+    line_ids: &'a [u32],
+}
+
+implement_osm_entity!(Relation<'a>);
+
+impl<'a> Relation<'a> {
+    pub fn line_count(&self) -> usize {
+        self.line_ids.len()
+    }
+
+    pub fn get_line(&self, idx: usize) -> Polygon<'a> {
+        let line_id = self.line_ids[idx];
+        self.entity.reader.get_polygon(line_id as usize, self.entity.lon_offset)
+    }
+}
@@ -0,0 +1,187 @@
+use crate::geodata::importer::EntityStorages;
+use crate::geodata::saver::data_extent;
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+// The statistics of the imported data and of the problems with its geometry, returned by the import functions and
+// saved as JSON with `--report FILE`. All counts except `duplicate_node_coords` are for the saved data.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ImportReport {
+    pub nodes: usize,
+    pub ways: usize,
+    pub multipolygons: usize,
+    pub boundaries: usize,
+    // The ways with less than 2 nodes, which are never drawn.
+    pub short_ways: usize,
+    // The closed ways (which may be drawn as areas) and the rings of the multipolygons with less than 3 distinct
+    // nodes or with less than about a square meter of area.
+    pub degenerate_polygons: usize,
+    pub repaired_multipolygons: usize,
+    pub incomplete_multipolygons: usize,
+    pub failed_multipolygons: usize,
+    // The nodes at the same location as an earlier node of the input, before the unused untagged ones are dropped.
+    pub duplicate_node_coords: usize,
+    pub ways_with_missing_nodes: usize,
+    pub relations_with_missing_ways: usize,
+    // The extent of all saved nodes as `[min_lat, min_lon, max_lat, max_lon]`.
+    pub bbox: Option<[f64; 4]>,
+}
+
+impl ImportReport {
+    pub(super) fn new(entity_storages: &EntityStorages, duplicate_node_coords: usize) -> ImportReport {
+        let nodes = entity_storages.node_storage.get_entities();
+        let ways = entity_storages.way_storage.get_entities();
+        let (repaired_multipolygons, incomplete_multipolygons, failed_multipolygons) =
+            entity_storages.assembly_report.counts();
+        let (ways_with_missing_nodes, relations_with_missing_ways) = entity_storages.dropped_refs.counts();
+        ImportReport {
+            nodes: nodes.len(),
+            ways: ways.len(),
+            multipolygons: entity_storages.multipolygon_storage.get_entities().len(),
+            boundaries: entity_storages.relation_storage.get_entities().len(),
+            short_ways: ways.iter().filter(|way| way.node_ids.len() < 2).count(),
+            degenerate_polygons: count_degenerate_polygons(entity_storages),
+            repaired_multipolygons,
+            incomplete_multipolygons,
+            failed_multipolygons,
+            duplicate_node_coords,
+            ways_with_missing_nodes,
+            relations_with_missing_ways,
+            bbox: data_extent(nodes),
+        }
+    }
+
+    // The problems that are worth a look, as one line for the importer output.
+    pub fn summary(&self) -> Option<String> {
+        let problems = [
+            (self.short_ways, "ways with less than 2 nodes"),
+            (self.degenerate_polygons, "degenerate polygons"),
+            (self.duplicate_node_coords, "nodes at the same location as another node"),
+        ];
+        let problems = problems
+            .iter()
+            .filter(|(count, _)| *count > 0)
+            .map(|(count, description)| format!("{} {}", count, description))
+            .collect::<Vec<_>>();
+        if problems.is_empty() {
+            None
+        } else {
+            Some(format!("Geometry problems: {}", problems.join(", ")))
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let counts = [
+            ("nodes", self.nodes),
+            ("ways", self.ways),
+            ("multipolygons", self.multipolygons),
+            ("boundaries", self.boundaries),
+            ("short_ways", self.short_ways),
+            ("degenerate_polygons", self.degenerate_polygons),
+            ("repaired_multipolygons", self.repaired_multipolygons),
+            ("incomplete_multipolygons", self.incomplete_multipolygons),
+            ("failed_multipolygons", self.failed_multipolygons),
+            ("duplicate_node_coords", self.duplicate_node_coords),
+            ("ways_with_missing_nodes", self.ways_with_missing_nodes),
+            ("relations_with_missing_ways", self.relations_with_missing_ways),
+        ];
+        let mut fields = counts
+            .iter()
+            .map(|(name, count)| format!("\"{}\":{}", name, count))
+            .collect::<Vec<_>>();
+        fields.push(match self.bbox {
+            Some([min_lat, min_lon, max_lat, max_lon]) => format!(
+                "\"bbox\":{{\"min_lat\":{},\"min_lon\":{},\"max_lat\":{},\"max_lon\":{}}}",
+                min_lat, min_lon, max_lat, max_lon
+            ),
+            None => "\"bbox\":null".to_string(),
+        });
+        format!("{{{}}}\n", fields.join(","))
+    }
+
+    pub(super) fn save(&self, file: &Path) -> Result<()> {
+        fs::write(file, self.to_json()).context(format!("Failed to write the import report to {}", file.display()))?;
+        println!("Saved the import report to {}", file.display());
+        Ok(())
+    }
+}
+
+// The coordinates are compared exactly, since the nodes of the same location come from the same OSM data.
+pub(super) fn count_duplicate_node_coords(entity_storages: &EntityStorages) -> usize {
+    let mut seen = HashSet::new();
+    entity_storages
+        .node_storage
+        .get_entities()
+        .iter()
+        .filter(|node| !seen.insert((node.lat.to_bits(), node.lon.to_bits())))
+        .count()
+}
+
+fn count_degenerate_polygons(entity_storages: &EntityStorages) -> usize {
+    // A square degree is about 10^10 square meters at the equator and less elsewhere, so this is at most a square
+    // meter or so.
+    const MIN_AREA: f64 = 1e-10;
+
+    let nodes = entity_storages.node_storage.get_entities();
+    let is_degenerate = |polygon: &&Vec<usize>| {
+        let distinct_nodes = polygon.iter().collect::<HashSet<_>>();
+        if distinct_nodes.len() < 3 {
+            return true;
+        }
+        // The coordinates are relative to the first node, so that the rounding errors don't add up to an area.
+        let origin = &nodes[polygon[0]];
+        let doubled_area = polygon
+            .windows(2)
+            .map(|w| {
+                let (from, to) = (&nodes[w[0]], &nodes[w[1]]);
+                let (x1, y1) = (from.lon - origin.lon, from.lat - origin.lat);
+                let (x2, y2) = (to.lon - origin.lon, to.lat - origin.lat);
+                x1 * y2 - x2 * y1
+            })
+            .sum::<f64>();
+        doubled_area.abs() / 2.0 < MIN_AREA
+    };
+    let closed_ways = entity_storages
+        .way_storage
+        .get_entities()
+        .iter()
+        .map(|way| &way.node_ids)
+        .filter(|node_ids| node_ids.len() >= 2 && node_ids.first() == node_ids.last());
+    closed_ways
+        .chain(&entity_storages.polygon_storage)
+        .filter(is_degenerate)
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_import_report_json() {
+        let mut report = ImportReport {
+            nodes: 4,
+            short_ways: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            report.to_json(),
+            "{\"nodes\":4,\"ways\":0,\"multipolygons\":0,\"boundaries\":0,\"short_ways\":1,\
+             \"degenerate_polygons\":0,\"repaired_multipolygons\":0,\"incomplete_multipolygons\":0,\
+             \"failed_multipolygons\":0,\"duplicate_node_coords\":0,\"ways_with_missing_nodes\":0,\
+             \"relations_with_missing_ways\":0,\"bbox\":null}\n"
+        );
+        assert_eq!(
+            report.summary().unwrap(),
+            "Geometry problems: 1 ways with less than 2 nodes"
+        );
+
+        report.bbox = Some([55.75, 37.5, 55.8, 37.625]);
+        assert!(report
+            .to_json()
+            .ends_with(",\"bbox\":{\"min_lat\":55.75,\"min_lon\":37.5,\"max_lat\":55.8,\"max_lon\":37.625}}\n"));
+        assert!(ImportReport::default().summary().is_none());
+    }
+}
@@ -1,16 +1,20 @@
-use crate::geodata::importer::{EntityStorages, Multipolygon, Polygon, RawNode, RawRefs, RawWay};
+use crate::geodata::importer::{EntityStorages, Multipolygon, Polygon, RawNode, RawRefs, RawTags, RawWay, Relation};
+use crate::geodata::string_table::{StringId, StringTable};
 use crate::tile;
 use anyhow::{bail, Result};
 use byteorder::{LittleEndian, WriteBytesExt};
-use std::cmp::{max, min};
-use std::collections::{BTreeMap, BTreeSet, HashMap};
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::collections::{BTreeMap, BTreeSet};
 use std::io::Write;
+use std::mem;
 
 #[derive(Default)]
 struct TileReferences {
     local_node_ids: BTreeSet<usize>,
     local_way_ids: BTreeSet<usize>,
     local_multipolygon_ids: BTreeSet<usize>,
+    local_relation_ids: BTreeSet<usize>,
 }
 
 #[derive(Default)]
@@ -18,9 +22,37 @@ struct TileIdToReferences {
     refs: BTreeMap<(u32, u32), TileReferences>,
 }
 
-pub(super) fn save_to_internal_format(writer: &mut dyn Write, entity_storages: &EntityStorages) -> Result<()> {
-    let mut buffered_data = BufferedData::default();
+// Every file starts with these bytes and the version of the format, which has to be bumped whenever the format changes
+// in a way that the flags below can't describe. The flags follow the version.
+pub(super) const GEODATA_MAGIC: [u8; 4] = *b"OSMR";
+pub(super) const GEODATA_VERSION: u32 = 1;
+// Set in the header if the ints and strings are compressed with zlib.
+pub(super) const COMPRESSED_DATA_FLAG: u32 = 1;
+// Set in the header if it's followed by the bounding box of all nodes (min_lat, min_lon, max_lat, max_lon as f64).
+// Older files don't have it, and neither do the files without nodes.
+pub(super) const DATA_EXTENT_FLAG: u32 = 2;
+
+// The output depends only on the imported data, so that re-importing the same files gives a byte-identical result
+// (which keeps the caches and diffs of the output files useful). Everything is written either in the parsing order
+// or sorted (hence the BTree collections); hash maps are fine only for lookups, never for iteration.
+pub(super) fn save_to_internal_format(
+    writer: &mut dyn Write,
+    entity_storages: &EntityStorages,
+    compress: bool,
+) -> Result<()> {
     let nodes = &entity_storages.node_storage.get_entities();
+    let extent = data_extent(nodes);
+
+    let mut flags = if compress { COMPRESSED_DATA_FLAG } else { 0 };
+    if extent.is_some() {
+        flags |= DATA_EXTENT_FLAG;
+    }
+    save_header(writer, flags)?;
+    for coord in extent.iter().flatten() {
+        writer.write_f64::<LittleEndian>(*coord)?;
+    }
+
+    let mut buffered_data = BufferedData::new(&entity_storages.strings);
     save_nodes(writer, nodes, &mut buffered_data)?;
 
     let ways = &entity_storages.way_storage.get_entities();
@@ -32,11 +64,40 @@ pub(super) fn save_to_internal_format(writer: &mut dyn Write, entity_storages: &
     let multipolygons = &entity_storages.multipolygon_storage.get_entities();
     save_multipolygons(writer, multipolygons, &mut buffered_data)?;
 
+    let relations = &entity_storages.relation_storage.get_entities();
+    save_relations(writer, relations, &mut buffered_data)?;
+
     let tile_references = get_tile_references(entity_storages);
-    save_tile_references(writer, &tile_references, &mut buffered_data)?;
+    save_tile_references(writer, &tile_references)?;
+
+    if compress {
+        buffered_data.save_compressed(writer)?;
+    } else {
+        buffered_data.save(writer)?;
+    }
 
-    buffered_data.save(writer)?;
+    Ok(())
+}
 
+// `[min_lat, min_lon, max_lat, max_lon]` of all nodes, or `None` if there are no nodes.
+pub(super) fn data_extent(nodes: &[RawNode]) -> Option<[f64; 4]> {
+    nodes.iter().fold(None, |extent: Option<[f64; 4]>, node| {
+        Some(match extent {
+            Some([min_lat, min_lon, max_lat, max_lon]) => [
+                min_lat.min(node.lat),
+                min_lon.min(node.lon),
+                max_lat.max(node.lat),
+                max_lon.max(node.lon),
+            ],
+            None => [node.lat, node.lon, node.lat, node.lon],
+        })
+    })
+}
+
+fn save_header(writer: &mut dyn Write, flags: u32) -> Result<()> {
+    writer.write_all(&GEODATA_MAGIC)?;
+    writer.write_u32::<LittleEndian>(GEODATA_VERSION)?;
+    writer.write_u32::<LittleEndian>(flags)?;
     Ok(())
 }
 
@@ -47,7 +108,7 @@ impl TileIdToReferences {
     }
 
     fn tile_ref_by_xy(&mut self, tile_x: u32, tile_y: u32) -> &mut TileReferences {
-        self.refs.entry((tile_x, tile_y)).or_insert_with(Default::default)
+        self.refs.entry((tile_x, tile_y)).or_default()
     }
 }
 
@@ -90,24 +151,64 @@ fn save_multipolygons(writer: &mut dyn Write, multipolygons: &[Multipolygon], da
     Ok(())
 }
 
-fn save_tile_references(
-    writer: &mut dyn Write,
-    tile_references: &TileIdToReferences,
-    data: &mut BufferedData,
-) -> Result<()> {
+fn save_relations(writer: &mut dyn Write, relations: &[Relation], data: &mut BufferedData) -> Result<()> {
+    writer.write_u32::<LittleEndian>(to_u32_safe(relations.len())?)?;
+    for relation in relations {
+        writer.write_u64::<LittleEndian>(relation.global_id)?;
+        save_refs(writer, relation.line_ids.iter(), data)?;
+        save_tags(writer, &relation.tags, data)?;
+    }
+    Ok(())
+}
+
+fn save_tile_references(writer: &mut dyn Write, tile_references: &TileIdToReferences) -> Result<()> {
+    let mut tile_ids = Vec::new();
+
     writer.write_u32::<LittleEndian>(to_u32_safe(tile_references.refs.len())?)?;
     for (k, v) in &tile_references.refs {
         writer.write_u32::<LittleEndian>(k.0)?;
         writer.write_u32::<LittleEndian>(k.1)?;
 
-        save_refs(writer, v.local_node_ids.iter(), data)?;
-        save_refs(writer, v.local_way_ids.iter(), data)?;
-        save_refs(writer, v.local_multipolygon_ids.iter(), data)?;
+        let offset = tile_ids.len();
+        for ids in [
+            &v.local_node_ids,
+            &v.local_way_ids,
+            &v.local_multipolygon_ids,
+            &v.local_relation_ids,
+        ] {
+            save_delta_encoded_ids(&mut tile_ids, ids)?;
+        }
+        writer.write_u32::<LittleEndian>(to_u32_safe(offset)?)?;
+        writer.write_u32::<LittleEndian>(to_u32_safe(tile_ids.len() - offset)?)?;
     }
 
+    writer.write_u32::<LittleEndian>(to_u32_safe(tile_ids.len())?)?;
+    writer.write_all(&tile_ids)?;
+    // Keep the ints that follow aligned to 4 bytes.
+    let padding = tile_ids.len().next_multiple_of(mem::size_of::<u32>()) - tile_ids.len();
+    writer.write_all(&[0; 3][..padding])?;
+
     Ok(())
 }
 
+fn save_delta_encoded_ids(output: &mut Vec<u8>, ids: &BTreeSet<usize>) -> Result<()> {
+    save_varint(output, to_u32_safe(ids.len())?);
+    let mut prev_id = 0;
+    for id in ids {
+        save_varint(output, to_u32_safe(id - prev_id)?);
+        prev_id = *id;
+    }
+    Ok(())
+}
+
+fn save_varint(output: &mut Vec<u8>, mut num: u32) {
+    while num >= 0x80 {
+        output.push((num & 0x7F) as u8 | 0x80);
+        num >>= 7;
+    }
+    output.push(num as u8);
+}
+
 fn save_refs<'a, I>(writer: &mut dyn Write, refs: I, data: &mut BufferedData) -> Result<()>
 where
     I: Iterator<Item = &'a usize>,
@@ -121,10 +222,13 @@ where
     Ok(())
 }
 
-fn save_tags(writer: &mut dyn Write, tags: &BTreeMap<String, String>, data: &mut BufferedData) -> Result<()> {
+fn save_tags(writer: &mut dyn Write, tags: &RawTags, data: &mut BufferedData) -> Result<()> {
     let mut kv_refs = RawRefs::new();
 
-    for (k, v) in tags.iter() {
+    // The reader looks up the keys with a binary search.
+    let mut kvs = tags.iter().collect::<Vec<_>>();
+    kvs.sort_unstable_by_key(|(k, _)| data.strings.get(*k));
+    for (k, v) in kvs {
         let (k_offset, k_length) = data.add_string(k);
         let (v_offset, v_length) = data.add_string(v);
         kv_refs.extend([k_offset, k_length, v_offset, v_length].iter());
@@ -135,18 +239,28 @@ fn save_tags(writer: &mut dyn Write, tags: &BTreeMap<String, String>, data: &mut
     Ok(())
 }
 
-#[derive(Default)]
-struct BufferedData {
+struct BufferedData<'s> {
     all_ints: Vec<u32>,
-    string_to_offset: HashMap<String, usize>,
+    strings: &'s StringTable,
+    // Indexed by the ids of the interned strings, `None` if the string isn't saved yet.
+    string_offsets: Vec<Option<usize>>,
     all_strings: Vec<u8>,
 }
 
-impl BufferedData {
-    fn add_string(&mut self, s: &str) -> (usize, usize) {
-        let bytes = s.as_bytes();
+impl<'s> BufferedData<'s> {
+    fn new(strings: &'s StringTable) -> BufferedData<'s> {
+        BufferedData {
+            all_ints: Vec::new(),
+            strings,
+            string_offsets: vec![None; strings.len()],
+            all_strings: Vec::new(),
+        }
+    }
+
+    fn add_string(&mut self, id: StringId) -> (usize, usize) {
+        let bytes = self.strings.get(id).as_bytes();
         let all_strings = &mut self.all_strings;
-        let offset = self.string_to_offset.entry(s.to_string()).or_insert_with(|| {
+        let offset = self.string_offsets[id.index()].get_or_insert_with(|| {
             let offset = all_strings.len();
             all_strings.extend_from_slice(bytes);
             offset
@@ -162,6 +276,19 @@ impl BufferedData {
         writer.write_all(&self.all_strings)?;
         Ok(())
     }
+
+    // The reader has to decompress everything into memory anyway, so we write the uncompressed
+    // size first to let it allocate the buffer upfront.
+    fn save_compressed(&self, writer: &mut dyn Write) -> Result<()> {
+        let uncompressed_size = mem::size_of::<u32>() * (self.all_ints.len() + 1) + self.all_strings.len();
+        writer.write_u64::<LittleEndian>(uncompressed_size as u64)?;
+        let mut uncompressed = Vec::with_capacity(uncompressed_size);
+        self.save(&mut uncompressed)?;
+        let mut encoder = ZlibEncoder::new(writer, Compression::default());
+        encoder.write_all(&uncompressed)?;
+        encoder.finish()?;
+        Ok(())
+    }
 }
 
 fn get_tile_references(entity_storages: &EntityStorages) -> TileIdToReferences {
@@ -173,60 +300,144 @@ fn get_tile_references(entity_storages: &EntityStorages) -> TileIdToReferences {
     }
 
     for (i, way) in entity_storages.way_storage.get_entities().iter().enumerate() {
-        let node_ids = way.node_ids.iter().map(|idx| &nodes[*idx]);
-
-        insert_entity_id_to_tiles(&mut result, node_ids, |x| &mut x.local_way_ids, i);
+        // The same check as in `OsmArea::is_closed()` for the ways the renderer can fill.
+        let is_area = match (way.node_ids.first(), way.node_ids.last()) {
+            (Some(first), Some(last)) if way.node_ids.len() > 2 => {
+                (nodes[*first].lat, nodes[*first].lon) == (nodes[*last].lat, nodes[*last].lon)
+            }
+            _ => false,
+        };
+        let lines = std::iter::once(way.node_ids.as_slice());
+        insert_entity_id_to_tiles(&mut result, lines, nodes, is_area, |x| &mut x.local_way_ids, i);
     }
 
     let polygons = &entity_storages.polygon_storage;
     for (i, multipolygon) in entity_storages.multipolygon_storage.get_entities().iter().enumerate() {
-        let node_ids = multipolygon
+        let lines = multipolygon
             .polygon_ids
             .iter()
-            .flat_map(move |poly_id| polygons[*poly_id].iter())
-            .map(|idx| &nodes[*idx]);
-        insert_entity_id_to_tiles(&mut result, node_ids, |x| &mut x.local_multipolygon_ids, i);
+            .map(|poly_id| polygons[*poly_id].as_slice());
+        insert_entity_id_to_tiles(&mut result, lines, nodes, true, |x| &mut x.local_multipolygon_ids, i);
+    }
+
+    for (i, relation) in entity_storages.relation_storage.get_entities().iter().enumerate() {
+        let lines = relation.line_ids.iter().map(|line_id| polygons[*line_id].as_slice());
+        insert_entity_id_to_tiles(&mut result, lines, nodes, false, |x| &mut x.local_relation_ids, i);
     }
 
     result
 }
 
-fn insert_entity_id_to_tiles<'a, I>(
+// Inserts the entity into the max zoom tiles its lines pass through and, if it's an area, into the tiles inside it.
+// Taking all tiles of the bounding box instead would be simpler, but then a long diagonal way (e.g. a river) would
+// be read for lots of tiles it doesn't even cross.
+fn insert_entity_id_to_tiles<'a>(
     result: &mut TileIdToReferences,
-    mut nodes: I,
+    lines: impl Iterator<Item = &'a [usize]>,
+    nodes: &[RawNode],
+    is_area: bool,
     get_refs: impl Fn(&mut TileReferences) -> &mut BTreeSet<usize>,
     entity_id: usize,
-) where
-    I: Iterator<Item = &'a RawNode>,
-{
-    let first_node = match nodes.next() {
-        Some(n) => n,
-        _ => return,
-    };
+) {
+    let mut tiles = BTreeSet::new();
+    // For each row of tiles, the x coordinates where the lines cross the horizontal line through its center.
+    let mut row_crossings = BTreeMap::new();
+    for line in lines {
+        let points = line.iter().map(|idx| to_tile_space(&nodes[*idx])).collect::<Vec<_>>();
+        match points.as_slice() {
+            [(x, y)] => {
+                tiles.insert((to_tile_index(*x), to_tile_index(*y)));
+            }
+            _ => {
+                for segment in points.windows(2) {
+                    insert_segment_tiles(segment[0], segment[1], &mut tiles);
+                }
+            }
+        }
+        if is_area {
+            // If the ring is not closed in the data, it's still filled as if it was.
+            let closing_segment = points.last().zip(points.first());
+            let segments = points.windows(2).map(|segment| (&segment[0], &segment[1]));
+            for (from, to) in segments.chain(closing_segment) {
+                add_row_crossings(*from, *to, &mut row_crossings);
+            }
+        }
+    }
 
-    let first_tile = tile::coords_to_max_zoom_tile(first_node);
-    let mut tile_range = tile::TileRange {
-        min_x: first_tile.x,
-        max_x: first_tile.x,
-        min_y: first_tile.y,
-        max_y: first_tile.y,
+    // The tiles without any lines are either completely inside the area or completely outside, so checking their
+    // centers is enough. The rings of a multipolygon are filled with the even-odd rule, which leaves out the holes.
+    for (y, mut crossings) in row_crossings {
+        crossings.sort_by(f64::total_cmp);
+        for pair in crossings.chunks_exact(2) {
+            let first_x = (pair[0] - 0.5).ceil().max(0.0) as u32;
+            let last_x = (pair[1] - 0.5).floor();
+            if last_x >= 0.0 {
+                tiles.extend((first_x..=last_x as u32).map(|x| (x, y)));
+            }
+        }
+    }
+
+    for (x, y) in tiles {
+        get_refs(result.tile_ref_by_xy(x, y)).insert(entity_id);
+    }
+}
+
+// The global coordinates of the node measured in max zoom tiles, clamped to the edges of the map like in
+// `tile::tile_at()`.
+fn to_tile_space(node: &RawNode) -> (f64, f64) {
+    let map_size = f64::from(1u32 << tile::MAX_ZOOM);
+    let (x, y) = tile::coords_to_xy(node, tile::MAX_ZOOM);
+    let clamp = |t: f64| (t / f64::from(tile::TILE_SIZE)).max(0.0).min(map_size);
+    (clamp(x), clamp(y))
+}
+
+fn to_tile_index(t: f64) -> u32 {
+    (t as u32).min((1u32 << tile::MAX_ZOOM) - 1)
+}
+
+// Walks the tiles crossed by the segment from one vertical or horizontal tile border to the next one.
+fn insert_segment_tiles(from: (f64, f64), to: (f64, f64), tiles: &mut BTreeSet<(u32, u32)>) {
+    let (mut x, mut y) = (to_tile_index(from.0), to_tile_index(from.1));
+    let (end_x, end_y) = (to_tile_index(to.0), to_tile_index(to.1));
+    tiles.insert((x, y));
+    // The fraction of the segment at which it crosses the next border in each direction.
+    let next_border = |start: f64, delta: f64, index: u32| {
+        let border = if delta > 0.0 { index + 1 } else { index };
+        if delta == 0.0 {
+            f64::INFINITY
+        } else {
+            (f64::from(border) - start) / delta
+        }
     };
-    for node in nodes {
-        let next_tile = tile::coords_to_max_zoom_tile(node);
-        tile_range.min_x = min(tile_range.min_x, next_tile.x);
-        tile_range.max_x = max(tile_range.max_x, next_tile.x);
-        tile_range.min_y = min(tile_range.min_y, next_tile.y);
-        tile_range.max_y = max(tile_range.max_y, next_tile.y);
-    }
-    for x in tile_range.min_x..=tile_range.max_x {
-        for y in tile_range.min_y..=tile_range.max_y {
-            get_refs(result.tile_ref_by_xy(x, y)).insert(entity_id);
+    let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+    let (mut next_x, mut next_y) = (next_border(from.0, dx, x), next_border(from.1, dy, y));
+    // Every step gets closer to the last tile, so that the rounding errors can't make the walk go astray.
+    while (x, y) != (end_x, end_y) {
+        if y == end_y || (x != end_x && next_x < next_y) {
+            x = if end_x > x { x + 1 } else { x - 1 };
+            next_x += 1.0 / dx.abs();
+        } else {
+            y = if end_y > y { y + 1 } else { y - 1 };
+            next_y += 1.0 / dy.abs();
         }
+        tiles.insert((x, y));
+    }
+}
+
+fn add_row_crossings(from: (f64, f64), to: (f64, f64), row_crossings: &mut BTreeMap<u32, Vec<f64>>) {
+    let ((x0, y0), (x1, y1)) = if from.1 <= to.1 { (from, to) } else { (to, from) };
+    // The rows with centers in [y0, y1), so that the vertex shared by two segments is counted once.
+    let first_row = (y0 - 0.5).ceil().max(0.0) as u32;
+    let end_row = (y1 - 0.5).ceil().max(0.0) as u32;
+    for row in first_row..end_row {
+        let center_y = f64::from(row) + 0.5;
+        let x = x0 + (center_y - y0) * (x1 - x0) / (y1 - y0);
+        row_crossings.entry(row).or_default().push(x);
     }
 }
 
 fn to_u32_safe(num: usize) -> Result<u32> {
-    if num > (u32::max_value() as usize) {
+    if num > (u32::MAX as usize) {
         bail!("{} doesn't fit into u32", num);
     }
     Ok(num as u32)
@@ -296,6 +507,7 @@ mod tests {
                 local_node_ids: [idx].iter().cloned().collect(),
                 local_way_ids: BTreeSet::default(),
                 local_multipolygon_ids: BTreeSet::default(),
+                local_relation_ids: BTreeSet::default(),
             });
         }
 
@@ -306,12 +518,15 @@ mod tests {
             let tmp_file = File::create(&tmp_path).unwrap();
             let mut writer = BufWriter::new(tmp_file);
 
-            let mut data = BufferedData::default();
+            let strings = StringTable::default();
+            let mut data = BufferedData::new(&strings);
+            save_header(&mut writer, 0).unwrap();
             save_nodes(&mut writer, &nodes, &mut data).unwrap();
             save_ways(&mut writer, &[], &mut data).unwrap();
             save_polygons(&mut writer, &[], &mut data).unwrap();
             save_multipolygons(&mut writer, &[], &mut data).unwrap();
-            save_tile_references(&mut writer, &tile_refs, &mut data).unwrap();
+            save_relations(&mut writer, &[], &mut data).unwrap();
+            save_tile_references(&mut writer, &tile_refs).unwrap();
             data.save(&mut writer).unwrap();
         }
 
@@ -321,4 +536,96 @@ mod tests {
         reader.get_entities_in_tile(&tile, &mut local_ids);
         assert_eq!(good_node_ids, local_ids.nodes);
     }
+
+    #[test]
+    fn test_tiles_of_lines_and_areas() {
+        let mut segment_tiles = BTreeSet::new();
+        insert_segment_tiles((0.5, 0.5), (3.5, 2.5), &mut segment_tiles);
+        let expected = [(0, 0), (1, 0), (1, 1), (2, 1), (2, 2), (3, 2)];
+        assert_eq!(segment_tiles, expected.into_iter().collect());
+
+        // A square ring of 10x10 tiles with a square hole of 4x4 tiles, far from the edges of the map.
+        let square = |from: f64, to: f64| -> Vec<RawNode> {
+            let corners = [(from, from), (to, from), (to, to), (from, to), (from, from)];
+            let to_node = |(x, y): (f64, f64)| {
+                let pixel = |t: f64| (1000.0 + t) * f64::from(tile::TILE_SIZE);
+                let (lat, lon) = tile::xy_to_coords(pixel(x), pixel(y), tile::MAX_ZOOM);
+                RawNode {
+                    global_id: 0,
+                    lat,
+                    lon,
+                    tags: crate::geodata::importer::RawTags::default(),
+                }
+            };
+            corners.into_iter().map(to_node).collect()
+        };
+        let nodes = [square(0.5, 9.5), square(3.5, 6.5)]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        let (outer, inner) = ((0..5).collect::<Vec<_>>(), (5..10).collect::<Vec<_>>());
+        let tiles = |is_area| {
+            let mut result = TileIdToReferences::default();
+            let lines = [outer.as_slice(), inner.as_slice()].into_iter();
+            insert_entity_id_to_tiles(&mut result, lines, &nodes, is_area, |x| &mut x.local_way_ids, 0);
+            let mut tiles = result
+                .refs
+                .keys()
+                .map(|(x, y)| (x - 1000, y - 1000))
+                .collect::<Vec<_>>();
+            tiles.sort_unstable();
+            tiles
+        };
+
+        let is_border = |(x, y): (u32, u32), from, to| {
+            (from..=to).contains(&x) && (from..=to).contains(&y) && [x, y].iter().any(|t| *t == from || *t == to)
+        };
+        let all_tiles = (0..10).flat_map(|x| (0..10).map(move |y| (x, y)));
+        let borders = all_tiles
+            .clone()
+            .filter(|tile| is_border(*tile, 0, 9) || is_border(*tile, 3, 6))
+            .collect::<Vec<_>>();
+        assert_eq!(tiles(false), borders);
+        let hole = [(4, 4), (4, 5), (5, 4), (5, 5)];
+        let area = all_tiles.filter(|tile| !hole.contains(tile)).collect::<Vec<_>>();
+        assert_eq!(tiles(true), area);
+    }
+
+    #[test]
+    fn test_compressed_data() {
+        use crate::geodata::importer::{import_with_options, ImportOptions};
+        use crate::geodata::reader::{GeodataReader, OsmEntity};
+
+        let input = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/osm/nano_moscow.osm");
+        let load_tile = |compress| {
+            let mut output = env::temp_dir();
+            output.push(format!("osm_renderer_compressed_test_{}.bin", compress));
+            let options = ImportOptions {
+                compress,
+                ..Default::default()
+            };
+            import_with_options(&input, &output, &options).unwrap();
+
+            let reader = GeodataReader::load(output.to_str().unwrap()).unwrap();
+            let tile = crate::tile::Tile {
+                zoom: 16,
+                x: 39_614,
+                y: 20_486,
+            };
+            let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+            entities
+                .ways
+                .iter()
+                .map(|w| {
+                    let tags = w.tags().to_map();
+                    let tags = tags.into_iter().map(|(k, v)| (k.to_string(), v.to_string()));
+                    (w.global_id(), w.node_count(), tags.collect::<Vec<_>>())
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let uncompressed = load_tile(false);
+        assert!(!uncompressed.is_empty());
+        assert_eq!(uncompressed, load_tile(true));
+    }
 }
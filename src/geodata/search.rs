@@ -0,0 +1,217 @@
+use crate::coords::Coords;
+use crate::geodata::reader::{GeodataReader, Node, OsmEntity};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+// An entity with a matching name and the center of its bounding box, which is good enough to show it on the map.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchResult {
+    // "node", "way", "multipolygon" or "relation".
+    pub kind: &'static str,
+    pub id: u64,
+    pub name: String,
+    pub lat: f64,
+    pub lon: f64,
+}
+
+// All `name` and `name:*` tags of the geodata, searchable by the prefixes of their words. Everything is copied out
+// of the geodata files, so the index stays valid after they are reloaded (but doesn't see the new data).
+#[derive(Default)]
+pub struct NameIndex {
+    entries: Vec<IndexEntry>,
+    // Every word of every name, with the indices of the entries that have it.
+    words: BTreeMap<String, Vec<usize>>,
+}
+
+struct IndexEntry {
+    result: SearchResult,
+    words: Vec<String>,
+}
+
+impl NameIndex {
+    pub fn build(readers: &[GeodataReader<'_>]) -> NameIndex {
+        let mut index = NameIndex::default();
+        // The entities that are present in several files (see `OsmEntities::merge()`) are indexed only once.
+        let mut seen = HashSet::new();
+        for reader in readers {
+            let mut add = |kind, entity: &dyn OsmEntity<'_>, nodes: &mut dyn Iterator<Item = Node<'_>>| {
+                if seen.contains(&(kind, entity.global_id())) {
+                    return;
+                }
+                let tags = entity.tags();
+                let names = (&tags)
+                    .into_iter()
+                    .filter(|(k, _)| *k == "name" || k.starts_with("name:"))
+                    .collect::<Vec<_>>();
+                if names.is_empty() {
+                    return;
+                }
+                let Some((lat, lon)) = bbox_center(nodes) else {
+                    return;
+                };
+                seen.insert((kind, entity.global_id()));
+                for (_, name) in names {
+                    index.add(SearchResult {
+                        kind,
+                        id: entity.global_id(),
+                        name: name.to_string(),
+                        lat,
+                        lon,
+                    });
+                }
+            };
+
+            for node in reader.nodes() {
+                add("node", &node, &mut std::iter::once(node.clone()));
+            }
+            for way in reader.ways() {
+                add("way", &way, &mut (0..way.node_count()).map(|idx| way.get_node(idx)));
+            }
+            for multipolygon in reader.multipolygons() {
+                let mut nodes = (0..multipolygon.polygon_count()).flat_map(|idx| {
+                    let polygon = multipolygon.get_polygon(idx);
+                    (0..polygon.node_count()).map(move |idx| polygon.get_node(idx))
+                });
+                add("multipolygon", &multipolygon, &mut nodes);
+            }
+            for relation in reader.relations() {
+                let mut nodes = (0..relation.line_count()).flat_map(|idx| {
+                    let line = relation.get_line(idx);
+                    (0..line.node_count()).map(move |idx| line.get_node(idx))
+                });
+                add("relation", &relation, &mut nodes);
+            }
+        }
+        index
+    }
+
+    // The number of indexed names (an entity can have several, e.g. in different languages).
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Finds the entities with a name in which every word of the query starts some word, ignoring the case and the
+    // punctuation. The exact matches come first, then the names starting with the query, then the shorter names.
+    pub fn search(&self, query: &str, limit: usize) -> Vec<SearchResult> {
+        let query_words = split_into_words(query);
+        let Some((first_word, other_words)) = query_words.split_first() else {
+            return Vec::new();
+        };
+
+        let mut candidates = self
+            .words
+            .range(first_word.clone()..)
+            .take_while(|(word, _)| word.starts_with(first_word.as_str()))
+            .flat_map(|(_, entry_ids)| entry_ids.iter().copied())
+            .collect::<BTreeSet<_>>();
+        candidates.retain(|idx| {
+            let words = &self.entries[*idx].words;
+            other_words
+                .iter()
+                .all(|q| words.iter().any(|w| w.starts_with(q.as_str())))
+        });
+
+        let rank = |entry: &IndexEntry| {
+            let starts_with_query = entry.words.len() >= query_words.len()
+                && query_words
+                    .iter()
+                    .zip(&entry.words)
+                    .all(|(q, w)| w.starts_with(q.as_str()));
+            match (entry.words == query_words, starts_with_query) {
+                (true, _) => 0,
+                (false, true) => 1,
+                (false, false) => 2,
+            }
+        };
+        let mut matches = candidates.into_iter().map(|idx| &self.entries[idx]).collect::<Vec<_>>();
+        matches.sort_by_key(|entry| (rank(entry), entry.result.name.chars().count(), &entry.result.name));
+
+        // An entity can match with several names, and only the best one is kept.
+        let mut found = HashSet::new();
+        matches
+            .into_iter()
+            .filter(|entry| found.insert((entry.result.kind, entry.result.id)))
+            .take(limit)
+            .map(|entry| entry.result.clone())
+            .collect()
+    }
+
+    fn add(&mut self, result: SearchResult) {
+        let words = split_into_words(&result.name);
+        let entry_id = self.entries.len();
+        for word in words.iter().collect::<BTreeSet<_>>() {
+            self.words.entry(word.clone()).or_default().push(entry_id);
+        }
+        self.entries.push(IndexEntry { result, words });
+    }
+}
+
+fn split_into_words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+fn bbox_center<'a>(nodes: &mut dyn Iterator<Item = Node<'a>>) -> Option<(f64, f64)> {
+    let mut bbox: Option<(f64, f64, f64, f64)> = None;
+    for node in nodes {
+        let (lat, lon) = (node.lat(), node.lon());
+        bbox = Some(match bbox {
+            Some((min_lat, min_lon, max_lat, max_lon)) => {
+                (min_lat.min(lat), min_lon.min(lon), max_lat.max(lat), max_lon.max(lon))
+            }
+            None => (lat, lon, lat, lon),
+        });
+    }
+    bbox.map(|(min_lat, min_lon, max_lat, max_lon)| ((min_lat + max_lat) / 2.0, (min_lon + max_lon) / 2.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index_of(names: &[&str]) -> NameIndex {
+        let mut index = NameIndex::default();
+        for (id, name) in names.iter().enumerate() {
+            index.add(SearchResult {
+                kind: "node",
+                id: id as u64,
+                name: name.to_string(),
+                lat: 0.0,
+                lon: 0.0,
+            });
+        }
+        index
+    }
+
+    #[test]
+    fn test_search() {
+        let index = index_of(&[
+            "Большой Кремлёвский сквер",
+            "Кремль",
+            "Кремлёвская набережная",
+            "Red Square",
+            "Square Café",
+        ]);
+        let names = |query, limit| {
+            let results = index.search(query, limit);
+            results.into_iter().map(|r| r.name).collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            names("кремл", 10),
+            ["Кремль", "Кремлёвская набережная", "Большой Кремлёвский сквер"]
+        );
+        assert_eq!(names("КРЕМЛЬ", 10), ["Кремль"]);
+        assert_eq!(names("кремл", 1), ["Кремль"]);
+        assert_eq!(names("сквер кремл", 10), ["Большой Кремлёвский сквер"]);
+        assert_eq!(names("square", 10), ["Square Café", "Red Square"]);
+        assert_eq!(names("red-squ", 10), ["Red Square"]);
+        assert_eq!(names("quare", 10), Vec::<String>::new());
+        assert_eq!(names(" ,", 10), Vec::<String>::new());
+    }
+}
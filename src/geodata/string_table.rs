@@ -0,0 +1,61 @@
+use indexmap::IndexSet;
+
+// The tag keys and values of all imported entities. Every distinct string is stored once, and the entities refer to
+// it by its index, since the same keys and values (like `highway` or `residential`) are repeated millions of times
+// in the large files.
+#[derive(Default)]
+pub(super) struct StringTable {
+    strings: IndexSet<Box<str>>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub(super) struct StringId(u32);
+
+impl StringTable {
+    pub(super) fn intern(&mut self, s: &str) -> StringId {
+        let idx = match self.strings.get_index_of(s) {
+            Some(idx) => idx,
+            None => self.strings.insert_full(s.into()).0,
+        };
+        StringId(u32::try_from(idx).expect("Too many distinct strings in the tags"))
+    }
+
+    // Unlike `intern()`, doesn't add the string to the table, so the lookups of missing strings don't grow it.
+    pub(super) fn find(&self, s: &str) -> Option<StringId> {
+        self.strings.get_index_of(s).map(|idx| StringId(idx as u32))
+    }
+
+    pub(super) fn get(&self, id: StringId) -> &str {
+        &self.strings[id.index()]
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.strings.len()
+    }
+}
+
+impl StringId {
+    // The ids go from 0 to `StringTable::len() - 1`.
+    pub(super) fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_string_table() {
+        let mut strings = StringTable::default();
+        let highway = strings.intern("highway");
+        let residential = strings.intern("residential");
+        assert_eq!(strings.intern("highway"), highway);
+        assert_ne!(highway, residential);
+        assert_eq!(strings.len(), 2);
+        assert_eq!(strings.get(residential), "residential");
+        assert_eq!(strings.find("residential"), Some(residential));
+        assert_eq!(strings.find("primary"), None);
+        assert_eq!(strings.len(), 2);
+    }
+}
@@ -0,0 +1,136 @@
+use crate::mapcss::parser::{parse_file, PropertyValue, Rule, Test};
+use crate::mapcss::styler::area_tag_keys;
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::Path;
+
+// Properties whose values are tag keys rather than literal values.
+const TAG_KEY_PROPERTIES: [&str; 1] = ["text"];
+
+// The styler looks at these (and at the keys from `area_tag_keys()`) even if no selector mentions them.
+const ALWAYS_KEPT_KEYS: [&str; 1] = ["layer"];
+
+#[derive(Default)]
+pub struct TagFilter {
+    keys: HashSet<String>,
+    prefixes: Vec<String>,
+}
+
+impl TagFilter {
+    // Reads either a list of keys (one per line, `#` starts a comment, `prefix*` keeps
+    // all keys starting with `prefix`), or a MapCSS stylesheet with `.mapcss` extension,
+    // in which case all keys referenced by the stylesheet are kept.
+    pub fn from_file(file_name: &Path) -> Result<TagFilter> {
+        if file_name.extension().and_then(OsStr::to_str) == Some("mapcss") {
+            let base_path = file_name.parent().unwrap_or_else(|| Path::new(""));
+            let stylesheet_name = file_name.file_name().and_then(OsStr::to_str).unwrap_or_default();
+            let rules = parse_file(base_path, stylesheet_name)?;
+            return Ok(TagFilter::from_stylesheet(&rules));
+        }
+
+        let content = fs::read_to_string(file_name)
+            .context(format!("Failed to read tag list from {}", file_name.to_string_lossy()))?;
+        let mut filter = TagFilter::default();
+        for line in content.lines() {
+            let key = line.split('#').next().unwrap_or_default().trim();
+            if !key.is_empty() {
+                filter.add_key(key);
+            }
+        }
+        Ok(filter)
+    }
+
+    pub fn from_stylesheet(rules: &[Rule]) -> TagFilter {
+        let mut filter = TagFilter::default();
+        for key in ALWAYS_KEPT_KEYS.into_iter().chain(area_tag_keys()) {
+            filter.add_key(key);
+        }
+        for rule in rules {
+            for test in rule.selectors.iter().flat_map(|s| s.tests.iter()) {
+                let tag_name = match test {
+                    Test::Unary { tag_name, .. } => tag_name,
+                    Test::BinaryStringCompare { tag_name, .. } => tag_name,
+                    Test::BinaryNumericCompare { tag_name, .. } => tag_name,
+                    Test::Id { .. } => continue,
+                };
+                filter.add_key(tag_name);
+            }
+            for prop in &rule.properties {
+                let is_tag_key = TAG_KEY_PROPERTIES.contains(&prop.name.as_str());
+                match prop.value {
+                    PropertyValue::Identifier(ref key) | PropertyValue::String(ref key) if is_tag_key => {
+                        filter.add_label_key(key);
+                    }
+                    PropertyValue::Identifiers(ref keys) if is_tag_key => {
+                        for key in keys {
+                            filter.add_label_key(key);
+                        }
+                    }
+                    PropertyValue::TagValue(ref key) => filter.add_key(key),
+                    _ => {}
+                }
+            }
+        }
+        filter
+    }
+
+    pub fn keeps(&self, key: &str) -> bool {
+        self.keys.contains(key) || self.prefixes.iter().any(|p| key.starts_with(p.as_str()))
+    }
+
+    fn add_label_key(&mut self, key: &str) {
+        self.add_key(key);
+        // The renderer may be configured to prefer the labels in other languages.
+        self.add_key(&format!("{}:*", key));
+    }
+
+    fn add_key(&mut self, key: &str) {
+        match key.strip_suffix('*') {
+            Some(prefix) => self.prefixes.push(prefix.to_string()),
+            None => {
+                self.keys.insert(key.to_string());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapcss::parser::{ObjectType, Property, Selector};
+
+    #[test]
+    fn test_keys_from_stylesheet() {
+        let stylesheet = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/mapcss/mapnik.mapcss");
+        let filter = TagFilter::from_file(&stylesheet).unwrap();
+        for key in &["highway", "building", "name", "name:en", "layer"] {
+            assert!(filter.keeps(key), "{} should be kept", key);
+        }
+        for key in &["created_by", "source", "tiger:county"] {
+            assert!(!filter.keeps(key), "{} should be removed", key);
+        }
+    }
+
+    #[test]
+    fn test_text_fallback_keys() {
+        let text = PropertyValue::Identifiers(vec!["name".to_string(), "ref".to_string()]);
+        let rule = Rule::new(vec![Selector::new(ObjectType::Node)], vec![Property::new("text", text)]);
+        let filter = TagFilter::from_stylesheet(&[rule]);
+        for key in &["name", "name:en", "ref", "ref:en"] {
+            assert!(filter.keeps(key), "{} should be kept", key);
+        }
+        assert!(!filter.keeps("operator"));
+    }
+
+    #[test]
+    fn test_prefixes() {
+        let mut filter = TagFilter::default();
+        filter.add_key("name");
+        filter.add_key("addr:*");
+        assert!(filter.keeps("name"));
+        assert!(filter.keeps("addr:street"));
+        assert!(!filter.keeps("name:en"));
+    }
+}
@@ -0,0 +1,81 @@
+use crate::tile::MAX_ZOOM;
+use anyhow::{bail, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+const MAGIC: &[u8; 4] = b"OSMZ";
+
+// For every entity of a geodata file (in the order in which they are stored), a bit mask of the zoom levels
+// at which the stylesheet draws anything for it. Entities that aren't drawn at the zoom level of a tile are
+// skipped before styling, which mostly helps with the nodes that are only used as parts of ways.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ZoomMasks {
+    // Identifies the stylesheet the masks were computed for (see `stylesheet_hash()`).
+    pub stylesheet_hash: u64,
+    pub nodes: Vec<u32>,
+    pub ways: Vec<u32>,
+    pub multipolygons: Vec<u32>,
+    pub relations: Vec<u32>,
+}
+
+impl ZoomMasks {
+    pub fn load(file_name: &str) -> Result<ZoomMasks> {
+        let file = File::open(file_name).context(format!("Failed to open {}", file_name))?;
+        ZoomMasks::read(&mut BufReader::new(file)).context(format!("Failed to read zoom masks from {}", file_name))
+    }
+
+    pub fn save(&self, file_name: &str) -> Result<()> {
+        let file = File::create(file_name).context(format!("Failed to create {}", file_name))?;
+        let mut writer = BufWriter::new(file);
+        self.write(&mut writer)
+            .and_then(|_| Ok(writer.flush()?))
+            .context(format!("Failed to write zoom masks to {}", file_name))
+    }
+
+    // Only the zoom levels up to `MAX_ZOOM` are covered, everything is drawn at the others.
+    pub fn is_drawn(masks: &[u32], idx: usize, zoom: u8) -> bool {
+        zoom > MAX_ZOOM || masks.get(idx).is_none_or(|mask| mask & (1 << zoom) != 0)
+    }
+
+    fn read(reader: &mut dyn Read) -> Result<ZoomMasks> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            bail!("Not a zoom mask file");
+        }
+        let stylesheet_hash = reader.read_u64::<LittleEndian>()?;
+        let mut read_masks = || -> Result<Vec<u32>> {
+            let count = reader.read_u32::<LittleEndian>()? as usize;
+            let mut masks = vec![0; count];
+            reader.read_u32_into::<LittleEndian>(&mut masks)?;
+            Ok(masks)
+        };
+        Ok(ZoomMasks {
+            stylesheet_hash,
+            nodes: read_masks()?,
+            ways: read_masks()?,
+            multipolygons: read_masks()?,
+            relations: read_masks()?,
+        })
+    }
+
+    fn write(&self, writer: &mut dyn Write) -> Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_u64::<LittleEndian>(self.stylesheet_hash)?;
+        for masks in [&self.nodes, &self.ways, &self.multipolygons, &self.relations] {
+            writer.write_u32::<LittleEndian>(masks.len() as u32)?;
+            for mask in masks {
+                writer.write_u32::<LittleEndian>(*mask)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// FNV-1a, which, unlike `DefaultHasher`, gives the same result on every platform and Rust version.
+pub fn stylesheet_hash(stylesheet: &str) -> u64 {
+    stylesheet.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
@@ -1,208 +1,869 @@
-use crate::draw::drawer::Drawer;
-use crate::draw::tile_pixels::TilePixels;
-use crate::geodata::reader::GeodataReader;
-use crate::mapcss::parser::parse_file;
-use crate::mapcss::styler::{StyleType, Styler};
-use crate::perf_stats::PerfStats;
-use crate::tile::{Tile, MAX_ZOOM};
-use anyhow::{anyhow, bail, Context, Result};
-use std::collections::HashSet;
+use crate::draw::drawer::{DeadlineExceeded, DebugOverlays};
+use crate::geodata::search::{NameIndex, SearchResult};
+use crate::perf_stats::{escape_json, PerfStats};
+use crate::render::{extent_zoom, EntityFilter, PixelRegion, QueriedEntity, RenderOptions, TileRenderer};
+use crate::tile::{LatLonBounds, Projection, Tile, MAX_ZOOM, TILE_SIZE};
+use anyhow::{bail, Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::io::prelude::*;
 use std::io::BufReader;
 use std::net::{TcpListener, TcpStream};
-use std::path::PathBuf;
 use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, Sender};
-use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::mpsc::{Receiver, TrySendError};
+use std::sync::{Mutex, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
+
+pub struct ServerOptions {
+    // The number of rendering threads, one per CPU if not set.
+    pub threads: Option<usize>,
+    // Pin every rendering thread to its own CPU (only on Linux), so that it keeps its caches warm.
+    pub pin_threads: bool,
+    // How many requests can wait for a free rendering thread before we start rejecting new ones with 503.
+    pub queue_size: usize,
+    // Requests that can't be served in this time (including the time spent in the queue) get 503.
+    pub timeout: Option<Duration>,
+    // Add an `X-Render-Time-Ms` header with the time it took to render the tile to every tile response.
+    pub render_time_header: bool,
+    // Print a line with the status, size and duration of every request to stderr.
+    pub request_log: bool,
+    // Print the durations of all rendering stages for the tiles that take longer than this.
+    pub slow_tile_threshold: Option<Duration>,
+    // The value of the `Access-Control-Allow-Origin` header, which lets the pages from these origins use the tiles.
+    pub allow_origin: String,
+    // Answer the requests for the tiles without any geodata in or around them with 404 instead of blank tiles.
+    pub not_found_outside_data: bool,
+    // Where the clients reach the server, for the tile URLs in `/tilejson.json` (`http://<address>` by default).
+    pub public_url: Option<String>,
+    // Only serve the paths under this prefix (e.g. `/tiles`), with the prefix removed, for running behind a reverse
+    // proxy that doesn't strip it. Empty by default.
+    pub base_path: String,
+    // Log the client address from the `X-Forwarded-For` header set by a reverse proxy instead of the proxy address.
+    pub trust_forwarded_for: bool,
+    // Serve a page with a Leaflet map of the tiles at `/`.
+    pub preview_page: bool,
+    // Index the names of the entities at startup and answer `/search` requests.
+    pub search: bool,
+}
+
+impl Default for ServerOptions {
+    fn default() -> ServerOptions {
+        ServerOptions {
+            threads: None,
+            pin_threads: false,
+            queue_size: 64,
+            timeout: None,
+            render_time_header: false,
+            request_log: true,
+            slow_tile_threshold: None,
+            allow_origin: "*".to_string(),
+            not_found_outside_data: false,
+            public_url: None,
+            base_path: String::new(),
+            trust_forwarded_for: false,
+            preview_page: true,
+            search: false,
+        }
+    }
+}
 
 enum HandlerMessage {
     Terminate,
-    ServeTile { path: String, stream: TcpStream },
+    // `client` is only used for logging.
+    Serve {
+        path: String,
+        client: String,
+        received_at: Instant,
+    },
 }
 
-struct HandlerState {
-    current_scale: usize,
-    current_pixels: Box<TilePixels>,
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Method {
+    Get,
+    // Same as GET, but only the headers are sent.
+    Head,
+    // A CORS preflight request, which is answered right away.
+    Options,
 }
 
-#[cfg_attr(feature = "cargo-clippy", allow(clippy::implicit_hasher))]
-pub fn run_server(
-    address: &str,
-    geodata_file: &str,
-    stylesheet_file: &str,
-    stylesheet_type: &StyleType,
-    font_size_multiplier: Option<f64>,
-    osm_ids: Option<HashSet<u64>>,
-) -> Result<()> {
-    let (base_path, file_name) = split_stylesheet_path(stylesheet_file)?;
-    let rules = parse_file(&base_path, &file_name).context("Failed to parse the stylesheet file")?;
-
-    let server = Arc::new(HttpServer {
-        styler: Styler::new(rules, stylesheet_type, font_size_multiplier),
-        reader: GeodataReader::load(geodata_file).context("Failed to load the geodata file")?,
-        drawer: Drawer::new(&base_path),
-        osm_ids,
-        perf_stats: Mutex::new(PerfStats::default()),
-    });
+enum Response {
+    Data {
+        data: Vec<u8>,
+        content_type: &'static str,
+        render_time: Option<Duration>,
+    },
+    // Sent with a small JSON body explaining what went wrong.
+    Error {
+        status: &'static str,
+        message: String,
+    },
+    ServiceUnavailable,
+}
 
-    let thread_count =
-        thread::available_parallelism().context("Failed to determine the number of threads to use for rendering")?;
+// Returned (wrapped in anyhow::Error) when something is wrong with the request itself. Unlike the other errors, these
+// are reported to the client.
+#[derive(Debug)]
+struct RequestError {
+    status: &'static str,
+    message: String,
+}
 
-    let mut senders: Vec<Sender<HandlerMessage>> = Vec::new();
-    let mut receivers: Vec<Receiver<HandlerMessage>> = Vec::new();
+impl RequestError {
+    fn bad_request(message: String) -> anyhow::Error {
+        RequestError {
+            status: "400 Bad Request",
+            message,
+        }
+        .into()
+    }
 
-    for _ in 0..thread_count.into() {
-        let (tx, rx) = mpsc::channel();
-        senders.push(tx);
-        receivers.push(rx);
+    fn not_found(message: String) -> anyhow::Error {
+        RequestError {
+            status: "404 Not Found",
+            message,
+        }
+        .into()
     }
+}
 
-    let mut handlers = Vec::new();
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
 
-    for receiver in receivers {
-        let server_ref = Arc::clone(&server);
-        handlers.push(thread::spawn(move || {
-            let initial_scale = 1;
+impl std::error::Error for RequestError {}
 
-            let mut handler_state = HandlerState {
-                current_scale: initial_scale,
-                current_pixels: Box::new(TilePixels::new(initial_scale)),
-            };
+// All connections waiting for a given path, with the methods of their requests. Identical requests that arrive
+// while the path is being rendered are attached to the existing entry instead of being rendered again.
+type InFlightRequests = Mutex<HashMap<String, Vec<(TcpStream, Method)>>>;
 
-            while let Ok(msg) = receiver.recv() {
-                match msg {
-                    HandlerMessage::Terminate => break,
-                    HandlerMessage::ServeTile { path, stream } => {
-                        server_ref.handle_connection(&path, stream, &mut handler_state)
-                    }
-                }
-            }
-        }));
+// The name of the stylesheet of `renderer` in the requests when there are other ones.
+pub const MAIN_STYLE_NAME: &str = "default";
+
+pub fn run_server(address: &str, renderer: TileRenderer<'_>, options: &ServerOptions) -> Result<()> {
+    run_server_with_styles(address, renderer, Vec::new(), options)
+}
+
+// Also serves the tiles with other stylesheets by their names (see `HttpServer::select_style()`), e.g. to compare
+// the versions of a stylesheet at `/compare`.
+pub fn run_server_with_styles<'a>(
+    address: &str,
+    renderer: TileRenderer<'a>,
+    styles: Vec<(String, TileRenderer<'a>)>,
+    options: &ServerOptions,
+) -> Result<()> {
+    for (idx, (name, _)) in styles.iter().enumerate() {
+        validate_style_name(name)?;
+        if styles[..idx].iter().any(|(other_name, _)| other_name == name) {
+            bail!("Duplicate style name: {}", name);
+        }
+    }
+
+    let base_path = options.base_path.trim_end_matches('/').to_string();
+    let mut server = HttpServer {
+        renderer,
+        styles,
+        perf_stats: Mutex::new(PerfStats::default()),
+        in_flight: Mutex::new(HashMap::new()),
+        timeout: options.timeout,
+        render_time_header: options.render_time_header,
+        request_log: options.request_log,
+        slow_tile_threshold: options.slow_tile_threshold,
+        allow_origin: options.allow_origin.clone(),
+        not_found_outside_data: options.not_found_outside_data,
+        public_url: match &options.public_url {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None => format!("http://{}{}", address, base_path),
+        },
+        // The page is served by the same server, so it can request the tiles without the host unless the clients
+        // reach them at a different URL.
+        preview_tile_url: options.preview_page.then(|| match &options.public_url {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None => base_path.clone(),
+        }),
+        base_path,
+        search_index: None,
+    };
+    if options.search {
+        server.search_index = Some(RwLock::new(server.build_search_index()));
     }
 
+    let thread_count: usize = match options.threads {
+        Some(threads) => threads,
+        None => thread::available_parallelism()
+            .context("Failed to determine the number of threads to use for rendering")?
+            .into(),
+    };
+
+    let (sender, receiver) = mpsc::sync_channel(options.queue_size);
+    let receiver = Mutex::new(receiver);
+
     let tcp_listener = TcpListener::bind(address).context(format!("Failed to bind to {}", address))?;
-    let mut thread_id = 0;
 
-    for mut stream in tcp_listener.incoming().flatten() {
-        let path = match extract_path_from_stream(&mut stream) {
-            Ok(path) => path,
-            Err(e) => {
-                eprintln!("{} didn't send a valid HTTP request: {}", peer_addr(&stream), e);
+    thread::scope(|scope| {
+        // All threads take the requests from the same queue, so a slow tile never delays the requests behind it
+        // while other threads are free.
+        for thread_idx in 0..thread_count {
+            let (server, receiver) = (&server, &receiver);
+            scope.spawn(move || {
+                if options.pin_threads {
+                    if let Err(e) = pin_current_thread(thread_idx) {
+                        eprintln!("Failed to pin rendering thread #{} to a CPU: {:#}", thread_idx, e);
+                    }
+                }
+                server.run_handler(receiver)
+            });
+        }
+
+        for mut stream in tcp_listener.incoming().flatten() {
+            let received_at = Instant::now();
+            let request = match extract_request_from_stream(&mut stream) {
+                Ok(request) => request,
+                Err(e) => {
+                    eprintln!("{} didn't send a valid HTTP request: {}", peer_addr(&stream), e);
+                    let response = Response::Error {
+                        status: "400 Bad Request",
+                        message: e.to_string(),
+                    };
+                    serve_response(&mut stream, Method::Get, &response, &server.allow_origin);
+                    continue;
+                }
+            };
+            let method = request.method;
+            let client = match request.forwarded_for {
+                Some(forwarded_for) if options.trust_forwarded_for => forwarded_for,
+                _ => peer_addr(&stream),
+            };
+            let Some(path) = strip_base_path(&request.path, &server.base_path) else {
+                let response = Response::Error {
+                    status: "404 Not Found",
+                    message: format!("Only the paths under {} are served", server.base_path),
+                };
+                serve_response(&mut stream, method, &response, &server.allow_origin);
+                server.log_request(&request.path, &client, "404", 0, 1, received_at);
+                continue;
+            };
+
+            if method == Method::Options {
+                serve_preflight(&mut stream, &server.allow_origin);
+                server.log_request(&path, &client, "204", 0, 1, received_at);
                 continue;
             }
-        };
 
-        if path == "/shutdown" {
-            eprintln!("Shutting down due to a shutdown request");
-            for sender in senders {
-                sender.send(HandlerMessage::Terminate).unwrap();
+            if path == "/shutdown" {
+                eprintln!("Shutting down due to a shutdown request");
+                for _ in 0..thread_count {
+                    sender.send(HandlerMessage::Terminate).unwrap();
+                }
+                break;
             }
-            break;
-        }
 
-        senders[thread_id]
-            .send(HandlerMessage::ServeTile { path, stream })
-            .unwrap();
-        thread_id = (thread_id + 1) % senders.len();
-    }
+            let mut in_flight = server.in_flight.lock().unwrap();
+            if let Some(streams) = in_flight.get_mut(&path) {
+                streams.push((stream, method));
+                continue;
+            }
 
-    for h in handlers {
-        h.join().unwrap();
-    }
+            let message = HandlerMessage::Serve {
+                path: path.clone(),
+                client: client.clone(),
+                received_at,
+            };
+            match sender.try_send(message) {
+                Ok(_) => {
+                    in_flight.insert(path, vec![(stream, method)]);
+                }
+                Err(TrySendError::Full(_)) => {
+                    drop(in_flight);
+                    eprintln!("Rejecting <{}> from {}: too many pending requests", path, client);
+                    serve_response(&mut stream, method, &Response::ServiceUnavailable, &server.allow_origin);
+                }
+                Err(TrySendError::Disconnected(_)) => unreachable!("The handlers outlive the listener"),
+            }
+        }
+    });
 
     Ok(())
 }
 
 struct HttpServer<'a> {
-    styler: Styler,
-    reader: GeodataReader<'a>,
-    drawer: Drawer,
-    osm_ids: Option<HashSet<u64>>,
+    renderer: TileRenderer<'a>,
+    // The other stylesheets by their names.
+    styles: Vec<(String, TileRenderer<'a>)>,
     perf_stats: Mutex<PerfStats>,
+    in_flight: InFlightRequests,
+    timeout: Option<Duration>,
+    render_time_header: bool,
+    request_log: bool,
+    slow_tile_threshold: Option<Duration>,
+    allow_origin: String,
+    not_found_outside_data: bool,
+    public_url: String,
+    // Without the trailing slash, empty if all paths are served.
+    base_path: String,
+    // The tile URL prefix for the preview page, or None if it's disabled.
+    preview_tile_url: Option<String>,
+    // None if the search is not enabled.
+    search_index: Option<RwLock<NameIndex>>,
 }
 
 impl<'a> HttpServer<'a> {
-    fn handle_connection(&self, path: &str, mut stream: TcpStream, state: &mut HandlerState) {
-        match self.try_handle_connection(path, &mut stream, state) {
-            Ok(_) => {}
-            Err(e) => eprintln!("Error processing request from {}: {}", peer_addr(&stream), e),
+    fn run_handler(&self, receiver: &Mutex<Receiver<HandlerMessage>>) {
+        loop {
+            let message = receiver.lock().unwrap().recv();
+            match message {
+                Ok(HandlerMessage::Serve {
+                    path,
+                    client,
+                    received_at,
+                }) => self.handle_request(&path, &client, received_at),
+                Ok(HandlerMessage::Terminate) | Err(_) => break,
+            }
         }
     }
 
-    fn try_handle_connection(&self, path: &str, stream: &mut TcpStream, state: &mut HandlerState) -> Result<()> {
-        if cfg!(feature = "perf-stats") && path == "/perf_stats" {
-            let perf_stats_html = self.perf_stats.lock().unwrap().to_html();
-            serve_data(stream, perf_stats_html.as_bytes(), "text/html");
-            return Ok(());
+    fn handle_request(&self, path: &str, client: &str, received_at: Instant) {
+        let deadline = self.timeout.map(|timeout| received_at + timeout);
+        let response = match self.try_handle_request(path, deadline) {
+            Ok(response) => response,
+            Err(e) if e.is::<DeadlineExceeded>() => {
+                eprintln!("Timed out while processing <{}>", path);
+                Response::ServiceUnavailable
+            }
+            Err(e) => match e.downcast::<RequestError>() {
+                Ok(e) => Response::Error {
+                    status: e.status,
+                    message: e.message,
+                },
+                Err(e) => {
+                    eprintln!("Error processing <{}>: {:#}", path, e);
+                    // Only the outermost context goes to the client, the details are for the logs.
+                    Response::Error {
+                        status: "500 Internal Server Error",
+                        message: e.to_string(),
+                    }
+                }
+            },
+        };
+
+        let streams = self.in_flight.lock().unwrap().remove(path).unwrap_or_default();
+        let client_count = streams.len();
+        for (mut stream, method) in streams {
+            serve_response(&mut stream, method, &response, &self.allow_origin);
+        }
+
+        let (status, size) = match response {
+            Response::Data { ref data, .. } => ("200", data.len()),
+            Response::Error { status, .. } => (&status[..3], 0),
+            Response::ServiceUnavailable => ("503", 0),
+        };
+        self.log_request(path, client, status, size, client_count, received_at);
+    }
+
+    // Only the first client is logged if the response was shared with others.
+    fn log_request(
+        &self,
+        path: &str,
+        client: &str,
+        status: &str,
+        size: usize,
+        client_count: usize,
+        received_at: Instant,
+    ) {
+        if !self.request_log {
+            return;
         }
+        let shared = if client_count > 1 {
+            format!(" (sent to {} clients)", client_count)
+        } else {
+            String::new()
+        };
+        eprintln!(
+            "{} <{}> {} {} bytes in {:.1} ms{}",
+            client,
+            path,
+            status,
+            size,
+            received_at.elapsed().as_secs_f64() * 1e3,
+            shared
+        );
+    }
+
+    fn build_search_index(&self) -> NameIndex {
+        let start = Instant::now();
+        let index = NameIndex::build(&self.renderer.readers());
+        eprintln!(
+            "Indexed {} names for the search in {:.1} s",
+            index.len(),
+            start.elapsed().as_secs_f64()
+        );
+        index
+    }
+
+    fn all_renderers(&self) -> impl Iterator<Item = &TileRenderer<'a>> {
+        std::iter::once(&self.renderer).chain(self.styles.iter().map(|(_, renderer)| renderer))
+    }
+
+    // The name is None for the main stylesheet.
+    fn find_style(&self, name: &str) -> Result<(Option<&str>, &TileRenderer<'a>)> {
+        if name == MAIN_STYLE_NAME {
+            return Ok((None, &self.renderer));
+        }
+        match self.styles.iter().find(|(style_name, _)| style_name == name) {
+            Some((style_name, renderer)) => Ok((Some(style_name.as_str()), renderer)),
+            None => Err(RequestError::not_found(format!("Unknown style: {}", name))),
+        }
+    }
 
-        let tile = match extract_tile_from_path(path) {
-            Some(tile) => tile,
-            _ => bail!("<{}> doesn't look like a valid tile ID", path),
+    // Every request can be served with another stylesheet, either with its name as a path prefix (e.g.
+    // `/dark/16/39614/20486.png`) or in the `style` parameter (e.g. `/16/39614/20486.png?style=dark`). Returns the
+    // path without the prefix.
+    fn select_style(&self, path: &str) -> Result<(Option<&str>, &TileRenderer<'a>, String)> {
+        for (name, renderer) in &self.styles {
+            if let Some(rest) = strip_base_path(path, &format!("/{}", name)) {
+                return Ok((Some(name.as_str()), renderer, rest));
+            }
+        }
+        let query = path.split_once('?').map_or("", |(_, query)| query);
+        let (style, renderer) = match query.split('&').find_map(|param| param.strip_prefix("style=")) {
+            Some(name) => self.find_style(&percent_decode(name))?,
+            None => (None, &self.renderer),
         };
+        Ok((style, renderer, path.to_string()))
+    }
+
+    // Parses `/compare[?left=NAME][&right=NAME]`. By default, the main stylesheet is compared with the first other
+    // one.
+    fn parse_compare_request(&self, path: &str) -> Result<[Option<&str>; 2]> {
+        let mut sides = [None, self.styles.first().map(|(name, _)| name.as_str())];
+        let query = path.split_once('?').map_or("", |(_, query)| query);
+        for param in query.split('&') {
+            let (side, name) = match param.split_once('=') {
+                Some(("left", name)) => (0, name),
+                Some(("right", name)) => (1, name),
+                _ => continue,
+            };
+            sides[side] = self.find_style(&percent_decode(name))?.0;
+        }
+        Ok(sides)
+    }
+
+    fn format_style_warnings(&self, renderer: &TileRenderer<'a>) -> String {
+        let (warnings, dropped) = renderer.styler().warnings();
+        let mut result = String::new();
+        for w in &warnings {
+            result.push_str(&format!(
+                "{} x property \"{}\": {} (e.g. value {} for entity #{})\n",
+                w.count, w.property, w.message, w.example_value, w.example_entity_id
+            ));
+        }
+        if dropped > 0 {
+            result.push_str(&format!("{} more warnings of other kinds\n", dropped));
+        }
+        result
+    }
 
-        if cfg!(feature = "perf-stats") {
+    fn try_handle_request(&self, path: &str, deadline: Option<Instant>) -> Result<Response> {
+        if deadline.is_some_and(|deadline| Instant::now() > deadline) {
+            return Err(DeadlineExceeded.into());
+        }
+        let (style, renderer, path) = self.select_style(path)?;
+        let path = path.as_str();
+
+        if cfg!(feature = "perf-stats") && (path == "/perf_stats" || path == "/perf_stats.json") {
+            let mut perf_stats = self.perf_stats.lock().unwrap();
+            perf_stats.set_style_cache_stats(renderer.styler().style_cache_stats());
+            let (data, content_type) = if path == "/perf_stats.json" {
+                (perf_stats.to_json(), "application/json")
+            } else {
+                (perf_stats.to_html(), "text/html")
+            };
+            return Ok(Response::Data {
+                data: data.into_bytes(),
+                content_type,
+                render_time: None,
+            });
+        }
+
+        if path == "/warnings" {
+            return Ok(Response::Data {
+                data: self.format_style_warnings(renderer).into_bytes(),
+                content_type: "text/plain; charset=utf-8",
+                render_time: None,
+            });
+        }
+
+        // The tiles that are being rendered right now are finished with the old data.
+        if path == "/reload-data" {
+            for renderer in self.all_renderers() {
+                renderer.reload_geodata()?;
+            }
+            eprintln!("Reloaded the geodata due to a reload request");
+            if let Some(search_index) = &self.search_index {
+                let new_index = self.build_search_index();
+                *search_index.write().unwrap() = new_index;
+            }
+            return Ok(Response::Data {
+                data: b"Reloaded the geodata\n".to_vec(),
+                content_type: "text/plain; charset=utf-8",
+                render_time: None,
+            });
+        }
+
+        if let Some(tile_url) = self
+            .preview_tile_url
+            .as_ref()
+            .filter(|_| path == "/" || path.starts_with("/?"))
+        {
+            return Ok(Response::Data {
+                data: format_preview_page(&style_url(tile_url, style), renderer.geodata_extent()).into_bytes(),
+                content_type: "text/html; charset=utf-8",
+                render_time: None,
+            });
+        }
+
+        if let Some(tile_url) = self
+            .preview_tile_url
+            .as_ref()
+            .filter(|_| path == "/compare" || path.starts_with("/compare?"))
+        {
+            let sides = self.parse_compare_request(path)?.map(|style| {
+                let name = style.unwrap_or(MAIN_STYLE_NAME);
+                (name, style_url(tile_url, style))
+            });
+            return Ok(Response::Data {
+                data: format_compare_page(&sides, self.renderer.geodata_extent()).into_bytes(),
+                content_type: "text/html; charset=utf-8",
+                render_time: None,
+            });
+        }
+
+        if path == "/tilejson.json" {
+            return Ok(Response::Data {
+                data: format_tilejson(&style_url(&self.public_url, style), renderer.geodata_extent()).into_bytes(),
+                content_type: "application/json",
+                render_time: None,
+            });
+        }
+
+        if path == "/staticmap" || path.starts_with("/staticmap?") {
+            let request = parse_static_map_request(path).map_err(|e| RequestError::bad_request(e.to_string()))?;
+            let (lat, lon) = request.center;
+            let region = PixelRegion::around_center(lat, lon, request.zoom, request.width, request.height)
+                .map_err(|e| RequestError::bad_request(e.to_string()))?;
+            let options = RenderOptions {
+                deadline,
+                ..Default::default()
+            };
+            let mut image = renderer
+                .render_region(&region, request.scale, &options)
+                .context("Failed to render the static map")?;
+            for &(marker_lat, marker_lon) in &request.markers {
+                image.draw_marker(marker_lat, marker_lon, &region, request.scale);
+            }
+            return Ok(Response::Data {
+                data: image.to_png()?,
+                content_type: "image/png",
+                render_time: None,
+            });
+        }
+
+        if path == "/search" || path.starts_with("/search?") {
+            let Some(search_index) = &self.search_index else {
+                return Err(RequestError::not_found(
+                    "The search is not enabled (add search = true to the [http] section)".to_string(),
+                ));
+            };
+            let request = parse_search_request(path).map_err(|e| RequestError::bad_request(e.to_string()))?;
+            let results = search_index.read().unwrap().search(&request.query, request.limit);
+            return Ok(Response::Data {
+                data: format_search_results(&results).into_bytes(),
+                content_type: "application/json",
+                render_time: None,
+            });
+        }
+
+        if path == "/query" || path.starts_with("/query?") {
+            let request = parse_point_query(path).map_err(|e| RequestError::bad_request(e.to_string()))?;
+            let entities = renderer
+                .query_point(request.lat, request.lon, request.zoom)
+                .context("Failed to query the entities")?;
+            return Ok(Response::Data {
+                data: format_queried_entities(&entities).into_bytes(),
+                content_type: "application/json",
+                render_time: None,
+            });
+        }
+
+        if path == "/wms" || path.starts_with("/wms?") {
+            let request = parse_wms_request(path).map_err(|e| RequestError::bad_request(e.to_string()))?;
+            extent_zoom(&request.bounds, request.width as usize, request.height as usize)
+                .map_err(|e| RequestError::bad_request(e.to_string()))?;
+            let options = RenderOptions {
+                deadline,
+                projection: request.projection,
+                ..Default::default()
+            };
+            let image = renderer
+                .render_extent(
+                    &request.bounds,
+                    request.width as usize,
+                    request.height as usize,
+                    &options,
+                )
+                .context("Failed to render the WMS map")?;
+            return Ok(Response::Data {
+                data: image.to_png()?,
+                content_type: "image/png",
+                render_time: None,
+            });
+        }
+
+        let tile = extract_tile_from_path(path)?;
+        // The plate carrée tiles are made of several Web Mercator tiles, so they are always rendered.
+        if self.not_found_outside_data
+            && tile.projection == Projection::WebMercator
+            && renderer.is_outside_geodata(&tile.tile)
+        {
+            let Tile { zoom, x, y } = tile.tile;
+            return Err(RequestError::not_found(format!(
+                "Tile {}/{}/{} is outside of the imported data",
+                zoom, x, y
+            )));
+        }
+
+        if cfg!(feature = "perf-stats") || self.slow_tile_threshold.is_some() {
             crate::perf_stats::start_tile(tile.tile.zoom);
         }
 
-        let entities = {
-            let _m = crate::perf_stats::measure("Get tile entities");
-            self.reader
-                .get_entities_in_tile_with_neighbors(&tile.tile, &self.osm_ids)
+        let options = RenderOptions {
+            filter: tile.filter,
+            deadline,
+            debug: tile.debug,
+            projection: tile.projection,
         };
+        let render_start = Instant::now();
+        let tile_bytes = if tile.svg {
+            renderer
+                .render_svg(&tile.tile, tile.scale, &options)
+                .map(String::into_bytes)
+        } else {
+            renderer.render_png_with_options(&tile.tile, tile.scale, &options)
+        }
+        .context("Failed to render the tile")?;
 
-        if tile.scale != state.current_scale {
-            let _m = crate::perf_stats::measure("Re-scaling TilePixels");
-            state.current_scale = tile.scale;
-            state.current_pixels = Box::new(TilePixels::new(tile.scale));
+        if let Some(tile_stats) = crate::perf_stats::finish_tile_stats() {
+            if self
+                .slow_tile_threshold
+                .is_some_and(|threshold| tile_stats.duration() > threshold)
+            {
+                eprintln!("Slow tile <{}>:\n{}", path, tile_stats.to_text().trim_end());
+            }
+            if cfg!(feature = "perf-stats") {
+                self.perf_stats.lock().unwrap().add_tile_stats(&tile_stats);
+            }
         }
 
-        let tile_png_bytes = self
-            .drawer
-            .draw_tile(
-                &entities,
-                &tile.tile,
-                &mut state.current_pixels,
-                state.current_scale,
-                &self.styler,
-            )
-            .unwrap();
+        Ok(Response::Data {
+            data: tile_bytes,
+            content_type: if tile.svg { "image/svg+xml" } else { "image/png" },
+            render_time: Some(render_start.elapsed()).filter(|_| self.render_time_header),
+        })
+    }
+}
 
-        if cfg!(feature = "perf-stats") {
-            crate::perf_stats::finish_tile(&mut self.perf_stats.lock().unwrap());
+fn serve_response(stream: &mut TcpStream, method: Method, response: &Response, allow_origin: &str) {
+    match response {
+        Response::Data {
+            data,
+            content_type,
+            render_time,
+        } => {
+            let header = format_data_header(data.len(), content_type, *render_time, allow_origin);
+            // Errors at this stage usually happen when the outstanding requests get terminated for some
+            // reason (e.g. the user scrolls the map). We're not interested in reporting these errors,
+            // but there's no point in continuing after a write fails either.
+            if stream.write_all(header.as_bytes()).is_ok() && method != Method::Head {
+                let _ = stream.write_all(data);
+            }
+        }
+        Response::Error { status, message } => {
+            let (header, body) = format_error_response(status, message, allow_origin);
+            if stream.write_all(header.as_bytes()).is_ok() && method != Method::Head {
+                let _ = stream.write_all(body.as_bytes());
+            }
         }
+        Response::ServiceUnavailable => {
+            let header = [
+                "HTTP/1.1 503 Service Unavailable",
+                "Content-Length: 0",
+                "Retry-After: 1",
+                "Connection: close",
+                "",
+                "",
+            ]
+            .join("\r\n");
+            let _ = stream.write_all(header.as_bytes());
+        }
+    }
+}
+
+fn format_data_header(
+    content_length: usize,
+    content_type: &str,
+    render_time: Option<Duration>,
+    allow_origin: &str,
+) -> String {
+    let mut header_lines = vec![
+        "HTTP/1.1 200 OK".to_string(),
+        format!("Content-Type: {}", content_type),
+        format!("Content-Length: {}", content_length),
+    ];
+    header_lines.extend(format_cors_headers(allow_origin));
+    if let Some(render_time) = render_time {
+        header_lines.push(format!("X-Render-Time-Ms: {:.3}", render_time.as_secs_f64() * 1e3));
+        // Otherwise, the header isn't visible to the scripts running on other origins.
+        header_lines.push("Access-Control-Expose-Headers: X-Render-Time-Ms".to_string());
+    }
+    header_lines.extend(["Connection: close".to_string(), String::new(), String::new()]);
+    header_lines.join("\r\n")
+}
 
-        serve_data(stream, &tile_png_bytes, "image/png");
+// Describes the Web Mercator tiles in the TileJSON format (https://github.com/mapbox/tilejson-spec).
+fn format_tilejson(public_url: &str, extent: Option<LatLonBounds>) -> String {
+    let mut fields = vec![
+        "\"tilejson\":\"3.0.0\"".to_string(),
+        format!("\"tiles\":[\"{}/{{z}}/{{x}}/{{y}}.png\"]", escape_json(public_url)),
+        format!("\"minzoom\":0,\"maxzoom\":{}", MAX_ZOOM),
+    ];
+    // Without the bounds, the clients assume that the tiles cover the whole world.
+    if let Some(extent) = extent {
+        fields.push(format!(
+            "\"bounds\":[{},{},{},{}]",
+            extent.min_lon, extent.min_lat, extent.max_lon, extent.max_lat
+        ));
+    }
+    format!("{{{}}}\n", fields.join(","))
+}
+
+// The tiles of the other stylesheets are under their names.
+fn style_url(url: &str, style: Option<&str>) -> String {
+    match style {
+        Some(name) => format!("{}/{}", url, name),
+        None => url.to_string(),
+    }
+}
 
-        Ok(())
+// The names end up in the URLs and the pages as is, and they must not be confused with the other paths.
+fn validate_style_name(name: &str) -> Result<()> {
+    const RESERVED_NAMES: &[&str] = &[
+        MAIN_STYLE_NAME,
+        "compare",
+        "epsg4326",
+        "perf_stats",
+        "query",
+        "reload-data",
+        "search",
+        "shutdown",
+        "staticmap",
+        "warnings",
+        "wms",
+    ];
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic())
+        || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!(
+            "Invalid style name: {} (expected a letter followed by letters, digits, - and _)",
+            name
+        );
+    }
+    if RESERVED_NAMES.contains(&name) {
+        bail!("Style name {} is reserved", name);
     }
+    Ok(())
+}
+
+// The maps start at the extent of the data, or show the whole world if it's unknown.
+fn fill_map_template(template: &str, extent: Option<LatLonBounds>) -> String {
+    let bounds = match extent {
+        Some(extent) => format!(
+            "[[{}, {}], [{}, {}]]",
+            extent.min_lat, extent.min_lon, extent.max_lat, extent.max_lon
+        ),
+        None => "null".to_string(),
+    };
+    template
+        .replace("{{MAX_ZOOM}}", &MAX_ZOOM.to_string())
+        .replace("{{BOUNDS}}", &bounds)
+}
+
+fn format_preview_page(tile_url: &str, extent: Option<LatLonBounds>) -> String {
+    fill_map_template(include_str!("preview.html"), extent).replace("{{TILE_URL}}", &escape_json(tile_url))
+}
+
+// Two maps side by side that always show the same place, each with a (name, tile URL prefix) pair.
+fn format_compare_page(sides: &[(&str, String); 2], extent: Option<LatLonBounds>) -> String {
+    let [(left_name, left_url), (right_name, right_url)] = sides;
+    fill_map_template(include_str!("compare.html"), extent)
+        .replace("{{LEFT_NAME}}", left_name)
+        .replace("{{LEFT_TILE_URL}}", &escape_json(left_url))
+        .replace("{{RIGHT_NAME}}", right_name)
+        .replace("{{RIGHT_TILE_URL}}", &escape_json(right_url))
 }
 
-fn serve_data(stream: &mut TcpStream, data: &[u8], content_type: &str) {
-    let header = [
-        "HTTP/1.1 200 OK",
-        &format!("Content-Type: {}", content_type),
-        &format!("Content-Length: {}", data.len()),
-        "Access-Control-Allow-Origin: *",
-        "Connection: close",
-        "",
-        "",
-    ]
-    .join("\r\n");
+// Returns the header and the body of an error response.
+fn format_error_response(status: &str, message: &str, allow_origin: &str) -> (String, String) {
+    let body = format!("{{\"error\":\"{}\"}}\n", escape_json(message));
+    let mut header_lines = vec![
+        format!("HTTP/1.1 {}", status),
+        "Content-Type: application/json".to_string(),
+        format!("Content-Length: {}", body.len()),
+    ];
+    header_lines.extend(format_cors_headers(allow_origin));
+    header_lines.extend(["Connection: close".to_string(), String::new(), String::new()]);
+    (header_lines.join("\r\n"), body)
+}
+
+// Any request headers are allowed, since the server ignores all of them anyway.
+fn serve_preflight(stream: &mut TcpStream, allow_origin: &str) {
+    let mut header_lines = vec!["HTTP/1.1 204 No Content".to_string()];
+    header_lines.extend(format_cors_headers(allow_origin));
+    header_lines.extend([
+        "Access-Control-Allow-Methods: GET, HEAD, OPTIONS".to_string(),
+        "Access-Control-Allow-Headers: *".to_string(),
+        "Access-Control-Max-Age: 86400".to_string(),
+        "Content-Length: 0".to_string(),
+        "Connection: close".to_string(),
+        String::new(),
+        String::new(),
+    ]);
+    let _ = stream.write_all(header_lines.join("\r\n").as_bytes());
+}
 
-    // Errors at this stage usually happen when the outstanding requests get terminated for some
-    // reason (e.g. the user scrolls the map). We're not interested in reporting these errors,
-    // but there's no point in continuing after a write fails either.
-    if stream.write_all(header.as_bytes()).is_ok() {
-        let _ = stream.write_all(data);
+fn format_cors_headers(allow_origin: &str) -> Vec<String> {
+    let mut header_lines = vec![format!("Access-Control-Allow-Origin: {}", allow_origin)];
+    // The caches have to keep the responses for different origins apart if the header isn't the same for all
+    // of them.
+    if allow_origin != "*" {
+        header_lines.push("Vary: Origin".to_string());
     }
+    header_lines
+}
+
+#[derive(Debug, PartialEq)]
+struct HttpRequest {
+    method: Method,
+    path: String,
+    // The original client address from the `X-Forwarded-For` header (i.e. its first address), if any.
+    forwarded_for: Option<String>,
 }
 
-fn extract_path_from_stream(stream: &mut TcpStream) -> Result<String> {
-    let mut rdr = BufReader::new(stream);
-    let first_line = match rdr.by_ref().lines().next() {
+fn extract_request_from_stream<R: Read>(stream: &mut R) -> Result<HttpRequest> {
+    let rdr = BufReader::new(stream);
+    let mut lines = rdr.lines();
+    let first_line = match lines.next() {
         Some(Ok(line)) => line,
         _ => bail!("Failed to read the first line from the TCP stream"),
     };
@@ -210,42 +871,84 @@ fn extract_path_from_stream(stream: &mut TcpStream) -> Result<String> {
     if tokens.len() != 3 {
         bail!("<{}> doesn't look like a valid HTTP request", first_line);
     }
-    let method = tokens[0];
-    if method != "GET" {
-        bail!("Invalid HTTP method: {}", method);
-    }
+    let method = match tokens[0] {
+        "GET" => Method::Get,
+        "HEAD" => Method::Head,
+        "OPTIONS" => Method::Options,
+        method => bail!("Invalid HTTP method: {}", method),
+    };
     let http_version = tokens[2];
     if http_version != "HTTP/1.1" && http_version != "HTTP/1.0" {
         bail!("Invalid HTTP version: {}", http_version);
     }
-    Ok(tokens[1].to_string())
+
+    let mut forwarded_for = None;
+    for line in lines {
+        let line = line.context("Failed to read the HTTP headers")?;
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if forwarded_for.is_none() && name.trim().eq_ignore_ascii_case("X-Forwarded-For") {
+                let first_addr = value.split(',').next().unwrap_or_default().trim();
+                forwarded_for = Some(first_addr.to_string()).filter(|addr| !addr.is_empty());
+            }
+        }
+    }
+
+    Ok(HttpRequest {
+        method,
+        path: tokens[1].to_string(),
+        forwarded_for,
+    })
+}
+
+// `/tiles/1/2/3.png` becomes `/1/2/3.png` under `/tiles`, and the paths outside of it are rejected.
+fn strip_base_path(path: &str, base_path: &str) -> Option<String> {
+    let rest = path.strip_prefix(base_path)?;
+    if rest.is_empty() || rest.starts_with('?') {
+        Some(format!("/{}", rest))
+    } else if rest.starts_with('/') {
+        Some(rest.to_string())
+    } else {
+        None
+    }
 }
 
 struct RequestTile {
     tile: Tile,
     scale: usize,
+    filter: EntityFilter,
+    debug: DebugOverlays,
+    projection: Projection,
+    // `/z/x/y.svg` instead of `/z/x/y.png`.
+    svg: bool,
 }
 
-fn extract_tile_from_path(path: &str) -> Option<RequestTile> {
+fn extract_tile_from_path(path: &str) -> Result<RequestTile> {
     let expected_token_count = 3;
+    let invalid = || RequestError::bad_request(format!("<{}> doesn't look like a valid tile ID", path));
 
-    let real_path = match path.rfind('?') {
-        Some(pos) => &path[..pos],
-        None => path,
+    let (real_path, query) = match path.rfind('?') {
+        Some(pos) => (&path[..pos], &path[pos + 1..]),
+        None => (path, ""),
     };
 
-    let mut tokens = real_path
-        .trim_end_matches(".png")
-        .rsplit('/')
-        .take(expected_token_count)
-        .collect::<Vec<_>>();
+    let svg = real_path.ends_with(".svg");
+    let real_path = real_path.strip_suffix(".svg").unwrap_or(real_path);
+    let mut all_tokens = real_path.trim_end_matches(".png").rsplit('/');
+    let mut tokens = all_tokens.by_ref().take(expected_token_count).collect::<Vec<_>>();
 
     if tokens.len() != expected_token_count {
-        return None;
+        return Err(invalid());
     }
 
     tokens.reverse();
     let (z_str, x_str, mut y_str) = (tokens[0], tokens[1], tokens[2]);
+    let projection = match all_tokens.next() {
+        Some("epsg4326") => Projection::PlateCarree,
+        _ => Projection::WebMercator,
+    };
 
     let mut scale = 1;
 
@@ -256,24 +959,407 @@ fn extract_tile_from_path(path: &str) -> Option<RequestTile> {
             scale = parsed_scale;
         }
     }
+    // The same scales as for the static maps: the pixels of a larger tile would take gigabytes.
+    if !(1..=4).contains(&scale) {
+        return Err(RequestError::bad_request(format!("Invalid scale: {}", scale)));
+    }
 
-    match (z_str.parse(), x_str.parse(), y_str.parse()) {
-        (Ok(z), Ok(x), Ok(y)) if z <= MAX_ZOOM => Some(RequestTile {
-            tile: Tile { zoom: z, x, y },
+    let (zoom, x, y) = match (z_str.parse(), x_str.parse(), y_str.parse()) {
+        (Ok(z), Ok(x), Ok(y)) => (z, x, y),
+        _ => return Err(invalid()),
+    };
+    let filter = extract_entity_filter(query).ok_or_else(invalid)?;
+    let debug = extract_debug_overlays(query).ok_or_else(invalid)?;
+    if svg && (projection != Projection::WebMercator || debug != DebugOverlays::default()) {
+        return Err(RequestError::bad_request(
+            "The SVG tiles are only available in Web Mercator and without the debug overlays".to_string(),
+        ));
+    }
+
+    if zoom > projection.max_zoom() {
+        return Err(RequestError::not_found(format!(
+            "There are no tiles beyond zoom level {}",
+            projection.max_zoom()
+        )));
+    }
+    let (columns, rows) = projection.tile_counts(zoom);
+    if x >= columns || y >= rows {
+        return Err(RequestError::not_found(format!(
+            "Tile {}/{}/{} is outside of the map",
+            zoom, x, y
+        )));
+    }
+
+    Ok(RequestTile {
+        tile: Tile { zoom, x, y },
+        scale,
+        filter,
+        debug,
+        projection,
+        svg,
+    })
+}
+
+// Handles `?ids=1,2,3` (render only these entities) and `?exclude=4,5` (render everything except them).
+// Other query parameters are ignored so that clients can still use them for cache busting.
+fn extract_entity_filter(query: &str) -> Option<EntityFilter> {
+    let parse_ids = |value: &str| -> Option<HashSet<u64>> {
+        percent_decode(value)
+            .split(',')
+            .filter(|id| !id.is_empty())
+            .map(|id| id.parse().ok())
+            .collect()
+    };
+
+    let mut filter = EntityFilter::default();
+    for param in query.split('&') {
+        match param.split_once('=') {
+            Some(("ids", value)) => filter.ids = Some(parse_ids(value)?),
+            Some(("exclude", value)) => filter.excluded_ids = parse_ids(value)?,
+            _ => {}
+        }
+    }
+    Some(filter)
+}
+
+// Handles `?debug=grid,labels,ids` (see `DebugOverlays`).
+fn extract_debug_overlays(query: &str) -> Option<DebugOverlays> {
+    let mut debug = DebugOverlays::default();
+    for param in query.split('&') {
+        if let Some(("debug", value)) = param.split_once('=') {
+            for flag in percent_decode(value).split(',').filter(|flag| !flag.is_empty()) {
+                match flag {
+                    "grid" => debug.grid = true,
+                    "labels" => debug.labels = true,
+                    "ids" => debug.ids = true,
+                    _ => return None,
+                }
+            }
+        }
+    }
+    Some(debug)
+}
+
+// The maximum width and height of a static map (before scaling).
+const MAX_STATIC_MAP_SIZE: u32 = 2048;
+
+#[derive(Debug, PartialEq)]
+struct StaticMapRequest {
+    center: (f64, f64),
+    zoom: u8,
+    width: u32,
+    height: u32,
+    scale: usize,
+    markers: Vec<(f64, f64)>,
+}
+
+// Parses `/staticmap?center=lat,lon&zoom=15&size=600x400[&scale=2][&marker=lat,lon...]`.
+fn parse_static_map_request(path: &str) -> Result<StaticMapRequest> {
+    let parse_lat_lon = |value: &str| -> Result<(f64, f64)> {
+        match value.split_once(',').map(|(lat, lon)| (lat.parse(), lon.parse())) {
+            Some((Ok(lat), Ok(lon))) => Ok((lat, lon)),
+            _ => bail!("<{}> is not a valid lat,lon pair", value),
+        }
+    };
+
+    let mut center = None;
+    let mut zoom = None;
+    let mut size = None;
+    let mut scale = 1;
+    let mut markers = Vec::new();
+
+    let query = path.split_once('?').map(|(_, query)| query).unwrap_or_default();
+    for param in query.split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = param.split_once('=').unwrap_or((param, ""));
+        let value = percent_decode(value);
+        match key {
+            "center" => center = Some(parse_lat_lon(&value)?),
+            "zoom" => match value.parse() {
+                Ok(z) if z <= MAX_ZOOM => zoom = Some(z),
+                _ => bail!("Invalid zoom level: {}", value),
+            },
+            "size" => match value.split_once('x').map(|(w, h)| (w.parse(), h.parse())) {
+                Some((Ok(w), Ok(h)))
+                    if (1..=MAX_STATIC_MAP_SIZE).contains(&w) && (1..=MAX_STATIC_MAP_SIZE).contains(&h) =>
+                {
+                    size = Some((w, h))
+                }
+                _ => bail!("Invalid static map size: {}", value),
+            },
+            "scale" => match value.parse() {
+                Ok(s) if (1..=4).contains(&s) => scale = s,
+                _ => bail!("Invalid scale: {}", value),
+            },
+            "marker" => markers.push(parse_lat_lon(&value)?),
+            _ => bail!("Unknown static map parameter: {}", key),
+        }
+    }
+
+    match (center, zoom, size) {
+        (Some(center), Some(zoom), Some((width, height))) => Ok(StaticMapRequest {
+            center,
+            zoom,
+            width,
+            height,
             scale,
+            markers,
         }),
-        _ => None,
+        _ => bail!("A static map request should have center, zoom and size parameters"),
+    }
+}
+
+// The maximum number of results of a `/search` request.
+const MAX_SEARCH_RESULTS: usize = 100;
+
+#[derive(Debug, PartialEq)]
+struct SearchRequest {
+    query: String,
+    limit: usize,
+}
+
+// Parses `/search?q=Red+Square[&limit=10]`.
+fn parse_search_request(path: &str) -> Result<SearchRequest> {
+    let mut query = None;
+    let mut limit = 10;
+
+    let params = path.split_once('?').map(|(_, params)| params).unwrap_or_default();
+    for param in params.split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = param.split_once('=').unwrap_or((param, ""));
+        // The HTML forms encode the spaces as pluses.
+        let value = percent_decode(&value.replace('+', " "));
+        match key {
+            "q" => query = Some(value),
+            "limit" => match value.parse() {
+                Ok(l) if (1..=MAX_SEARCH_RESULTS).contains(&l) => limit = l,
+                _ => bail!("Invalid limit: {}", value),
+            },
+            _ => bail!("Unknown search parameter: {}", key),
+        }
+    }
+
+    match query {
+        Some(query) => Ok(SearchRequest { query, limit }),
+        None => bail!("A search request should have a q parameter"),
+    }
+}
+
+fn format_search_results(results: &[SearchResult]) -> String {
+    let results = results
+        .iter()
+        .map(|result| {
+            format!(
+                "{{\"type\":\"{}\",\"id\":{},\"name\":\"{}\",\"lat\":{},\"lon\":{}}}",
+                result.kind,
+                result.id,
+                escape_json(&result.name),
+                result.lat,
+                result.lon
+            )
+        })
+        .collect::<Vec<_>>();
+    format!("[{}]\n", results.join(","))
+}
+
+#[derive(Debug, PartialEq)]
+struct PointQuery {
+    lat: f64,
+    lon: f64,
+    zoom: u8,
+}
+
+// Parses `/query?lat=55.75&lon=37.61&zoom=16`.
+fn parse_point_query(path: &str) -> Result<PointQuery> {
+    let mut lat = None;
+    let mut lon = None;
+    let mut zoom = None;
+
+    let query = path.split_once('?').map(|(_, query)| query).unwrap_or_default();
+    for param in query.split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = param.split_once('=').unwrap_or((param, ""));
+        let value = percent_decode(value);
+        match key {
+            "lat" => match value.parse() {
+                Ok(l) if (-90.0..=90.0).contains(&l) => lat = Some(l),
+                _ => bail!("Invalid latitude: {}", value),
+            },
+            "lon" => match value.parse() {
+                Ok(l) if (-180.0..=180.0).contains(&l) => lon = Some(l),
+                _ => bail!("Invalid longitude: {}", value),
+            },
+            "zoom" => match value.parse() {
+                Ok(z) if z <= MAX_ZOOM => zoom = Some(z),
+                _ => bail!("Invalid zoom level: {}", value),
+            },
+            _ => bail!("Unknown query parameter: {}", key),
+        }
+    }
+
+    match (lat, lon, zoom) {
+        (Some(lat), Some(lon), Some(zoom)) => Ok(PointQuery { lat, lon, zoom }),
+        _ => bail!("A query should have lat, lon and zoom parameters"),
+    }
+}
+
+fn format_queried_entities(entities: &[QueriedEntity]) -> String {
+    let entities = entities
+        .iter()
+        .map(|entity| {
+            let tags = entity
+                .tags
+                .iter()
+                .map(|(k, v)| format!("\"{}\":\"{}\"", escape_json(k), escape_json(v)))
+                .collect::<Vec<_>>();
+            format!(
+                "{{\"type\":\"{}\",\"id\":{},\"tags\":{{{}}}}}",
+                entity.kind,
+                entity.id,
+                tags.join(",")
+            )
+        })
+        .collect::<Vec<_>>();
+    format!("[{}]\n", entities.join(","))
+}
+
+#[derive(Debug, PartialEq)]
+struct WmsRequest {
+    projection: Projection,
+    bounds: LatLonBounds,
+    width: u32,
+    height: u32,
+}
+
+// Half of the length of the equator in meters, which is where the EPSG:3857 coordinates end.
+const EPSG_3857_HALF_SIZE: f64 = 20_037_508.342_789_244;
+
+// Parses WMS 1.1.1 and 1.3.0 GetMap requests like `/wms?REQUEST=GetMap&SRS=EPSG:3857&BBOX=..&WIDTH=256&HEIGHT=256`.
+// There is only one layer with one style, so LAYERS, STYLES and the other parameters that don't matter are ignored.
+fn parse_wms_request(path: &str) -> Result<WmsRequest> {
+    let query = path.split_once('?').map(|(_, query)| query).unwrap_or_default();
+    // Unlike the values, the parameter names are case-insensitive.
+    let params = query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|param| {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            (key.to_ascii_uppercase(), percent_decode(value))
+        })
+        .collect::<HashMap<_, _>>();
+    let param = |key: &str| params.get(key).map(String::as_str);
+
+    if param("SERVICE").is_some_and(|service| service != "WMS") || param("REQUEST") != Some("GetMap") {
+        bail!("Only WMS GetMap requests are supported");
+    }
+    if let Some(format) = param("FORMAT").filter(|&format| format != "image/png") {
+        bail!("Unsupported WMS image format: {}", format);
+    }
+
+    let bbox = param("BBOX")
+        .and_then(|bbox| {
+            bbox.split(',')
+                .map(|coord| coord.parse().ok())
+                .collect::<Option<Vec<f64>>>()
+        })
+        .unwrap_or_default();
+    let &[x1, y1, x2, y2] = bbox.as_slice() else {
+        bail!("A WMS request should have a BBOX parameter with four numbers");
+    };
+    let from_degrees = |min_lat, min_lon, max_lat, max_lon| LatLonBounds {
+        min_lat,
+        min_lon,
+        max_lat,
+        max_lon,
+    };
+    // WMS 1.3.0 calls the SRS parameter CRS.
+    let srs = param("CRS").or(param("SRS")).unwrap_or_default();
+    let (projection, bounds) = match srs {
+        "EPSG:3857" | "EPSG:900913" => {
+            let to_coords = |x: f64, y: f64| {
+                let half_tile = f64::from(TILE_SIZE) / 2.0;
+                Projection::WebMercator.xy_to_coords(
+                    (1.0 + x / EPSG_3857_HALF_SIZE) * half_tile,
+                    (1.0 - y / EPSG_3857_HALF_SIZE) * half_tile,
+                    0,
+                )
+            };
+            let ((min_lat, min_lon), (max_lat, max_lon)) = (to_coords(x1, y1), to_coords(x2, y2));
+            (
+                Projection::WebMercator,
+                from_degrees(min_lat, min_lon, max_lat, max_lon),
+            )
+        }
+        // WMS 1.3.0 follows the axis order of EPSG:4326, which has the latitude first.
+        "EPSG:4326" if param("VERSION") == Some("1.3.0") => (Projection::PlateCarree, from_degrees(x1, y1, x2, y2)),
+        "EPSG:4326" | "CRS:84" => (Projection::PlateCarree, from_degrees(y1, x1, y2, x2)),
+        _ => bail!("Unsupported WMS SRS: <{}>", srs),
+    };
+
+    let size = |key: &str| param(key).and_then(|value| value.parse().ok());
+    match (size("WIDTH"), size("HEIGHT")) {
+        (Some(width), Some(height))
+            if (1..=MAX_STATIC_MAP_SIZE).contains(&width) && (1..=MAX_STATIC_MAP_SIZE).contains(&height) =>
+        {
+            Ok(WmsRequest {
+                projection,
+                bounds,
+                width,
+                height,
+            })
+        }
+        _ => bail!(
+            "A WMS request should have WIDTH and HEIGHT parameters up to {}",
+            MAX_STATIC_MAP_SIZE
+        ),
     }
 }
 
-fn split_stylesheet_path(file_path: &str) -> Result<(PathBuf, String)> {
-    let mut result = PathBuf::from(file_path);
-    let file_name = result
-        .file_name()
-        .and_then(|x| x.to_str().map(ToString::to_string))
-        .ok_or_else(|| anyhow!("Failed to extract the file name for {}", file_path))?;
-    result.pop();
-    Ok((result, file_name))
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        match bytes.get(idx..idx + 3) {
+            Some(&[b'%', hi, lo]) if hi.is_ascii_hexdigit() && lo.is_ascii_hexdigit() => {
+                let hex = [hi, lo];
+                result.push(u8::from_str_radix(std::str::from_utf8(&hex).unwrap(), 16).unwrap());
+                idx += 3;
+            }
+            _ => {
+                result.push(bytes[idx]);
+                idx += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+// The threads are spread over the CPUs the process is allowed to run on (e.g. with `taskset`).
+#[cfg(target_os = "linux")]
+fn pin_current_thread(thread_idx: usize) -> Result<()> {
+    let set_size = std::mem::size_of::<libc::cpu_set_t>();
+    // Safe, since the sets are plain bitmasks of the size that is passed to the calls.
+    unsafe {
+        let mut allowed_cpus: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, set_size, &mut allowed_cpus) != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let cpus = (0..libc::CPU_SETSIZE as usize)
+            .filter(|cpu| libc::CPU_ISSET(*cpu, &allowed_cpus))
+            .collect::<Vec<_>>();
+        if cpus.is_empty() {
+            bail!("No CPUs are available");
+        }
+        let mut thread_cpus: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpus[thread_idx % cpus.len()], &mut thread_cpus);
+        if libc::sched_setaffinity(0, set_size, &thread_cpus) != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_current_thread(_thread_idx: usize) -> Result<()> {
+    bail!("Pinning threads is only supported on Linux")
 }
 
 fn peer_addr(stream: &TcpStream) -> String {
@@ -282,3 +1368,375 @@ fn peer_addr(stream: &TcpStream) -> String {
         .map(|x| format!("{}", x))
         .unwrap_or_else(|_| "N/A".to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_static_map_request() {
+        let request = parse_static_map_request(
+            "/staticmap?center=55.75,37.61&zoom=15&size=600x400&marker=55.751%2C37.612&marker=55.7,37.6",
+        )
+        .unwrap();
+        assert_eq!(
+            request,
+            StaticMapRequest {
+                center: (55.75, 37.61),
+                zoom: 15,
+                width: 600,
+                height: 400,
+                scale: 1,
+                markers: vec![(55.751, 37.612), (55.7, 37.6)],
+            }
+        );
+
+        assert!(parse_static_map_request("/staticmap?center=55.75,37.61&zoom=15").is_err());
+        assert!(parse_static_map_request("/staticmap?center=55.75&zoom=15&size=600x400").is_err());
+        assert!(parse_static_map_request("/staticmap?center=55.75,37.61&zoom=15&size=100000x400").is_err());
+        assert!(parse_static_map_request("/staticmap?center=55.75,37.61&zoom=25&size=600x400").is_err());
+    }
+
+    #[test]
+    fn test_extract_request() {
+        let mut request: &[u8] = b"HEAD /16/39614/20486.png HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        assert_eq!(
+            extract_request_from_stream(&mut request).unwrap(),
+            HttpRequest {
+                method: Method::Head,
+                path: "/16/39614/20486.png".to_string(),
+                forwarded_for: None,
+            }
+        );
+        let mut request: &[u8] = b"OPTIONS /16/39614/20486.png HTTP/1.1\r\n\r\n";
+        assert_eq!(
+            extract_request_from_stream(&mut request).unwrap().method,
+            Method::Options
+        );
+        let mut request: &[u8] =
+            b"GET / HTTP/1.1\r\nx-forwarded-for:  203.0.113.7, 10.0.0.1\r\nX-Forwarded-For: 10.0.0.2\r\n\r\n";
+        assert_eq!(
+            extract_request_from_stream(&mut request)
+                .unwrap()
+                .forwarded_for
+                .as_deref(),
+            Some("203.0.113.7")
+        );
+        let mut request: &[u8] = b"POST /16/39614/20486.png HTTP/1.1\r\n\r\n";
+        assert!(extract_request_from_stream(&mut request).is_err());
+    }
+
+    #[test]
+    fn test_strip_base_path() {
+        assert_eq!(strip_base_path("/1/2/3.png", "").as_deref(), Some("/1/2/3.png"));
+        assert_eq!(
+            strip_base_path("/tiles/1/2/3.png", "/tiles").as_deref(),
+            Some("/1/2/3.png")
+        );
+        assert_eq!(strip_base_path("/tiles", "/tiles").as_deref(), Some("/"));
+        assert_eq!(strip_base_path("/tiles?q=1", "/tiles").as_deref(), Some("/?q=1"));
+        assert_eq!(strip_base_path("/tilesets/1/2/3.png", "/tiles"), None);
+        assert_eq!(strip_base_path("/1/2/3.png", "/tiles"), None);
+    }
+
+    #[test]
+    fn test_format_data_header() {
+        let header = format_data_header(10, "image/png", None, "*");
+        assert!(header.contains("\r\nContent-Length: 10\r\n"));
+        assert!(header.contains("\r\nAccess-Control-Allow-Origin: *\r\n"));
+        assert!(!header.contains("Vary"));
+        assert!(header.ends_with("\r\n\r\n"));
+
+        let header = format_data_header(10, "image/png", None, "https://example.com");
+        assert!(header.contains("\r\nAccess-Control-Allow-Origin: https://example.com\r\nVary: Origin\r\n"));
+    }
+
+    #[test]
+    fn test_format_tilejson() {
+        let extent = LatLonBounds {
+            min_lat: 55.7,
+            min_lon: 37.5,
+            max_lat: 55.8,
+            max_lon: 37.75,
+        };
+        assert_eq!(
+            format_tilejson("https://tiles.example.com", Some(extent)),
+            format!(
+                "{{\"tilejson\":\"3.0.0\",\"tiles\":[\"https://tiles.example.com/{{z}}/{{x}}/{{y}}.png\"],\
+                 \"minzoom\":0,\"maxzoom\":{},\"bounds\":[37.5,55.7,37.75,55.8]}}\n",
+                MAX_ZOOM
+            )
+        );
+        assert!(!format_tilejson("http://localhost:8080", None).contains("bounds"));
+    }
+
+    #[test]
+    fn test_format_preview_page() {
+        let extent = LatLonBounds {
+            min_lat: 55.7,
+            min_lon: 37.5,
+            max_lat: 55.8,
+            max_lon: 37.75,
+        };
+        let page = format_preview_page("/tiles", Some(extent));
+        assert!(page.contains("L.tileLayer(\"/tiles/{z}/{x}/{y}{r}.png\""));
+        assert!(page.contains(&format!("maxZoom: {},", MAX_ZOOM)));
+        assert!(page.contains("var bounds = [[55.7, 37.5], [55.8, 37.75]];"));
+        assert!(!page.contains("{{"));
+        assert!(format_preview_page("", None).contains("var bounds = null;"));
+    }
+
+    #[test]
+    fn test_format_compare_page() {
+        let sides = [("default", "/tiles".to_string()), ("v2", "/tiles/v2".to_string())];
+        let page = format_compare_page(&sides, None);
+        assert!(page.contains("addMap(\"left\", \"default\", \"/tiles\");"));
+        assert!(page.contains("addMap(\"right\", \"v2\", \"/tiles/v2\");"));
+        assert!(page.contains("var bounds = null;"));
+        assert!(!page.contains("{{"));
+    }
+
+    #[test]
+    fn test_validate_style_name() {
+        assert!(validate_style_name("v2").is_ok());
+        assert!(validate_style_name("dark_night-2").is_ok());
+        assert!(validate_style_name("2").is_err());
+        assert!(validate_style_name("").is_err());
+        assert!(validate_style_name("a/b").is_err());
+        assert!(validate_style_name("default").is_err());
+        assert!(validate_style_name("wms").is_err());
+    }
+
+    #[test]
+    fn test_parse_search_request() {
+        let request = parse_search_request("/search?q=Red+Square%21&limit=3").unwrap();
+        assert_eq!(
+            request,
+            SearchRequest {
+                query: "Red Square!".to_string(),
+                limit: 3
+            }
+        );
+        assert_eq!(parse_search_request("/search?q=%D0%9A").unwrap().query, "К");
+        assert_eq!(parse_search_request("/search?q=x").unwrap().limit, 10);
+        assert!(parse_search_request("/search").is_err());
+        assert!(parse_search_request("/search?q=x&limit=0").is_err());
+        assert!(parse_search_request(&format!("/search?q=x&limit={}", MAX_SEARCH_RESULTS + 1)).is_err());
+        assert!(parse_search_request("/search?q=x&bbox=1,2,3,4").is_err());
+
+        let results = [SearchResult {
+            kind: "way",
+            id: 5,
+            name: "\"Red\" Square".to_string(),
+            lat: 55.75,
+            lon: 37.62,
+        }];
+        assert_eq!(
+            format_search_results(&results),
+            "[{\"type\":\"way\",\"id\":5,\"name\":\"\\\"Red\\\" Square\",\"lat\":55.75,\"lon\":37.62}]\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_point_query() {
+        let query = parse_point_query("/query?lat=55.75&lon=37.61&zoom=16").unwrap();
+        assert_eq!(
+            query,
+            PointQuery {
+                lat: 55.75,
+                lon: 37.61,
+                zoom: 16
+            }
+        );
+        assert!(parse_point_query("/query?lat=55.75&lon=37.61").is_err());
+        assert!(parse_point_query("/query?lat=95&lon=37.61&zoom=16").is_err());
+        assert!(parse_point_query(&format!("/query?lat=55.75&lon=37.61&zoom={}", MAX_ZOOM + 1)).is_err());
+        assert!(parse_point_query("/query?lat=55.75&lon=37.61&zoom=16&radius=5").is_err());
+    }
+
+    #[test]
+    fn test_format_queried_entities() {
+        let entities = [
+            QueriedEntity {
+                kind: "node",
+                id: 1,
+                tags: vec![("amenity".to_string(), "cafe".to_string())],
+            },
+            QueriedEntity {
+                kind: "way",
+                id: 2,
+                tags: vec![
+                    ("highway".to_string(), "path".to_string()),
+                    ("name".to_string(), "\"Old\" path".to_string()),
+                ],
+            },
+        ];
+        assert_eq!(
+            format_queried_entities(&entities),
+            "[{\"type\":\"node\",\"id\":1,\"tags\":{\"amenity\":\"cafe\"}},\
+             {\"type\":\"way\",\"id\":2,\"tags\":{\"highway\":\"path\",\"name\":\"\\\"Old\\\" path\"}}]\n"
+        );
+        assert_eq!(format_queried_entities(&[]), "[]\n");
+    }
+
+    #[test]
+    fn test_format_error_response() {
+        let (header, body) = format_error_response("404 Not Found", "No \"tile\" here", "*");
+        assert!(header.starts_with("HTTP/1.1 404 Not Found\r\nContent-Type: application/json\r\n"));
+        assert_eq!(body, "{\"error\":\"No \\\"tile\\\" here\"}\n");
+        assert!(header.contains(&format!("\r\nContent-Length: {}\r\n", body.len())));
+        assert!(header.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_extract_entity_filter() {
+        let tile = extract_tile_from_path("/16/39614/20486@2x.png?ids=1,2%2C3&exclude=4&v=5").unwrap();
+        assert_eq!(tile.scale, 2);
+        assert_eq!(
+            tile.filter,
+            EntityFilter {
+                ids: Some([1, 2, 3].into_iter().collect()),
+                excluded_ids: [4].into_iter().collect(),
+            }
+        );
+
+        assert_eq!(
+            extract_tile_from_path("/16/39614/20486.png").unwrap().filter,
+            EntityFilter::default()
+        );
+        assert!(extract_tile_from_path("/16/39614/20486.png?ids=1,x").is_err());
+    }
+
+    #[test]
+    fn test_extract_debug_overlays() {
+        let tile = extract_tile_from_path("/16/39614/20486.png?debug=grid%2Cids&v=5").unwrap();
+        assert_eq!(
+            tile.debug,
+            DebugOverlays {
+                grid: true,
+                labels: false,
+                ids: true,
+            }
+        );
+
+        assert_eq!(
+            extract_tile_from_path("/16/39614/20486.png").unwrap().debug,
+            DebugOverlays::default()
+        );
+        assert!(extract_tile_from_path("/16/39614/20486.png?debug=grid,boxes").is_err());
+    }
+
+    #[test]
+    fn test_parse_wms_request() {
+        let request = parse_wms_request(
+            "/wms?SERVICE=WMS&VERSION=1.1.1&REQUEST=GetMap&LAYERS=&STYLES=&SRS=EPSG%3A3857\
+             &BBOX=-20037508.342789244,0,0,20037508.342789244&WIDTH=256&HEIGHT=128&FORMAT=image%2Fpng",
+        )
+        .unwrap();
+        assert_eq!(request.projection, Projection::WebMercator);
+        assert_eq!((request.width, request.height), (256, 128));
+        let bounds = request.bounds;
+        assert_eq!((bounds.min_lat, bounds.min_lon, bounds.max_lon), (0.0, -180.0, 0.0));
+        assert!((bounds.max_lat - 85.0511287798).abs() < 1e-9);
+
+        let in_degrees = |query: &str| parse_wms_request(&format!("/wms?request=GetMap&width=10&height=20&{}", query));
+        let expected = WmsRequest {
+            projection: Projection::PlateCarree,
+            bounds: LatLonBounds {
+                min_lat: 55.7,
+                min_lon: 37.5,
+                max_lat: 55.8,
+                max_lon: 37.7,
+            },
+            width: 10,
+            height: 20,
+        };
+        assert_eq!(in_degrees("srs=EPSG:4326&bbox=37.5,55.7,37.7,55.8").unwrap(), expected);
+        assert_eq!(
+            in_degrees("crs=CRS:84&version=1.3.0&bbox=37.5,55.7,37.7,55.8").unwrap(),
+            expected
+        );
+        assert_eq!(
+            in_degrees("crs=EPSG:4326&version=1.3.0&bbox=55.7,37.5,55.8,37.7").unwrap(),
+            expected
+        );
+
+        assert!(in_degrees("srs=EPSG:4326&bbox=37.5,55.7,37.7").is_err());
+        assert!(in_degrees("srs=EPSG:32637&bbox=37.5,55.7,37.7,55.8").is_err());
+        assert!(in_degrees("srs=EPSG:4326&bbox=37.5,55.7,37.7,55.8&format=image/jpeg").is_err());
+        assert!(parse_wms_request("/wms?REQUEST=GetCapabilities&SERVICE=WMS").is_err());
+        assert!(parse_wms_request("/wms?REQUEST=GetMap&SRS=EPSG:4326&BBOX=0,0,1,1&WIDTH=100000&HEIGHT=1").is_err());
+    }
+
+    #[test]
+    fn test_extract_projection() {
+        let tile = extract_tile_from_path("/epsg4326/15/39614/10243@2x.png").unwrap();
+        assert_eq!(tile.projection, Projection::PlateCarree);
+        assert_eq!(
+            tile.tile,
+            Tile {
+                zoom: 15,
+                x: 39614,
+                y: 10243
+            }
+        );
+        assert_eq!(tile.scale, 2);
+
+        assert_eq!(
+            extract_tile_from_path("/tiles/16/39614/20486.png").unwrap().projection,
+            Projection::WebMercator
+        );
+        assert!(extract_tile_from_path(&format!("/{}/0/0.png", MAX_ZOOM)).is_ok());
+        assert!(extract_tile_from_path(&format!("/epsg4326/{}/0/0.png", MAX_ZOOM)).is_err());
+        assert!(extract_tile_from_path("/epsg4326/0/1/0.png").is_ok());
+    }
+
+    #[test]
+    fn test_extract_svg_tile() {
+        let tile = extract_tile_from_path("/16/39614/20486@2x.svg?v=5").unwrap();
+        assert!(tile.svg);
+        assert_eq!(tile.scale, 2);
+        assert_eq!(tile.tile.y, 20486);
+        assert!(!extract_tile_from_path("/16/39614/20486.png").unwrap().svg);
+        assert!(extract_tile_from_path("/epsg4326/15/39614/10243.svg").is_err());
+        assert!(extract_tile_from_path("/16/39614/20486.svg?debug=grid").is_err());
+    }
+
+    #[test]
+    fn test_tile_coordinates_out_of_range() {
+        let status = |path: &str| match extract_tile_from_path(path) {
+            Ok(_) => "200",
+            Err(e) => &e.downcast_ref::<RequestError>().unwrap().status[..3],
+        };
+        assert_eq!(status("/18/262143/262143.png"), "200");
+        assert_eq!(status("/18/999999999/0.png"), "404");
+        assert_eq!(status("/18/0/262144.png"), "404");
+        assert_eq!(status("/0/1/0.png"), "404");
+        assert_eq!(status("/epsg4326/0/2/0.png"), "404");
+        assert_eq!(status("/19/0/0.png"), "404");
+        assert_eq!(status("/18/99999999999/0.png"), "400");
+        assert_eq!(status("/18/-1/0.png"), "400");
+        assert_eq!(status("/favicon.ico"), "400");
+        assert_eq!(status("/16/1/1@4x.png"), "200");
+        assert_eq!(status("/16/1/1@100x.png"), "400");
+        assert_eq!(status("/16/1/1@0x.png"), "400");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_pin_current_thread() {
+        let cpu_count = || unsafe {
+            let mut cpus: libc::cpu_set_t = std::mem::zeroed();
+            assert_eq!(libc::sched_getaffinity(0, std::mem::size_of_val(&cpus), &mut cpus), 0);
+            libc::CPU_COUNT(&cpus)
+        };
+        let available = cpu_count();
+        // Any thread index is fine, even if there are fewer CPUs.
+        let pinned = thread::spawn(move || {
+            pin_current_thread(available as usize + 1).unwrap();
+            cpu_count()
+        });
+        assert_eq!(pinned.join().unwrap(), 1);
+        assert_eq!(cpu_count(), available);
+    }
+}
@@ -0,0 +1,250 @@
+use crate::mapcss::parser::{
+    BinaryNumericTestType, BinaryStringTestType, ObjectType, PropertyValue, PseudoClass, Rule, Selector, Test,
+    UnaryTestType,
+};
+use crate::mapcss::styler::zoom_matches;
+use crate::render::PixelRegion;
+use crate::tile::{xy_to_coords, TILE_SIZE};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+pub const SWATCH_WIDTH: u32 = 128;
+pub const SWATCH_HEIGHT: u32 = 64;
+
+// The way nodes are never rendered on their own, so their ids must not clash with the ids of the samples.
+const FIRST_WAY_NODE_ID: u64 = 1 << 40;
+
+// A synthetic entity that matches the selector of a rule, e.g. `highway=primary` for `way[highway=primary]`.
+pub struct LegendSample {
+    pub id: u64,
+    pub selector: String,
+    pub tags: BTreeMap<String, String>,
+    object_type: SampleType,
+}
+
+#[derive(Clone, Copy)]
+enum SampleType {
+    Node,
+    Way,
+    Area,
+}
+
+// All samples are placed in the same swatch and rendered one at a time by filtering on their ids.
+pub struct Legend {
+    pub zoom: u8,
+    pub samples: Vec<LegendSample>,
+}
+
+impl Legend {
+    // Every rule gets a sample for the first of its selectors that can be drawn at this zoom level.
+    pub fn from_rules(rules: &[Rule], zoom: u8) -> Legend {
+        let samples = rules
+            .iter()
+            .filter_map(|rule| {
+                rule.selectors.iter().find_map(|selector| {
+                    // E.g. `way:closed` can't be drawn as an open way.
+                    let needs_closed_way = selector.pseudo_classes.iter().any(|test| {
+                        !test.negated && matches!(test.pseudo_class, PseudoClass::Closed | PseudoClass::Area)
+                    });
+                    let object_type = match selector.object_type {
+                        ObjectType::Node => SampleType::Node,
+                        ObjectType::Way | ObjectType::All if needs_closed_way => SampleType::Area,
+                        ObjectType::Way | ObjectType::All => SampleType::Way,
+                        ObjectType::Area => SampleType::Area,
+                        _ => return None,
+                    };
+                    if !zoom_matches(selector, zoom) {
+                        return None;
+                    }
+                    Some((selector, object_type, rule))
+                })
+            })
+            .enumerate()
+            .map(|(idx, (selector, object_type, rule))| LegendSample {
+                id: idx as u64 + 1,
+                selector: selector.to_string(),
+                tags: sample_tags(selector, rule),
+                object_type,
+            })
+            .collect();
+        Legend { zoom, samples }
+    }
+
+    // Fits into a single tile, so that every swatch is rendered from only one tile.
+    pub fn swatch_region(&self) -> PixelRegion {
+        let middle_tile = (1u32 << self.zoom) / 2;
+        PixelRegion {
+            zoom: self.zoom,
+            left: middle_tile * TILE_SIZE + (TILE_SIZE - SWATCH_WIDTH) / 2,
+            top: middle_tile * TILE_SIZE + (TILE_SIZE - SWATCH_HEIGHT) / 2,
+            width: SWATCH_WIDTH,
+            height: SWATCH_HEIGHT,
+        }
+    }
+
+    // The samples in the OSM XML format, ready to be imported.
+    pub fn to_osm_xml(&self) -> String {
+        let region = self.swatch_region();
+        let to_coords = |x: f64, y: f64| xy_to_coords(f64::from(region.left) + x, f64::from(region.top) + y, self.zoom);
+        let (width, height) = (f64::from(SWATCH_WIDTH), f64::from(SWATCH_HEIGHT));
+
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<osm version=\"0.6\">\n");
+        let mut next_way_node_id = FIRST_WAY_NODE_ID;
+        let mut ways = String::new();
+        for sample in &self.samples {
+            let points = match sample.object_type {
+                SampleType::Node => {
+                    write_node(&mut xml, sample.id, to_coords(width / 2.0, height / 2.0), &sample.tags);
+                    continue;
+                }
+                SampleType::Way => vec![(0.1, 0.5), (0.9, 0.5)],
+                SampleType::Area => vec![(0.2, 0.2), (0.8, 0.2), (0.8, 0.8), (0.2, 0.8)],
+            };
+            let first_node_id = next_way_node_id;
+            let mut node_ids = Vec::new();
+            for (x, y) in points {
+                write_node(
+                    &mut xml,
+                    next_way_node_id,
+                    to_coords(x * width, y * height),
+                    &BTreeMap::new(),
+                );
+                node_ids.push(next_way_node_id);
+                next_way_node_id += 1;
+            }
+            if let SampleType::Area = sample.object_type {
+                node_ids.push(first_node_id);
+            }
+
+            writeln!(ways, "  <way id=\"{}\">", sample.id).unwrap();
+            for node_id in node_ids {
+                writeln!(ways, "    <nd ref=\"{}\"/>", node_id).unwrap();
+            }
+            if let SampleType::Area = sample.object_type {
+                // Not every closed way is an area (e.g. `area[highway=pedestrian]` needs `area=yes`).
+                let mut tags = sample.tags.clone();
+                tags.entry("area".to_string()).or_insert_with(|| "yes".to_string());
+                write_tags(&mut ways, &tags);
+            } else {
+                write_tags(&mut ways, &sample.tags);
+            }
+            ways.push_str("  </way>\n");
+        }
+        xml.push_str(&ways);
+        xml.push_str("</osm>\n");
+        xml
+    }
+}
+
+fn sample_tags(selector: &Selector, rule: &Rule) -> BTreeMap<String, String> {
+    let mut tags = BTreeMap::new();
+    for test in &selector.tests {
+        let (tag_name, value) = match test {
+            Test::Unary {
+                tag_name,
+                test_type: UnaryTestType::Exists | UnaryTestType::True,
+            } => (tag_name, "yes".to_string()),
+            Test::BinaryStringCompare {
+                tag_name,
+                value,
+                test_type: BinaryStringTestType::Equal,
+            } => (tag_name, value.clone()),
+            Test::BinaryNumericCompare {
+                tag_name,
+                value,
+                test_type,
+            } => {
+                let value = match test_type {
+                    BinaryNumericTestType::Less => value - 1.0,
+                    BinaryNumericTestType::Greater => value + 1.0,
+                    BinaryNumericTestType::LessOrEqual | BinaryNumericTestType::GreaterOrEqual => *value,
+                };
+                (tag_name, value.to_string())
+            }
+            // Negative tests are satisfied by not adding the tag.
+            _ => continue,
+        };
+        tags.insert(tag_name.clone(), value);
+    }
+
+    // Make sure the label is drawn if the rule has one.
+    for property in rule.properties.iter().filter(|p| p.name == "text") {
+        let tag_name = match property.value {
+            PropertyValue::Identifier(ref tag_name) | PropertyValue::String(ref tag_name) => tag_name,
+            PropertyValue::Identifiers(ref tag_names) => &tag_names[0],
+            _ => continue,
+        };
+        tags.entry(tag_name.clone()).or_insert_with(|| "Sample".to_string());
+    }
+
+    tags
+}
+
+fn write_node(xml: &mut String, id: u64, (lat, lon): (f64, f64), tags: &BTreeMap<String, String>) {
+    if tags.is_empty() {
+        writeln!(xml, "  <node id=\"{}\" lat=\"{}\" lon=\"{}\"/>", id, lat, lon).unwrap();
+    } else {
+        writeln!(xml, "  <node id=\"{}\" lat=\"{}\" lon=\"{}\">", id, lat, lon).unwrap();
+        write_tags(xml, tags);
+        xml.push_str("  </node>\n");
+    }
+}
+
+fn write_tags(xml: &mut String, tags: &BTreeMap<String, String>) {
+    for (k, v) in tags {
+        writeln!(xml, "    <tag k=\"{}\" v=\"{}\"/>", escape_xml(k), escape_xml(v)).unwrap();
+    }
+}
+
+pub fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tile::coords_to_xy;
+
+    #[test]
+    fn test_legend_samples() {
+        let dir = std::env::temp_dir().join("osm_renderer_test_legend");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("legend.mapcss"),
+            "canvas { fill-color: #ffffff; }\n\
+             way|z12-[highway=primary][!tunnel] { color: #ff0000; width: 3; }\n\
+             node|z17-[amenity=cafe], area|z14[building] { text: name; }\n\
+             area[landuse=forest][\"ele\">100] { fill-color: #00ff00; }",
+        )
+        .unwrap();
+        let rules = crate::mapcss::parser::parse_file(&dir, "legend.mapcss").unwrap();
+
+        let legend = Legend::from_rules(&rules, 14);
+        let tags = |idx: usize| {
+            let sample: &LegendSample = &legend.samples[idx];
+            sample
+                .tags
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        assert_eq!(legend.samples.len(), 3);
+        assert_eq!(legend.samples[0].selector, "way|z12-[highway=primary][!tunnel]");
+        assert_eq!(tags(0), "highway=primary");
+        assert_eq!(legend.samples[1].selector, "area|z14[building]");
+        assert_eq!(tags(1), "building=yes,name=Sample");
+        assert_eq!(tags(2), "ele=101,landuse=forest");
+
+        let region = legend.swatch_region();
+        let (x, y) = coords_to_xy(&xy_to_coords(f64::from(region.left), f64::from(region.top), 14), 14);
+        assert!((x - f64::from(region.left)).abs() < 1e-6 && (y - f64::from(region.top)).abs() < 1e-6);
+
+        let xml = legend.to_osm_xml();
+        assert!(xml.contains("<way id=\"2\">"));
+        assert!(xml.contains("<tag k=\"building\" v=\"yes\"/>"));
+    }
+}
@@ -1,7 +1,12 @@
 pub mod coords;
 pub mod draw;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 pub mod geodata;
 pub mod http_server;
+pub mod legend;
 pub mod mapcss;
+pub mod overlay;
 pub mod perf_stats;
+pub mod render;
 pub mod tile;
@@ -5,6 +5,32 @@ pub struct Color {
     pub b: u8,
 }
 
+// Parses colors written in tag values, e.g. `colour=#c0ffee` or `colour=Red`.
+pub fn from_tag_value(value: &str) -> Option<Color> {
+    let value = value.trim();
+    let hex = match value.strip_prefix('#') {
+        Some(hex) => hex,
+        None => return from_color_name(&value.to_ascii_lowercase()),
+    };
+    if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let digit = |idx: usize| u8::from_str_radix(&hex[idx..=idx], 16).unwrap();
+    match hex.len() {
+        6 => Some(Color {
+            r: digit(0) * 16 + digit(1),
+            g: digit(2) * 16 + digit(3),
+            b: digit(4) * 16 + digit(5),
+        }),
+        3 => Some(Color {
+            r: digit(0) * 17,
+            g: digit(1) * 17,
+            b: digit(2) * 17,
+        }),
+        _ => None,
+    }
+}
+
 pub fn from_color_name(name: &str) -> Option<Color> {
     match name {
         "white" => Some(Color { r: 255, g: 255, b: 255 }),
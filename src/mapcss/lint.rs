@@ -0,0 +1,126 @@
+use crate::mapcss::parser::{parse_file_with_recovery, Diagnostic, PropertyValue, PseudoClass, Rule};
+use anyhow::Result;
+use std::path::Path;
+
+// Besides the properties the renderer understands, this includes the ones that are commonly found in JOSM and
+// Maps.ME stylesheets, but are ignored when drawing. Anything else is most likely a typo.
+const KNOWN_PROPERTIES: &[&str] = &[
+    "acknowledgement",
+    "antialiasing",
+    "background-color",
+    "background-opacity",
+    "blend-mode",
+    "casing-color",
+    "casing-dashes",
+    "casing-linecap",
+    "casing-linejoin",
+    "casing-opacity",
+    "casing-width",
+    "color",
+    "dashes",
+    "default-lines",
+    "default-points",
+    "description",
+    "fill-color",
+    "fill-image",
+    "fill-opacity",
+    "fill-position",
+    "font-family",
+    "font-size",
+    "font-style",
+    "font-weight",
+    "icon-anchor",
+    "icon-height",
+    "icon-image",
+    "icon-opacity",
+    "icon-width",
+    "linecap",
+    "linejoin",
+    "major-z-index",
+    "min-area",
+    "opacity",
+    "pattern-image",
+    "text",
+    "text-allow-overlap",
+    "text-anchor",
+    "text-anchor-horizontal",
+    "text-anchor-vertical",
+    "text-color",
+    "text-halo-color",
+    "text-halo-radius",
+    "text-letter-spacing",
+    "text-line-break",
+    "text-max-width",
+    "text-offset",
+    "text-offset-x",
+    "text-offset-y",
+    "text-opacity",
+    "text-position",
+    "text-spacing",
+    "text-transform",
+    "text-wrap-character",
+    "text-wrap-width",
+    "title",
+    "version",
+    "width",
+    "z-index",
+];
+
+const IMAGE_PROPERTIES: &[&str] = &["fill-image", "icon-image", "pattern-image"];
+
+// Parses the stylesheet (following @import) and reports everything that looks like a mistake in it, including
+// the syntax errors (the rules with them are skipped).
+// Icon paths are resolved relative to `base_path`, just like when drawing.
+pub fn lint_file(base_path: &Path, file_name: &str) -> Result<Vec<Diagnostic>> {
+    let (rules, mut diagnostics) = parse_file_with_recovery(base_path, file_name)?;
+    for rule in &rules {
+        lint_rule(rule, base_path, &mut diagnostics);
+    }
+    diagnostics.sort_by_key(|d| (d.file_name.clone(), d.position.line, d.position.character));
+    Ok(diagnostics)
+}
+
+fn lint_rule(rule: &Rule, base_path: &Path, diagnostics: &mut Vec<Diagnostic>) {
+    let mut report = |position, message| {
+        diagnostics.push(Diagnostic {
+            file_name: rule.file_name.clone(),
+            position,
+            message,
+        })
+    };
+
+    for selector in &rule.selectors {
+        if let (Some(min_zoom), Some(max_zoom)) = (selector.min_zoom, selector.max_zoom) {
+            if min_zoom > max_zoom {
+                report(
+                    selector.position,
+                    format!("Selector {} never matches: the zoom range is empty", selector),
+                );
+            }
+        }
+        for test in &selector.pseudo_classes {
+            if let PseudoClass::Other(ref name) = test.pseudo_class {
+                report(
+                    selector.position,
+                    format!("Unsupported pseudo-class (ignored): {}", name),
+                );
+            }
+        }
+    }
+
+    for property in &rule.properties {
+        if !KNOWN_PROPERTIES.contains(&property.name.as_str()) {
+            report(property.position, format!("Unknown property: {}", property.name));
+        }
+        if IMAGE_PROPERTIES.contains(&property.name.as_str()) {
+            if let PropertyValue::String(ref image) | PropertyValue::Identifier(ref image) = property.value {
+                if !base_path.join(image).is_file() {
+                    report(
+                        property.position,
+                        format!("Missing image file for {}: {}", property.name, image),
+                    );
+                }
+            }
+        }
+    }
+}
@@ -1,5 +1,7 @@
 pub mod color;
+pub mod lint;
 pub mod parser;
+mod rule_index;
 mod style_cache;
 pub mod styler;
 pub mod token;
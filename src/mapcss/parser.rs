@@ -9,7 +9,10 @@ use std::fs::File;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 
-#[derive(Debug)]
+// The pseudo-tag of `Test::Id`.
+const ID_TEST_NAME: &str = "osm_id";
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum ObjectType {
     All,
     Canvas,
@@ -17,6 +20,7 @@ pub enum ObjectType {
     Node,
     Way,
     Area,
+    Relation,
 }
 
 impl fmt::Display for ObjectType {
@@ -28,12 +32,13 @@ impl fmt::Display for ObjectType {
             ObjectType::Node => "node",
             ObjectType::Way => "way",
             ObjectType::Area => "area",
+            ObjectType::Relation => "relation",
         };
         write!(f, "{}", object_type)
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum UnaryTestType {
     Exists,
     NotExists,
@@ -41,13 +46,13 @@ pub enum UnaryTestType {
     False,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum BinaryStringTestType {
     Equal,
     NotEqual,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum BinaryNumericTestType {
     Less,
     LessOrEqual,
@@ -55,7 +60,7 @@ pub enum BinaryNumericTestType {
     GreaterOrEqual,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Test {
     Unary {
         tag_name: String,
@@ -71,6 +76,11 @@ pub enum Test {
         value: f64,
         test_type: BinaryNumericTestType,
     },
+    // `[osm_id=123]` compares the OSM id of the entity instead of a tag, like in JOSM.
+    Id {
+        id: u64,
+        test_type: BinaryStringTestType,
+    },
 }
 
 impl fmt::Display for Test {
@@ -116,18 +126,31 @@ impl fmt::Display for Test {
                 };
                 format!("{}{}{}", quote(tag_name), sign, value)
             }
+            Test::Id { id, ref test_type } => {
+                let sign = match *test_type {
+                    BinaryStringTestType::Equal => "=",
+                    BinaryStringTestType::NotEqual => "!=",
+                };
+                format!("{}{}{}", ID_TEST_NAME, sign, id)
+            }
         };
         write!(f, "[{}]", result)
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum PropertyValue {
     Identifier(String),
+    // `name, ref`, e.g. the tag keys for `text` tried in this order.
+    Identifiers(Vec<String>),
     String(String),
     Color(Color),
     Numbers(Vec<f64>),
     WidthDelta(f64),
+    // (zoom, value) pairs sorted by zoom; the value is interpolated linearly between the zoom levels.
+    ZoomStops(Vec<(u8, f64)>),
+    // `tag("colour")`, i.e. the value of the tag of the entity being styled.
+    TagValue(String),
 }
 
 impl fmt::Display for PropertyValue {
@@ -135,34 +158,110 @@ impl fmt::Display for PropertyValue {
         match *self {
             PropertyValue::Color(Color { r, g, b }) => write!(f, "#{:02x}{:02x}{:02x}", r, g, b),
             PropertyValue::Identifier(ref id) => write!(f, "{}", id),
+            PropertyValue::Identifiers(ref ids) => write!(f, "{}", ids.join(", ")),
             PropertyValue::String(ref s) => write!(f, "\"{}\"", s),
             PropertyValue::Numbers(ref nums) => {
                 write!(f, "{}", nums.iter().map(fmt_item::<f64>).collect::<Vec<_>>().join(","))
             }
             PropertyValue::WidthDelta(ref delta) => write!(f, "eval(prop(\"width\")) + {}", delta),
+            PropertyValue::TagValue(ref tag_name) => write!(f, "tag(\"{}\")", tag_name),
+            PropertyValue::ZoomStops(ref stops) => write!(
+                f,
+                "{}",
+                stops
+                    .iter()
+                    .map(|(zoom, value)| format!("{}@{}", value, zoom))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Property {
     pub name: String,
     pub value: PropertyValue,
+    pub position: InputPosition,
+    // `width: 3 !important;` can't be overridden by the properties without `!important` (see `Styler::style_area()`).
+    pub important: bool,
+}
+
+impl Property {
+    pub fn new(name: &str, value: PropertyValue) -> Property {
+        Property {
+            name: name.to_string(),
+            value,
+            position: InputPosition::default(),
+            important: false,
+        }
+    }
 }
 
 impl fmt::Display for Property {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}: {};", self.name, self.value)
+        let importance = if self.important { " !important" } else { "" };
+        write!(f, "{}: {}{};", self.name, self.value, importance)
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
+pub enum PseudoClass {
+    // Closed ways and multipolygons.
+    Closed,
+    // The ways and the relations that aren't closed.
+    Unclosed,
+    // The objects with any tags.
+    Tagged,
+    // The objects matched by `area` selectors.
+    Area,
+    // The pseudo-classes that make sense only in an editor (e.g. `:selected`) are parsed, but ignored.
+    Other(String),
+}
+
+// `:closed` in `way[highway]:closed`, or `!:closed`, which matches the opposite.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PseudoClassTest {
+    pub pseudo_class: PseudoClass,
+    pub negated: bool,
+}
+
+impl fmt::Display for PseudoClassTest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self.pseudo_class {
+            PseudoClass::Closed => "closed",
+            PseudoClass::Unclosed => "unclosed",
+            PseudoClass::Tagged => "tagged",
+            PseudoClass::Area => "area",
+            PseudoClass::Other(ref name) => name,
+        };
+        write!(f, "{}:{}", if self.negated { "!" } else { "" }, name)
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct Selector {
     pub object_type: ObjectType,
     pub min_zoom: Option<u8>,
     pub max_zoom: Option<u8>,
     pub tests: Vec<Test>,
+    pub pseudo_classes: Vec<PseudoClassTest>,
     pub layer_id: Option<String>,
+    pub position: InputPosition,
+}
+
+impl Selector {
+    pub fn new(object_type: ObjectType) -> Selector {
+        Selector {
+            object_type,
+            min_zoom: None,
+            max_zoom: None,
+            tests: Vec::new(),
+            pseudo_classes: Vec::new(),
+            layer_id: None,
+            position: InputPosition::default(),
+        }
+    }
 }
 
 impl fmt::Display for Selector {
@@ -185,20 +284,37 @@ impl fmt::Display for Selector {
         };
         write!(
             f,
-            "{}{}{}{}{}",
+            "{}{}{}{}{}{}",
             self.object_type,
             if formatted_zoom_range.is_empty() { "" } else { "|z" },
             formatted_zoom_range,
             self.tests.iter().map(fmt_item::<Test>).collect::<Vec<_>>().join(""),
+            self.pseudo_classes
+                .iter()
+                .map(fmt_item::<PseudoClassTest>)
+                .collect::<Vec<_>>()
+                .join(""),
             formatted_layer_id
         )
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Rule {
     pub selectors: Vec<Selector>,
     pub properties: Vec<Property>,
+    // The file the rule comes from, which is not necessarily the top-level stylesheet because of @import.
+    pub file_name: String,
+}
+
+impl Rule {
+    pub fn new(selectors: Vec<Selector>, properties: Vec<Property>) -> Rule {
+        Rule {
+            selectors,
+            properties,
+            file_name: String::new(),
+        }
+    }
 }
 
 impl fmt::Display for Rule {
@@ -220,84 +336,198 @@ impl fmt::Display for Rule {
     }
 }
 
+// Something suspicious in a stylesheet that doesn't prevent it from being parsed.
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub file_name: String,
+    pub position: InputPosition,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.file_name, self.position.line, self.position.character, self.message
+        )
+    }
+}
+
+// The output can be parsed back into the same rules (minus the positions and constant definitions),
+// so it can be used to save the rules after changing them.
+pub fn format_rules(rules: &[Rule]) -> String {
+    rules.iter().map(fmt_item::<Rule>).collect::<Vec<_>>().join("\n\n")
+}
+
 pub fn parse_file(base_path: &Path, file_name: &str) -> Result<Vec<Rule>> {
+    parse_file_with_diagnostics(base_path, file_name).map(|(rules, _)| rules)
+}
+
+pub fn parse_file_with_diagnostics(base_path: &Path, file_name: &str) -> Result<(Vec<Rule>, Vec<Diagnostic>)> {
+    parse_file_impl(base_path, file_name, true)
+}
+
+// Unlike `parse_file_with_diagnostics()`, doesn't stop at the first syntax error. The rule (or the `@` definition)
+// with the error is skipped up to its closing `}` (or `;`), and the error is reported as a diagnostic instead.
+pub fn parse_file_with_recovery(base_path: &Path, file_name: &str) -> Result<(Vec<Rule>, Vec<Diagnostic>)> {
+    parse_file_impl(base_path, file_name, false)
+}
+
+fn parse_file_impl(base_path: &Path, file_name: &str, strict: bool) -> Result<(Vec<Rule>, Vec<Diagnostic>)> {
     let content = read_stylesheet(base_path, file_name)?;
     let mut parser = Parser {
         tokenizer: Tokenizer::new(&content),
         base_path: base_path.to_owned(),
         file_name: file_name.to_string(),
-        color_defs: ColorDefs::default(),
+        constants: Constants::default(),
+        diagnostics: Vec::new(),
+        strict,
+        last_token: None,
     };
-    parser.parse()
+    let rules = parser.parse()?;
+    Ok((rules, parser.diagnostics))
 }
 
-type ColorDefs = HashMap<String, Color>;
+// The values of `@name: value;` definitions, which can be used instead of the property values.
+type Constants = HashMap<String, PropertyValue>;
 
 struct Parser<'a> {
     tokenizer: Tokenizer<'a>,
     base_path: PathBuf,
     file_name: String,
-    color_defs: ColorDefs,
+    constants: Constants,
+    diagnostics: Vec<Diagnostic>,
+    // Whether to fail on the first error instead of skipping the statement with the error.
+    strict: bool,
+    // The last successfully read token, used to find where the statement with an error ends.
+    last_token: Option<Token<'a>>,
 }
 
 impl<'a> Parser<'a> {
     pub fn parse(&mut self) -> Result<Vec<Rule>> {
         let mut result = Vec::new();
-        loop {
-            match self.read_optional_token() {
-                None => break,
-                Some(token_or_err) => {
-                    let token = token_or_err?;
-                    match token.token {
-                        Token::Import(imported_file) => {
-                            self.expect_simple_token(&Token::SemiColon)?;
-                            let (rules, color_defs) = self.import_file(imported_file)?;
-                            result.extend(rules);
-                            self.color_defs.extend(color_defs);
-                        }
-                        Token::ColorRef(color_name) => self.read_color_def(color_name)?,
-                        _ => result.push(self.read_rule(token)?),
-                    }
+        while let Some(token_or_err) = self.read_optional_token() {
+            let (statement_end, statement_position, statement) = match token_or_err {
+                Ok(token) => {
+                    let statement_end = match token.token {
+                        Token::Import(_) | Token::ConstantRef(_) => Token::SemiColon,
+                        _ => Token::RightBrace,
+                    };
+                    (statement_end, token.position, self.read_statement(token, &mut result))
+                }
+                Err(err) => (Token::RightBrace, self.tokenizer.position(), Err(err)),
+            };
+            if let Err(err) = statement {
+                if self.strict {
+                    return Err(err);
                 }
+                self.skip_statement(&err, &statement_end, statement_position);
             }
         }
         Ok(result)
     }
 
-    fn import_file(&mut self, file_name: &str) -> Result<(Vec<Rule>, ColorDefs)> {
+    fn read_statement(&mut self, token: TokenWithPosition<'a>, rules: &mut Vec<Rule>) -> Result<()> {
+        match token.token {
+            Token::Import(imported_file) => {
+                self.expect_simple_token(&Token::SemiColon)?;
+                let imported = self.import_file(imported_file)?;
+                rules.extend(imported.rules);
+                self.constants.extend(imported.constants);
+                self.diagnostics.extend(imported.diagnostics);
+            }
+            Token::ConstantRef(name) => self.read_constant_def(name, token.position)?,
+            _ => rules.push(self.read_rule(token)?),
+        }
+        Ok(())
+    }
+
+    // Reports the error and skips the tokens up to `statement_end`, unless it's the token the error is about.
+    fn skip_statement(&mut self, err: &Error, statement_end: &Token<'static>, statement_position: InputPosition) {
+        let (message, position) = match err.downcast_ref::<MapcssError>() {
+            Some(MapcssError::LexerError { message, pos }) | Some(MapcssError::ParseError { message, pos, .. }) => {
+                (message.clone(), *pos)
+            }
+            // E.g. a missing imported file.
+            None => (format!("{:#}", err), statement_position),
+        };
+        self.diagnostics.push(Diagnostic {
+            file_name: self.file_name.clone(),
+            position,
+            message: format!("{} (skipped until the next '{}')", message, statement_end),
+        });
+
+        if self.last_token.as_ref() == Some(statement_end) {
+            return;
+        }
+        while let Some(token_or_err) = self.read_optional_token() {
+            // The errors in the skipped part are not reported.
+            if token_or_err.is_ok_and(|token| token.token == *statement_end) {
+                break;
+            }
+        }
+    }
+
+    fn import_file(&mut self, file_name: &str) -> Result<ImportedFile> {
         let content = read_stylesheet(&self.base_path, file_name)?;
         let mut parser = Parser {
             tokenizer: Tokenizer::new(&content),
             base_path: self.base_path.clone(),
             file_name: file_name.to_string(),
-            color_defs: self.color_defs.clone(),
+            constants: self.constants.clone(),
+            diagnostics: Vec::new(),
+            strict: self.strict,
+            last_token: None,
         };
-        let imported_rules = parser.parse()?;
-        Ok((imported_rules, parser.color_defs))
+        let rules = parser.parse()?;
+        Ok(ImportedFile {
+            rules,
+            constants: parser.constants,
+            diagnostics: parser.diagnostics,
+        })
     }
 
-    fn read_color_def(&mut self, color_name: &str) -> Result<()> {
+    fn read_constant_def(&mut self, name: &str, position: InputPosition) -> Result<()> {
         self.expect_simple_token(&Token::Colon)?;
-        let color_value = {
-            let color_value_token = self.read_mandatory_token()?;
-            match color_value_token.token {
-                Token::Color(color) => Some(color),
-                // Don't add unknown values to the color definitions,
-                // but don't fail the parsing process either.
-                _ => None,
+        let value_token = self.read_mandatory_token()?;
+        let value = match value_token.token {
+            // Also consumes the semicolon.
+            Token::Number(num) => match self.read_number_list(num)? {
+                (value, false) => Some(value),
+                (_, true) => return Err(self.parse_error("Definitions can't be !important", position)),
+            },
+            token => {
+                self.expect_simple_token(&Token::SemiColon)?;
+                match token {
+                    Token::Color(color) => Some(PropertyValue::Color(color)),
+                    Token::String(s) => Some(PropertyValue::String(s.to_string())),
+                    // Don't add unknown values to the definitions, but don't fail the parsing process either.
+                    _ => None,
+                }
             }
         };
-        self.expect_simple_token(&Token::SemiColon)?;
-        if let Some(val) = color_value {
-            self.color_defs.insert(color_name.to_string(), val);
+        if let Some(value) = value {
+            let kind = if let PropertyValue::Color(_) = value {
+                "Color"
+            } else {
+                "Constant"
+            };
+            if self.constants.insert(name.to_string(), value).is_some() {
+                self.diagnostics.push(Diagnostic {
+                    file_name: self.file_name.clone(),
+                    position,
+                    message: format!("{} @{} is defined more than once", kind, name),
+                });
+            }
         }
         Ok(())
     }
 
     fn read_rule(&mut self, mut selector_start: TokenWithPosition<'a>) -> Result<Rule> {
         let mut rule = Rule {
-            selectors: Vec::new(),
-            properties: Vec::new(),
+            file_name: self.file_name.clone(),
+            ..Rule::new(Vec::new(), Vec::new())
         };
 
         loop {
@@ -335,11 +565,8 @@ impl<'a> Parser<'a> {
                     self.parse_error(format!("Unknown object type: {}", id), selector_first_token.position)
                 })?;
                 Selector {
-                    object_type,
-                    min_zoom: None,
-                    max_zoom: None,
-                    tests: Vec::new(),
-                    layer_id: None,
+                    position: selector_first_token.position,
+                    ..Selector::new(object_type)
                 }
             }
             _ => return self.unexpected_token(selector_first_token),
@@ -364,9 +591,11 @@ impl<'a> Parser<'a> {
                     selector.tests.push(self.read_test()?);
                 }
                 Token::Colon => {
-                    // This is a pseudo-class. Even though we don't use them,
-                    // we still have to parse them correctly.
-                    self.read_identifier()?;
+                    selector.pseudo_classes.push(self.read_pseudo_class(false)?);
+                }
+                Token::Bang => {
+                    self.expect_simple_token(&Token::Colon)?;
+                    selector.pseudo_classes.push(self.read_pseudo_class(true)?);
                 }
                 Token::DoubleColon => {
                     selector.layer_id = Some(self.read_identifier()?);
@@ -383,6 +612,17 @@ impl<'a> Parser<'a> {
         }
     }
 
+    fn read_pseudo_class(&mut self, negated: bool) -> Result<PseudoClassTest> {
+        let pseudo_class = match self.read_identifier()?.as_str() {
+            "closed" => PseudoClass::Closed,
+            "unclosed" => PseudoClass::Unclosed,
+            "tagged" => PseudoClass::Tagged,
+            "area" => PseudoClass::Area,
+            other => PseudoClass::Other(other.to_string()),
+        };
+        Ok(PseudoClassTest { pseudo_class, negated })
+    }
+
     fn read_test(&mut self) -> Result<Test> {
         let mut starts_with_bang = false;
 
@@ -416,6 +656,17 @@ impl<'a> Parser<'a> {
                     _ => return self.unexpected_token(&current_token),
                 };
 
+                if lhs == ID_TEST_NAME {
+                    let id = rhs
+                        .parse()
+                        .map_err(|_| self.parse_error(format!("Invalid OSM id: {}", rhs), current_token.position))?;
+                    self.expect_simple_token(&Token::RightBracket)?;
+                    return Ok(Test::Id {
+                        id,
+                        test_type: binary_op,
+                    });
+                }
+
                 self.expect_simple_token(&Token::RightBracket)?;
 
                 return Ok(Test::BinaryStringCompare {
@@ -484,9 +735,11 @@ impl<'a> Parser<'a> {
             match token.token {
                 Token::Identifier(id) => {
                     self.expect_simple_token(&Token::Colon)?;
+                    let (value, important) = self.read_property_value()?;
                     result.push(Property {
-                        name: String::from(id),
-                        value: self.read_property_value()?,
+                        position: token.position,
+                        important,
+                        ..Property::new(id, value)
                     });
                 }
                 Token::RightBrace => break,
@@ -496,63 +749,84 @@ impl<'a> Parser<'a> {
         Ok(result)
     }
 
-    fn read_property_value(&mut self) -> Result<PropertyValue> {
+    // Returns the value and whether it's marked with `!important`.
+    fn read_property_value(&mut self) -> Result<(PropertyValue, bool)> {
         let token = self.read_mandatory_token()?;
-        let mut expect_semicolon = true;
-        let result = match token.token {
-            Token::Identifier(id) => {
-                expect_semicolon = false;
-                match id {
-                    "eval" => self.read_simple_eval(token.position)?,
-                    _ => {
-                        let mut full_id = id.to_string();
-                        let token = self.read_mandatory_token()?;
-                        match token.token {
-                            Token::Colon => {
-                                full_id.push(':');
-                                full_id.push_str(&self.read_identifier()?);
-                                self.expect_simple_token(&Token::SemiColon)?;
-                            }
-                            Token::SemiColon => {}
-                            _ => return self.unexpected_token(&token),
-                        }
-                        PropertyValue::Identifier(full_id)
-                    }
-                }
-            }
+        let value = match token.token {
+            Token::Identifier(id) => match id {
+                "eval" => return self.read_simple_eval(token.position),
+                "tag" => self.read_tag_value()?,
+                _ => return self.read_identifier_list(id),
+            },
             Token::String(s) => PropertyValue::String(String::from(s)),
             Token::Color(color) => PropertyValue::Color(color),
-            Token::ColorRef(color_name) => match self.color_defs.get(color_name) {
-                Some(color) => PropertyValue::Color(color.clone()),
+            Token::ConstantRef(name) => match self.constants.get(name) {
+                Some(value) => value.clone(),
                 None => {
                     return Err(self.parse_error(
-                        format!("Unknown color reference: {}", color_name),
+                        format!("Unknown constant reference: @{}", name),
                         self.tokenizer.position(),
                     ));
                 }
             },
-            Token::Number(num) => {
-                expect_semicolon = false;
-                PropertyValue::Numbers(self.read_number_list(num)?)
-            }
+            Token::Number(num) => return self.read_number_list(num),
             _ => return self.unexpected_token(&token)?,
         };
-        if expect_semicolon {
-            self.expect_simple_token(&Token::SemiColon)?;
+        let token = self.read_mandatory_token()?;
+        match token.token {
+            Token::SemiColon => Ok((value, false)),
+            Token::Bang => Ok((value, self.read_importance()?)),
+            _ => self.unexpected_token(&token),
+        }
+    }
+
+    // Identifiers like `name:en`, optionally separated by commas.
+    fn read_identifier_list(&mut self, first_id: &str) -> Result<(PropertyValue, bool)> {
+        let mut ids = vec![first_id.to_string()];
+        let important = loop {
+            let token = self.read_mandatory_token()?;
+            match token.token {
+                Token::Colon => {
+                    let id = self.read_identifier()?;
+                    let last_id = ids.last_mut().unwrap();
+                    last_id.push(':');
+                    last_id.push_str(&id);
+                }
+                Token::Comma => ids.push(self.read_identifier()?),
+                Token::SemiColon => break false,
+                Token::Bang => break self.read_importance()?,
+                _ => return self.unexpected_token(&token),
+            }
+        };
+        let value = if ids.len() == 1 {
+            PropertyValue::Identifier(ids.pop().unwrap())
+        } else {
+            PropertyValue::Identifiers(ids)
+        };
+        Ok((value, important))
+    }
+
+    // The rest of ` !important;` after the `!`.
+    fn read_importance(&mut self) -> Result<bool> {
+        let token = self.read_mandatory_token()?;
+        if token.token != Token::Identifier("important") {
+            return self.unexpected_token(&token);
         }
-        Ok(result)
+        self.expect_simple_token(&Token::SemiColon)?;
+        Ok(true)
     }
 
     // Support the only form of eval() used in Maps.ME: eval(prop("width") + X);
-    fn read_simple_eval(&mut self, position: InputPosition) -> Result<PropertyValue> {
+    fn read_simple_eval(&mut self, position: InputPosition) -> Result<(PropertyValue, bool)> {
         let mut tokens = Vec::new();
-        loop {
+        let important = loop {
             let token = self.read_mandatory_token()?;
             match token.token {
-                Token::SemiColon => break,
+                Token::SemiColon => break false,
+                Token::Bang => break self.read_importance()?,
                 token => tokens.push(token),
             }
-        }
+        };
         let expected_prefix = [
             Token::LeftParen,
             Token::Identifier("prop"),
@@ -581,29 +855,57 @@ impl<'a> Parser<'a> {
         };
 
         match width_increment {
-            Some(num) => Ok(PropertyValue::WidthDelta(num)),
+            Some(num) => Ok((PropertyValue::WidthDelta(num), important)),
             _ => Err(self.parse_error("Unknown eval(...) form", position)),
         }
     }
 
-    fn read_number_list(&mut self, first_num: f64) -> Result<Vec<f64>> {
+    // Either a plain list of numbers or a list of `value@zoom` stops.
+    fn read_tag_value(&mut self) -> Result<PropertyValue> {
+        self.expect_simple_token(&Token::LeftParen)?;
+        let token = self.read_mandatory_token()?;
+        let tag_name = match token.token {
+            Token::String(s) | Token::Identifier(s) => s.to_string(),
+            _ => return self.unexpected_token(&token),
+        };
+        self.expect_simple_token(&Token::RightParen)?;
+        Ok(PropertyValue::TagValue(tag_name))
+    }
+
+    fn read_number_list(&mut self, first_num: f64) -> Result<(PropertyValue, bool)> {
         let mut numbers = vec![first_num];
+        let mut zooms = Vec::new();
         let mut consumed_number = true;
-        loop {
+        let important = loop {
             let next_token = self.read_mandatory_token()?;
             match next_token.token {
+                Token::ZoomStop(zoom) if consumed_number && zooms.len() + 1 == numbers.len() => {
+                    zooms.push(zoom);
+                }
                 Token::Comma if consumed_number => {
                     consumed_number = false;
                 }
-                Token::SemiColon if consumed_number => break,
+                Token::SemiColon if consumed_number => break false,
+                Token::Bang if consumed_number => break self.read_importance()?,
                 Token::Number(next_num) if !consumed_number => {
                     consumed_number = true;
                     numbers.push(next_num);
                 }
                 _ => return self.unexpected_token(&next_token),
             }
+        };
+        if zooms.is_empty() {
+            return Ok((PropertyValue::Numbers(numbers), important));
+        }
+        if zooms.len() != numbers.len() {
+            return Err(self.parse_error(
+                "Either all or none of the numbers should have a zoom level",
+                self.tokenizer.position(),
+            ));
         }
-        Ok(numbers)
+        let mut stops = zooms.into_iter().zip(numbers).collect::<Vec<_>>();
+        stops.sort_by_key(|&(zoom, _)| zoom);
+        Ok((PropertyValue::ZoomStops(stops), important))
     }
 
     fn read_identifier(&mut self) -> Result<String> {
@@ -622,10 +924,14 @@ impl<'a> Parser<'a> {
     }
 
     fn read_optional_token(&mut self) -> Option<Result<TokenWithPosition<'a>>> {
-        self.tokenizer.next().map(|x| {
-            x.context(format!("Failed to tokenize {}", self.file_name))
-                .map_err(Error::from)
-        })
+        let token = self
+            .tokenizer
+            .next()
+            .map(|x| x.context(format!("Failed to tokenize {}", self.file_name)));
+        if let Some(Ok(token)) = &token {
+            self.last_token = Some(token.token.clone());
+        }
+        token
     }
 
     fn expect_simple_token(&mut self, expected: &Token<'static>) -> Result<()> {
@@ -671,10 +977,17 @@ fn id_to_object_type(id: &str) -> Option<ObjectType> {
         "node" => Some(ObjectType::Node),
         "way" | "line" => Some(ObjectType::Way),
         "area" => Some(ObjectType::Area),
+        "relation" => Some(ObjectType::Relation),
         _ => None,
     }
 }
 
+struct ImportedFile {
+    rules: Vec<Rule>,
+    constants: Constants,
+    diagnostics: Vec<Diagnostic>,
+}
+
 struct ConsumedSelector {
     selector: Selector,
     expect_more_selectors: bool,
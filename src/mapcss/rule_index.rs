@@ -0,0 +1,104 @@
+use crate::geodata::reader::Tags;
+use crate::mapcss::parser::{BinaryStringTestType, Rule, Selector, Test, UnaryTestType};
+use std::collections::HashMap;
+
+// Most selectors require some tag to be present (`[highway]`, `[building=yes]`, `[population>1000]`), so they can
+// only match entities that have it. The index maps every such tag key to the rules that need it; an entity is then
+// checked only against the rules for its own keys and the few generic ones (e.g. `canvas` or `node[!name]`).
+pub struct RuleIndex {
+    by_key: HashMap<String, Vec<usize>>,
+    generic: Vec<usize>,
+}
+
+impl RuleIndex {
+    pub fn new(rules: &[Rule]) -> RuleIndex {
+        let mut by_key: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut generic = Vec::new();
+        for (idx, rule) in rules.iter().enumerate() {
+            let required_keys = rule.selectors.iter().map(required_key).collect::<Option<Vec<_>>>();
+            match required_keys {
+                Some(keys) if !keys.is_empty() => {
+                    for key in keys {
+                        let rule_ids = by_key.entry(key.to_string()).or_default();
+                        if rule_ids.last() != Some(&idx) {
+                            rule_ids.push(idx);
+                        }
+                    }
+                }
+                _ => generic.push(idx),
+            }
+        }
+        RuleIndex { by_key, generic }
+    }
+
+    // The indices of the rules that can match an entity with these tags, in the stylesheet order.
+    pub fn candidates(&self, tags: &Tags<'_>) -> Vec<usize> {
+        let mut result = self.generic.clone();
+        for (key, _) in tags.iter() {
+            if let Some(rule_ids) = self.by_key.get(key.str) {
+                result.extend_from_slice(rule_ids);
+            }
+        }
+        result.sort_unstable();
+        result.dedup();
+        result
+    }
+}
+
+// A tag that has to be present for the selector to match.
+fn required_key(selector: &Selector) -> Option<&str> {
+    selector.tests.iter().find_map(|test| match test {
+        Test::Unary {
+            tag_name,
+            test_type: UnaryTestType::Exists | UnaryTestType::True,
+        }
+        | Test::BinaryStringCompare {
+            tag_name,
+            test_type: BinaryStringTestType::Equal,
+            ..
+        }
+        | Test::BinaryNumericCompare { tag_name, .. } => Some(tag_name.as_str()),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geodata::reader::{GeodataReader, OsmEntity};
+
+    #[test]
+    fn test_rule_candidates() {
+        let dir = std::env::temp_dir().join("osm_renderer_test_rule_index");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("index.mapcss"),
+            "canvas { fill-color: #ffffff; }\n\
+             node[amenity=cafe] { z-index: 1; }\n\
+             node[!name] { z-index: 2; }\n\
+             node[shop], node[\"population\">1000] { z-index: 3; }\n\
+             node[name][amenity!=cafe] { z-index: 4; }",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("index.osm"),
+            "<osm><node id=\"1\" lat=\"55.75\" lon=\"37.61\"><tag k=\"amenity\" v=\"cafe\"/><tag k=\"name\" v=\"A\"/>\
+             </node><node id=\"2\" lat=\"55.75\" lon=\"37.62\"><tag k=\"population\" v=\"5000\"/></node></osm>",
+        )
+        .unwrap();
+        let rules = crate::mapcss::parser::parse_file(&dir, "index.mapcss").unwrap();
+        let index = RuleIndex::new(&rules);
+        assert_eq!(index.generic, vec![0, 2]);
+
+        let bin_file = dir.join("index.bin").to_string_lossy().into_owned();
+        crate::geodata::importer::import(dir.join("index.osm").to_string_lossy().as_ref(), &bin_file).unwrap();
+        let reader = GeodataReader::load(&bin_file).unwrap();
+        let entities = reader.get_entities_in_bbox(55.7, 37.6, 55.8, 37.7, &None);
+        let candidates = |id| {
+            let node = entities.nodes.iter().find(|n| n.global_id() == id).unwrap();
+            index.candidates(&node.tags())
+        };
+        assert_eq!(candidates(1), vec![0, 1, 2, 4]);
+        assert_eq!(candidates(2), vec![0, 2, 3]);
+    }
+}
@@ -1,22 +1,50 @@
 use crate::geodata::reader::OsmEntity;
+use crate::mapcss::parser::PropertyValue;
 use crate::mapcss::parser::Rule;
 use crate::mapcss::parser::Test;
 use crate::mapcss::parser::UnaryTestType;
-use crate::mapcss::styler::CacheableEntity;
 use crate::mapcss::styler::Style;
+use crate::mapcss::styler::{area_tag_keys, CacheableEntity};
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
 use std::sync::Arc;
 
+pub const DEFAULT_STYLE_CACHE_CAPACITY: usize = 10_000;
+
 #[derive(Hash, Eq, PartialEq)]
 struct StyleCacheKey {
-    cache_slot: usize,
+    // The tags are identified by the offsets of their strings, which are different in every geodata file.
+    data_id: usize,
     tags: Vec<usize>,
-    zoom: u8,
+    // Even if none of the tags matter, `:tagged` tells the entities without tags from the other ones.
+    is_tagged: bool,
+}
+
+struct CachedStyles {
+    styles: Vec<Arc<Style>>,
+    // The value of `StyleCache::clock` when these styles were last accessed.
+    last_used: AtomicU64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct StyleCacheStats {
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
 }
 
 pub struct StyleCache {
-    cache: HashMap<StyleCacheKey, Vec<Arc<Style>>>,
+    // There is a separate cache for every (cache slot, zoom) pair, so that e.g. lots of distinct nodes
+    // at one zoom level don't evict the styles for everything else.
+    caches: HashMap<(usize, u8), HashMap<StyleCacheKey, CachedStyles>>,
     tag_value_matters: HashMap<String, bool>,
+    capacity: usize,
+    // `get()` is called under a read lock, so everything it updates is atomic.
+    clock: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: u64,
 }
 
 impl StyleCache {
@@ -24,6 +52,9 @@ impl StyleCache {
         let mut tag_value_matters = HashMap::new();
 
         tag_value_matters.insert("layer".to_string(), true);
+        for key in area_tag_keys() {
+            tag_value_matters.insert(key.to_string(), true);
+        }
 
         for r in rules.iter() {
             for sel in r.selectors.iter() {
@@ -38,16 +69,37 @@ impl StyleCache {
                         }
                         Test::BinaryStringCompare { ref tag_name, .. } => (tag_name, true),
                         Test::BinaryNumericCompare { ref tag_name, .. } => (tag_name, true),
+                        // The styles of these entities are never cached (see `Styler::styled_ids`).
+                        Test::Id { .. } => continue,
                     };
 
                     *tag_value_matters.entry(tag_name.clone()).or_default() |= value_matters;
                 }
             }
+            // The styles depend on the values of the tags used as property values.
+            for prop in r.properties.iter() {
+                if let PropertyValue::TagValue(ref tag_name) = prop.value {
+                    tag_value_matters.insert(tag_name.clone(), true);
+                }
+            }
         }
 
         StyleCache {
-            cache: HashMap::default(),
+            caches: HashMap::default(),
             tag_value_matters,
+            capacity: DEFAULT_STYLE_CACHE_CAPACITY,
+            clock: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: 0,
+        }
+    }
+
+    // The maximum number of entries for every (cache slot, zoom) pair.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+        for cache in self.caches.values_mut() {
+            self.evictions += evict_least_recently_used(cache, self.capacity);
         }
     }
 
@@ -55,22 +107,60 @@ impl StyleCache {
     where
         E: CacheableEntity + OsmEntity<'e>,
     {
-        self.cache.get(&self.to_cache_key(entity, zoom)).cloned()
+        let cached = self
+            .caches
+            .get(&(entity.cache_slot(), zoom))
+            .and_then(|cache| cache.get(&self.to_cache_key(entity)));
+        match cached {
+            Some(cached) => {
+                cached.last_used.store(self.tick(), Ordering::Relaxed);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(cached.styles.clone())
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
     }
 
     pub fn insert<'e, E>(&mut self, entity: &E, zoom: u8, styles: Vec<Arc<Style>>)
     where
         E: CacheableEntity + OsmEntity<'e>,
     {
-        self.cache.insert(self.to_cache_key(entity, zoom), styles);
+        let key = self.to_cache_key(entity);
+        let cached = CachedStyles {
+            styles,
+            last_used: AtomicU64::new(self.tick()),
+        };
+        let cache = self.caches.entry((entity.cache_slot(), zoom)).or_default();
+        cache.insert(key, cached);
+        if cache.len() > self.capacity {
+            // Evicting a quarter of the entries at once keeps the amortized cost of an insertion low.
+            self.evictions += evict_least_recently_used(cache, self.capacity - self.capacity / 4);
+        }
+    }
+
+    pub fn stats(&self) -> StyleCacheStats {
+        StyleCacheStats {
+            entries: self.caches.values().map(HashMap::len).sum(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions,
+        }
     }
 
-    fn to_cache_key<'e, E>(&self, entity: &E, zoom: u8) -> StyleCacheKey
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn to_cache_key<'e, E>(&self, entity: &E) -> StyleCacheKey
     where
         E: CacheableEntity + OsmEntity<'e>,
     {
         let mut tags = Vec::new();
-        for (k, v) in entity.tags().iter() {
+        let entity_tags = entity.tags();
+        for (k, v) in entity_tags.iter() {
             if let Some(value_matters) = self.tag_value_matters.get(k.str) {
                 tags.push(k.offset);
                 if *value_matters {
@@ -80,9 +170,58 @@ impl StyleCache {
         }
 
         StyleCacheKey {
-            cache_slot: entity.cache_slot(),
+            data_id: entity_tags.data_id(),
             tags,
-            zoom,
+            is_tagged: !entity_tags.is_empty(),
+        }
+    }
+}
+
+// Whether the entities without tags get any styles, for every (cache slot, zoom) pair. Most nodes have no tags
+// and no styles, and this lets `Styler` skip them without locking the style cache or building a cache key.
+pub struct UntaggedStyleCache {
+    states: Vec<AtomicU8>,
+}
+
+const UNKNOWN: u8 = 0;
+const UNSTYLED: u8 = 1;
+const STYLED: u8 = 2;
+
+impl UntaggedStyleCache {
+    pub fn new(cache_slot_count: usize) -> UntaggedStyleCache {
+        UntaggedStyleCache {
+            states: (0..cache_slot_count * (usize::from(u8::MAX) + 1))
+                .map(|_| AtomicU8::new(UNKNOWN))
+                .collect(),
         }
     }
+
+    pub fn is_unstyled(&self, cache_slot: usize, zoom: u8) -> bool {
+        self.state(cache_slot, zoom).load(Ordering::Relaxed) == UNSTYLED
+    }
+
+    pub fn record(&self, cache_slot: usize, zoom: u8, styles: &[Arc<Style>]) {
+        let state = if styles.is_empty() { UNSTYLED } else { STYLED };
+        self.state(cache_slot, zoom).store(state, Ordering::Relaxed);
+    }
+
+    fn state(&self, cache_slot: usize, zoom: u8) -> &AtomicU8 {
+        &self.states[cache_slot * (usize::from(u8::MAX) + 1) + usize::from(zoom)]
+    }
+}
+
+// Leaves at most `target_len` most recently used entries and returns the number of evicted ones.
+fn evict_least_recently_used(cache: &mut HashMap<StyleCacheKey, CachedStyles>, target_len: usize) -> u64 {
+    if cache.len() <= target_len {
+        return 0;
+    }
+    let to_evict = cache.len() - target_len;
+    let mut timestamps = cache
+        .values()
+        .map(|c| c.last_used.load(Ordering::Relaxed))
+        .collect::<Vec<_>>();
+    // The timestamps are unique, so exactly `to_evict` entries are not newer than this one.
+    let (_, &mut threshold, _) = timestamps.select_nth_unstable(to_evict - 1);
+    cache.retain(|_, c| c.last_used.load(Ordering::Relaxed) > threshold);
+    to_evict as u64
 }
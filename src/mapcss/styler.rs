@@ -1,11 +1,16 @@
-use crate::mapcss::color::{from_color_name, Color};
+use crate::mapcss::color::{from_color_name, from_tag_value, Color};
 use crate::mapcss::parser::*;
-use crate::mapcss::style_cache::StyleCache;
+use crate::mapcss::rule_index::RuleIndex;
+use crate::mapcss::style_cache::{StyleCache, UntaggedStyleCache};
+pub use crate::mapcss::style_cache::{StyleCacheStats, DEFAULT_STYLE_CACHE_CAPACITY};
 
-use crate::geodata::reader::{Multipolygon, Node, OsmArea, OsmEntity, Way};
+use crate::geodata::reader::{Multipolygon, Node, OsmArea, OsmEntity, Relation, Tags, Way};
+use crate::tile::MAX_ZOOM;
 use indexmap::IndexMap;
 use std::cmp::Ordering;
+use std::collections::HashSet;
 use std::sync::Arc;
+use std::sync::Mutex;
 use std::sync::RwLock;
 
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
@@ -15,10 +20,56 @@ pub enum LineCap {
     Square,
 }
 
+// How the segments of a line are joined at its inner nodes.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum LineJoin {
+    Round,
+    Miter,
+    Bevel,
+}
+
+// How the colors of an entity are combined with the colors drawn below it.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub enum BlendMode {
+    #[default]
+    Normal,
+    // Darkens the colors below, e.g. to let the tunnels show through what is drawn above them.
+    Multiply,
+    // The opposite of multiplying: lightens the colors below, e.g. for tinting the landuse areas.
+    Screen,
+}
+
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub enum TextPosition {
     Center,
     Line,
+    // Along a gentle arc through the label position, for the names of large areas.
+    Curved,
+}
+
+// Where the text of a label goes relative to its icon, or to the label position if there is no icon.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum TextAnchor {
+    Center,
+    Above,
+    Below,
+    Left,
+    Right,
+}
+
+// Which point of an icon is placed at the label position.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum IconAnchor {
+    Center,
+    Top,
+    Bottom,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum TextTransform {
+    Uppercase,
+    Lowercase,
+    Capitalize,
 }
 
 pub fn is_non_trivial_cap(line_cap: &Option<LineCap>) -> bool {
@@ -33,53 +84,149 @@ pub enum StyleType {
 pub trait StyleableEntity {
     fn default_z_index(&self) -> f64;
     fn matches_object_type(&self, object_type: &ObjectType) -> bool;
+    // Whether the entity is matched by `:closed` or by `:unclosed`. Neither matches the nodes.
+    fn closedness(&self) -> Option<bool>;
 }
 
 pub trait CacheableEntity {
     fn cache_slot(&self) -> usize;
 }
 
+// Nodes, closed ways, open ways, multipolygons and relations.
+const CACHE_SLOT_COUNT: usize = 5;
+
 pub struct TextStyle {
-    pub text: String,
+    // The keys of the tags with the text in the order they are tried, e.g. `name:en`, `name:de`, `name` and `ref`
+    // for `text: name, ref;` (see `Styler::set_label_languages()`).
+    pub text_keys: Vec<String>,
     pub text_color: Option<Color>,
     pub text_position: Option<TextPosition>,
-    pub font_size: Option<f64>,
+    pub text_anchor: Option<TextAnchor>,
+    // The gap between the text and the icon (or the label position), in pixels.
+    pub text_offset: Option<f64>,
+    // JOSM draws the text without `font-size` at 8 pixels (see `DEFAULT_FONT_SIZE`).
+    pub font_size: f64,
+    pub text_transform: Option<TextTransform>,
+    // The additional space between the letters, in pixels.
+    pub letter_spacing: Option<f64>,
+    // The maximum width of a line of a centered label, in ems. Wrapping is turned off by 0.
+    pub max_width: Option<f64>,
+    // A new line of a centered label is started instead of each of these characters.
+    pub line_break_chars: Vec<char>,
+    // The label is drawn even if it collides with other labels (which still can't be drawn over it).
+    pub allow_overlap: bool,
+}
+
+impl TextStyle {
+    pub fn resolve_text<'t>(&self, tags: &Tags<'t>) -> Option<&'t str> {
+        self.text_keys.iter().find_map(|key| tags.get_by_key(key))
+    }
 }
 
 pub struct Style {
+    // Overrides the default order of drawing the areas and the lines (see `Drawer::draw_to_pixels()`).
+    pub major_z_index: Option<f64>,
     pub layer: Option<i64>,
     pub z_index: f64,
 
     pub color: Option<Color>,
     pub fill_color: Option<Color>,
     pub is_foreground_fill: bool,
+    // The closed ways and multipolygons with a smaller area (in square pixels) are not drawn at all.
+    pub min_area: Option<f64>,
     pub background_color: Option<Color>,
     pub opacity: Option<f64>,
     pub fill_opacity: Option<f64>,
+    pub blend_mode: Option<BlendMode>,
 
     pub width: Option<f64>,
     pub dashes: Option<Vec<f64>>,
     pub line_cap: Option<LineCap>,
+    pub line_join: Option<LineJoin>,
 
     pub casing_color: Option<Color>,
     pub casing_width: Option<f64>,
     pub casing_dashes: Option<Vec<f64>>,
     pub casing_line_cap: Option<LineCap>,
+    pub casing_line_join: Option<LineJoin>,
 
     pub icon_image: Option<String>,
+    pub icon_anchor: Option<IconAnchor>,
     pub fill_image: Option<String>,
     pub text_style: Option<TextStyle>,
 }
 
 pub struct Styler {
-    pub canvas_fill_color: Option<Color>,
     pub use_caps_for_dashes: bool,
 
+    // Indexed by zoom level.
+    canvas_styles: Vec<CanvasStyle>,
+
     casing_width_multiplier: f64,
     font_size_multiplier: Option<f64>,
+    label_languages: Vec<String>,
+    // The cascade of the properties of this entity is printed when it's styled (see `set_debug_entity_id()`).
+    debug_entity_id: Option<u64>,
     rules: Vec<Rule>,
+    rule_index: RuleIndex,
+    // The entities that the stylesheet refers to by id (`[osm_id=123]`). Their styles differ from the styles of the
+    // entities with the same tags, so they are never cached.
+    styled_ids: HashSet<u64>,
 
     style_cache: RwLock<StyleCache>,
+    untagged_style_cache: UntaggedStyleCache,
+    warnings: Mutex<StyleWarnings>,
+}
+
+// Properties that can't be turned into a style, e.g. `color: 12;`. The same problem usually shows up
+// for lots of entities, so only the first occurrence is printed, and the rest are just counted.
+#[derive(Clone, Debug)]
+pub struct StyleWarning {
+    pub property: String,
+    pub message: String,
+    pub example_value: String,
+    pub example_entity_id: u64,
+    // Entities with cached styles are not counted.
+    pub count: usize,
+}
+
+// New kinds of warnings beyond this limit are counted in `StyleWarnings::dropped`.
+const MAX_WARNING_KINDS: usize = 1000;
+
+const DEFAULT_FONT_SIZE: f64 = 8.0;
+
+#[derive(Default)]
+struct StyleWarnings {
+    by_kind: IndexMap<(String, String), StyleWarning>,
+    dropped: usize,
+}
+
+impl StyleWarnings {
+    fn add(&mut self, property: &str, message: &str, value: &PropertyValue, entity_id: u64) {
+        let key = (property.to_string(), message.to_string());
+        if let Some(warning) = self.by_kind.get_mut(&key) {
+            warning.count += 1;
+            return;
+        }
+        if self.by_kind.len() >= MAX_WARNING_KINDS {
+            self.dropped += 1;
+            return;
+        }
+        eprintln!(
+            "Entity #{}, property \"{}\" (value {:?}): {} (similar warnings will not be shown)",
+            entity_id, property, value, message
+        );
+        self.by_kind.insert(
+            key,
+            StyleWarning {
+                property: property.to_string(),
+                message: message.to_string(),
+                example_value: value.to_string(),
+                example_entity_id: entity_id,
+                count: 1,
+            },
+        );
+    }
 }
 
 pub enum StyledArea<'a, 'wr>
@@ -93,7 +240,9 @@ where
 impl Styler {
     pub fn new(rules: Vec<Rule>, style_type: &StyleType, font_size_multiplier: Option<f64>) -> Styler {
         let use_caps_for_dashes = matches!(*style_type, StyleType::Josm);
-        let canvas_fill_color = extract_canvas_fill_color(&rules, style_type);
+        let canvas_styles = (0..=MAX_ZOOM)
+            .map(|zoom| extract_canvas_style(&rules, style_type, zoom))
+            .collect();
 
         let casing_width_multiplier = match *style_type {
             StyleType::MapsMe => 1.0,
@@ -101,17 +250,79 @@ impl Styler {
         };
 
         let style_cache = StyleCache::new(&rules);
+        let rule_index = RuleIndex::new(&rules);
+        let styled_ids = rules
+            .iter()
+            .flat_map(|rule| rule.selectors.iter().flat_map(|selector| selector.tests.iter()))
+            .filter_map(|test| match test {
+                Test::Id { id, .. } => Some(*id),
+                _ => None,
+            })
+            .collect();
 
         Styler {
             use_caps_for_dashes,
-            canvas_fill_color,
+            canvas_styles,
             casing_width_multiplier,
             font_size_multiplier,
+            label_languages: Vec::new(),
+            debug_entity_id: None,
             rules,
+            rule_index,
+            styled_ids,
             style_cache: RwLock::new(style_cache),
+            untagged_style_cache: UntaggedStyleCache::new(CACHE_SLOT_COUNT),
+            warnings: Mutex::new(StyleWarnings::default()),
         }
     }
 
+    pub fn set_style_cache_capacity(&mut self, capacity: usize) {
+        self.style_cache.get_mut().unwrap().set_capacity(capacity);
+    }
+
+    // Labels are taken from the tags in these languages first, e.g. from `name:en` for `text: name;` and
+    // `en`, falling back to the tag from the stylesheet. Must be set before anything is styled, since
+    // the styles are cached.
+    pub fn set_label_languages(&mut self, languages: Vec<String>) {
+        self.label_languages = languages;
+    }
+
+    // Prints which properties of the entity with this id override the earlier ones (or can't override them because
+    // of `!important`), which helps to find out where a style comes from. The entity isn't taken from the style
+    // cache, so that this is printed whenever it's styled.
+    pub fn set_debug_entity_id(&mut self, id: Option<u64>) {
+        self.debug_entity_id = id;
+    }
+
+    pub fn style_cache_stats(&self) -> StyleCacheStats {
+        self.style_cache.read().unwrap().stats()
+    }
+
+    // Returns the warnings collected so far and the number of warnings that didn't fit.
+    pub fn warnings(&self) -> (Vec<StyleWarning>, usize) {
+        let warnings = self.warnings.lock().unwrap();
+        (warnings.by_kind.values().cloned().collect(), warnings.dropped)
+    }
+
+    pub fn canvas_fill_color(&self, zoom: u8) -> Option<&Color> {
+        self.canvas_style(zoom).and_then(|canvas| canvas.fill_color.as_ref())
+    }
+
+    // The image that is repeated over the canvas (on top of its color), relative to the stylesheet just like the
+    // fill images of the areas.
+    pub fn canvas_fill_image(&self, zoom: u8) -> Option<&str> {
+        self.canvas_style(zoom).and_then(|canvas| canvas.fill_image.as_deref())
+    }
+
+    // Whether the edges of the lines are smoothed (`antialiasing: full`, the default) or not (`antialiasing: none`).
+    pub fn canvas_antialiasing(&self, zoom: u8) -> bool {
+        self.canvas_style(zoom).is_none_or(|canvas| canvas.antialiasing)
+    }
+
+    fn canvas_style(&self, zoom: u8) -> Option<&CanvasStyle> {
+        self.canvas_styles.get(usize::from(zoom))
+    }
+
     pub fn style_entities<'e, 'wp, I, A>(&self, areas: I, zoom: u8, for_labels: bool) -> Vec<(&'wp A, Arc<Style>)>
     where
         A: CacheableEntity + StyleableEntity + OsmEntity<'e>,
@@ -125,7 +336,13 @@ impl Styler {
                 }
             };
 
-            {
+            let is_untagged = area.tags().is_empty();
+            let is_cacheable = !self.styled_ids.contains(&area.global_id());
+            if is_untagged && is_cacheable && self.untagged_style_cache.is_unstyled(area.cache_slot(), zoom) {
+                continue;
+            }
+
+            if is_cacheable && self.debug_entity_id != Some(area.global_id()) {
                 let read_cache = self.style_cache.read().unwrap();
                 if let Some(styles) = read_cache.get(area, zoom) {
                     add_styles(&styles);
@@ -146,17 +363,23 @@ impl Styler {
             for (layer, prop_map) in &all_property_maps {
                 if *layer != "*" {
                     styles.push(Arc::new(property_map_to_style(
+                        self,
                         prop_map,
                         base_layer,
                         default_z_index,
-                        self.casing_width_multiplier,
-                        &self.font_size_multiplier,
+                        zoom,
                         area,
                     )))
                 }
             }
 
             add_styles(&styles);
+            if !is_cacheable {
+                continue;
+            }
+            if is_untagged {
+                self.untagged_style_cache.record(area.cache_slot(), zoom, &styles);
+            }
             self.style_cache.write().unwrap().insert(area, zoom, styles)
         }
 
@@ -207,32 +430,74 @@ impl Styler {
         A: StyleableEntity + OsmEntity<'e>,
     {
         let mut result: LayerToPropertyMap<'r> = IndexMap::new();
-
-        for rule in &self.rules {
+        // The (layer, property) pairs set with `!important`. Like in CSS, they are overridden only by the later
+        // properties with `!important`.
+        let mut important = HashSet::<(&'r str, &'r str)>::new();
+        let is_debugged = self.debug_entity_id == Some(area.global_id());
+
+        for rule in self
+            .rule_index
+            .candidates(&area.tags())
+            .into_iter()
+            .map(|idx| &self.rules[idx])
+        {
             for sel in rule.selectors.iter().filter(|x| area_matches(area, x, zoom)) {
                 let layer_id = get_layer_id(sel);
 
-                let update_layer = |layer: &mut PropertyMap<'r>| {
+                // Can't use result.entry(...).or_insert_with(...) because we need to immutably
+                // borrow the result to compute the default value in or_insert_with(), and the
+                // map is already borrowed as mutable when we call entry().
+                if !result.contains_key(layer_id) {
+                    let parent_layer = result.get("*").cloned().unwrap_or_default();
+                    result.insert(layer_id, parent_layer);
+                    let inherited = important
+                        .iter()
+                        .filter(|(parent_id, _)| *parent_id == "*")
+                        .map(|&(_, name)| (layer_id, name))
+                        .collect::<Vec<_>>();
+                    important.extend(inherited);
+                }
+
+                let mut update_layer = |layer_id: &'r str, layer: &mut PropertyMap<'r>| {
                     for prop in &rule.properties {
-                        layer.insert(prop.name.clone(), &prop.value);
+                        let key = (layer_id, prop.name.as_str());
+                        if !prop.important && important.contains(&key) {
+                            if is_debugged {
+                                eprintln!(
+                                    "#{} at zoom {}, layer {}: `{}` ({}) is ignored, `{}` is !important",
+                                    area.global_id(),
+                                    zoom,
+                                    layer_id,
+                                    prop,
+                                    prop.position,
+                                    layer[&prop.name]
+                                );
+                            }
+                            continue;
+                        }
+                        if prop.important {
+                            important.insert(key);
+                        }
+                        let old_value = layer.insert(prop.name.clone(), &prop.value);
+                        if let Some(old_value) = old_value.filter(|_| is_debugged) {
+                            eprintln!(
+                                "#{} at zoom {}, layer {}: `{}` ({}) overrides `{}`",
+                                area.global_id(),
+                                zoom,
+                                layer_id,
+                                prop,
+                                prop.position,
+                                old_value
+                            );
+                        }
                     }
                 };
 
-                {
-                    // Can't use result.entry(...).or_insert_with(...) because we need to immutably
-                    // borrow the result to compute the default value in or_insert_with(), and the
-                    // map is already borrowed as mutable when we call entry().
-                    if !result.contains_key(layer_id) {
-                        let parent_layer = result.get("*").cloned().unwrap_or_default();
-                        result.insert(layer_id, parent_layer);
-                    }
-
-                    update_layer(result.get_mut(layer_id).unwrap());
-                }
+                update_layer(layer_id, result.get_mut(layer_id).unwrap());
 
                 if layer_id == "*" {
-                    for (_, v) in result.iter_mut().filter(|&(k, _)| k != &"*") {
-                        update_layer(v);
+                    for (k, v) in result.iter_mut().filter(|&(k, _)| k != &"*") {
+                        update_layer(k, v);
                     }
                 }
             }
@@ -242,7 +507,6 @@ impl Styler {
     }
 }
 
-#[cfg_attr(feature = "cargo-clippy", allow(clippy::float_cmp))]
 fn compare_styled_entities<'a, E1, E2>(
     (a, a_style): &(&E1, Arc<Style>),
     (b, b_style): &(&E2, Arc<Style>),
@@ -264,22 +528,22 @@ where
         return a_style.is_foreground_fill.cmp(&b_style.is_foreground_fill);
     }
 
-    if a_style.z_index != b_style.z_index {
-        return a_style.z_index.partial_cmp(&b_style.z_index).unwrap();
-    }
-
-    a.global_id().cmp(&b.global_id())
+    // `total_cmp()` keeps the order consistent even if a z-index read from a tag turns out to be NaN.
+    a_style
+        .z_index
+        .total_cmp(&b_style.z_index)
+        .then_with(|| a.global_id().cmp(&b.global_id()))
 }
 
 type LayerToPropertyMap<'r> = IndexMap<&'r str, PropertyMap<'r>>;
 type PropertyMap<'r> = IndexMap<String, &'r PropertyValue>;
 
 fn property_map_to_style<'r, 'e, E>(
+    styler: &Styler,
     current_layer_map: &'r PropertyMap<'r>,
     base_layer_map: Option<&'r PropertyMap<'r>>,
     default_z_index: f64,
-    casing_width_multiplier: f64,
-    font_size_multiplier: &Option<f64>,
+    zoom: u8,
     osm_entity: &E,
 ) -> Style
 where
@@ -287,13 +551,11 @@ where
 {
     let warn = |prop_map: &'r PropertyMap<'r>, prop_name, msg| {
         if let Some(val) = prop_map.get(prop_name) {
-            eprintln!(
-                "Entity #{}, property \"{}\" (value {:?}): {}",
-                osm_entity.global_id(),
-                prop_name,
-                val,
-                msg
-            );
+            styler
+                .warnings
+                .lock()
+                .unwrap()
+                .add(prop_name, msg, val, osm_entity.global_id());
         }
     };
 
@@ -306,6 +568,14 @@ where
             }
             color
         }
+        Some(&PropertyValue::TagValue(tag_name)) => {
+            let tag_value = osm_entity.tags().get_by_key(tag_name);
+            let color = tag_value.and_then(from_tag_value);
+            if tag_value.is_some() && color.is_none() {
+                warn(current_layer_map, prop_name, "the tag value is not a valid color");
+            }
+            color
+        }
         _ => {
             warn(current_layer_map, prop_name, "expected a valid color");
             None
@@ -314,6 +584,15 @@ where
 
     let get_num = |prop_map: &'r PropertyMap<'r>, prop_name| match prop_map.get(prop_name) {
         Some(&PropertyValue::Numbers(nums)) if nums.len() == 1 => Some(nums[0]),
+        Some(&PropertyValue::ZoomStops(stops)) if !stops.is_empty() => Some(interpolate(stops, zoom)),
+        Some(&PropertyValue::TagValue(tag_name)) => {
+            let tag_value = osm_entity.tags().get_by_key(tag_name);
+            let num = tag_value.and_then(|x| x.trim().parse().ok());
+            if tag_value.is_some() && num.is_none() {
+                warn(prop_map, prop_name, "the tag value is not a number");
+            }
+            num
+        }
         _ => {
             warn(prop_map, prop_name, "expected a number");
             None
@@ -337,6 +616,15 @@ where
         }
     };
 
+    let get_bool = |prop_name| match get_id(prop_name) {
+        Some("true") | Some("yes") => Some(true),
+        Some("false") | Some("no") => Some(false),
+        _ => {
+            warn(current_layer_map, prop_name, "expected true or false");
+            None
+        }
+    };
+
     let get_line_cap = |prop_name| match get_id(prop_name) {
         Some("none") | Some("butt") => Some(LineCap::Butt),
         Some("round") => Some(LineCap::Round),
@@ -347,15 +635,69 @@ where
         }
     };
 
+    let get_line_join = |prop_name| match get_id(prop_name) {
+        Some("round") => Some(LineJoin::Round),
+        Some("miter") => Some(LineJoin::Miter),
+        Some("bevel") => Some(LineJoin::Bevel),
+        _ => {
+            warn(current_layer_map, prop_name, "unknown line join value");
+            None
+        }
+    };
+
+    let get_blend_mode = |prop_name| match get_id(prop_name) {
+        Some("normal") => Some(BlendMode::Normal),
+        Some("multiply") => Some(BlendMode::Multiply),
+        Some("screen") => Some(BlendMode::Screen),
+        _ => {
+            warn(current_layer_map, prop_name, "unknown blend mode");
+            None
+        }
+    };
+
     let get_text_position = |prop_name| match get_id(prop_name) {
         Some("center") => Some(TextPosition::Center),
         Some("line") => Some(TextPosition::Line),
+        Some("curved") => Some(TextPosition::Curved),
         _ => {
             warn(current_layer_map, prop_name, "unknown text position type");
             None
         }
     };
 
+    let get_text_anchor = |prop_name| match get_id(prop_name) {
+        Some("center") => Some(TextAnchor::Center),
+        Some("above") => Some(TextAnchor::Above),
+        Some("below") => Some(TextAnchor::Below),
+        Some("left") => Some(TextAnchor::Left),
+        Some("right") => Some(TextAnchor::Right),
+        _ => {
+            warn(current_layer_map, prop_name, "unknown text anchor type");
+            None
+        }
+    };
+
+    let get_icon_anchor = |prop_name| match get_id(prop_name) {
+        Some("center") => Some(IconAnchor::Center),
+        Some("top") => Some(IconAnchor::Top),
+        Some("bottom") => Some(IconAnchor::Bottom),
+        _ => {
+            warn(current_layer_map, prop_name, "unknown icon anchor type");
+            None
+        }
+    };
+
+    let get_text_transform = |prop_name| match get_id(prop_name) {
+        Some("none") => None,
+        Some("uppercase") => Some(TextTransform::Uppercase),
+        Some("lowercase") => Some(TextTransform::Lowercase),
+        Some("capitalize") => Some(TextTransform::Capitalize),
+        _ => {
+            warn(current_layer_map, prop_name, "unknown text transform type");
+            None
+        }
+    };
+
     let get_dashes = |prop_name| match current_layer_map.get(prop_name) {
         Some(&PropertyValue::Numbers(nums)) => Some(nums.clone()),
         _ => {
@@ -369,6 +711,7 @@ where
         .get_by_key("layer")
         .and_then(|x| x.parse::<i64>().ok());
     let z_index = get_num(current_layer_map, "z-index").unwrap_or(default_z_index);
+    let major_z_index = get_num(current_layer_map, "major-z-index");
 
     let is_foreground_fill =
         !matches!(current_layer_map.get("fill-position"), Some(&PropertyValue::Identifier(id)) if *id == "background");
@@ -380,6 +723,7 @@ where
         .unwrap_or_default();
     let casing_only_width = match current_layer_map.get("casing-width") {
         Some(&PropertyValue::Numbers(nums)) if nums.len() == 1 => Some(nums[0]),
+        Some(&PropertyValue::ZoomStops(stops)) if !stops.is_empty() => Some(interpolate(stops, zoom)),
         Some(&&PropertyValue::WidthDelta(num)) => Some(base_width_for_casing + num),
         _ => {
             warn(
@@ -390,61 +734,152 @@ where
             None
         }
     };
-    let full_casing_width = casing_only_width.map(|w| base_width_for_casing + casing_width_multiplier * w);
-    let text = get_string("text");
-
-    let font_size = get_num(current_layer_map, "font-size").map(|x| x * font_size_multiplier.unwrap_or(1.0));
+    let full_casing_width = casing_only_width.map(|w| base_width_for_casing + styler.casing_width_multiplier * w);
+    let text_keys = match current_layer_map.get("text") {
+        Some(&PropertyValue::Identifiers(keys)) => Some(keys.clone()),
+        _ => get_string("text").map(|key| vec![key]),
+    };
 
-    let text_style = text.map(|text| TextStyle {
-        text,
+    let font_size = get_num(current_layer_map, "font-size").unwrap_or(DEFAULT_FONT_SIZE)
+        * styler.font_size_multiplier.unwrap_or(1.0);
+
+    let text_style = text_keys.map(|keys| TextStyle {
+        text_keys: keys
+            .into_iter()
+            .flat_map(|key| {
+                // Keys like `name:ru` already have a language.
+                let languages = if key.contains(':') {
+                    &[][..]
+                } else {
+                    &styler.label_languages[..]
+                };
+                languages
+                    .iter()
+                    .map(|language| format!("{}:{}", key, language))
+                    .chain(std::iter::once(key.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect(),
         text_color: get_color("text-color"),
         text_position: get_text_position("text-position"),
+        text_anchor: get_text_anchor("text-anchor"),
+        text_offset: get_num(current_layer_map, "text-offset"),
         font_size,
+        text_transform: get_text_transform("text-transform"),
+        letter_spacing: get_num(current_layer_map, "text-letter-spacing"),
+        max_width: get_num(current_layer_map, "text-max-width"),
+        line_break_chars: get_string("text-line-break")
+            .map(|chars| chars.chars().collect())
+            .unwrap_or_default(),
+        allow_overlap: get_bool("text-allow-overlap").unwrap_or_default(),
     });
 
     Style {
+        major_z_index,
         layer,
         z_index,
 
         color: get_color("color"),
         fill_color: get_color("fill-color"),
         is_foreground_fill,
+        min_area: get_num(current_layer_map, "min-area"),
         background_color: get_color("background-color"),
         opacity: get_num(current_layer_map, "opacity"),
         fill_opacity: get_num(current_layer_map, "fill-opacity"),
+        blend_mode: get_blend_mode("blend-mode"),
 
         width,
         dashes: get_dashes("dashes"),
         line_cap: get_line_cap("linecap"),
+        line_join: get_line_join("linejoin"),
 
         casing_color: get_color("casing-color"),
         casing_width: full_casing_width,
         casing_dashes: get_dashes("casing-dashes"),
         casing_line_cap: get_line_cap("casing-linecap"),
+        casing_line_join: get_line_join("casing-linejoin"),
 
         icon_image: get_string("icon-image"),
+        icon_anchor: get_icon_anchor("icon-anchor"),
         fill_image: get_string("fill-image"),
         text_style,
     }
 }
 
-fn extract_canvas_fill_color(rules: &[Rule], style_type: &StyleType) -> Option<Color> {
-    let color_prop = match *style_type {
-        StyleType::Josm => "fill-color",
-        StyleType::MapsMe => "background-color",
+// Values below the first stop and above the last one are constant. `stops` should not be empty.
+fn interpolate(stops: &[(u8, f64)], zoom: u8) -> f64 {
+    let next_idx = stops.iter().position(|&(stop_zoom, _)| stop_zoom >= zoom);
+    match next_idx {
+        Some(0) => stops[0].1,
+        Some(idx) => {
+            let ((zoom1, value1), (zoom2, value2)) = (stops[idx - 1], stops[idx]);
+            let t = f64::from(zoom - zoom1) / f64::from(zoom2 - zoom1);
+            value1 + t * (value2 - value1)
+        }
+        None => stops[stops.len() - 1].1,
+    }
+}
+
+struct CanvasStyle {
+    fill_color: Option<Color>,
+    fill_image: Option<String>,
+    antialiasing: bool,
+}
+
+// Later canvas rules override the earlier ones, just like for all other objects.
+// The color is blended with black (the default canvas color) according to the canvas opacity.
+fn extract_canvas_style(rules: &[Rule], style_type: &StyleType, zoom: u8) -> CanvasStyle {
+    let (color_prop, opacity_prop) = match *style_type {
+        StyleType::Josm => ("fill-color", "fill-opacity"),
+        StyleType::MapsMe => ("background-color", "background-opacity"),
     };
+
+    let mut color = None;
+    let mut opacity = 1.0;
+    let mut fill_image = None;
+    let mut antialiasing = true;
     for r in rules {
-        for selector in &r.selectors {
-            if let ObjectType::Canvas = selector.object_type {
-                for prop in r.properties.iter().filter(|x| x.name == *color_prop) {
-                    if let PropertyValue::Color(ref color) = prop.value {
-                        return Some(color.clone());
+        let is_canvas_rule = r
+            .selectors
+            .iter()
+            .any(|s| matches!(s.object_type, ObjectType::Canvas) && zoom_matches(s, zoom));
+        if !is_canvas_rule {
+            continue;
+        }
+        for prop in &r.properties {
+            match &prop.value {
+                PropertyValue::Color(c) if prop.name == color_prop => color = Some(c.clone()),
+                PropertyValue::Identifier(id) if prop.name == color_prop => {
+                    if let Some(c) = from_color_name(id) {
+                        color = Some(c);
                     }
                 }
+                PropertyValue::Numbers(nums) if prop.name == opacity_prop && nums.len() == 1 => {
+                    opacity = nums[0].clamp(0.0, 1.0);
+                }
+                PropertyValue::Identifier(image) | PropertyValue::String(image) if prop.name == "fill-image" => {
+                    fill_image = Some(image.clone());
+                }
+                PropertyValue::Identifier(id) if prop.name == "antialiasing" => match id.as_str() {
+                    "full" => antialiasing = true,
+                    "none" => antialiasing = false,
+                    _ => {}
+                },
+                _ => {}
             }
         }
     }
-    None
+
+    let blend = |c: u8| (f64::from(c) * opacity).round() as u8;
+    CanvasStyle {
+        fill_color: color.map(|c| Color {
+            r: blend(c.r),
+            g: blend(c.g),
+            b: blend(c.b),
+        }),
+        fill_image,
+        antialiasing,
+    }
 }
 
 fn matches_by_tags<'e, E>(entity: &E, test: &Test) -> bool
@@ -453,40 +888,32 @@ where
 {
     let tags = entity.tags();
 
-    let is_true_value = |x| x == "yes" || x == "true" || x == "1";
-
     match *test {
         Test::Unary {
             ref tag_name,
             ref test_type,
-        } => {
-            let tag_val = tags.get_by_key(tag_name);
-            match *test_type {
-                UnaryTestType::Exists => tag_val.is_some(),
-                UnaryTestType::NotExists => tag_val.is_none(),
-                UnaryTestType::True => matches!(tag_val, Some(x) if is_true_value(x)),
-                UnaryTestType::False => !matches!(tag_val, Some(x) if is_true_value(x)),
-            }
-        }
+        } => match *test_type {
+            UnaryTestType::Exists => tags.contains_key(tag_name),
+            UnaryTestType::NotExists => !tags.contains_key(tag_name),
+            UnaryTestType::True => tags.get_bool(tag_name) == Some(true),
+            UnaryTestType::False => tags.get_bool(tag_name) != Some(true),
+        },
         Test::BinaryStringCompare {
             ref tag_name,
             ref value,
             ref test_type,
-        } => {
-            let tag_val = tags.get_by_key(tag_name);
-            match *test_type {
-                BinaryStringTestType::Equal => tag_val == Some(value),
-                BinaryStringTestType::NotEqual => tag_val != Some(value),
-            }
-        }
+        } => match *test_type {
+            BinaryStringTestType::Equal => tags.has_tag(tag_name, value),
+            BinaryStringTestType::NotEqual => !tags.has_tag(tag_name, value),
+        },
         Test::BinaryNumericCompare {
             ref tag_name,
             ref value,
             ref test_type,
         } => {
-            let tag_val = match tags.get_by_key(tag_name).map(str::parse::<f64>) {
-                Some(Ok(x)) => x,
-                _ => return false,
+            let tag_val = match tags.get_f64(tag_name) {
+                Some(x) => x,
+                None => return false,
             };
             match *test_type {
                 BinaryNumericTestType::Less => tag_val < *value,
@@ -495,6 +922,10 @@ where
                 BinaryNumericTestType::GreaterOrEqual => tag_val >= *value,
             }
         }
+        Test::Id { id, ref test_type } => match *test_type {
+            BinaryStringTestType::Equal => entity.global_id() == id,
+            BinaryStringTestType::NotEqual => entity.global_id() != id,
+        },
     }
 }
 
@@ -502,21 +933,34 @@ fn area_matches<'e, A>(area: &A, selector: &Selector, zoom: u8) -> bool
 where
     A: StyleableEntity + OsmEntity<'e>,
 {
-    if let Some(min_zoom) = selector.min_zoom {
-        if zoom < min_zoom {
-            return false;
-        }
-    }
-
-    if let Some(max_zoom) = selector.max_zoom {
-        if zoom > max_zoom {
-            return false;
-        }
+    if !zoom_matches(selector, zoom) {
+        return false;
     }
 
     let good_object_type = area.matches_object_type(&selector.object_type);
 
-    good_object_type && selector.tests.iter().all(|x| matches_by_tags(area, x))
+    good_object_type
+        && selector.tests.iter().all(|x| matches_by_tags(area, x))
+        && selector.pseudo_classes.iter().all(|x| matches_pseudo_class(area, x))
+}
+
+fn matches_pseudo_class<'e, A>(area: &A, test: &PseudoClassTest) -> bool
+where
+    A: StyleableEntity + OsmEntity<'e>,
+{
+    let matches = match test.pseudo_class {
+        PseudoClass::Closed => area.closedness() == Some(true),
+        PseudoClass::Unclosed => area.closedness() == Some(false),
+        PseudoClass::Tagged => !area.tags().is_empty(),
+        PseudoClass::Area => area.matches_object_type(&ObjectType::Area),
+        PseudoClass::Other(_) => return true,
+    };
+    matches != test.negated
+}
+
+pub(crate) fn zoom_matches(selector: &Selector, zoom: u8) -> bool {
+    selector.min_zoom.is_none_or(|min_zoom| zoom >= min_zoom)
+        && selector.max_zoom.is_none_or(|max_zoom| zoom <= max_zoom)
 }
 
 fn get_layer_id(selector: &Selector) -> &str {
@@ -536,11 +980,15 @@ impl<'a> StyleableEntity for Node<'a> {
     fn matches_object_type(&self, object_type: &ObjectType) -> bool {
         matches!(*object_type, ObjectType::Node)
     }
+
+    fn closedness(&self) -> Option<bool> {
+        None
+    }
 }
 
-impl<A: OsmArea> StyleableEntity for A {
+impl<'a> StyleableEntity for Way<'a> {
     fn default_z_index(&self) -> f64 {
-        if self.is_closed() {
+        if is_area(self) {
             1.0
         } else {
             3.0
@@ -550,10 +998,109 @@ impl<A: OsmArea> StyleableEntity for A {
     fn matches_object_type(&self, object_type: &ObjectType) -> bool {
         match *object_type {
             ObjectType::Way => true,
-            ObjectType::Area => self.is_closed(),
+            ObjectType::Area => is_area(self),
             _ => false,
         }
     }
+
+    fn closedness(&self) -> Option<bool> {
+        Some(self.is_closed())
+    }
+}
+
+impl<'a> StyleableEntity for Multipolygon<'a> {
+    fn default_z_index(&self) -> f64 {
+        1.0
+    }
+
+    fn matches_object_type(&self, object_type: &ObjectType) -> bool {
+        matches!(*object_type, ObjectType::Way | ObjectType::Area)
+    }
+
+    fn closedness(&self) -> Option<bool> {
+        Some(true)
+    }
+}
+
+// The keys that make a closed way an area, except for the listed values, which describe linear features.
+const AREA_KEYS: &[(&str, &[&str])] = &[
+    ("aeroway", &["jet_bridge", "parking_position", "runway", "taxiway"]),
+    ("amenity", &["bench"]),
+    ("area:highway", &[]),
+    ("building", &[]),
+    ("building:part", &[]),
+    ("craft", &[]),
+    ("historic", &["citywalls"]),
+    ("indoor", &["wall"]),
+    ("landuse", &[]),
+    ("leisure", &["slipway", "track"]),
+    (
+        "man_made",
+        &["breakwater", "cutline", "dyke", "embankment", "groyne", "pipeline"],
+    ),
+    ("military", &["trench"]),
+    (
+        "natural",
+        &["arete", "cliff", "coastline", "ridge", "tree_row", "valley"],
+    ),
+    ("office", &[]),
+    ("place", &[]),
+    ("power", &["cable", "line", "minor_line"]),
+    ("public_transport", &[]),
+    ("shop", &[]),
+    ("sport", &[]),
+    ("tourism", &[]),
+];
+
+// The tags that make a closed way an area even though other values of their keys are linear features.
+const AREA_TAGS: &[(&str, &[&str])] = &[
+    ("aerialway", &["station"]),
+    ("highway", &["rest_area", "services"]),
+    ("railway", &["platform", "roundhouse", "station", "turntable"]),
+    ("waterway", &["boatyard", "dock", "riverbank"]),
+];
+
+// The tags whose values decide whether a closed way is an area, even if they aren't mentioned by the stylesheet.
+pub(crate) fn area_tag_keys() -> impl Iterator<Item = &'static str> {
+    std::iter::once("area").chain(AREA_KEYS.iter().chain(AREA_TAGS).map(|(key, _)| *key))
+}
+
+// Follows the usual OSM conventions: a closed way is an area if it's tagged with `area=yes` or with one of
+// the tags above, unless it's tagged with `area=no`. E.g. a roundabout is not an area, but a building is.
+fn is_area(way: &Way<'_>) -> bool {
+    if !way.is_closed() {
+        return false;
+    }
+    let tags = way.tags();
+    match tags.get_by_key("area") {
+        Some("yes") => true,
+        Some("no") => false,
+        _ => {
+            let has_area_key = AREA_KEYS.iter().any(|(key, linear_values)| {
+                tags.get_by_key(key)
+                    .is_some_and(|value| value != "no" && !linear_values.contains(&value))
+            });
+            has_area_key
+                || AREA_TAGS
+                    .iter()
+                    .any(|(key, area_values)| tags.get_by_key(key).is_some_and(|value| area_values.contains(&value)))
+        }
+    }
+}
+
+impl<'a> StyleableEntity for Relation<'a> {
+    fn default_z_index(&self) -> f64 {
+        3.0
+    }
+
+    fn matches_object_type(&self, object_type: &ObjectType) -> bool {
+        matches!(*object_type, ObjectType::Relation)
+    }
+
+    // The boundaries are drawn as lines.
+    fn closedness(&self) -> Option<bool> {
+        Some(false)
+    }
 }
 
 impl<'a> CacheableEntity for Node<'a> {
@@ -577,3 +1124,9 @@ impl<'a> CacheableEntity for Multipolygon<'a> {
         3
     }
 }
+
+impl<'a> CacheableEntity for Relation<'a> {
+    fn cache_slot(&self) -> usize {
+        4
+    }
+}
@@ -15,8 +15,10 @@ pub enum Token<'a> {
     String(&'a str),
     Number(f64),
     ZoomRange { min_zoom: ZoomLevel, max_zoom: ZoomLevel },
-    ColorRef(&'a str),
+    ConstantRef(&'a str),
     Color(Color),
+    // `@12` in `width: 2@12, 6@16;`.
+    ZoomStop(u8),
 
     LeftParen,
     RightParen,
@@ -83,7 +85,8 @@ impl<'a> fmt::Display for Token<'a> {
     }
 }
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+// Positions that don't come from a file (e.g. of the rules created programmatically) are all zeroes.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
 pub struct InputPosition {
     pub line: usize,
     pub character: usize,
@@ -112,6 +115,8 @@ type CharWithPos = (usize, char);
 
 impl<'a> Tokenizer<'a> {
     pub fn new(input: &'a str) -> Tokenizer<'a> {
+        // Some Windows editors begin UTF-8 files with a byte order mark.
+        let input = input.strip_prefix('\u{feff}').unwrap_or(input);
         Tokenizer {
             text: input,
             chars: input.char_indices().peekable(),
@@ -183,7 +188,7 @@ impl<'a> Tokenizer<'a> {
             }
 
             let import_text = match self.next_char_with_pos() {
-                Some((idx, ch)) if ch == '"' => match self.read_string(idx + 1)? {
+                Some((idx, '"')) => match self.read_string(idx + 1)? {
                     Token::String(text) => Ok(text),
                     _ => panic!("read_string() returned a non-string; this is a bug"),
                 },
@@ -196,8 +201,13 @@ impl<'a> Tokenizer<'a> {
             }
 
             Ok(Token::Import(import_text))
+        } else if directive_text.bytes().all(|b| b.is_ascii_digit()) {
+            match directive_text.parse() {
+                Ok(zoom) => Ok(Token::ZoomStop(zoom)),
+                Err(_) => self.lexer_error(format!("Invalid zoom level: {}", directive_text)),
+            }
         } else {
-            Ok(Token::ColorRef(directive_text))
+            Ok(Token::ConstantRef(directive_text))
         }
     }
 
@@ -382,7 +392,11 @@ impl<'a> Tokenizer<'a> {
     }
 
     fn next_char_with_pos(&mut self) -> Option<CharWithPos> {
-        let res = self.chars.next();
+        let mut res = self.chars.next();
+        // `\r\n` is a single line break, just like `\n`.
+        if matches!(res, Some((_, '\r'))) && self.peek_char() == Some('\n') {
+            res = self.chars.next();
+        }
 
         if self.had_newline {
             self.current_position.line += 1;
@@ -510,7 +524,7 @@ fn with_pos(token: Token<'_>, position: InputPosition) -> TokenWithPosition<'_>
 mod tests {
     use super::*;
 
-    fn tokenize(s: &str) -> Vec<TokenWithPosition> {
+    fn tokenize(s: &str) -> Vec<TokenWithPosition<'_>> {
         Tokenizer::new(s)
             .map(|x| x.expect("Unexpected lexer error"))
             .collect::<Vec<_>>()
@@ -579,7 +593,7 @@ mod tests {
                 (Token::LeftBrace, 3, 6),
                 (Token::Identifier("color"), 4, 5),
                 (Token::Colon, 4, 10),
-                (Token::ColorRef("black"), 4, 12),
+                (Token::ConstantRef("black"), 4, 12),
                 (Token::SemiColon, 4, 18),
                 (Token::Identifier("dashes"), 5, 5),
                 (Token::Colon, 5, 11),
@@ -606,7 +620,7 @@ mod tests {
                 (Token::RightBrace, 10, 1),
                 (Token::Import("include.mapcss"), 11, 1),
                 (Token::SemiColon, 11, 26),
-                (Token::ColorRef("black"), 12, 1),
+                (Token::ConstantRef("black"), 12, 1),
                 (Token::Colon, 12, 7),
                 (Token::Color(Color { r: 255, g: 204, b: 0 }), 12, 9),
                 (Token::SemiColon, 12, 16),
@@ -759,6 +773,21 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_bom_and_crlf() {
+        assert_eq!(
+            tokenize("\u{feff}way {\r\n  width: 2;\r\n}"),
+            tokenize("way {\n  width: 2;\n}")
+        );
+
+        let errors = Tokenizer::new("\u{feff}way\r\n{ $").collect::<Vec<_>>();
+        let err = errors.last().unwrap().as_ref().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "lexer error: Unexpected symbol: '$' (at line 2, col 3)"
+        );
+    }
+
     #[test]
     fn test_errors() {
         let malformed_strings = ["/*abc", "-", "123.", "\"abc", "|z-", "#", "&", "+"];
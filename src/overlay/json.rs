@@ -0,0 +1,281 @@
+use anyhow::{bail, Result};
+
+// Just enough of JSON to read GeoJSON files.
+#[derive(Debug, PartialEq)]
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<JsonValue>),
+    Object(Vec<(String, JsonValue)>),
+}
+
+impl JsonValue {
+    pub fn get(&self, key: &str) -> Option<&JsonValue> {
+        match self {
+            JsonValue::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            JsonValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            JsonValue::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[JsonValue]> {
+        match self {
+            JsonValue::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+pub fn parse_json(text: &str) -> Result<JsonValue> {
+    let mut parser = JsonParser {
+        bytes: text.as_bytes(),
+        pos: 0,
+    };
+    let value = parser.read_value()?;
+    parser.skip_whitespace();
+    if parser.pos != parser.bytes.len() {
+        return parser.error("Unexpected trailing characters");
+    }
+    Ok(value)
+}
+
+struct JsonParser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> JsonParser<'a> {
+    fn read_value(&mut self) -> Result<JsonValue> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') => self.read_object(),
+            Some(b'[') => self.read_array(),
+            Some(b'"') => Ok(JsonValue::String(self.read_string()?)),
+            Some(b't') => self.read_literal("true", JsonValue::Bool(true)),
+            Some(b'f') => self.read_literal("false", JsonValue::Bool(false)),
+            Some(b'n') => self.read_literal("null", JsonValue::Null),
+            Some(b'-' | b'0'..=b'9') => self.read_number(),
+            Some(_) => self.error("Unexpected character"),
+            None => self.error("Unexpected end of input"),
+        }
+    }
+
+    fn read_object(&mut self) -> Result<JsonValue> {
+        self.expect(b'{')?;
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(JsonValue::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.read_string()?;
+            self.skip_whitespace();
+            self.expect(b':')?;
+            members.push((key, self.read_value()?));
+            self.skip_whitespace();
+            match self.next() {
+                Some(b',') => {}
+                Some(b'}') => return Ok(JsonValue::Object(members)),
+                _ => return self.error("Expected ',' or '}'"),
+            }
+        }
+    }
+
+    fn read_array(&mut self) -> Result<JsonValue> {
+        self.expect(b'[')?;
+        let mut values = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(JsonValue::Array(values));
+        }
+        loop {
+            values.push(self.read_value()?);
+            self.skip_whitespace();
+            match self.next() {
+                Some(b',') => {}
+                Some(b']') => return Ok(JsonValue::Array(values)),
+                _ => return self.error("Expected ',' or ']'"),
+            }
+        }
+    }
+
+    fn read_string(&mut self) -> Result<String> {
+        self.expect(b'"')?;
+        let mut result = Vec::new();
+        loop {
+            match self.next() {
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    let unescaped = match self.next() {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => self.read_unicode_escape()?,
+                        _ => return self.error("Invalid escape sequence"),
+                    };
+                    let mut buf = [0; 4];
+                    result.extend_from_slice(unescaped.encode_utf8(&mut buf).as_bytes());
+                }
+                Some(b) => result.push(b),
+                None => return self.error("Unterminated string"),
+            }
+        }
+        match String::from_utf8(result) {
+            Ok(s) => Ok(s),
+            Err(_) => self.error("Invalid UTF-8 in a string"),
+        }
+    }
+
+    fn read_unicode_escape(&mut self) -> Result<char> {
+        let first = self.read_hex_code_unit()?;
+        let code_point = if (0xD800..0xDC00).contains(&first) {
+            if self.next() != Some(b'\\') || self.next() != Some(b'u') {
+                return self.error("Expected a low surrogate");
+            }
+            let second = self.read_hex_code_unit()?;
+            if !(0xDC00..0xE000).contains(&second) {
+                return self.error("Invalid low surrogate");
+            }
+            0x10000 + ((first - 0xD800) << 10) + (second - 0xDC00)
+        } else {
+            first
+        };
+        match char::from_u32(code_point) {
+            Some(c) => Ok(c),
+            None => self.error("Invalid unicode escape"),
+        }
+    }
+
+    fn read_hex_code_unit(&mut self) -> Result<u32> {
+        let digits = self.bytes.get(self.pos..self.pos + 4);
+        let code_unit = digits
+            .and_then(|d| std::str::from_utf8(d).ok())
+            .filter(|d| d.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|d| u32::from_str_radix(d, 16).ok());
+        match code_unit {
+            Some(code_unit) => {
+                self.pos += 4;
+                Ok(code_unit)
+            }
+            None => self.error("Expected 4 hex digits"),
+        }
+    }
+
+    fn read_number(&mut self) -> Result<JsonValue> {
+        let start = self.pos;
+        while let Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') = self.peek() {
+            self.pos += 1;
+        }
+        let number = std::str::from_utf8(&self.bytes[start..self.pos])
+            .ok()
+            .and_then(|s| s.parse().ok());
+        match number {
+            Some(number) => Ok(JsonValue::Number(number)),
+            None => {
+                self.pos = start;
+                self.error("Invalid number")
+            }
+        }
+    }
+
+    fn read_literal(&mut self, literal: &str, value: JsonValue) -> Result<JsonValue> {
+        if self.bytes[self.pos..].starts_with(literal.as_bytes()) {
+            self.pos += literal.len();
+            Ok(value)
+        } else {
+            self.error("Unexpected character")
+        }
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<()> {
+        if self.peek() == Some(expected) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            self.error(&format!("Expected '{}'", expected as char))
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let result = self.peek();
+        if result.is_some() {
+            self.pos += 1;
+        }
+        result
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T> {
+        bail!("{} at byte {}", message, self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_json() {
+        let value =
+            parse_json(r#" {"a": [1, -2.5e1, true, null], "b": {"c": "x\"\u00e9\ud83d\ude00"}, "d": []} "#).unwrap();
+        assert_eq!(
+            value,
+            JsonValue::Object(vec![
+                (
+                    "a".to_string(),
+                    JsonValue::Array(vec![
+                        JsonValue::Number(1.0),
+                        JsonValue::Number(-25.0),
+                        JsonValue::Bool(true),
+                        JsonValue::Null,
+                    ])
+                ),
+                (
+                    "b".to_string(),
+                    JsonValue::Object(vec![("c".to_string(), JsonValue::String("x\"é😀".to_string()))])
+                ),
+                ("d".to_string(), JsonValue::Array(vec![])),
+            ])
+        );
+        assert_eq!(
+            value.get("b").and_then(|b| b.get("c")).and_then(JsonValue::as_str),
+            Some("x\"é😀")
+        );
+
+        for invalid in &["", "[1,]", "{\"a\" 1}", "\"abc", "[1] 2", "tru", "\"\\x\""] {
+            assert!(parse_json(invalid).is_err(), "{} should be invalid", invalid);
+        }
+    }
+}
@@ -0,0 +1,270 @@
+mod json;
+
+use crate::mapcss::color::{from_color_name, Color};
+use crate::tile::{coords_to_xy, Tile, TILE_SIZE};
+use anyhow::{bail, Context, Result};
+use json::{parse_json, JsonValue};
+use std::fs;
+
+// A GeoJSON file that is drawn on top of the OSM data with a single style.
+#[derive(Clone)]
+pub struct Overlay {
+    pub style: OverlayStyle,
+    pub shapes: Vec<Shape>,
+}
+
+#[derive(Clone)]
+pub struct OverlayStyle {
+    pub color: Color,
+    pub width: f64,
+    pub opacity: f64,
+    pub fill_color: Option<Color>,
+    pub fill_opacity: f64,
+    pub point_radius: f64,
+}
+
+impl Default for OverlayStyle {
+    fn default() -> OverlayStyle {
+        OverlayStyle {
+            color: Color {
+                r: 0xd0,
+                g: 0x20,
+                b: 0x20,
+            },
+            width: 3.0,
+            opacity: 0.8,
+            fill_color: None,
+            fill_opacity: 0.3,
+            point_radius: 5.0,
+        }
+    }
+}
+
+impl OverlayStyle {
+    pub fn set_property(&mut self, name: &str, value: &str) -> Result<()> {
+        let parse_number = |value: &str| -> Result<f64> {
+            match value.parse() {
+                Ok(num) if num >= 0.0 => Ok(num),
+                _ => bail!("Invalid value for {}: {}", name, value),
+            }
+        };
+        match name {
+            "color" => self.color = parse_color(value)?,
+            "width" => self.width = parse_number(value)?,
+            "opacity" => self.opacity = parse_number(value)?.min(1.0),
+            "fill-color" => self.fill_color = Some(parse_color(value)?),
+            "fill-opacity" => self.fill_opacity = parse_number(value)?.min(1.0),
+            "point-radius" => self.point_radius = parse_number(value)?,
+            _ => bail!("Unknown overlay style property: {}", name),
+        }
+        Ok(())
+    }
+}
+
+// All coordinates are (lat, lon) pairs.
+#[derive(Clone)]
+pub enum Geometry {
+    Point((f64, f64)),
+    Line(Vec<(f64, f64)>),
+    Polygon(Vec<Vec<(f64, f64)>>),
+}
+
+#[derive(Clone)]
+pub struct Shape {
+    pub geometry: Geometry,
+    // (min_x, min_y, max_x, max_y) in pixels at zoom level 0, to quickly skip shapes outside of a tile.
+    extent: (f64, f64, f64, f64),
+}
+
+impl Shape {
+    fn new(geometry: Geometry) -> Shape {
+        let mut extent = (f64::MAX, f64::MAX, f64::MIN, f64::MIN);
+        let mut add_point = |point: &(f64, f64)| {
+            let (x, y) = coords_to_xy(point, 0);
+            extent = (extent.0.min(x), extent.1.min(y), extent.2.max(x), extent.3.max(y));
+        };
+        match &geometry {
+            Geometry::Point(point) => add_point(point),
+            Geometry::Line(points) => points.iter().for_each(add_point),
+            Geometry::Polygon(rings) => rings.iter().flatten().for_each(add_point),
+        }
+        Shape { geometry, extent }
+    }
+
+    // `margin` is in pixels at the zoom level of the tile.
+    pub fn intersects(&self, tile: &Tile, margin: f64) -> bool {
+        let zoom_scale = f64::from(1 << tile.zoom);
+        let tile_size = f64::from(TILE_SIZE);
+        let (min_x, min_y) = (
+            f64::from(tile.x) * tile_size - margin,
+            f64::from(tile.y) * tile_size - margin,
+        );
+        let (max_x, max_y) = (min_x + tile_size + 2.0 * margin, min_y + tile_size + 2.0 * margin);
+        let (ext_min_x, ext_min_y, ext_max_x, ext_max_y) = self.extent;
+        ext_min_x * zoom_scale <= max_x
+            && ext_max_x * zoom_scale >= min_x
+            && ext_min_y * zoom_scale <= max_y
+            && ext_max_y * zoom_scale >= min_y
+    }
+}
+
+impl Overlay {
+    pub fn load(file_name: &str, style: OverlayStyle) -> Result<Overlay> {
+        let text = fs::read_to_string(file_name).context(format!("Failed to read {}", file_name))?;
+        let json = parse_json(&text).context(format!("Failed to parse {} as JSON", file_name))?;
+        let mut shapes = Vec::new();
+        add_shapes(&json, &mut shapes).context(format!("Failed to read GeoJSON from {}", file_name))?;
+        Ok(Overlay { style, shapes })
+    }
+}
+
+fn add_shapes(value: &JsonValue, shapes: &mut Vec<Shape>) -> Result<()> {
+    let geometry_type = match value.get("type").and_then(JsonValue::as_str) {
+        Some(geometry_type) => geometry_type,
+        None => bail!("A GeoJSON object without a type"),
+    };
+    let coordinates = || match value.get("coordinates") {
+        Some(coordinates) => Ok(coordinates),
+        None => bail!("{} without coordinates", geometry_type),
+    };
+    let members = |key| match value.get(key).and_then(JsonValue::as_array) {
+        Some(members) => Ok(members),
+        None => bail!("{} without {}", geometry_type, key),
+    };
+
+    match geometry_type {
+        "FeatureCollection" => {
+            for feature in members("features")? {
+                add_shapes(feature, shapes)?;
+            }
+        }
+        "Feature" => match value.get("geometry") {
+            Some(JsonValue::Null) | None => {}
+            Some(geometry) => add_shapes(geometry, shapes)?,
+        },
+        "GeometryCollection" => {
+            for geometry in members("geometries")? {
+                add_shapes(geometry, shapes)?;
+            }
+        }
+        "Point" => shapes.push(Shape::new(Geometry::Point(to_position(coordinates()?)?))),
+        "MultiPoint" => {
+            for point in to_positions(coordinates()?)? {
+                shapes.push(Shape::new(Geometry::Point(point)));
+            }
+        }
+        "LineString" => shapes.push(Shape::new(Geometry::Line(to_positions(coordinates()?)?))),
+        "MultiLineString" => {
+            for line in to_array(coordinates()?)? {
+                shapes.push(Shape::new(Geometry::Line(to_positions(line)?)));
+            }
+        }
+        "Polygon" => shapes.push(Shape::new(Geometry::Polygon(to_rings(coordinates()?)?))),
+        "MultiPolygon" => {
+            for polygon in to_array(coordinates()?)? {
+                shapes.push(Shape::new(Geometry::Polygon(to_rings(polygon)?)));
+            }
+        }
+        _ => bail!("Unsupported GeoJSON type: {}", geometry_type),
+    }
+    Ok(())
+}
+
+fn to_array(value: &JsonValue) -> Result<&[JsonValue]> {
+    match value.as_array() {
+        Some(values) => Ok(values),
+        None => bail!("Expected an array of coordinates"),
+    }
+}
+
+// GeoJSON positions are [lon, lat] (with an optional altitude, which we ignore).
+fn to_position(value: &JsonValue) -> Result<(f64, f64)> {
+    match to_array(value)? {
+        [lon, lat, ..] => match (lat.as_f64(), lon.as_f64()) {
+            (Some(lat), Some(lon)) => Ok((lat, lon)),
+            _ => bail!("Position coordinates should be numbers"),
+        },
+        _ => bail!("A position should have at least 2 coordinates"),
+    }
+}
+
+fn to_positions(value: &JsonValue) -> Result<Vec<(f64, f64)>> {
+    to_array(value)?.iter().map(to_position).collect()
+}
+
+fn to_rings(value: &JsonValue) -> Result<Vec<Vec<(f64, f64)>>> {
+    to_array(value)?.iter().map(to_positions).collect()
+}
+
+// '#' starts a comment in the config files, so the hex colors are also accepted without it.
+fn parse_color(value: &str) -> Result<Color> {
+    if let Some(color) = from_color_name(value) {
+        return Ok(color);
+    }
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    let component = |idx: usize| u8::from_str_radix(&hex[2 * idx..2 * idx + 2], 16);
+    match (hex.len(), hex.bytes().all(|b| b.is_ascii_hexdigit())) {
+        (6, true) => Ok(Color {
+            r: component(0)?,
+            g: component(1)?,
+            b: component(2)?,
+        }),
+        _ => bail!("Invalid color (expected a color name or RRGGBB): {}", value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_geojson_shapes() {
+        let json = parse_json(
+            r##"{"type": "FeatureCollection", "features": [
+                {"type": "Feature", "properties": {}, "geometry": {"type": "Point", "coordinates": [37.61, 55.75]}},
+                {"type": "Feature", "properties": {}, "geometry": null},
+                {"type": "Feature", "geometry": {"type": "MultiLineString", "coordinates": [[[37.6, 55.7], [37.7, 55.8, 150.0]], [[0, 0], [1, 1]]]}},
+                {"type": "Feature", "geometry": {"type": "Polygon", "coordinates": [[[37.6, 55.7], [37.7, 55.7], [37.7, 55.8], [37.6, 55.7]]]}}
+            ]}"##,
+        )
+        .unwrap();
+        let mut shapes = Vec::new();
+        add_shapes(&json, &mut shapes).unwrap();
+        assert_eq!(shapes.len(), 4);
+        assert!(matches!(shapes[0].geometry, Geometry::Point((lat, lon)) if lat == 55.75 && lon == 37.61));
+        assert!(matches!(&shapes[1].geometry, Geometry::Line(points) if points == &[(55.7, 37.6), (55.8, 37.7)]));
+        assert!(matches!(&shapes[3].geometry, Geometry::Polygon(rings) if rings.len() == 1 && rings[0].len() == 4));
+
+        let moscow_tile = Tile {
+            zoom: 16,
+            x: 39_614,
+            y: 20_486,
+        };
+        assert!(!shapes[0].intersects(&moscow_tile, 0.0));
+        assert!(shapes[1].intersects(&moscow_tile, 0.0));
+        assert!(!shapes[2].intersects(&moscow_tile, 10.0));
+
+        assert!(add_shapes(
+            &parse_json(r#"{"type": "Point", "coordinates": [1]}"#).unwrap(),
+            &mut shapes
+        )
+        .is_err());
+        assert_eq!(
+            parse_color("#c4d4f5").unwrap(),
+            Color {
+                r: 0xc4,
+                g: 0xd4,
+                b: 0xf5
+            }
+        );
+        assert_eq!(
+            parse_color("c4d4f5").unwrap(),
+            Color {
+                r: 0xc4,
+                g: 0xd4,
+                b: 0xf5
+            }
+        );
+        assert!(parse_color("#c4d4").is_err());
+    }
+}
@@ -1,21 +0,0 @@
-use std::marker::PhantomData;
-
-#[derive(Default)]
-pub struct PerfStats {
-    _dummy: PhantomData<()>,
-}
-pub struct Measurer;
-
-impl PerfStats {
-    pub fn to_html(&self) -> String {
-        unimplemented!("This dummy implementation doesn't support HTML rendering")
-    }
-}
-
-pub fn start_tile(_: u8) {}
-
-pub fn finish_tile(_: &mut PerfStats) {}
-
-pub fn measure(_: impl Into<String>) -> Measurer {
-    Measurer {}
-}
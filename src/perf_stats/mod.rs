@@ -1,9 +1,419 @@
-#[cfg(feature = "perf-stats")]
-mod real_impl;
-#[cfg(feature = "perf-stats")]
-pub use real_impl::{finish_tile, measure, start_tile, PerfStats};
-
-#[cfg(not(feature = "perf-stats"))]
-mod dummy_impl;
-#[cfg(not(feature = "perf-stats"))]
-pub use dummy_impl::{finish_tile, measure, start_tile, PerfStats};
+use crate::mapcss::styler::StyleCacheStats;
+use indexmap::IndexMap;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::time::Duration;
+use std::time::Instant;
+
+// The rendering stages of a tile are timed only after `start_tile()` has been called on the current thread,
+// and `measure()` does nothing otherwise. The server does this for every tile when built with the `perf-stats`
+// feature (to collect the averages shown at /perf_stats) or when it's asked to trace slow tiles.
+// Besides the durations, the stages can `count()` the things they skip (e.g. the labels dropped by the limit).
+
+struct PerfStatsElement {
+    duration: Duration,
+    children: IndexMap<String, Rc<RefCell<PerfStatsElement>>>,
+}
+
+type PerfStatsElementRef = Rc<RefCell<PerfStatsElement>>;
+type PerfStatsElementStackRef = Rc<RefCell<Vec<PerfStatsElementRef>>>;
+
+impl PerfStatsElement {
+    fn create() -> PerfStatsElementRef {
+        Rc::new(RefCell::new(PerfStatsElement {
+            duration: Duration::default(),
+            children: IndexMap::new(),
+        }))
+    }
+}
+
+struct Measurement {
+    start_time: Instant,
+    element: PerfStatsElementRef,
+    element_stack: PerfStatsElementStackRef,
+}
+
+pub struct Measurer(Option<Measurement>);
+
+impl Drop for Measurer {
+    fn drop(&mut self) {
+        if let Some(ref measurement) = self.0 {
+            measurement.element.borrow_mut().duration += Instant::now() - measurement.start_time;
+            measurement.element_stack.borrow_mut().pop();
+        }
+    }
+}
+
+pub struct TilePerfStats {
+    zoom: u8,
+    root_element: PerfStatsElementRef,
+    element_stack: PerfStatsElementStackRef,
+    counters: IndexMap<String, u64>,
+    start_time: Instant,
+}
+
+impl TilePerfStats {
+    fn new(zoom: u8) -> TilePerfStats {
+        let root = PerfStatsElement::create();
+        TilePerfStats {
+            zoom,
+            root_element: Rc::clone(&root),
+            element_stack: Rc::new(RefCell::new(vec![Rc::clone(&root)])),
+            counters: IndexMap::new(),
+            start_time: Instant::now(),
+        }
+    }
+
+    fn measure(&self, name: impl Into<String>) -> Measurer {
+        let mut stack = self.element_stack.borrow_mut();
+
+        let new_element = {
+            let name = name.into();
+            let mut current_element = stack.last().unwrap().borrow_mut();
+
+            if let Some(existing_child) = current_element.children.get(&name) {
+                Rc::clone(existing_child)
+            } else {
+                let new_child = PerfStatsElement::create();
+                current_element.children.insert(name, Rc::clone(&new_child));
+                new_child
+            }
+        };
+
+        stack.push(Rc::clone(&new_element));
+
+        Measurer(Some(Measurement {
+            start_time: Instant::now(),
+            element: Rc::clone(&new_element),
+            element_stack: Rc::clone(&self.element_stack),
+        }))
+    }
+
+    fn count(&mut self, name: impl Into<String>, value: u64) {
+        *self.counters.entry(name.into()).or_default() += value;
+    }
+
+    fn finalize(&mut self) {
+        self.root_element.borrow_mut().duration = Instant::now() - self.start_time;
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.root_element.borrow().duration
+    }
+
+    // One line per stage, indented according to the nesting of the stages.
+    pub fn to_text(&self) -> String {
+        let mut text_dump = String::new();
+        dump_perf_stats_element("TOTAL", &self.root_element, 0, &mut text_dump);
+        for (name, value) in self.counters.iter() {
+            text_dump += &format!("{}: {}\n", name, value);
+        }
+        text_dump
+    }
+}
+
+fn dump_perf_stats_element(
+    current_name: &str,
+    current_element: &PerfStatsElementRef,
+    depth: usize,
+    text_dump: &mut String,
+) {
+    let element = current_element.borrow();
+    *text_dump += &format!("{}{}: {:.3?}\n", "  ".repeat(depth), current_name, element.duration);
+    for (child_name, child) in element.children.iter() {
+        dump_perf_stats_element(child_name, child, depth + 1, text_dump);
+    }
+}
+
+#[derive(Default)]
+struct SummedPerfStatsElement {
+    duration_sum: Duration,
+    children: IndexMap<String, Box<SummedPerfStatsElement>>,
+}
+
+impl SummedPerfStatsElement {
+    fn add(&mut self, element: &PerfStatsElementRef) {
+        self.duration_sum += element.borrow().duration;
+        for (other_child_name, other_child) in element.borrow().children.iter() {
+            if let Some(our_child) = self.children.get_mut(other_child_name) {
+                our_child.add(other_child);
+            } else {
+                let mut new_child = Box::<SummedPerfStatsElement>::default();
+                new_child.add(other_child);
+                self.children.insert(other_child_name.clone(), new_child);
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct SummedPerfStats {
+    root_element: SummedPerfStatsElement,
+    counters: IndexMap<String, u64>,
+    count: u32,
+}
+
+#[derive(Default)]
+pub struct PerfStats {
+    stats_by_zoom: BTreeMap<u8, SummedPerfStats>,
+    style_cache_stats: Option<StyleCacheStats>,
+}
+
+impl PerfStats {
+    pub fn add_tile_stats(&mut self, tile_stats: &TilePerfStats) {
+        let zoom_stats = self.stats_by_zoom.entry(tile_stats.zoom).or_default();
+        zoom_stats.root_element.add(&tile_stats.root_element);
+        for (name, value) in tile_stats.counters.iter() {
+            *zoom_stats.counters.entry(name.clone()).or_default() += value;
+        }
+        zoom_stats.count += 1;
+    }
+
+    pub fn set_style_cache_stats(&mut self, stats: StyleCacheStats) {
+        self.style_cache_stats = Some(stats);
+    }
+
+    pub fn to_html(&self) -> String {
+        let html_template = include_str!("perf_stats.html");
+        let mut html_dump = String::new();
+        if let Some(ref cache_stats) = self.style_cache_stats {
+            let lookups = cache_stats.hits + cache_stats.misses;
+            html_dump += "<h1>Style cache</h1><dl>";
+            html_dump += &format!("<dt>Entries</dt> <dd>{}</dd>", cache_stats.entries);
+            html_dump += &format!(
+                "<dt>Hits</dt> <dd>{} ({:.2}%)</dd>",
+                cache_stats.hits,
+                100.0 * cache_stats.hits as f64 / lookups.max(1) as f64
+            );
+            html_dump += &format!("<dt>Misses</dt> <dd>{}</dd>", cache_stats.misses);
+            html_dump += &format!("<dt>Evictions</dt> <dd>{}</dd>", cache_stats.evictions);
+            html_dump += "</dl>";
+        }
+        for (zoom, zoom_stats) in self.stats_by_zoom.iter() {
+            html_dump += &format!("<h1>Zoom {} ({} tiles)</h1>", zoom, zoom_stats.count);
+            html_dump += "<dl>";
+            dump_summed_perf_stats_element(
+                "TOTAL",
+                &zoom_stats.root_element,
+                0,
+                None,
+                zoom_stats.count,
+                &mut html_dump,
+            );
+            html_dump += "</dl>";
+            if !zoom_stats.counters.is_empty() {
+                html_dump += "<dl>";
+                for (name, value) in zoom_stats.counters.iter() {
+                    html_dump += &format!(
+                        "<dt>{}</dt> <dd>{:.2} per tile ({} total)</dd>",
+                        name,
+                        *value as f64 / f64::from(zoom_stats.count),
+                        value
+                    );
+                }
+                html_dump += "</dl>";
+            }
+        }
+        html_template.replace("{{CONTENT}}", &html_dump)
+    }
+
+    // The same data as in `to_html()`, with the average durations per tile in milliseconds.
+    pub fn to_json(&self) -> String {
+        let mut json_dump = String::from("{");
+        if let Some(ref cache_stats) = self.style_cache_stats {
+            json_dump += &format!(
+                "\"style_cache\":{{\"entries\":{},\"hits\":{},\"misses\":{},\"evictions\":{}}},",
+                cache_stats.entries, cache_stats.hits, cache_stats.misses, cache_stats.evictions
+            );
+        }
+        json_dump += "\"zooms\":[";
+        for (idx, (zoom, zoom_stats)) in self.stats_by_zoom.iter().enumerate() {
+            if idx > 0 {
+                json_dump += ",";
+            }
+            json_dump += &format!("{{\"zoom\":{},\"tiles\":{},\"total\":", zoom, zoom_stats.count);
+            dump_summed_perf_stats_element_json("TOTAL", &zoom_stats.root_element, zoom_stats.count, &mut json_dump);
+            // The totals of the counters, unlike the durations, which are averaged.
+            if !zoom_stats.counters.is_empty() {
+                json_dump += ",\"counters\":{";
+                for (idx, (name, value)) in zoom_stats.counters.iter().enumerate() {
+                    if idx > 0 {
+                        json_dump += ",";
+                    }
+                    json_dump += &format!("\"{}\":{}", escape_json(name), value);
+                }
+                json_dump += "}";
+            }
+            json_dump += "}";
+        }
+        json_dump += "]}";
+        json_dump
+    }
+}
+
+fn dump_summed_perf_stats_element(
+    current_name: &str,
+    current_element: &SummedPerfStatsElement,
+    depth: usize,
+    parent_duration: Option<Duration>,
+    duration_count: u32,
+    html_dump: &mut String,
+) {
+    let normalized_duration = current_element.duration_sum / duration_count;
+    let to_float = |d: Duration| d.as_secs() as f64 + d.subsec_nanos() as f64 * 1e-9;
+    let percentage = if let Some(parent_duration) = parent_duration {
+        to_float(normalized_duration) / to_float(parent_duration)
+    } else {
+        1.0
+    };
+
+    let mut time_info = format!(
+        "<span class='percentage'>{:.2}%</span> <span class='duration'>({:.3?})</span>",
+        100.0 * percentage,
+        normalized_duration
+    );
+    if depth == 1 {
+        time_info = format!(
+            "<span style='background-color: rgba(255, 0, 0, {})'>{}</span>",
+            percentage, time_info
+        );
+    }
+
+    *html_dump += &format!("<dt>{}</dt> <dd>{}</dd>", current_name, time_info);
+
+    if !current_element.children.is_empty() {
+        *html_dump += "<dl>";
+        for (child_name, child) in current_element.children.iter() {
+            dump_summed_perf_stats_element(
+                child_name,
+                child,
+                depth + 1,
+                Some(normalized_duration),
+                duration_count,
+                html_dump,
+            );
+        }
+        *html_dump += "</dl>";
+    }
+}
+
+fn dump_summed_perf_stats_element_json(
+    current_name: &str,
+    current_element: &SummedPerfStatsElement,
+    duration_count: u32,
+    json_dump: &mut String,
+) {
+    let normalized_duration = current_element.duration_sum / duration_count;
+    *json_dump += &format!(
+        "{{\"name\":\"{}\",\"ms\":{:.3},\"children\":[",
+        escape_json(current_name),
+        normalized_duration.as_secs_f64() * 1e3
+    );
+    for (idx, (child_name, child)) in current_element.children.iter().enumerate() {
+        if idx > 0 {
+            *json_dump += ",";
+        }
+        dump_summed_perf_stats_element_json(child_name, child, duration_count, json_dump);
+    }
+    *json_dump += "]}";
+}
+
+pub(crate) fn escape_json(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => result += "\\\"",
+            '\\' => result += "\\\\",
+            c if c.is_control() => result += &format!("\\u{:04x}", c as u32),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+thread_local!(static TLS_PERF_STATS: RefCell<Option<TilePerfStats>> = const { RefCell::new(None) });
+
+pub fn start_tile(zoom: u8) {
+    TLS_PERF_STATS.with(|stats| stats.borrow_mut().replace(TilePerfStats::new(zoom)));
+}
+
+// Stops timing the current tile and returns what has been measured, if `start_tile()` was called before.
+pub fn finish_tile_stats() -> Option<TilePerfStats> {
+    TLS_PERF_STATS.with(|stats| {
+        let mut tile_stats = stats.borrow_mut().take()?;
+        tile_stats.finalize();
+        Some(tile_stats)
+    })
+}
+
+pub fn finish_tile(total_stats: &mut PerfStats) {
+    if let Some(tile_stats) = finish_tile_stats() {
+        total_stats.add_tile_stats(&tile_stats);
+    }
+}
+
+// Adds `value` to the counter with this name for the current tile, if `start_tile()` was called before.
+pub fn count(name: impl Into<String>, value: u64) {
+    TLS_PERF_STATS.with(|stats| {
+        if let Some(ref mut tile_stats) = *stats.borrow_mut() {
+            tile_stats.count(name, value);
+        }
+    })
+}
+
+pub fn measure(name: impl Into<String>) -> Measurer {
+    TLS_PERF_STATS.with(|stats| match *stats.borrow() {
+        Some(ref tile_stats) => tile_stats.measure(name),
+        None => Measurer(None),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_perf_stats_json() {
+        let mut perf_stats = PerfStats::default();
+        for _ in 0..2 {
+            start_tile(16);
+            {
+                let _m = measure("Draw \"areas\"");
+                let _m = measure("Fill");
+            }
+            finish_tile(&mut perf_stats);
+        }
+
+        let json = perf_stats.to_json();
+        assert!(json.starts_with("{\"zooms\":[{\"zoom\":16,\"tiles\":2,\"total\":{\"name\":\"TOTAL\",\"ms\":"));
+        assert!(json.contains("{\"name\":\"Draw \\\"areas\\\"\",\"ms\":"));
+        assert!(json.contains("\"children\":[{\"name\":\"Fill\",\"ms\":"));
+        assert!(json.ends_with("\"children\":[]}]}]}}]}"));
+
+        // Nothing is measured unless a tile has been started.
+        drop(measure("Unused"));
+        assert!(finish_tile_stats().is_none());
+
+        start_tile(17);
+        drop(measure("Style nodes"));
+        let lines = finish_tile_stats()
+            .unwrap()
+            .to_text()
+            .lines()
+            .map(|line| line.split(':').next().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec!["TOTAL", "  Style nodes"]);
+
+        start_tile(18);
+        count("Dropped labels", 2);
+        count("Dropped labels", 3);
+        let tile_stats = finish_tile_stats().unwrap();
+        assert!(tile_stats.to_text().ends_with("\nDropped labels: 5\n"));
+        perf_stats.add_tile_stats(&tile_stats);
+        perf_stats.add_tile_stats(&tile_stats);
+        assert!(perf_stats
+            .to_json()
+            .ends_with("\"counters\":{\"Dropped labels\":10}}]}"));
+    }
+}
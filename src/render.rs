@@ -0,0 +1,1069 @@
+use crate::coords::Coords;
+use crate::draw::drawer::{DebugOverlays, DrawOptions, Drawer, TileRenderedPixels};
+use crate::draw::labeler::LabelLimits;
+use crate::draw::pdf::{PdfDocument, PdfPage};
+use crate::draw::png_writer::{rgb_triples_to_png, PngCache, PngCompression};
+use crate::draw::svg::SvgDocument;
+use crate::draw::tile_pixels::{RgbTriples, TilePixels};
+use crate::draw::vector::VectorCanvas;
+use crate::geodata::prefilter::Prefilter;
+use crate::geodata::reader::{GeodataReader, OsmArea, OsmEntities, OsmEntity};
+use crate::geodata::zoom_masks::{stylesheet_hash, ZoomMasks};
+use crate::mapcss::parser::{format_rules, parse_file, parse_file_with_recovery};
+use crate::mapcss::styler::{CacheableEntity, Style, StyleType, StyleableEntity, Styler};
+use crate::overlay::Overlay;
+use crate::tile::{
+    coords_to_xy, neighbor_tiles, tile_at, tile_to_max_zoom_tile_range, LatLonBounds, Projection, Tile, MAX_ZOOM,
+    TILE_SIZE,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+// Everything needed to render tiles from one or several geodata files with a given stylesheet.
+// Can be shared between threads; each rendering thread gets its own TilePixels from the pool.
+pub struct TileRenderer<'a> {
+    // The file passed to `new()` comes first, followed by the ones added with `with_geodata_file()`.
+    // Every tile holds onto the readers it started with, so `reload_geodata()` can swap them at any time,
+    // and the old files are unmapped when the last tile rendered from them is done.
+    readers: RwLock<Arc<Vec<GeodataReader<'a>>>>,
+    geodata_files: Vec<String>,
+    styler: Styler,
+    drawer: Drawer,
+    osm_ids: Option<HashSet<u64>>,
+    prefilter: Prefilter,
+    overlays: Vec<Overlay>,
+    gamma_correction: bool,
+    stylesheet_hash: u64,
+    pixels_pool: Mutex<Vec<TilePixels>>,
+    png_cache: PngCache,
+    png_compression: PngCompression,
+    // The PNGs of the tiles outside of the geodata by the zoom level and the scale (see `is_outside_geodata()`).
+    blank_tiles: Mutex<HashMap<(u8, usize), Vec<u8>>>,
+}
+
+pub struct RgbaImage {
+    pub dimension: usize,
+    // `dimension * dimension` pixels, 4 bytes per pixel, row by row.
+    pub bytes: Vec<u8>,
+}
+
+// Restricts rendering to a subset of entities on top of `TileRenderer::with_osm_ids()`.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct EntityFilter {
+    // If set, only entities with these ids are rendered.
+    pub ids: Option<HashSet<u64>>,
+    pub excluded_ids: HashSet<u64>,
+}
+
+#[derive(Default)]
+pub struct RenderOptions {
+    pub filter: EntityFilter,
+    // If rendering takes longer than this, it's aborted with `DeadlineExceeded`.
+    pub deadline: Option<Instant>,
+    pub debug: DebugOverlays,
+    // Only `render_png_with_options()` reprojects the tiles; everything else renders in Web Mercator.
+    pub projection: Projection,
+}
+
+// An entity that is drawn at the point passed to `TileRenderer::query_point()`.
+#[derive(Debug, PartialEq)]
+pub struct QueriedEntity {
+    // "node", "way", "multipolygon" or "relation".
+    pub kind: &'static str,
+    pub id: u64,
+    pub tags: Vec<(String, String)>,
+}
+
+// The nodes with icons or labels are found within this many pixels, which is about the half of an icon.
+const QUERY_NODE_RADIUS: f64 = 8.0;
+// The thin lines are found within this many pixels, so that they are not too hard to hit.
+const QUERY_MIN_LINE_DISTANCE: f64 = 2.0;
+
+// A rectangle in global pixel coordinates (see `tile::coords_to_xy()`) at a given zoom level.
+#[derive(Debug, Eq, PartialEq)]
+pub struct PixelRegion {
+    pub zoom: u8,
+    pub left: u32,
+    pub top: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl PixelRegion {
+    pub fn from_bbox(min_lat: f64, min_lon: f64, max_lat: f64, max_lon: f64, zoom: u8) -> Result<PixelRegion> {
+        if min_lat >= max_lat || min_lon >= max_lon {
+            bail!("Invalid bounding box: {},{},{},{}", min_lat, min_lon, max_lat, max_lon);
+        }
+        let (left, top) = coords_to_xy(&(max_lat, min_lon), zoom);
+        let (right, bottom) = coords_to_xy(&(min_lat, max_lon), zoom);
+        let region = PixelRegion {
+            zoom,
+            left: left as u32,
+            top: top as u32,
+            width: (right - left).ceil().max(1.0) as u32,
+            height: (bottom - top).ceil().max(1.0) as u32,
+        };
+        region.validate()?;
+        Ok(region)
+    }
+
+    pub fn around_center(lat: f64, lon: f64, zoom: u8, width: u32, height: u32) -> Result<PixelRegion> {
+        let (x, y) = coords_to_xy(&(lat, lon), zoom);
+        // Shift the region if the center is too close to the edge of the map.
+        let world_size = f64::from(TILE_SIZE) * f64::from(1 << zoom.min(MAX_ZOOM));
+        let start = |center: f64, len: u32| {
+            (center - f64::from(len) / 2.0)
+                .min(world_size - f64::from(len))
+                .max(0.0)
+        };
+        let region = PixelRegion {
+            zoom,
+            left: start(x, width) as u32,
+            top: start(y, height) as u32,
+            width,
+            height,
+        };
+        region.validate()?;
+        Ok(region)
+    }
+
+    // Covers the region with pages of the same size, row by row, so that the neighboring pages share `overlap`
+    // pixels, like in an atlas. The last page of a row (or a column) is moved back to end where the region ends, and
+    // the pages are never larger than the region.
+    pub fn split_into_pages(&self, page_width: u32, page_height: u32, overlap: u32) -> Result<Vec<PixelRegion>> {
+        if overlap >= page_width.min(page_height) {
+            bail!(
+                "The pages overlap by {} pixels, which doesn't fit into {}x{} pages",
+                overlap,
+                page_width,
+                page_height
+            );
+        }
+        let page_starts = |start: u32, len: u32, page_len: u32| {
+            if len <= page_len {
+                return vec![(start, len)];
+            }
+            let step = page_len - overlap;
+            let mut starts = (0..)
+                .map(|idx| start + idx * step)
+                .take_while(|page_start| page_start + page_len < start + len)
+                .map(|page_start| (page_start, page_len))
+                .collect::<Vec<_>>();
+            starts.push((start + len - page_len, page_len));
+            starts
+        };
+        let columns = page_starts(self.left, self.width, page_width);
+        let mut pages = Vec::new();
+        for (top, height) in page_starts(self.top, self.height, page_height) {
+            for &(left, width) in &columns {
+                pages.push(PixelRegion {
+                    zoom: self.zoom,
+                    left,
+                    top,
+                    width,
+                    height,
+                });
+            }
+        }
+        Ok(pages)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let world_size = u64::from(TILE_SIZE) << self.zoom;
+        let fits = |start: u32, len: u32| len > 0 && u64::from(start) + u64::from(len) <= world_size;
+        if self.zoom > MAX_ZOOM || !fits(self.left, self.width) || !fits(self.top, self.height) {
+            bail!("{:?} is outside of the map", self);
+        }
+        Ok(())
+    }
+}
+
+// Enough for the largest WMS images, even if their extents are stretched a couple of times along one axis.
+const MAX_EXTENT_SOURCE_TILES: f64 = 1024.0;
+
+// Returns the first Web Mercator zoom level that has enough pixels for a `width` x `height` image of `bounds`. Fails
+// if the whole extent covers too many tiles at that zoom level, which happens when the image is much more stretched
+// than the extent (e.g. a thin strip around the world on a tall image).
+pub fn extent_zoom(bounds: &LatLonBounds, width: usize, height: usize) -> Result<u8> {
+    if bounds.min_lat >= bounds.max_lat || bounds.min_lon >= bounds.max_lon || width == 0 || height == 0 {
+        bail!("Invalid extent: {:?} at {}x{}", bounds, width, height);
+    }
+
+    let max_lat = Projection::WebMercator.xy_to_coords(0.0, 0.0, 0).0;
+    let clamp_lat = |lat: f64| lat.clamp(-max_lat, max_lat);
+    let source_size = |zoom: u8| {
+        let (left, top) = coords_to_xy(&(clamp_lat(bounds.max_lat), bounds.min_lon), zoom);
+        let (right, bottom) = coords_to_xy(&(clamp_lat(bounds.min_lat), bounds.max_lon), zoom);
+        (right - left, bottom - top)
+    };
+    let zoom = (0..=MAX_ZOOM)
+        .find(|&zoom| {
+            let (source_width, source_height) = source_size(zoom);
+            // Being half a pixel short is better than rendering four times more pixels.
+            source_width + 0.5 >= width as f64 && source_height + 0.5 >= height as f64
+        })
+        .unwrap_or(MAX_ZOOM);
+
+    let (source_width, source_height) = source_size(zoom);
+    let tile_count = |len: f64| (len / f64::from(TILE_SIZE)).ceil() + 1.0;
+    if tile_count(source_width) * tile_count(source_height) > MAX_EXTENT_SOURCE_TILES {
+        bail!(
+            "{:?} is too stretched for a {}x{} image: it would take {:.0}x{:.0} pixels at zoom level {}",
+            bounds,
+            width,
+            height,
+            source_width,
+            source_height,
+            zoom
+        );
+    }
+    Ok(zoom)
+}
+
+// The tiles outside of the map would overflow the tile coordinates at the maximum zoom level.
+fn validate_tile(tile: &Tile, projection: Projection) -> Result<()> {
+    if !projection.contains(tile) {
+        bail!("{:?} is outside of the {:?} map", tile, projection);
+    }
+    Ok(())
+}
+
+pub struct RgbImage {
+    pub width: usize,
+    pub height: usize,
+    // `width * height` pixels, row by row.
+    pub triples: RgbTriples,
+}
+
+impl RgbImage {
+    pub fn to_png(&self) -> Result<Vec<u8>> {
+        rgb_triples_to_png(&self.triples, self.width, self.height)
+    }
+
+    // Draws a round marker at a given geopoint, assuming that the image was rendered from `region` at `scale`.
+    pub fn draw_marker(&mut self, lat: f64, lon: f64, region: &PixelRegion, scale: usize) {
+        const FILL_COLOR: (u8, u8, u8) = (0xd0, 0x20, 0x20);
+        const OUTLINE_COLOR: (u8, u8, u8) = (0xff, 0xff, 0xff);
+
+        let (x, y) = coords_to_xy(&(lat, lon), region.zoom);
+        let scale_f = scale as f64;
+        let center_x = (x - f64::from(region.left)) * scale_f;
+        let center_y = (y - f64::from(region.top)) * scale_f;
+        let (radius, outline_radius) = (5.0 * scale_f, 7.0 * scale_f);
+
+        let to_range = |center: f64, limit: usize| {
+            let from = (center - outline_radius).floor().max(0.0) as usize;
+            let to = ((center + outline_radius).ceil().max(0.0) as usize).min(limit);
+            from..to
+        };
+        for py in to_range(center_y, self.height) {
+            for px in to_range(center_x, self.width) {
+                let dist = (px as f64 + 0.5 - center_x).hypot(py as f64 + 0.5 - center_y);
+                if dist <= radius {
+                    self.triples[py * self.width + px] = FILL_COLOR;
+                } else if dist <= outline_radius {
+                    self.triples[py * self.width + px] = OUTLINE_COLOR;
+                }
+            }
+        }
+    }
+}
+
+impl<'a> TileRenderer<'a> {
+    pub fn new(
+        geodata_file: &str,
+        stylesheet_file: &str,
+        stylesheet_type: &StyleType,
+        font_size_multiplier: Option<f64>,
+    ) -> Result<TileRenderer<'a>> {
+        Self::new_with_strictness(
+            geodata_file,
+            stylesheet_file,
+            stylesheet_type,
+            font_size_multiplier,
+            true,
+        )
+    }
+
+    // With `strict` set to false, the stylesheet rules with syntax errors are skipped (with a warning on stderr)
+    // instead of failing.
+    pub fn new_with_strictness(
+        geodata_file: &str,
+        stylesheet_file: &str,
+        stylesheet_type: &StyleType,
+        font_size_multiplier: Option<f64>,
+        strict: bool,
+    ) -> Result<TileRenderer<'a>> {
+        let (base_path, file_name) = split_stylesheet_path(stylesheet_file)?;
+        let rules = if strict {
+            parse_file(&base_path, &file_name).context("Failed to parse the stylesheet file")?
+        } else {
+            let (rules, diagnostics) =
+                parse_file_with_recovery(&base_path, &file_name).context("Failed to parse the stylesheet file")?;
+            for diagnostic in diagnostics {
+                eprintln!("Warning: {}", diagnostic);
+            }
+            rules
+        };
+        let type_name = match stylesheet_type {
+            StyleType::Josm => "josm",
+            StyleType::MapsMe => "mapsme",
+        };
+        let stylesheet_hash = stylesheet_hash(&format!("{}\n{}", type_name, format_rules(&rules)));
+
+        Ok(TileRenderer {
+            readers: RwLock::new(Arc::new(vec![
+                GeodataReader::load(geodata_file).context("Failed to load the geodata file")?
+            ])),
+            geodata_files: vec![geodata_file.to_string()],
+            styler: Styler::new(rules, stylesheet_type, font_size_multiplier),
+            drawer: Drawer::new(&base_path),
+            osm_ids: None,
+            prefilter: Prefilter::default(),
+            overlays: Vec::new(),
+            gamma_correction: false,
+            stylesheet_hash,
+            pixels_pool: Mutex::new(Vec::new()),
+            png_cache: PngCache::default(),
+            png_compression: PngCompression::default(),
+            blank_tiles: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Only entities with these ids will be rendered.
+    #[allow(clippy::implicit_hasher)]
+    pub fn with_osm_ids(mut self, osm_ids: Option<HashSet<u64>>) -> TileRenderer<'a> {
+        self.osm_ids = osm_ids;
+        self
+    }
+
+    // These will be drawn on top of all OSM data.
+    pub fn with_overlays(mut self, overlays: Vec<Overlay>) -> TileRenderer<'a> {
+        self.overlays = overlays;
+        self
+    }
+
+    // Limits the number of cached styles for every zoom level and kind of entities.
+    pub fn with_style_cache_capacity(mut self, capacity: usize) -> TileRenderer<'a> {
+        self.styler.set_style_cache_capacity(capacity);
+        self
+    }
+
+    // Prefer the labels in these languages (see `Styler::set_label_languages()`).
+    pub fn with_label_languages(mut self, languages: Vec<String>) -> TileRenderer<'a> {
+        self.styler.set_label_languages(languages);
+        self
+    }
+
+    // Print how the properties of this entity override each other (see `Styler::set_debug_entity_id()`).
+    pub fn with_debug_entity_id(mut self, id: Option<u64>) -> TileRenderer<'a> {
+        self.styler.set_debug_entity_id(id);
+        self
+    }
+
+    // Lets a single tile use several cores (see `Drawer::set_drawing_threads()`).
+    pub fn with_drawing_threads(mut self, threads: usize) -> TileRenderer<'a> {
+        self.drawer.set_drawing_threads(threads);
+        self
+    }
+
+    // Drops the entities that are unlikely to be drawn at low zoom levels before styling them (see `Prefilter`).
+    pub fn with_prefilter(mut self, prefilter: Prefilter) -> TileRenderer<'a> {
+        self.prefilter = prefilter;
+        self
+    }
+
+    // Limits the number of labels on a tile, keeping the most important ones (see `Labeler::label_entities()`).
+    pub fn with_label_limits(mut self, label_limits: LabelLimits) -> TileRenderer<'a> {
+        self.drawer.set_label_limits(label_limits);
+        self
+    }
+
+    // Trade the size of the PNGs for the time it takes to encode them (see `PngCompression`).
+    pub fn with_png_compression(mut self, compression: PngCompression) -> TileRenderer<'a> {
+        self.png_compression = compression;
+        self
+    }
+
+    // Blend the colors in linear light instead of sRGB (see `TilePixels::set_gamma_correction()`).
+    pub fn with_gamma_correction(mut self, enabled: bool) -> TileRenderer<'a> {
+        self.gamma_correction = enabled;
+        self
+    }
+
+    // Lets the tiles skip the entities that aren't drawn at their zoom level without styling them.
+    // The masks must be computed by `bake_zoom_masks()` for the same geodata and stylesheet.
+    pub fn with_zoom_masks(mut self, zoom_masks: ZoomMasks) -> Result<TileRenderer<'a>> {
+        if zoom_masks.stylesheet_hash != self.stylesheet_hash {
+            bail!("The zoom masks were computed for a different stylesheet");
+        }
+        self.readers_mut()[0].set_zoom_masks(zoom_masks)?;
+        Ok(self)
+    }
+
+    // Renders the entities from another geodata file (e.g. for an adjacent region) together with the ones
+    // that are already loaded. The entities present in several files are drawn only once.
+    pub fn with_geodata_file(mut self, geodata_file: &str, zoom_masks: Option<ZoomMasks>) -> Result<TileRenderer<'a>> {
+        let mut reader =
+            GeodataReader::load(geodata_file).context(format!("Failed to load the geodata file {}", geodata_file))?;
+        if let Some(zoom_masks) = zoom_masks {
+            if zoom_masks.stylesheet_hash != self.stylesheet_hash {
+                bail!(
+                    "The zoom masks for {} were computed for a different stylesheet",
+                    geodata_file
+                );
+            }
+            reader
+                .set_zoom_masks(zoom_masks)
+                .context(format!("Failed to use the zoom masks for {}", geodata_file))?;
+        }
+        self.readers_mut().push(reader);
+        self.geodata_files.push(geodata_file.to_string());
+        Ok(self)
+    }
+
+    // Loads all geodata files again (e.g. after they were re-imported) and uses them for the tiles that start
+    // rendering after this. If any of the files fails to load, the old ones are kept. The zoom masks aren't used
+    // after a reload, since they describe the entities of the old files.
+    pub fn reload_geodata(&self) -> Result<()> {
+        let mut new_readers = Vec::new();
+        for geodata_file in &self.geodata_files {
+            new_readers.push(
+                GeodataReader::load(geodata_file)
+                    .context(format!("Failed to reload the geodata file {}", geodata_file))?,
+            );
+        }
+        if self.readers().iter().any(GeodataReader::has_zoom_masks) {
+            eprintln!("WARNING: the zoom masks are not used for the reloaded geodata, bake them again and restart");
+        }
+        *self.readers.write().unwrap() = Arc::new(new_readers);
+        Ok(())
+    }
+
+    // Styles every entity of the geodata file passed to `new()` at every zoom level, which takes a while,
+    // but only has to be done once.
+    pub fn bake_zoom_masks(&self) -> ZoomMasks {
+        fn compute_masks<'e, E>(styler: &Styler, entities: &[E]) -> Vec<u32>
+        where
+            E: CacheableEntity + StyleableEntity + OsmEntity<'e>,
+        {
+            entities
+                .iter()
+                .map(|entity| {
+                    (0..=MAX_ZOOM)
+                        .filter(|zoom| !styler.style_entities(std::iter::once(entity), *zoom, false).is_empty())
+                        .fold(0, |mask, zoom| mask | (1 << zoom))
+                })
+                .collect()
+        }
+
+        let readers = self.readers();
+        let OsmEntities {
+            nodes,
+            ways,
+            multipolygons,
+            relations,
+        } = readers[0].all_entities();
+        ZoomMasks {
+            stylesheet_hash: self.stylesheet_hash,
+            nodes: compute_masks(&self.styler, &nodes),
+            ways: compute_masks(&self.styler, &ways),
+            multipolygons: compute_masks(&self.styler, &multipolygons),
+            relations: compute_masks(&self.styler, &relations),
+        }
+    }
+
+    // The geodata files that are currently used for rendering, in the order in which they were added.
+    pub fn readers(&self) -> Arc<Vec<GeodataReader<'a>>> {
+        self.readers.read().unwrap().clone()
+    }
+
+    // Whether the tile is certainly blank because there is no geodata in it or in its neighbors (which are drawn
+    // together with it). The overlays can be anywhere, so the tiles are never blank with them.
+    pub fn is_outside_geodata(&self, tile: &Tile) -> bool {
+        if !self.overlays.is_empty() {
+            return false;
+        }
+        let neighbor_ranges = neighbor_tiles(tile)
+            .iter()
+            .map(|(neighbor, _)| tile_to_max_zoom_tile_range(neighbor))
+            .collect::<Vec<_>>();
+        self.readers().iter().all(|reader| {
+            reader
+                .tile_range()
+                .is_none_or(|data_range| neighbor_ranges.iter().all(|range| !range.intersects(data_range)))
+        })
+    }
+
+    // Whether the tile shows nothing but the canvas, because it's outside of the geodata or none of the entities drawn
+    // with it has a style at its zoom level (like the tiles in the middle of the sea, which has no polygons in OSM).
+    // All such tiles of a zoom level look the same.
+    pub fn is_blank(&self, tile: &Tile) -> bool {
+        if self.is_outside_geodata(tile) {
+            return true;
+        }
+        if !self.overlays.is_empty() {
+            return false;
+        }
+        let readers = self.readers();
+        let entities = self.get_entities(&readers, tile, &EntityFilter::default());
+        is_unstyled(&self.styler, &entities.nodes, tile.zoom)
+            && is_unstyled(&self.styler, &entities.ways, tile.zoom)
+            && is_unstyled(&self.styler, &entities.multipolygons, tile.zoom)
+            && is_unstyled(&self.styler, &entities.relations, tile.zoom)
+    }
+
+    // The bounding box of all geodata files, if all of them have it (see `GeodataReader::extent()`).
+    pub fn geodata_extent(&self) -> Option<LatLonBounds> {
+        let extents = self
+            .readers()
+            .iter()
+            .map(|reader| reader.extent())
+            .collect::<Option<Vec<_>>>()?;
+        extents.into_iter().reduce(|a, b| LatLonBounds {
+            min_lat: a.min_lat.min(b.min_lat),
+            min_lon: a.min_lon.min(b.min_lon),
+            max_lat: a.max_lat.max(b.max_lat),
+            max_lon: a.max_lon.max(b.max_lon),
+        })
+    }
+
+    pub fn styler(&self) -> &Styler {
+        &self.styler
+    }
+
+    // Finds the entities that are drawn at the geopoint at the given zoom level: the areas that are filled there,
+    // the lines passing through it, and the nodes with icons or labels around it. The result lists the nodes first,
+    // followed by the ways, the multipolygons and the relations, each kind in the order in which it's stored.
+    pub fn query_point(&self, lat: f64, lon: f64, zoom: u8) -> Result<Vec<QueriedEntity>> {
+        if zoom > MAX_ZOOM {
+            bail!("Zoom level {} is too high (the maximum is {})", zoom, MAX_ZOOM);
+        }
+        let readers = self.readers();
+        let entities = self.get_entities(&readers, &tile_at(&(lat, lon), zoom), &EntityFilter::default());
+        let point = coords_to_xy(&(lat, lon), zoom);
+        let to_xy = |coords: &dyn Coords| coords_to_xy(&(coords.lat(), coords.lon()), zoom);
+
+        let mut result = Vec::new();
+        query_entities(
+            &self.styler,
+            &entities.nodes,
+            zoom,
+            "node",
+            &mut result,
+            |node, style| {
+                let has_marker = style.icon_image.is_some() || style.text_style.is_some();
+                has_marker && distance(point, to_xy(node)) <= QUERY_NODE_RADIUS
+            },
+        );
+        query_entities(&self.styler, &entities.ways, zoom, "way", &mut result, |way, style| {
+            let line = (0..way.node_count()).map(|idx| to_xy(&way.get_node(idx))).collect();
+            is_drawn_at(point, &[line], style, way.is_closed())
+        });
+        query_entities(
+            &self.styler,
+            &entities.multipolygons,
+            zoom,
+            "multipolygon",
+            &mut result,
+            |mp, style| {
+                let polygons = (0..mp.polygon_count())
+                    .map(|idx| {
+                        let polygon = mp.get_polygon(idx);
+                        (0..polygon.node_count())
+                            .map(|idx| to_xy(&polygon.get_node(idx)))
+                            .collect()
+                    })
+                    .collect::<Vec<_>>();
+                is_drawn_at(point, &polygons, style, true)
+            },
+        );
+        query_entities(
+            &self.styler,
+            &entities.relations,
+            zoom,
+            "relation",
+            &mut result,
+            |rel, style| {
+                let lines = (0..rel.line_count())
+                    .map(|idx| {
+                        let line = rel.get_line(idx);
+                        (0..line.node_count()).map(|idx| to_xy(&line.get_node(idx))).collect()
+                    })
+                    .collect::<Vec<_>>();
+                is_drawn_at(point, &lines, style, false)
+            },
+        );
+        Ok(result)
+    }
+
+    pub fn render_png(&self, tile: &Tile, scale: usize) -> Result<Vec<u8>> {
+        self.render_png_with_options(tile, scale, &RenderOptions::default())
+    }
+
+    pub fn render_png_with_options(&self, tile: &Tile, scale: usize, options: &RenderOptions) -> Result<Vec<u8>> {
+        if options.projection == Projection::PlateCarree {
+            let image = self.render_plate_carree(tile, scale, options)?;
+            let _m = crate::perf_stats::measure("RGB bytes to PNG");
+            let bytes = image
+                .triples
+                .iter()
+                .flat_map(|&(r, g, b)| [r, g, b])
+                .collect::<Vec<_>>();
+            return self
+                .png_cache
+                .rgb_bytes_to_png(&bytes, image.width, image.height, self.png_compression);
+        }
+
+        validate_tile(tile, Projection::WebMercator)?;
+        // All blank tiles of a zoom level look the same, so only the first one is rendered.
+        if options.debug == DebugOverlays::default() && self.is_outside_geodata(tile) {
+            if let Some(png) = self.blank_tiles.lock().unwrap().get(&(tile.zoom, scale)) {
+                return Ok(png.clone());
+            }
+            let png = self.render_mercator_png(tile, scale, options)?;
+            self.blank_tiles.lock().unwrap().insert((tile.zoom, scale), png.clone());
+            return Ok(png);
+        }
+        self.render_mercator_png(tile, scale, options)
+    }
+
+    fn render_mercator_png(&self, tile: &Tile, scale: usize, options: &RenderOptions) -> Result<Vec<u8>> {
+        self.with_rendered_pixels(tile, scale, options, |pixels| {
+            let _m = crate::perf_stats::measure("RGB bytes to PNG");
+            let dimension = pixels.dimension();
+            self.png_cache
+                .rgb_bytes_to_png(pixels.to_rgb_bytes(), dimension, dimension, self.png_compression)
+        })
+    }
+
+    // The same tile as `render_png_with_options()` in Web Mercator, but drawn as an SVG document without the icons and
+    // the debug overlays (see `Drawer::draw_svg()`).
+    pub fn render_svg(&self, tile: &Tile, scale: usize, options: &RenderOptions) -> Result<String> {
+        validate_tile(tile, Projection::WebMercator)?;
+        let dimension = scale * TILE_SIZE as usize;
+        let mut svg = SvgDocument::new(dimension, self.styler.canvas_fill_color(tile.zoom));
+        self.draw_vector_tile(tile, scale, options, &mut svg)?;
+        Ok(svg.finish())
+    }
+
+    // Draws every region on its own page, which is as large in PDF points as the region is in the pixels of the
+    // tiles at this scale. The tiles are drawn like by `render_svg()` and clipped to the page.
+    pub fn render_pdf(&self, pages: &[PixelRegion], scale: usize, options: &RenderOptions) -> Result<Vec<u8>> {
+        let mut document = PdfDocument::default();
+        let tile_size = TILE_SIZE as usize * scale;
+        for region in pages {
+            region.validate()?;
+            let (left, top) = (region.left as usize * scale, region.top as usize * scale);
+            let (width, height) = (region.width as usize * scale, region.height as usize * scale);
+            let mut page = PdfPage::new(width as f64, height as f64, self.styler.canvas_fill_color(region.zoom));
+            for tile_y in top / tile_size..=(top + height - 1) / tile_size {
+                for tile_x in left / tile_size..=(left + width - 1) / tile_size {
+                    let tile = Tile {
+                        zoom: region.zoom,
+                        x: tile_x as u32,
+                        y: tile_y as u32,
+                    };
+                    let (tile_left, tile_top) = (tile_x * tile_size, tile_y * tile_size);
+                    page.begin_tile(
+                        tile_left as f64 - left as f64,
+                        tile_top as f64 - top as f64,
+                        tile_size as f64,
+                    );
+                    self.draw_vector_tile(&tile, scale, options, &mut page)?;
+                    page.end_tile();
+                }
+            }
+            document.add_page(page);
+        }
+        document.to_bytes()
+    }
+
+    fn draw_vector_tile(
+        &self,
+        tile: &Tile,
+        scale: usize,
+        options: &RenderOptions,
+        canvas: &mut dyn VectorCanvas,
+    ) -> Result<()> {
+        let readers = self.readers();
+        let entities = {
+            let _m = crate::perf_stats::measure("Get tile entities");
+            self.get_entities(&readers, tile, &options.filter)
+        };
+
+        // The labels are still placed on the pixels to find out which of them collide.
+        let mut pixels = self.take_pixels(scale);
+        let result = self.drawer.draw_vector(
+            &entities,
+            tile,
+            &mut pixels,
+            scale,
+            &self.styler,
+            &DrawOptions {
+                deadline: options.deadline,
+                debug: DebugOverlays::default(),
+            },
+            canvas,
+        );
+        self.pixels_pool.lock().unwrap().push(pixels);
+
+        result
+    }
+
+    pub fn render_rgba(&self, tile: &Tile, scale: usize) -> Result<RgbaImage> {
+        validate_tile(tile, Projection::WebMercator)?;
+        let rendered_pixels = self.render_pixels(tile, scale, &RenderOptions::default())?;
+        let bytes = rendered_pixels
+            .triples
+            .iter()
+            .flat_map(|&(r, g, b)| [r, g, b, u8::MAX])
+            .collect();
+        Ok(RgbaImage {
+            dimension: rendered_pixels.dimension,
+            bytes,
+        })
+    }
+
+    // Stitches together all tiles intersecting the region and crops the result.
+    pub fn render_region(&self, region: &PixelRegion, scale: usize, options: &RenderOptions) -> Result<RgbImage> {
+        region.validate()?;
+
+        let tile_size = TILE_SIZE as usize * scale;
+        let (left, top) = (region.left as usize * scale, region.top as usize * scale);
+        let (width, height) = (region.width as usize * scale, region.height as usize * scale);
+        let mut triples = vec![(0, 0, 0); width * height];
+
+        for tile_y in top / tile_size..=(top + height - 1) / tile_size {
+            for tile_x in left / tile_size..=(left + width - 1) / tile_size {
+                let tile = Tile {
+                    zoom: region.zoom,
+                    x: tile_x as u32,
+                    y: tile_y as u32,
+                };
+                let rendered = self.render_pixels(&tile, scale, options)?;
+
+                let (tile_left, tile_top) = (tile_x * tile_size, tile_y * tile_size);
+                let (from_x, to_x) = (left.max(tile_left), (left + width).min(tile_left + tile_size));
+                for y in top.max(tile_top)..(top + height).min(tile_top + tile_size) {
+                    let src = (y - tile_top) * tile_size;
+                    let dst = (y - top) * width;
+                    triples[dst + from_x - left..dst + to_x - left]
+                        .copy_from_slice(&rendered.triples[src + from_x - tile_left..src + to_x - tile_left]);
+                }
+            }
+        }
+
+        Ok(RgbImage { width, height, triples })
+    }
+
+    // A plate carrée tile has as many pixels per degree of longitude as a Web Mercator tile one zoom level deeper,
+    // and the same columns, so every row of it is a row of the Web Mercator image covering the same latitudes. The
+    // rows closer to the poles than Web Mercator goes are filled with the canvas color.
+    pub fn render_plate_carree(&self, tile: &Tile, scale: usize, options: &RenderOptions) -> Result<RgbImage> {
+        let projection = Projection::PlateCarree;
+        validate_tile(tile, projection)?;
+
+        let mercator_zoom = tile.zoom + 1;
+        let size = TILE_SIZE as usize * scale;
+        let pixel_center = |start: u32, idx: usize| f64::from(start * TILE_SIZE) + (idx as f64 + 0.5) / scale as f64;
+        let source_columns = (0..size)
+            .map(|column| Some(pixel_center(tile.x, column)))
+            .collect::<Vec<_>>();
+        let source_rows = (0..size)
+            .map(|row| {
+                let coords = projection.xy_to_coords(0.0, pixel_center(tile.y, row), tile.zoom);
+                to_mercator_xy(&coords, mercator_zoom).1
+            })
+            .collect::<Vec<_>>();
+        self.render_resampled(mercator_zoom, scale, &source_columns, &source_rows, options)
+    }
+
+    // Renders `bounds` in `options.projection` onto a `width` x `height` image (e.g. for WMS requests). The pixels
+    // are taken from the Web Mercator image at the zoom level chosen by `extent_zoom()`.
+    pub fn render_extent(
+        &self,
+        bounds: &LatLonBounds,
+        width: usize,
+        height: usize,
+        options: &RenderOptions,
+    ) -> Result<RgbImage> {
+        let zoom = extent_zoom(bounds, width, height)?;
+        let projection = options.projection;
+        let (left, top) = projection.coords_to_xy(&(bounds.max_lat, bounds.min_lon), 0);
+        let (right, bottom) = projection.coords_to_xy(&(bounds.min_lat, bounds.max_lon), 0);
+        let pixel_center =
+            |start: f64, end: f64, idx: usize, count: usize| start + (end - start) * (idx as f64 + 0.5) / count as f64;
+        // In both projections, the longitude depends only on the column and the latitude only on the row.
+        let lons = (0..width)
+            .map(|column| {
+                projection
+                    .xy_to_coords(pixel_center(left, right, column, width), top, 0)
+                    .1
+            })
+            .collect::<Vec<_>>();
+        let lats = (0..height)
+            .map(|row| {
+                projection
+                    .xy_to_coords(left, pixel_center(top, bottom, row, height), 0)
+                    .0
+            })
+            .collect::<Vec<_>>();
+
+        let source_columns = lons
+            .iter()
+            .map(|&lon| to_mercator_xy(&(0.0, lon), zoom).0)
+            .collect::<Vec<_>>();
+        let source_rows = lats
+            .iter()
+            .map(|&lat| to_mercator_xy(&(lat, 0.0), zoom).1)
+            .collect::<Vec<_>>();
+        self.render_resampled(zoom, 1, &source_columns, &source_rows, options)
+    }
+
+    // Makes an image with a pixel for every pair of a source column and a source row, which are the global pixel
+    // coordinates in Web Mercator at `zoom` (`None` if they are off the map and should get the canvas color).
+    fn render_resampled(
+        &self,
+        zoom: u8,
+        scale: usize,
+        source_columns: &[Option<f64>],
+        source_rows: &[Option<f64>],
+        options: &RenderOptions,
+    ) -> Result<RgbImage> {
+        let (width, height) = (source_columns.len(), source_rows.len());
+        let canvas_color = self
+            .styler
+            .canvas_fill_color(zoom)
+            .map_or((0, 0, 0), |c| (c.r, c.g, c.b));
+        let mut triples = vec![canvas_color; width * height];
+
+        let source_range = |coords: &[Option<f64>]| {
+            let mut valid = coords.iter().flatten().map(|&coord| coord as u32);
+            let first = valid.next()?;
+            Some(valid.fold((first, first), |(min, max), coord| (min.min(coord), max.max(coord))))
+        };
+        if let (Some((left, right)), Some((top, bottom))) = (source_range(source_columns), source_range(source_rows)) {
+            let region = PixelRegion {
+                zoom,
+                left,
+                top,
+                width: right - left + 1,
+                height: bottom - top + 1,
+            };
+            let image = self.render_region(&region, scale, options)?;
+            let to_source = |coord: f64, start: u32, len: usize| {
+                (((coord - f64::from(start)) * scale as f64) as usize).min(len - 1)
+            };
+            for (row, source_y) in source_rows.iter().enumerate() {
+                let Some(source_y) = source_y else {
+                    continue;
+                };
+                let source_row = to_source(*source_y, top, image.height);
+                for (column, source_x) in source_columns.iter().enumerate() {
+                    if let Some(source_x) = source_x {
+                        let source_column = to_source(*source_x, left, image.width);
+                        triples[row * width + column] = image.triples[source_row * image.width + source_column];
+                    }
+                }
+            }
+        }
+
+        Ok(RgbImage { width, height, triples })
+    }
+
+    fn render_pixels(&self, tile: &Tile, scale: usize, options: &RenderOptions) -> Result<TileRenderedPixels> {
+        self.with_rendered_pixels(tile, scale, options, |pixels| {
+            Ok(TileRenderedPixels {
+                triples: pixels.to_rgb_triples(),
+                dimension: pixels.dimension(),
+            })
+        })
+    }
+
+    // Draws the tile on the pixels from the pool and lets `f` read them before they are returned to the pool.
+    fn with_rendered_pixels<T>(
+        &self,
+        tile: &Tile,
+        scale: usize,
+        options: &RenderOptions,
+        f: impl FnOnce(&mut TilePixels) -> Result<T>,
+    ) -> Result<T> {
+        let readers = self.readers();
+        let entities = {
+            let _m = crate::perf_stats::measure("Get tile entities");
+            self.get_entities(&readers, tile, &options.filter)
+        };
+
+        let mut pixels = self.take_pixels(scale);
+        let result = self
+            .drawer
+            .draw_into_pixels(
+                &entities,
+                tile,
+                &mut pixels,
+                scale,
+                &self.styler,
+                &self.overlays,
+                &DrawOptions {
+                    deadline: options.deadline,
+                    debug: options.debug,
+                },
+            )
+            .and_then(|()| f(&mut pixels));
+        self.pixels_pool.lock().unwrap().push(pixels);
+
+        result
+    }
+
+    // The entities of the tile and its neighbors from all geodata files.
+    fn get_entities<'r>(
+        &self,
+        readers: &'r [GeodataReader<'r>],
+        tile: &Tile,
+        filter: &EntityFilter,
+    ) -> OsmEntities<'r> {
+        let intersected_ids;
+        let osm_ids = match (&filter.ids, &self.osm_ids) {
+            (Some(ids), Some(global_ids)) => {
+                intersected_ids = Some(ids.intersection(global_ids).copied().collect());
+                &intersected_ids
+            }
+            (Some(_), None) => &filter.ids,
+            (None, _) => &self.osm_ids,
+        };
+        let mut entities = readers[0].get_entities_in_tile_with_neighbors(tile, osm_ids);
+        for reader in &readers[1..] {
+            entities.merge(reader.get_entities_in_tile_with_neighbors(tile, osm_ids));
+        }
+        if !filter.excluded_ids.is_empty() {
+            entities.exclude_ids(&filter.excluded_ids);
+        }
+        self.prefilter.apply(&mut entities, tile.zoom);
+        entities
+    }
+
+    // Only the builder methods can change the readers in place, since nothing else can hold onto them yet.
+    fn readers_mut(&mut self) -> &mut Vec<GeodataReader<'a>> {
+        Arc::get_mut(self.readers.get_mut().unwrap()).unwrap()
+    }
+
+    fn take_pixels(&self, scale: usize) -> TilePixels {
+        let mut pool = self.pixels_pool.lock().unwrap();
+        let dimension = scale * crate::tile::TILE_SIZE as usize;
+        match pool.iter().position(|p| p.dimension() == dimension) {
+            Some(idx) => pool.swap_remove(idx),
+            None => {
+                let _m = crate::perf_stats::measure("Allocating TilePixels");
+                let mut pixels = TilePixels::new(scale);
+                pixels.set_gamma_correction(self.gamma_correction);
+                pixels
+            }
+        }
+    }
+}
+
+fn is_unstyled<'e, E>(styler: &Styler, entities: &[E], zoom: u8) -> bool
+where
+    E: CacheableEntity + StyleableEntity + OsmEntity<'e>,
+{
+    styler.style_entities(entities.iter(), zoom, false).is_empty()
+}
+
+// Adds the entities with at least one style for which `is_hit` is true.
+fn query_entities<'e, E>(
+    styler: &Styler,
+    entities: &[E],
+    zoom: u8,
+    kind: &'static str,
+    result: &mut Vec<QueriedEntity>,
+    is_hit: impl Fn(&E, &Style) -> bool,
+) where
+    E: CacheableEntity + StyleableEntity + OsmEntity<'e>,
+{
+    // All styles of an entity (e.g. for different layers) come one after another.
+    for (entity, style) in styler.style_entities(entities.iter(), zoom, false) {
+        if result
+            .last()
+            .is_some_and(|last| last.kind == kind && last.id == entity.global_id())
+        {
+            continue;
+        }
+        if is_hit(entity, &style) {
+            let tags = entity.tags();
+            result.push(QueriedEntity {
+                kind,
+                id: entity.global_id(),
+                tags: (&tags)
+                    .into_iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            });
+        }
+    }
+}
+
+// Whether the lines (in global pixel coordinates) drawn with the style cover the point. If `can_fill` is true, the
+// lines are the rings of an area, which are filled with the even-odd rule like in `fill_contour()`.
+fn is_drawn_at(point: (f64, f64), lines: &[Vec<(f64, f64)>], style: &Style, can_fill: bool) -> bool {
+    let segments = || {
+        lines
+            .iter()
+            .flat_map(|line| line.windows(2).map(|segment| (segment[0], segment[1])))
+    };
+
+    if can_fill && (style.fill_color.is_some() || style.fill_image.is_some()) {
+        let (x, y) = point;
+        let crossings = segments()
+            .filter(|&((x1, y1), (x2, y2))| (y1 > y) != (y2 > y) && x < x1 + (y - y1) * (x2 - x1) / (y2 - y1))
+            .count();
+        if crossings % 2 == 1 {
+            return true;
+        }
+    }
+
+    let stroke_width = style.color.as_ref().map(|_| style.width.unwrap_or(1.0));
+    let casing_width = style.casing_color.as_ref().and(style.casing_width);
+    match stroke_width.into_iter().chain(casing_width).reduce(f64::max) {
+        Some(width) => {
+            let max_distance = (width / 2.0).max(QUERY_MIN_LINE_DISTANCE);
+            segments().any(|(from, to)| distance_to_segment(point, from, to) <= max_distance)
+        }
+        None => false,
+    }
+}
+
+fn distance(a: (f64, f64), b: (f64, f64)) -> f64 {
+    (a.0 - b.0).hypot(a.1 - b.1)
+}
+
+fn distance_to_segment(point: (f64, f64), from: (f64, f64), to: (f64, f64)) -> f64 {
+    let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+    let length_squared = dx * dx + dy * dy;
+    if length_squared == 0.0 {
+        return distance(point, from);
+    }
+    let t = (((point.0 - from.0) * dx + (point.1 - from.1) * dy) / length_squared).clamp(0.0, 1.0);
+    distance(point, (from.0 + t * dx, from.1 + t * dy))
+}
+
+// Projects a geopoint to Web Mercator at `zoom`, leaving out the coordinates that are off the map.
+fn to_mercator_xy(coords: &(f64, f64), zoom: u8) -> (Option<f64>, Option<f64>) {
+    let world_size = f64::from(TILE_SIZE) * f64::from(1u32 << zoom);
+    let (x, y) = coords_to_xy(coords, zoom);
+    let on_map = |coord: f64| Some(coord).filter(|coord| (0.0..world_size).contains(coord));
+    (on_map(x), on_map(y))
+}
+
+fn split_stylesheet_path(file_path: &str) -> Result<(PathBuf, String)> {
+    let mut result = PathBuf::from(file_path);
+    let file_name = result
+        .file_name()
+        .and_then(|x| x.to_str().map(ToString::to_string))
+        .ok_or_else(|| anyhow!("Failed to extract the file name for {}", file_path))?;
+    result.pop();
+    Ok((result, file_name))
+}
@@ -5,6 +5,9 @@ use std::f64::consts::PI;
 pub const MAX_ZOOM: u8 = 18;
 pub const TILE_SIZE: u32 = 256;
 
+// The length of the equator in the spherical model used by Web Mercator.
+const EARTH_CIRCUMFERENCE_METERS: f64 = 40_075_016.686;
+
 #[derive(Eq, PartialEq, Debug)]
 pub struct Tile {
     pub zoom: u8,
@@ -12,6 +15,107 @@ pub struct Tile {
     pub y: u32,
 }
 
+// A rectangle on the map in degrees.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct LatLonBounds {
+    pub min_lat: f64,
+    pub min_lon: f64,
+    pub max_lat: f64,
+    pub max_lon: f64,
+}
+
+// The tile grid: the usual Web Mercator one, or the plate carrée one (EPSG:4326, as in the WorldCRS84Quad tile matrix
+// of WMTS), which has two 180x180 degree tiles at zoom level 0 and the same number of pixels per degree everywhere.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Projection {
+    #[default]
+    WebMercator,
+    PlateCarree,
+}
+
+impl Projection {
+    /// Plate carrée tiles are made from the Web Mercator ones one zoom level deeper (see
+    /// `TileRenderer::render_plate_carree()`).
+    pub fn max_zoom(self) -> u8 {
+        match self {
+            Projection::WebMercator => MAX_ZOOM,
+            Projection::PlateCarree => MAX_ZOOM - 1,
+        }
+    }
+
+    /// Returns the number of tiles in a row and in a column of the map at a given zoom level.
+    /// # Examples
+    /// ```
+    /// use renderer::tile::Projection;
+    /// assert_eq!(Projection::WebMercator.tile_counts(2), (4, 4));
+    /// assert_eq!(Projection::PlateCarree.tile_counts(0), (2, 1));
+    /// ```
+    pub fn tile_counts(self, zoom: u8) -> (u32, u32) {
+        match self {
+            Projection::WebMercator => (1 << zoom, 1 << zoom),
+            Projection::PlateCarree => (2 << zoom, 1 << zoom),
+        }
+    }
+
+    /// Checks that a given tile is on the map.
+    /// # Examples
+    /// ```
+    /// use renderer::tile::{Projection,Tile};
+    /// assert!(Projection::WebMercator.contains(&Tile { zoom: 2, x: 3, y: 3 }));
+    /// assert!(!Projection::WebMercator.contains(&Tile { zoom: 2, x: 4, y: 0 }));
+    /// assert!(Projection::PlateCarree.contains(&Tile { zoom: 0, x: 1, y: 0 }));
+    /// assert!(!Projection::PlateCarree.contains(&Tile { zoom: 18, x: 0, y: 0 }));
+    /// ```
+    pub fn contains(self, tile: &Tile) -> bool {
+        if tile.zoom > self.max_zoom() {
+            return false;
+        }
+        let (columns, rows) = self.tile_counts(tile.zoom);
+        tile.x < columns && tile.y < rows
+    }
+
+    /// Projects a given geopoint to the global pixel coordinates at a given zoom level.
+    /// # Examples
+    /// ```
+    /// use renderer::tile::{coords_to_xy,Projection};
+    /// let moscow = (55.747764f64, 37.437745f64);
+    /// assert_eq!(Projection::WebMercator.coords_to_xy(&moscow, 12), coords_to_xy(&moscow, 12));
+    /// assert_eq!(Projection::PlateCarree.coords_to_xy(&(90.0f64, -180.0f64), 0), (0.0, 0.0));
+    /// assert_eq!(Projection::PlateCarree.coords_to_xy(&(-45.0f64, 90.0f64), 1), (768.0, 384.0));
+    /// ```
+    pub fn coords_to_xy<C: Coords>(self, coords: &C, zoom: u8) -> (f64, f64) {
+        match self {
+            Projection::WebMercator => coords_to_xy(coords, zoom),
+            Projection::PlateCarree => {
+                let pixels_per_degree = f64::from(TILE_SIZE) * f64::from(1u32 << zoom) / 180.0;
+                (
+                    (coords.lon() + 180.0) * pixels_per_degree,
+                    (90.0 - coords.lat()) * pixels_per_degree,
+                )
+            }
+        }
+    }
+
+    /// The inverse of `coords_to_xy()`: returns the geopoint at given global pixel coordinates as (lat, lon).
+    /// # Examples
+    /// ```
+    /// use renderer::tile::Projection;
+    /// assert_eq!(Projection::PlateCarree.xy_to_coords(768.0, 384.0, 1), (-45.0, 90.0));
+    /// let (x, y) = Projection::WebMercator.coords_to_xy(&(55.747764f64, 37.437745f64), 18);
+    /// let (lat, lon) = Projection::WebMercator.xy_to_coords(x, y, 18);
+    /// assert!((lat - 55.747764).abs() < 1e-9 && (lon - 37.437745).abs() < 1e-9);
+    /// ```
+    pub fn xy_to_coords(self, x: f64, y: f64, zoom: u8) -> (f64, f64) {
+        match self {
+            Projection::WebMercator => xy_to_coords(x, y, zoom),
+            Projection::PlateCarree => {
+                let degrees_per_pixel = 180.0 / (f64::from(TILE_SIZE) * f64::from(1u32 << zoom));
+                (90.0 - y * degrees_per_pixel, x * degrees_per_pixel - 180.0)
+            }
+        }
+    }
+}
+
 #[derive(Eq, PartialEq, Debug)]
 pub struct TileRange {
     pub min_x: u32,
@@ -20,6 +124,21 @@ pub struct TileRange {
     pub max_y: u32,
 }
 
+impl TileRange {
+    /// # Examples
+    /// ```
+    /// use renderer::tile::TileRange;
+    /// let range = |min_x, max_x, min_y, max_y| TileRange { min_x, max_x, min_y, max_y };
+    /// assert!(range(0, 10, 0, 10).intersects(&range(10, 20, 5, 6)));
+    /// assert!(range(3, 4, 3, 4).intersects(&range(0, 10, 0, 10)));
+    /// assert!(!range(0, 10, 0, 10).intersects(&range(11, 20, 0, 10)));
+    /// assert!(!range(0, 10, 0, 10).intersects(&range(0, 10, 11, 11)));
+    /// ```
+    pub fn intersects(&self, other: &TileRange) -> bool {
+        self.min_x <= other.max_x && other.min_x <= self.max_x && self.min_y <= other.max_y && other.min_y <= self.max_y
+    }
+}
+
 /// # Examples
 /// ```
 /// use renderer::tile::{coords_to_max_zoom_tile,Tile};
@@ -28,15 +147,78 @@ pub struct TileRange {
 /// assert_eq!(coords_to_max_zoom_tile(&(-35.306536f64, 149.126545f64)), Tile { zoom: 18, x: 239662, y: 158582 });
 /// ```
 pub fn coords_to_max_zoom_tile<C: Coords>(coords: &C) -> Tile {
-    let (x, y) = coords_to_xy(coords, MAX_ZOOM);
-    let tile_index = |t| (t as u32) / TILE_SIZE;
+    tile_at(coords, MAX_ZOOM)
+}
+
+/// Returns the tile that contains a given geopoint at a given zoom level. The points beyond the edges of the map
+/// (e.g. closer to the poles than Web Mercator goes) are moved to the nearest tile.
+/// # Examples
+/// ```
+/// use renderer::tile::{tile_at,Tile};
+/// assert_eq!(tile_at(&(55.747764f64, 37.437745f64), 16), Tile { zoom: 16, x: 39583, y: 20489 });
+/// assert_eq!(tile_at(&(-35.306536f64, 149.126545f64), 0), Tile { zoom: 0, x: 0, y: 0 });
+/// assert_eq!(tile_at(&(89.9f64, 180f64), 2), Tile { zoom: 2, x: 3, y: 0 });
+/// ```
+pub fn tile_at<C: Coords>(coords: &C, zoom: u8) -> Tile {
+    let (x, y) = coords_to_xy(coords, zoom);
+    let max_index = (1u32 << zoom) - 1;
+    let tile_index = |t: f64| ((t / f64::from(TILE_SIZE)) as u32).min(max_index);
     Tile {
-        zoom: MAX_ZOOM,
+        zoom,
         x: tile_index(x),
         y: tile_index(y),
     }
 }
 
+/// Returns the area covered by a given tile.
+/// # Examples
+/// ```
+/// use renderer::tile::{bbox_of_tile,Tile};
+/// let bbox = bbox_of_tile(&Tile { zoom: 1, x: 1, y: 0 });
+/// assert_eq!((bbox.min_lat, bbox.min_lon, bbox.max_lon), (0.0, 0.0, 180.0));
+/// assert!((bbox.max_lat - 85.0511).abs() < 1e-4);
+///
+/// let bbox = bbox_of_tile(&Tile { zoom: 16, x: 39583, y: 20489 });
+/// assert!(bbox.min_lat < 55.747764 && bbox.max_lat > 55.747764);
+/// assert!(bbox.min_lon < 37.437745 && bbox.max_lon > 37.437745);
+/// ```
+pub fn bbox_of_tile(tile: &Tile) -> LatLonBounds {
+    let size = f64::from(TILE_SIZE);
+    let (left, top) = (f64::from(tile.x) * size, f64::from(tile.y) * size);
+    let (max_lat, min_lon) = xy_to_coords(left, top, tile.zoom);
+    let (min_lat, max_lon) = xy_to_coords(left + size, top + size, tile.zoom);
+    LatLonBounds {
+        min_lat,
+        min_lon,
+        max_lat,
+        max_lon,
+    }
+}
+
+/// Returns how many meters on the ground a pixel covers at a given latitude. The zoom level can be fractional,
+/// e.g. for a map that is scaled smoothly.
+/// # Examples
+/// ```
+/// use renderer::tile::meters_per_pixel;
+/// assert_eq!(meters_per_pixel(0.0, 0.0).round(), 156543.0);
+/// assert_eq!(meters_per_pixel(0.0, 1.0), meters_per_pixel(0.0, 0.0) / 2.0);
+/// assert!((meters_per_pixel(60.0, 16.5) - 0.8445).abs() < 1e-4);
+/// ```
+pub fn meters_per_pixel(lat: f64, zoom: f64) -> f64 {
+    EARTH_CIRCUMFERENCE_METERS * lat.to_radians().cos() / (f64::from(TILE_SIZE) * zoom.exp2())
+}
+
+/// The inverse of `meters_per_pixel()`: returns the (fractional) zoom level with a given resolution.
+/// # Examples
+/// ```
+/// use renderer::tile::{meters_per_pixel,zoom_for_meters_per_pixel};
+/// assert!((zoom_for_meters_per_pixel(55.75, meters_per_pixel(55.75, 13.25)) - 13.25).abs() < 1e-9);
+/// assert!((zoom_for_meters_per_pixel(0.0, 1.0) - 17.256).abs() < 1e-3);
+/// ```
+pub fn zoom_for_meters_per_pixel(lat: f64, meters_per_pixel: f64) -> f64 {
+    (EARTH_CIRCUMFERENCE_METERS * lat.to_radians().cos() / (f64::from(TILE_SIZE) * meters_per_pixel)).log2()
+}
+
 /// Return the range of all smallest tiles that are covered by a given tile.
 /// # Examples
 /// ```
@@ -72,6 +254,55 @@ pub fn tile_to_max_zoom_tile_range(tile: &Tile) -> TileRange {
     }
 }
 
+/// Returns the tile and the tiles around it, each with the number of world widths its contents have to be moved by to be
+/// next to the tile. The map wraps around at the antimeridian, so the leftmost and the rightmost tiles are neighbors,
+/// but there is nothing beyond the poles.
+/// # Examples
+/// ```
+/// use renderer::tile::{neighbor_tiles, Tile};
+/// let neighbors = |zoom, x, y| {
+///     let tiles = neighbor_tiles(&Tile { zoom, x, y });
+///     tiles.iter().map(|(t, world_offset)| (t.x, t.y, *world_offset)).collect::<Vec<_>>()
+/// };
+/// assert_eq!(
+///     neighbors(2, 1, 1),
+///     vec![(0, 0, 0), (0, 1, 0), (0, 2, 0), (1, 0, 0), (1, 1, 0), (1, 2, 0), (2, 0, 0), (2, 1, 0), (2, 2, 0)]
+/// );
+/// assert_eq!(
+///     neighbors(2, 0, 2),
+///     vec![(3, 1, -1), (3, 2, -1), (3, 3, -1), (0, 1, 0), (0, 2, 0), (0, 3, 0), (1, 1, 0), (1, 2, 0), (1, 3, 0)]
+/// );
+/// assert_eq!(neighbors(2, 3, 0), vec![(2, 0, 0), (2, 1, 0), (3, 0, 0), (3, 1, 0), (0, 0, 1), (0, 1, 1)]);
+/// assert_eq!(neighbors(1, 0, 1), vec![(0, 0, 0), (0, 1, 0), (1, 0, 0), (1, 1, 0)]);
+/// assert_eq!(neighbors(0, 0, 0), vec![(0, 0, 0)]);
+/// ```
+pub fn neighbor_tiles(tile: &Tile) -> Vec<(Tile, i32)> {
+    let tile_count = i64::from(1u32 << tile.zoom);
+    let mut columns = Vec::with_capacity(3);
+    for dx in [-1, 0, 1] {
+        let x = i64::from(tile.x) + dx;
+        columns.push((x.rem_euclid(tile_count) as u32, x.div_euclid(tile_count) as i32));
+    }
+    // At the lowest zoom levels, the same tile can be both across the antimeridian and right next to the tile (or be
+    // the tile itself), and then it's taken as it is.
+    let unshifted = columns
+        .iter()
+        .filter(|(_, offset)| *offset == 0)
+        .map(|(x, _)| *x)
+        .collect::<Vec<_>>();
+    columns.retain(|(x, offset)| *offset == 0 || !unshifted.contains(x));
+
+    let mut tiles = Vec::with_capacity(9);
+    for (x, world_offset) in columns {
+        for dy in [-1, 0, 1] {
+            if let Some(y) = tile.y.checked_add_signed(dy).filter(|&y| i64::from(y) < tile_count) {
+                tiles.push((Tile { zoom: tile.zoom, x, y }, world_offset));
+            }
+        }
+    }
+    tiles
+}
+
 /// Projects a given geopoint to Web Mercator coordinates for a given zoom level.
 /// # Examples
 /// ```
@@ -100,7 +331,40 @@ pub fn coords_to_xy<C: Coords>(coords: &C, zoom: u8) -> (f64, f64) {
     (rescale(x), rescale(y))
 }
 
+/// The inverse of `coords_to_xy()`: returns the geopoint at given Web Mercator coordinates as (lat, lon).
+/// # Examples
+/// ```
+/// use renderer::tile::{coords_to_xy,xy_to_coords};
+/// assert_eq!(xy_to_coords(128.0, 128.0, 0), (0.0, 0.0));
+/// let (x, y) = coords_to_xy(&(55.747764f64, 37.437745f64), 18);
+/// let (lat, lon) = xy_to_coords(x, y, 18);
+/// assert!((lat - 55.747764).abs() < 1e-9 && (lon - 37.437745).abs() < 1e-9);
+/// ```
+pub fn xy_to_coords(x: f64, y: f64, zoom: u8) -> (f64, f64) {
+    let world_size = f64::from(TILE_SIZE) * f64::from(1u32 << zoom);
+    let lon = x / world_size * 360.0 - 180.0;
+    let lat = (PI - 2.0 * PI * y / world_size).sinh().atan().to_degrees();
+    (lat, lon)
+}
+
 pub fn coords_to_xy_tile_relative<C: Coords>(coords: &C, tile: &Tile) -> (f64, f64) {
     let (x, y) = coords_to_xy(coords, tile.zoom);
     (x - f64::from(tile.x * TILE_SIZE), y - f64::from(tile.y * TILE_SIZE))
 }
+
+/// The inverse of `coords_to_xy_tile_relative()`: returns the geopoint at given pixel coordinates within a tile.
+/// # Examples
+/// ```
+/// use renderer::tile::{coords_to_xy_tile_relative,tile_relative_xy_to_coords,Tile};
+/// let tile = Tile { zoom: 16, x: 39583, y: 20489 };
+/// let (lat, lon) = tile_relative_xy_to_coords(100.5, 20.25, &tile);
+/// let (x, y) = coords_to_xy_tile_relative(&(lat, lon), &tile);
+/// assert!((x - 100.5).abs() < 1e-6 && (y - 20.25).abs() < 1e-6);
+/// ```
+pub fn tile_relative_xy_to_coords(x: f64, y: f64, tile: &Tile) -> (f64, f64) {
+    xy_to_coords(
+        x + f64::from(tile.x * TILE_SIZE),
+        y + f64::from(tile.y * TILE_SIZE),
+        tile.zoom,
+    )
+}
@@ -1,3 +1,11 @@
+// Every test binary uses only some of the helpers.
+#![allow(dead_code)]
+
+use renderer::geodata::fixture::GeodataBuilder;
+use renderer::geodata::reader::GeodataReader;
+use renderer::mapcss::parser::parse_file;
+use renderer::mapcss::styler::{StyleType, Styler};
+use renderer::render::{RgbaImage, TileRenderer};
 use std::path::PathBuf;
 
 pub fn get_test_path(relative_path: &[&str]) -> String {
@@ -9,3 +17,71 @@ pub fn get_test_path(relative_path: &[&str]) -> String {
 
     test_path.to_str().unwrap().to_string()
 }
+
+// Every test gets a directory of its own, since the tests run in parallel.
+pub fn temp_test_dir(test_name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("osm_renderer_test_{}", test_name));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// The geodata and the stylesheet of a test, saved to its directory.
+pub struct TestMap {
+    pub dir: PathBuf,
+    pub geodata_file: String,
+    pub stylesheet_file: String,
+}
+
+impl TestMap {
+    pub fn new(test_name: &str, geodata: &GeodataBuilder, mapcss: &str) -> TestMap {
+        let dir = temp_test_dir(test_name);
+        let geodata_file = dir.join("geodata.bin").to_string_lossy().into_owned();
+        geodata.save(&geodata_file).unwrap();
+        TestMap::with_geodata_file(test_name, &geodata_file, mapcss)
+    }
+
+    // For the geodata that has to go through the importer.
+    pub fn with_geodata_file(test_name: &str, geodata_file: &str, mapcss: &str) -> TestMap {
+        let dir = temp_test_dir(test_name);
+        let stylesheet_file = dir.join("style.mapcss").to_string_lossy().into_owned();
+        std::fs::write(&stylesheet_file, mapcss).unwrap();
+        TestMap {
+            dir,
+            geodata_file: geodata_file.to_string(),
+            stylesheet_file,
+        }
+    }
+
+    // Imports `tests/osm/nano_moscow.osm` into the test directory.
+    pub fn nano_moscow(test_name: &str, mapcss: &str) -> TestMap {
+        let osm_file = get_test_path(&["osm", "nano_moscow.osm"]);
+        let bin_file = temp_test_dir(test_name)
+            .join("nano_moscow.bin")
+            .to_string_lossy()
+            .into_owned();
+        renderer::geodata::importer::import(&osm_file, &bin_file).unwrap();
+        TestMap::with_geodata_file(test_name, &bin_file, mapcss)
+    }
+
+    // For the tests that render the same geodata with several stylesheets.
+    pub fn set_stylesheet(&self, mapcss: &str) {
+        std::fs::write(&self.stylesheet_file, mapcss).unwrap();
+    }
+
+    pub fn renderer(&self) -> TileRenderer<'static> {
+        TileRenderer::new(&self.geodata_file, &self.stylesheet_file, &StyleType::Josm, None).unwrap()
+    }
+
+    pub fn styler(&self) -> Styler {
+        Styler::new(parse_file(&self.dir, "style.mapcss").unwrap(), &StyleType::Josm, None)
+    }
+
+    pub fn reader(&self) -> GeodataReader<'static> {
+        GeodataReader::load(&self.geodata_file).unwrap()
+    }
+}
+
+pub fn pixel(image: &RgbaImage, x: usize, y: usize) -> (u8, u8, u8) {
+    let idx = 4 * (y * image.dimension + x);
+    (image.bytes[idx], image.bytes[idx + 1], image.bytes[idx + 2])
+}
@@ -0,0 +1,669 @@
+mod common;
+
+use crate::common::{get_test_path, temp_test_dir, TestMap};
+use renderer::coords::Coords;
+use renderer::geodata::expiry::expired_tiles;
+use renderer::geodata::fixture::GeodataBuilder;
+use renderer::geodata::reader::{GeodataReader, OsmEntity};
+use renderer::tile::{self, coords_to_xy, LatLonBounds, Tile};
+
+#[test]
+fn test_bbox_query() {
+    let bin_file = get_test_path(&["osm", "nano_moscow_bbox.bin"]);
+    renderer::geodata::importer::import(&get_test_path(&["osm", "nano_moscow.osm"]), &bin_file).unwrap();
+    let reader = GeodataReader::load(&bin_file).unwrap();
+
+    let way_ids = |ways: &[renderer::geodata::reader::Way<'_>]| ways.iter().map(|w| w.global_id()).collect::<Vec<_>>();
+
+    let everything = reader.get_entities_in_bbox(55.7, 37.5, 55.8, 37.7, &None);
+    let tile = Tile {
+        x: 158_458,
+        y: 81_948,
+        zoom: 18,
+    };
+    let in_tile = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let all_way_ids = way_ids(&everything.ways);
+    assert!(way_ids(&in_tile.ways).iter().all(|id| all_way_ids.contains(id)));
+
+    // A bbox around a single node of Романов переулок.
+    let small = reader.get_entities_in_bbox(55.7554, 37.6095, 55.7555, 37.6096, &None);
+    assert!(way_ids(&small.ways).contains(&23_369_934));
+    assert!(small.ways.len() < everything.ways.len());
+
+    let mut without_street = reader.get_entities_in_bbox(55.7554, 37.6095, 55.7555, 37.6096, &None);
+    without_street.exclude_ids(&[23_369_934].into_iter().collect());
+    assert!(!way_ids(&without_street.ways).contains(&23_369_934));
+    assert_eq!(without_street.ways.len() + 1, small.ways.len());
+
+    let far_away = reader.get_entities_in_bbox(40.0, 10.0, 40.1, 10.1, &None);
+    assert!(far_away.nodes.is_empty() && far_away.ways.is_empty());
+}
+
+#[test]
+fn test_entity_iterators() {
+    let bin_file = get_test_path(&["osm", "nano_moscow_iterators.bin"]);
+    renderer::geodata::importer::import(&get_test_path(&["osm", "nano_moscow.osm"]), &bin_file).unwrap();
+    let reader = GeodataReader::load(&bin_file).unwrap();
+
+    let all = reader.all_entities();
+    assert_eq!(reader.nodes().len(), all.nodes.len());
+    assert_eq!(reader.multipolygons().len(), all.multipolygons.len());
+    assert_eq!(reader.relations().len(), all.relations.len());
+    let way_ids = reader.ways().map(|way| way.global_id()).collect::<Vec<_>>();
+    assert_eq!(way_ids, all.ways.iter().map(|way| way.global_id()).collect::<Vec<_>>());
+    assert!(way_ids.len() > 1000);
+
+    let street = reader.ways().find(|way| way.global_id() == 23_369_934).unwrap();
+    assert!(street.tags().get_by_key("highway").is_some());
+    assert!(reader.nodes().any(|node| !node.tags().is_empty()));
+}
+
+#[test]
+fn test_tag_helpers() {
+    let tags = [
+        ("amenity", "cafe"),
+        ("capacity", "12.5"),
+        ("wheelchair", "no"),
+        ("outdoor_seating", "yes"),
+        ("internet_access", "wlan"),
+    ];
+    let geodata = GeodataBuilder::default().with_node(1, 55.75, 37.61, &tags);
+    let reader = TestMap::new("tag_helpers", &geodata, "").reader();
+    let tags = reader.nodes().next().unwrap().tags();
+
+    assert!(tags.contains_key("amenity") && !tags.contains_key("name"));
+    assert!(tags.has_tag("amenity", "cafe") && !tags.has_tag("amenity", "bar"));
+    assert_eq!(tags.get_f64("capacity"), Some(12.5));
+    assert_eq!(tags.get_f64("amenity"), None);
+    assert_eq!(tags.get_bool("outdoor_seating"), Some(true));
+    assert_eq!(tags.get_bool("wheelchair"), Some(false));
+    assert_eq!(tags.get_bool("internet_access"), None);
+    assert_eq!(tags.get_bool("name"), None);
+
+    let keys = (&tags).into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+    assert_eq!(
+        keys,
+        [
+            "amenity",
+            "capacity",
+            "internet_access",
+            "outdoor_seating",
+            "wheelchair"
+        ]
+    );
+    let map = tags.to_map();
+    assert_eq!(map.len(), tags.len());
+    assert_eq!(map["internet_access"], "wlan");
+}
+
+#[test]
+fn test_name_search() {
+    use renderer::geodata::search::NameIndex;
+
+    let bin_file = get_test_path(&["osm", "nano_moscow_search.bin"]);
+    renderer::geodata::importer::import(&get_test_path(&["osm", "nano_moscow.osm"]), &bin_file).unwrap();
+    let readers = [
+        GeodataReader::load(&bin_file).unwrap(),
+        GeodataReader::load(&bin_file).unwrap(),
+    ];
+    let index = NameIndex::build(&readers[..1]);
+    assert!(!index.is_empty());
+    // The same entities in another file are not indexed again.
+    assert_eq!(NameIndex::build(&readers).len(), index.len());
+
+    let results = index.search("романов пер", 100);
+    assert!(results.iter().all(|r| r.name == "Романов переулок"));
+    let street = results.iter().find(|r| (r.kind, r.id) == ("way", 23_369_934)).unwrap();
+    assert!((55.75..55.76).contains(&street.lat) && (37.60..37.62).contains(&street.lon));
+    let in_english = index.search("Romanov Lane", 100);
+    assert!(in_english
+        .iter()
+        .any(|r| r.id == 23_369_934 && r.name == "Romanov Lane"));
+
+    assert_eq!(index.search("романов", 3).len(), 3);
+    assert!(index.search("несуществующая улица", 10).is_empty());
+}
+
+#[test]
+fn test_truncated_input() {
+    use renderer::geodata::importer::{import_with_options, ImportOptions, TruncatedInput};
+
+    let dir = temp_test_dir("truncated");
+    let full = std::fs::read(get_test_path(&["osm", "nano_moscow.osm"])).unwrap();
+    // Cut the file in the middle of the ways.
+    let way_start = full.windows(5).position(|w| w == b"<way ").unwrap();
+    let truncated_file = dir.join("truncated.osm");
+    std::fs::write(&truncated_file, &full[..way_start + 100_000]).unwrap();
+    let bin_file = dir.join("truncated.bin");
+
+    let err = import_with_options(&truncated_file, &bin_file, &ImportOptions::default()).unwrap_err();
+    assert!(err.chain().any(|cause| cause.is::<TruncatedInput>()));
+
+    let options = ImportOptions {
+        tolerate_truncation: true,
+        ..Default::default()
+    };
+    import_with_options(&truncated_file, &bin_file, &options).unwrap();
+    let reader = GeodataReader::load(bin_file.to_str().unwrap()).unwrap();
+    let entities = reader.get_entities_in_bbox(55.7, 37.5, 55.8, 37.7, &None);
+    assert!(!entities.ways.is_empty());
+
+    let complete_file = get_test_path(&["osm", "nano_moscow.osm"]);
+    let complete_bin_file = get_test_path(&["osm", "nano_moscow_truncated.bin"]);
+    import_with_options(complete_file.as_str(), complete_bin_file.as_str(), &options).unwrap();
+    let complete_reader = GeodataReader::load(&complete_bin_file).unwrap();
+    let all_entities = complete_reader.get_entities_in_bbox(55.7, 37.5, 55.8, 37.7, &None);
+    assert!(entities.ways.len() < all_entities.ways.len());
+    assert!(entities.ways.iter().all(|way| way.node_count() > 0));
+}
+
+#[test]
+fn test_deterministic_output() {
+    use renderer::geodata::importer::{import_with_options, ImportOptions};
+
+    let dir = temp_test_dir("deterministic");
+    let input = get_test_path(&["osm", "nano_moscow.osm"]);
+    for compress in [false, true] {
+        let options = ImportOptions {
+            compress,
+            ..Default::default()
+        };
+        let import = |name: &str| {
+            let bin_file = dir.join(format!("{}_{}.bin", name, compress));
+            import_with_options(input.as_ref(), bin_file.as_path(), &options).unwrap();
+            std::fs::read(bin_file).unwrap()
+        };
+        // Every import uses hash maps with different random seeds.
+        let first = import("first");
+        let second = import("second");
+        assert!(
+            first == second,
+            "re-importing with compress={} gave a different file",
+            compress
+        );
+    }
+}
+
+#[test]
+fn test_input_format_detection() {
+    let dir = temp_test_dir("input_format");
+    let import_error = |file_name: &str, contents: &[u8]| {
+        let input = dir.join(file_name);
+        std::fs::write(&input, contents).unwrap();
+        let err = renderer::geodata::importer::import(&input, &dir.join("output.bin")).unwrap_err();
+        format!("{:#}", err)
+    };
+
+    assert!(import_error("empty.osm", b"").contains("The file is empty"));
+    assert!(import_error("text.osm", b"node 1 55.75 37.61").contains("neither XML nor PBF"));
+    assert!(import_error("city.osm.gz", b"\x1f\x8b\x08\x00").contains("gzip"));
+    let gpx = b"<?xml version=\"1.0\"?>\n<gpx version=\"1.1\"><trk/></gpx>";
+    assert!(import_error("track.osm", gpx).contains("The root element is <gpx> instead of <osm>"));
+    if !cfg!(feature = "pbf") {
+        let pbf_header = b"\x00\x00\x00\x0d\x0a\x09OSMHeader\x18\x7c";
+        assert!(import_error("city.osm", pbf_header).contains("rebuild it with --features pbf"));
+    }
+
+    // The extension doesn't matter as long as the contents are OSM XML.
+    let xml_file = dir.join("data.txt");
+    std::fs::write(&xml_file, "\u{feff}<?xml version=\"1.0\"?>\n<osm version=\"0.6\"/>\n").unwrap();
+    renderer::geodata::importer::import(&xml_file, &dir.join("output.bin")).unwrap();
+}
+
+#[test]
+fn test_antimeridian_neighbors() {
+    // Two villages on both sides of the antimeridian in Chukotka, and an island next to the top of the map.
+    let geodata = GeodataBuilder::default()
+        .with_node(1, 65.5, 179.99, &[("place", "village")])
+        .with_node(2, 65.5, -179.99, &[("place", "village")])
+        .with_node(3, 85.04, -179.99, &[("place", "island")]);
+    let reader = TestMap::new("antimeridian_neighbors", &geodata, "").reader();
+
+    let zoom = 10;
+    let tile_of = |lat: f64, lon: f64| {
+        let (x, y) = coords_to_xy(&(lat, lon), zoom);
+        Tile {
+            zoom,
+            x: x as u32 / 256,
+            y: y as u32 / 256,
+        }
+    };
+    let node_ids = |tile: &Tile| {
+        let entities = reader.get_entities_in_tile_with_neighbors(tile, &None);
+        let mut ids = entities.nodes.iter().map(|n| n.global_id()).collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids
+    };
+
+    let (east, west, top) = (tile_of(65.5, 179.99), tile_of(65.5, -179.99), tile_of(85.04, -179.99));
+    assert_eq!((east.x, west.x, top.x, top.y), ((1 << zoom) - 1, 0, 0, 0));
+    assert_eq!(node_ids(&east), vec![1, 2]);
+    assert_eq!(node_ids(&west), vec![1, 2]);
+    // The nodes from across the antimeridian are moved next to the tile.
+    let lons = |tile: &Tile| {
+        let entities = reader.get_entities_in_tile_with_neighbors(tile, &None);
+        let mut lons = entities
+            .nodes
+            .iter()
+            .map(|n| (n.global_id(), n.lon()))
+            .collect::<Vec<_>>();
+        lons.sort_by_key(|(id, _)| *id);
+        lons.into_iter().map(|(_, lon)| lon).collect::<Vec<_>>()
+    };
+    let close = |actual: Vec<f64>, expected: [f64; 2]| actual.iter().zip(expected).all(|(a, e)| (a - e).abs() < 1e-9);
+    assert!(close(lons(&east), [179.99, 180.01]), "{:?}", lons(&east));
+    assert!(close(lons(&west), [-180.01, -179.99]), "{:?}", lons(&west));
+    assert_eq!(node_ids(&top), vec![3]);
+    assert_eq!(node_ids(&Tile { zoom: 0, x: 0, y: 0 }), vec![1, 2, 3]);
+}
+
+#[test]
+fn test_data_extent() {
+    let geodata = GeodataBuilder::default()
+        .with_node(1, 55.75, 37.62, &[("amenity", "cafe")])
+        .with_node(2, 55.74, 37.64, &[("amenity", "bar")]);
+    let reader = TestMap::new("data_extent", &geodata, "").reader();
+    assert_eq!(
+        reader.extent(),
+        Some(LatLonBounds {
+            min_lat: 55.74,
+            min_lon: 37.62,
+            max_lat: 55.75,
+            max_lon: 37.64,
+        })
+    );
+    let (node_tile, range) = (
+        tile::coords_to_max_zoom_tile(&(55.75, 37.62)),
+        reader.tile_range().unwrap(),
+    );
+    assert_eq!((range.min_x, range.min_y), (node_tile.x, node_tile.y));
+
+    let empty = TestMap::new("data_extent_empty", &GeodataBuilder::default(), "").reader();
+    assert_eq!(empty.extent(), None);
+    assert!(empty.tile_range().is_none());
+}
+
+#[test]
+fn test_nested_relations() {
+    let dir = temp_test_dir("nested_relations");
+    let input = dir.join("nested.osm");
+    // Relation 10 is a forest made of the ways of relation 20, which comes later in the file.
+    // Boundary 30 consists of the way of relation 40.
+    std::fs::write(
+        &input,
+        r#"<osm>
+  <node id="1" lat="55.750" lon="37.610"/>
+  <node id="2" lat="55.750" lon="37.612"/>
+  <node id="3" lat="55.752" lon="37.612"/>
+  <node id="4" lat="55.752" lon="37.610"/>
+  <way id="1"><nd ref="1"/><nd ref="2"/><nd ref="3"/></way>
+  <way id="2"><nd ref="3"/><nd ref="4"/><nd ref="1"/></way>
+  <way id="3"><nd ref="1"/><nd ref="3"/></way>
+  <relation id="10">
+    <member type="relation" ref="20" role="outer"/>
+    <tag k="type" v="multipolygon"/><tag k="landuse" v="forest"/>
+  </relation>
+  <relation id="20">
+    <member type="way" ref="1" role="outer"/><member type="way" ref="2" role="outer"/>
+    <tag k="type" v="multipolygon"/>
+  </relation>
+  <relation id="30">
+    <member type="relation" ref="40" role=""/><member type="relation" ref="30" role=""/>
+    <tag k="type" v="boundary"/><tag k="admin_level" v="8"/>
+  </relation>
+  <relation id="40">
+    <member type="way" ref="3" role=""/>
+    <tag k="type" v="collection"/>
+  </relation>
+</osm>"#,
+    )
+    .unwrap();
+    let bin_file = dir.join("nested.bin");
+    renderer::geodata::importer::import(&input, &bin_file).unwrap();
+
+    let reader = GeodataReader::load(bin_file.to_str().unwrap()).unwrap();
+    let entities = reader.get_entities_in_bbox(55.7, 37.6, 55.8, 37.7, &None);
+    let forest = entities.multipolygons.iter().find(|mp| mp.global_id() == 10).unwrap();
+    assert_eq!(forest.polygon_count(), 1);
+    assert!(entities.multipolygons.iter().any(|mp| mp.global_id() == 20));
+    let boundary = entities.relations.iter().find(|rel| rel.global_id() == 30).unwrap();
+    assert_eq!(boundary.line_count(), 1);
+    assert!(entities.relations.iter().all(|rel| rel.global_id() != 40));
+}
+
+#[test]
+fn test_merged_inputs() {
+    let dir = temp_test_dir("merged_inputs");
+    // Two overlapping extracts: both contain nodes 3 and 4, the second one has a shorter copy of way 1
+    // (as if it was cut at the border), and each of them has only one member of forest 10.
+    let first = dir.join("first.osm");
+    std::fs::write(
+        &first,
+        r#"<osm>
+  <bounds minlat="55.749" minlon="37.609" maxlat="55.751" maxlon="37.613"/>
+  <node id="1" lat="55.750" lon="37.610"/>
+  <node id="2" lat="55.750" lon="37.612"/>
+  <node id="3" lat="55.752" lon="37.612"/>
+  <node id="4" lat="55.752" lon="37.610"><tag k="amenity" v="cafe"/></node>
+  <way id="1"><nd ref="1"/><nd ref="2"/><nd ref="3"/><tag k="highway" v="path"/></way>
+  <relation id="10">
+    <member type="way" ref="1" role="outer"/>
+    <tag k="type" v="multipolygon"/><tag k="landuse" v="forest"/>
+  </relation>
+</osm>"#,
+    )
+    .unwrap();
+    let second = dir.join("second.osm");
+    std::fs::write(
+        &second,
+        r#"<osm>
+  <bounds minlat="55.751" minlon="37.609" maxlat="55.753" maxlon="37.613"/>
+  <node id="3" lat="55.752" lon="37.612"/>
+  <node id="4" lat="55.752" lon="37.610"><tag k="amenity" v="cafe"/></node>
+  <way id="1"><nd ref="3"/><nd ref="2"/><tag k="highway" v="path"/></way>
+  <way id="2"><nd ref="3"/><nd ref="4"/><nd ref="1"/></way>
+  <relation id="10">
+    <member type="way" ref="2" role="outer"/>
+    <tag k="type" v="multipolygon"/><tag k="landuse" v="forest"/>
+  </relation>
+</osm>"#,
+    )
+    .unwrap();
+    let bin_file = dir.join("merged.bin");
+    renderer::geodata::importer::import_files(&[&first, &second], &bin_file, &Default::default()).unwrap();
+
+    let reader = GeodataReader::load(bin_file.to_str().unwrap()).unwrap();
+    let entities = reader.all_entities();
+    let cafes = entities
+        .nodes
+        .iter()
+        .filter(|node| node.tags().get_by_key("amenity") == Some("cafe"))
+        .count();
+    assert_eq!(cafes, 1);
+    assert_eq!(entities.ways.len(), 2);
+    let path = entities.ways.iter().find(|way| way.global_id() == 1).unwrap();
+    assert_eq!(path.node_count(), 3);
+    let forest = entities.multipolygons.iter().find(|mp| mp.global_id() == 10).unwrap();
+    assert_eq!(forest.polygon_count(), 1);
+    assert_eq!(entities.multipolygons.len(), 1);
+}
+
+#[test]
+fn test_import_report() {
+    let dir = temp_test_dir("import_report");
+    let input = dir.join("report.osm");
+    // Way 2 has only one node. Closed way 1 has no area, since its nodes are on one line, and neither has forest 10.
+    // Node 5 is at the same location as node 1.
+    std::fs::write(
+        &input,
+        r#"<osm>
+  <node id="1" lat="55.750" lon="37.610"/>
+  <node id="2" lat="55.751" lon="37.611"/>
+  <node id="3" lat="55.752" lon="37.612"/>
+  <node id="4" lat="55.760" lon="37.620"/>
+  <node id="5" lat="55.750" lon="37.610"><tag k="amenity" v="cafe"/></node>
+  <way id="1"><nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="1"/></way>
+  <way id="2"><nd ref="4"/><tag k="highway" v="path"/></way>
+  <relation id="10">
+    <member type="way" ref="1" role="outer"/>
+    <tag k="type" v="multipolygon"/><tag k="landuse" v="forest"/>
+  </relation>
+</osm>"#,
+    )
+    .unwrap();
+    let bin_file = dir.join("report.bin");
+    let report = renderer::geodata::importer::import(&input, &bin_file).unwrap();
+    assert_eq!(report.ways, 2);
+    assert_eq!(report.short_ways, 1);
+    assert_eq!(report.multipolygons, 1);
+    assert_eq!(report.degenerate_polygons, 2);
+    assert_eq!(report.failed_multipolygons, 0);
+    assert_eq!(report.duplicate_node_coords, 1);
+    assert_eq!(report.bbox, Some([55.75, 37.61, 55.76, 37.62]));
+}
+
+#[test]
+fn test_unsorted_input() {
+    use renderer::geodata::importer::{import_with_options, ImportOptions};
+
+    let dir = temp_test_dir("unsorted_input");
+    let input = dir.join("unsorted.osm");
+    // The relation comes before its ways and the ways come before their nodes. Node 5 and way 3 are missing.
+    std::fs::write(
+        &input,
+        r#"<osm>
+  <relation id="10">
+    <member type="way" ref="1" role="outer"/><member type="way" ref="2" role="outer"/>
+    <member type="way" ref="3" role="inner"/>
+    <tag k="type" v="multipolygon"/><tag k="landuse" v="forest"/>
+  </relation>
+  <way id="1"><nd ref="1"/><nd ref="2"/><nd ref="3"/></way>
+  <way id="2"><nd ref="3"/><nd ref="4"/><nd ref="1"/></way>
+  <way id="4"><nd ref="1"/><nd ref="5"/><nd ref="3"/><tag k="highway" v="path"/></way>
+  <node id="1" lat="55.750" lon="37.610"/>
+  <node id="2" lat="55.750" lon="37.612"/>
+  <node id="3" lat="55.752" lon="37.612"/>
+  <node id="4" lat="55.752" lon="37.610"/>
+</osm>"#,
+    )
+    .unwrap();
+    let bin_file = dir.join("unsorted.bin");
+    let dropped_refs_file = dir.join("dropped_refs.csv");
+    let options = ImportOptions {
+        dropped_refs_file: Some(dropped_refs_file.clone()),
+        ..Default::default()
+    };
+    import_with_options(&input, &bin_file, &options).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&dropped_refs_file).unwrap(),
+        "type,id,missing_refs\nway,4,1\nrelation,10,1\n"
+    );
+
+    let reader = GeodataReader::load(bin_file.to_str().unwrap()).unwrap();
+    let entities = reader.all_entities();
+    let forest = entities.multipolygons.iter().find(|mp| mp.global_id() == 10).unwrap();
+    assert_eq!(forest.polygon_count(), 1);
+    assert_eq!(forest.get_polygon(0).node_count(), 5);
+    let path = entities.ways.iter().find(|way| way.global_id() == 4).unwrap();
+    assert_eq!(path.node_count(), 2);
+}
+
+#[test]
+fn test_expired_tiles() {
+    let import = |name: &str, cafe_tag: &str, with_bar: bool| {
+        let mut geodata = GeodataBuilder::default().with_node(1, 55.75, 37.61, &[("amenity", cafe_tag)]);
+        if with_bar {
+            geodata = geodata.with_node(2, 55.80, 37.70, &[("amenity", "bar")]);
+        }
+        TestMap::new(&format!("expired_tiles_{}", name), &geodata, "").reader()
+    };
+    let old = import("old", "cafe", true);
+    let same = import("same", "cafe", true);
+    let changed = import("changed", "restaurant", true);
+    let without_bar = import("without_bar", "cafe", false);
+
+    assert!(expired_tiles(&old, &same, 0..=18).is_empty());
+
+    // The tile with the changed node and its neighbors.
+    let cafe_tile = renderer::tile::coords_to_max_zoom_tile(&(55.75, 37.61));
+    let mut expected = Vec::new();
+    for x in cafe_tile.x - 1..=cafe_tile.x + 1 {
+        for y in cafe_tile.y - 1..=cafe_tile.y + 1 {
+            expected.push(Tile { zoom: 18, x, y });
+        }
+    }
+    assert_eq!(expired_tiles(&old, &changed, 18..=18), expected);
+
+    let bar_tile = renderer::tile::coords_to_max_zoom_tile(&(55.80, 37.70));
+    let removed = expired_tiles(&old, &without_bar, 17..=18);
+    assert!(removed.contains(&bar_tile));
+    assert!(!removed.contains(&cafe_tile));
+    assert_eq!(removed.len(), 2 * 9);
+}
+
+#[test]
+fn test_pruned_nodes() {
+    use renderer::geodata::importer::{import_with_options, ImportOptions};
+
+    let dir = temp_test_dir("pruned_nodes");
+    // Node 5 is not a part of any way and has no tags, and node 3 is kept even though it's at the same location as
+    // node 2.
+    let osm_file = dir.join("pruned.osm");
+    std::fs::write(
+        &osm_file,
+        r#"<osm>
+  <node id="1" lat="55.750" lon="37.610"/>
+  <node id="2" lat="55.750" lon="37.612"/>
+  <node id="3" lat="55.750" lon="37.612"/>
+  <node id="4" lat="55.752" lon="37.610"><tag k="amenity" v="cafe"/></node>
+  <node id="5" lat="55.752" lon="37.612"/>
+  <way id="1"><nd ref="1"/><nd ref="2"/><nd ref="4"/><tag k="highway" v="path"/></way>
+  <way id="2"><nd ref="3"/><nd ref="4"/><tag k="highway" v="path"/></way>
+</osm>"#,
+    )
+    .unwrap();
+
+    let load_ways_and_node_count = |keep_all_nodes| {
+        let bin_file = dir.join(format!("pruned_{}.bin", keep_all_nodes));
+        let options = ImportOptions {
+            keep_all_nodes,
+            ..Default::default()
+        };
+        import_with_options(&osm_file, &bin_file, &options).unwrap();
+        let reader = GeodataReader::load(bin_file.to_str().unwrap()).unwrap();
+        let entities = reader.all_entities();
+        let mut ways = entities
+            .ways
+            .iter()
+            .map(|way| {
+                let nodes = (0..way.node_count()).map(|idx| way.get_node(idx));
+                (
+                    way.global_id(),
+                    nodes.map(|node| (node.lat(), node.lon())).collect::<Vec<_>>(),
+                )
+            })
+            .collect::<Vec<_>>();
+        ways.sort_by_key(|(id, _)| *id);
+        (ways, entities.nodes.len())
+    };
+
+    let (all_ways, all_node_count) = load_ways_and_node_count(true);
+    assert_eq!(all_node_count, 5);
+    let (pruned_ways, pruned_node_count) = load_ways_and_node_count(false);
+    assert_eq!(pruned_node_count, 4);
+    assert_eq!(pruned_ways, all_ways);
+    assert_eq!(
+        pruned_ways[1].1,
+        vec![(55.750, 37.612), (55.752, 37.610)],
+        "{:?}",
+        pruned_ways
+    );
+}
+
+#[test]
+fn test_geodata_builder() {
+    let dir = temp_test_dir("geodata_builder");
+    let bin_file = dir.join("built.bin").to_string_lossy().into_owned();
+
+    let builder = GeodataBuilder::default()
+        .with_node(1, 55.75, 37.61, &[("amenity", "cafe")])
+        .with_node(2, 55.75, 37.62, &[])
+        .with_node(3, 55.76, 37.62, &[])
+        .with_way(10, &[1, 2, 3], &[("highway", "path")])
+        .with_multipolygon(20, &[&[1, 2, 3]], &[("landuse", "forest")]);
+    for compress in [false, true] {
+        builder.clone().with_compression(compress).save(&bin_file).unwrap();
+        let reader = GeodataReader::load(&bin_file).unwrap();
+
+        let nodes = reader
+            .nodes()
+            .map(|n| (n.global_id(), n.lat(), n.lon()))
+            .collect::<Vec<_>>();
+        assert_eq!(nodes, [(1, 55.75, 37.61), (2, 55.75, 37.62), (3, 55.76, 37.62)]);
+        let ways = reader.ways().collect::<Vec<_>>();
+        assert_eq!(ways.len(), 1);
+        assert_eq!(ways[0].tags().get_by_key("highway"), Some("path"));
+        let way_nodes = (0..ways[0].node_count()).map(|idx| ways[0].get_node(idx).global_id());
+        assert_eq!(way_nodes.collect::<Vec<_>>(), [1, 2, 3]);
+        // The ring is closed automatically.
+        let multipolygon = reader.multipolygons().next().unwrap();
+        let ring = multipolygon.get_polygon(0);
+        let ring_nodes = (0..ring.node_count()).map(|idx| ring.get_node(idx).global_id());
+        assert_eq!(ring_nodes.collect::<Vec<_>>(), [1, 2, 3, 1]);
+        assert!(reader.tile_range().is_some());
+    }
+
+    let broken = GeodataBuilder::default().with_way(10, &[1, 2], &[]);
+    assert!(broken.save(&bin_file).is_err());
+}
+
+#[test]
+fn test_broken_geodata() {
+    let dir = temp_test_dir("broken_geodata");
+    let bin_file = dir.join("full.bin");
+    let broken_file = dir.join("broken.bin");
+    let load_broken = |bytes: &[u8]| {
+        std::fs::write(&broken_file, bytes).unwrap();
+        GeodataReader::load(broken_file.to_str().unwrap()).map(|_| ())
+    };
+
+    let builder = GeodataBuilder::default()
+        .with_node(1, 55.75, 37.61, &[])
+        .with_node(2, 55.76, 37.62, &[])
+        .with_way(10, &[1, 2], &[]);
+    // The strings are at the end of the file, and the last one is cut in the middle of a character first.
+    let tagged_builder = builder
+        .clone()
+        .with_node(3, 55.77, 37.63, &[("place", "city"), ("name", "Москва")]);
+    for builder in [&builder, &tagged_builder] {
+        for compress in [false, true] {
+            builder.clone().with_compression(compress).save(&bin_file).unwrap();
+            let bytes = std::fs::read(&bin_file).unwrap();
+            assert!(load_broken(&bytes).is_ok());
+            for len in 0..bytes.len() {
+                // The end of the zlib stream is not needed to decompress the data.
+                let result = load_broken(&bytes[..len]);
+                assert!(compress || result.is_err(), "{} bytes should not be loaded", len);
+            }
+        }
+    }
+    tagged_builder.save(&bin_file).unwrap();
+    let bytes = std::fs::read(&bin_file).unwrap();
+    let err = format!("{:#}", load_broken(&bytes[..bytes.len() - 1]).unwrap_err());
+    assert!(err.contains("strings"), "{}", err);
+
+    builder.save(&bin_file).unwrap();
+    let bytes = std::fs::read(&bin_file).unwrap();
+    let assert_error = |broken: &[u8], expected: &str| {
+        let err = format!("{:#}", load_broken(broken).unwrap_err());
+        assert!(err.contains(expected), "{}", err);
+    };
+    // The header is the magic, the version and the flags, followed by the data extent.
+    assert_error(&bytes[..20], "The data extent is truncated at offset 12");
+    assert_error(&bytes[8..], "This is not a geodata file");
+    let mut broken = bytes.clone();
+    broken[4] = 2;
+    assert_error(&broken, "The geodata format version is 2");
+    let mut broken = bytes.clone();
+    broken[9] = 1;
+    assert_error(&broken, "Unknown header flags 0x102");
+
+    // The tags of the first node are the last 8 bytes of its 32, after the node count.
+    let mut broken = bytes.clone();
+    broken[44 + 4 + 28] = 100;
+    assert_error(&broken, "The node #0 refers to 100 ints at offset");
+    // The tiles come after the counts of the nodes, the ways and the three empty storages, and the tile count.
+    // The first 16-byte tile ends with the length of its ids.
+    let tile_ids_length_pos = 44 + (4 + 2 * 32) + (4 + 24) + 3 * 4 + 4 + 12;
+    let mut broken = bytes.clone();
+    broken[tile_ids_length_pos + 3] = 1;
+    assert_error(&broken, "The tile #0 refers to");
+    broken[tile_ids_length_pos + 3] = 0;
+    broken[tile_ids_length_pos] -= 1;
+    assert_error(&broken, "The ids of the tile #0 are broken");
+    // Without the tags, the file ends with the ints, and the last one is the second node of the way.
+    let mut broken = bytes.clone();
+    let last_int_pos = bytes.len() - 4;
+    broken[last_int_pos] = 5;
+    assert_error(&broken, "The way #0 refers to node #5 of 2");
+}
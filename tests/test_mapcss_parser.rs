@@ -1,7 +1,12 @@
 mod common;
 
-use crate::common::get_test_path;
-use renderer::mapcss::parser::parse_file;
+use crate::common::{get_test_path, temp_test_dir};
+use renderer::mapcss::color::Color;
+use renderer::mapcss::lint::lint_file;
+use renderer::mapcss::parser::{
+    format_rules, parse_file, parse_file_with_recovery, BinaryStringTestType, ObjectType, Property, PropertyValue,
+    Rule, Selector, Test,
+};
 use std::fs::File;
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
@@ -16,7 +21,7 @@ fn test_mapnik_parse() {
     let mapnik_path = get_test_path(&["mapcss", "mapnik.mapcss"]);
     let rules = parse_file(Path::new(&mapnik_base_path), "mapnik.mapcss").unwrap();
 
-    let rules_str = rules.iter().map(|x| format!("{}", x)).collect::<Vec<_>>().join("\n\n");
+    let rules_str = format_rules(&rules);
     let mapnik_path_parsed = PathBuf::from(&mapnik_path).with_extension("parsed");
     File::create(mapnik_path_parsed)
         .unwrap()
@@ -41,6 +46,134 @@ fn test_parsing_is_idempotent() {
     File::open(mapnik_path).unwrap().read_to_string(&mut canonical).unwrap();
     let rules = parse_file(Path::new(&mapnik_base_path), "mapnik.parsed.canonical").unwrap();
 
-    let rules_str = rules.iter().map(|x| format!("{}", x)).collect::<Vec<_>>().join("\n\n");
+    let rules_str = format_rules(&rules);
     assert_eq!(rules_str, canonize_newlines(&canonical));
 }
+
+#[test]
+fn test_transform_rules() {
+    let mapnik_base_path = get_test_path(&["mapcss"]);
+    let rules = parse_file(Path::new(&mapnik_base_path), "mapnik.parsed.canonical").unwrap();
+
+    // Keep only the areas, make them all red and add a rule for the sea.
+    let red = Color { r: 0xff, g: 0, b: 0 };
+    let mut transformed = rules
+        .iter()
+        .filter(|rule| rule.selectors.iter().all(|s| matches!(s.object_type, ObjectType::Area)))
+        .cloned()
+        .collect::<Vec<_>>();
+    for property in transformed.iter_mut().flat_map(|rule| rule.properties.iter_mut()) {
+        if let PropertyValue::Color(_) = property.value {
+            property.value = PropertyValue::Color(red.clone());
+        }
+    }
+    let mut sea_selector = Selector::new(ObjectType::Area);
+    sea_selector.min_zoom = Some(10);
+    sea_selector.tests.push(Test::BinaryStringCompare {
+        tag_name: "natural".to_string(),
+        value: "sea".to_string(),
+        test_type: BinaryStringTestType::Equal,
+    });
+    transformed.push(Rule::new(
+        vec![sea_selector],
+        vec![Property::new("fill-color", PropertyValue::Color(red.clone()))],
+    ));
+    assert!(transformed.len() > 1 && transformed.len() < rules.len());
+
+    let dir = temp_test_dir("transform");
+    let formatted = format_rules(&transformed);
+    std::fs::write(dir.join("transformed.mapcss"), &formatted).unwrap();
+    let reparsed = parse_file(&dir, "transformed.mapcss").unwrap();
+    assert_eq!(format_rules(&reparsed), formatted);
+    assert!(formatted.ends_with("area|z10-[natural=sea] {\nfill-color: #ff0000;\n}"));
+    assert_eq!(
+        reparsed.last().unwrap().selectors[0].tests,
+        transformed.last().unwrap().selectors[0].tests
+    );
+}
+
+#[test]
+fn test_lint() {
+    let dir = temp_test_dir("lint");
+    std::fs::write(dir.join("colors.mapcss"), "@water: #c4d4f5;\nway|z12 { colour: red; }").unwrap();
+    std::fs::write(
+        dir.join("main.mapcss"),
+        "@import(\"colors.mapcss\");\n@water: #b5d0d0;\n\
+         node|z9-5 { icon-image: \"icons/missing.png\"; }\narea { fill-color: @water; }\nway:new { width: 2; }",
+    )
+    .unwrap();
+
+    let diagnostics = lint_file(&dir, "main.mapcss").unwrap();
+    let messages = diagnostics.iter().map(ToString::to_string).collect::<Vec<_>>();
+    assert_eq!(
+        messages,
+        vec![
+            "colors.mapcss:2:11: Unknown property: colour",
+            "main.mapcss:2:1: Color @water is defined more than once",
+            "main.mapcss:3:1: Selector node|z9-5 never matches: the zoom range is empty",
+            "main.mapcss:3:13: Missing image file for icon-image: icons/missing.png",
+            "main.mapcss:5:1: Unsupported pseudo-class (ignored): new",
+        ]
+    );
+}
+
+#[test]
+fn test_constants() {
+    let dir = temp_test_dir("constants");
+    std::fs::write(
+        dir.join("constants.mapcss"),
+        "@minor_width: 2;\n@major_width: 2@12, 6@16;\n@font: \"DejaVu Sans\";\n@water: #b5d0d0;",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("main.mapcss"),
+        "@import(\"constants.mapcss\");\n\
+         way[highway=service] { width: @minor_width; font-family: @font; }\n\
+         way[highway=primary] { width: @major_width !important; color: @water; }",
+    )
+    .unwrap();
+
+    let rules = parse_file(&dir, "main.mapcss").unwrap();
+    assert_eq!(
+        format_rules(&rules),
+        "way[highway=service] {\nwidth: 2;\nfont-family: \"DejaVu Sans\";\n}\n\n\
+         way[highway=primary] {\nwidth: 2@12,6@16 !important;\ncolor: #b5d0d0;\n}"
+    );
+
+    std::fs::write(dir.join("unknown.mapcss"), "way { width: @missing_width; }").unwrap();
+    let err = parse_file(&dir, "unknown.mapcss").unwrap_err();
+    assert!(err.to_string().contains("Unknown constant reference: @missing_width"));
+}
+
+#[test]
+fn test_parse_with_recovery() {
+    let dir = temp_test_dir("recovery");
+    std::fs::write(
+        dir.join("main.mapcss"),
+        "way[highway=primary] { width: 3; }\n\
+         way[highway=secondary { width: 2; }\n\
+         @width: 2 3;\n\
+         way[highway=tertiary] { width: }\n\
+         way[highway=service] { color: $; width: 1; }\n\
+         area[building] { fill-color: #c0c0c0; }",
+    )
+    .unwrap();
+
+    assert!(parse_file(&dir, "main.mapcss").is_err());
+
+    let (rules, diagnostics) = parse_file_with_recovery(&dir, "main.mapcss").unwrap();
+    assert_eq!(
+        format_rules(&rules),
+        "way[highway=primary] {\nwidth: 3;\n}\n\narea[building] {\nfill-color: #c0c0c0;\n}"
+    );
+    let messages = diagnostics.iter().map(ToString::to_string).collect::<Vec<_>>();
+    assert_eq!(
+        messages,
+        vec![
+            "main.mapcss:2:23: Expected ']', found '{' instead (skipped until the next '}')",
+            "main.mapcss:3:11: Unexpected token: 'Number(3.0)' (skipped until the next ';')",
+            "main.mapcss:4:32: Unexpected token: '}' (skipped until the next '}')",
+            "main.mapcss:5:31: Unexpected symbol: '$' (skipped until the next '}')",
+        ]
+    );
+}
@@ -1,10 +1,11 @@
 mod common;
 
-use crate::common::get_test_path;
+use crate::common::{get_test_path, temp_test_dir, TestMap};
+use renderer::geodata::fixture::GeodataBuilder;
 use renderer::geodata::reader::OsmEntity;
 use renderer::mapcss::color::{from_color_name, Color};
 use renderer::mapcss::parser::parse_file;
-use renderer::mapcss::styler::{LineCap, Style, StyleType, Styler};
+use renderer::mapcss::styler::{LineCap, LineJoin, Style, StyleType, Styler, TextTransform};
 use renderer::tile::Tile;
 use std::collections::HashMap;
 use std::path::Path;
@@ -110,6 +111,7 @@ fn assert_styles_eq(our_style: &Style, josm_style: &Style) {
     assert_eq!(our_style.width, josm_style.width);
     assert_eq!(our_style.dashes, josm_style.dashes);
     assert_eq!(our_style.line_cap, josm_style.line_cap);
+    assert_eq!(our_style.line_join, josm_style.line_join);
 }
 
 fn from_josm_style(way_is_closed: bool, style: &str) -> Style {
@@ -142,15 +144,18 @@ fn from_josm_style(way_is_closed: bool, style: &str) -> Style {
     let parse_num = |prop_name| props.get(prop_name).map(|x| x.parse().unwrap());
 
     Style {
+        major_z_index: None,
         layer: None,
         z_index: parse_num("z-index").unwrap_or(if way_is_closed { 1.0 } else { 3.0 }),
 
         color: parse_color("color"),
         fill_color: parse_color("fill-color"),
         is_foreground_fill: false,
+        min_area: None,
         background_color: None,
         opacity: parse_num("opacity"),
         fill_opacity: parse_num("fill-opacity"),
+        blend_mode: None,
 
         width: parse_num("width"),
         dashes: props.get("dashes").map(|x| {
@@ -169,14 +174,566 @@ fn from_josm_style(way_is_closed: bool, style: &str) -> Style {
                 })
                 .unwrap_or(LineCap::Butt),
         ),
+        line_join: props.get("linejoin").map(|x| match *x {
+            "Keyword{round}" => LineJoin::Round,
+            "Keyword{miter}" => LineJoin::Miter,
+            "Keyword{bevel}" => LineJoin::Bevel,
+            _ => unreachable!(),
+        }),
 
         casing_color: None,
         casing_width: None,
         casing_dashes: None,
         casing_line_cap: None,
+        casing_line_join: None,
 
         icon_image: None,
+        icon_anchor: None,
         fill_image: None,
         text_style: None,
     }
 }
+
+#[test]
+fn test_canvas_per_zoom() {
+    let dir = temp_test_dir("canvas");
+    std::fs::write(
+        dir.join("canvas.mapcss"),
+        "canvas { fill-color: #f1eee8; }\ncanvas|z0-9 { fill-color: #204080; }\ncanvas|z5 { fill-opacity: 0.5; }",
+    )
+    .unwrap();
+    let styler = Styler::new(parse_file(&dir, "canvas.mapcss").unwrap(), &StyleType::Josm, None);
+
+    let color = |r, g, b| Some(Color { r, g, b });
+    assert_eq!(styler.canvas_fill_color(2).cloned(), color(0x20, 0x40, 0x80));
+    assert_eq!(styler.canvas_fill_color(5).cloned(), color(0x10, 0x20, 0x40));
+    assert_eq!(styler.canvas_fill_color(10).cloned(), color(0xf1, 0xee, 0xe8));
+}
+
+#[test]
+fn test_warnings_are_aggregated() {
+    let map = TestMap::nano_moscow(
+        "warnings",
+        "way[building] { color: 12; width: wide; }\nway[highway] { color: #ff0000; width: wide; }",
+    );
+    let reader = map.reader();
+    let styler = map.styler();
+
+    let entities = reader.get_entities_in_tile_with_neighbors(
+        &Tile {
+            x: 158_458,
+            y: 81_948,
+            zoom: 18,
+        },
+        &None,
+    );
+    let styled_ways = styler.style_entities(entities.ways.iter(), 18, false);
+    let has_tag = |key| styled_ways.iter().any(|(w, _)| w.tags().get_by_key(key).is_some());
+    assert!(has_tag("building") && has_tag("highway"));
+
+    let (warnings, dropped) = styler.warnings();
+    assert_eq!(dropped, 0);
+    let mut summary = warnings
+        .iter()
+        .map(|w| (w.property.as_str(), w.message.as_str(), w.example_value.as_str()))
+        .collect::<Vec<_>>();
+    summary.sort();
+    assert_eq!(
+        summary,
+        vec![
+            ("color", "expected a valid color", "12"),
+            ("width", "expected a number", "wide")
+        ]
+    );
+    // Both ways with buildings and with highways have invalid widths.
+    let count = |property| warnings.iter().find(|w| w.property == property).unwrap().count;
+    assert!(count("width") > count("color"));
+}
+
+#[test]
+fn test_style_cache_capacity() {
+    let reader = TestMap::nano_moscow("style_cache", "").reader();
+    let rules = || parse_file(Path::new(&get_test_path(&["mapcss"])), "mapnik.mapcss").unwrap();
+    let unlimited_styler = Styler::new(rules(), &StyleType::Josm, None);
+    let mut styler = Styler::new(rules(), &StyleType::Josm, None);
+    styler.set_style_cache_capacity(4);
+
+    let entities = reader.get_entities_in_tile_with_neighbors(
+        &Tile {
+            x: 158_458,
+            y: 81_948,
+            zoom: 18,
+        },
+        &None,
+    );
+    let style_count = |styler: &Styler| styler.style_entities(entities.ways.iter(), 18, false).len();
+    let expected_style_count = style_count(&unlimited_styler);
+    assert!(unlimited_styler.style_cache_stats().entries > 4);
+
+    for _ in 0..2 {
+        assert_eq!(style_count(&styler), expected_style_count);
+    }
+    let stats = styler.style_cache_stats();
+    // Closed and unclosed ways are cached separately.
+    assert!(stats.entries <= 2 * 4);
+    assert!(stats.evictions > 0);
+    assert!(stats.hits > 0);
+    // Untagged ways may skip the cache altogether.
+    let tagged_way_count = entities.ways.iter().filter(|w| !w.tags().is_empty()).count() as u64;
+    assert!(stats.hits + stats.misses >= 2 * tagged_way_count);
+    assert!(stats.hits + stats.misses <= 2 * entities.ways.len() as u64);
+}
+
+#[test]
+fn test_zoom_interpolation() {
+    let map = TestMap::nano_moscow(
+        "interpolation",
+        "way[highway] { width: 6@16, 2@12; opacity: 0.5@14; color: #ff0000; }",
+    );
+    std::fs::write(map.dir.join("invalid.mapcss"), "way { width: 2@12, 6; }").unwrap();
+    assert!(parse_file(&map.dir, "invalid.mapcss").is_err());
+
+    let rules = parse_file(&map.dir, "style.mapcss").unwrap();
+    assert_eq!(
+        rules[0].properties.iter().map(ToString::to_string).collect::<Vec<_>>(),
+        vec!["width: 2@12,6@16;", "opacity: 0.5@14;", "color: #ff0000;"]
+    );
+
+    let reader = map.reader();
+    let styler = Styler::new(rules, &StyleType::Josm, None);
+    let entities = reader.get_entities_in_tile_with_neighbors(
+        &Tile {
+            x: 158_458,
+            y: 81_948,
+            zoom: 18,
+        },
+        &None,
+    );
+
+    let style_at = |zoom| {
+        let styles = styler.style_entities(entities.ways.iter(), zoom, false);
+        let style = &styles.first().unwrap().1;
+        (style.width.unwrap(), style.opacity.unwrap())
+    };
+    assert_eq!(style_at(10), (2.0, 0.5));
+    assert_eq!(style_at(13), (3.0, 0.5));
+    assert_eq!(style_at(15), (5.0, 0.5));
+    assert_eq!(style_at(18), (6.0, 0.5));
+}
+
+#[test]
+fn test_tag_values() {
+    let bus_route = |colour, width: Option<&'static str>| {
+        let mut tags = vec![("route", "bus"), ("colour", colour)];
+        tags.extend(width.map(|width| ("width", width)));
+        tags
+    };
+    let geodata = GeodataBuilder::default()
+        .with_node(1, 55.75, 37.61, &[])
+        .with_node(2, 55.76, 37.62, &[])
+        .with_way(10, &[1, 2], &bus_route("#ff8000", Some("3")))
+        .with_way(11, &[1, 2], &bus_route("Blue", None))
+        .with_way(12, &[1, 2], &bus_route("not a color", Some("wide")));
+    let map = TestMap::new(
+        "tag_values",
+        &geodata,
+        "way[route] { color: tag(\"colour\"); width: tag(\"width\"); }",
+    );
+    let rules = parse_file(&map.dir, "style.mapcss").unwrap();
+    assert_eq!(rules[0].properties[0].to_string(), "color: tag(\"colour\");");
+
+    let reader = map.reader();
+    let styler = Styler::new(rules, &StyleType::Josm, None);
+    let entities = reader.get_entities_in_bbox(55.7, 37.6, 55.8, 37.7, &None);
+
+    let styles = styler.style_entities(entities.ways.iter(), 16, false);
+    let style_of = |id| &styles.iter().find(|(w, _)| w.global_id() == id).unwrap().1;
+    assert_eq!(style_of(10).color, Some(Color { r: 0xff, g: 0x80, b: 0 }));
+    assert_eq!(style_of(10).width, Some(3.0));
+    assert_eq!(style_of(11).color, from_color_name("blue"));
+    assert_eq!(style_of(11).width, None);
+    assert_eq!(style_of(12).color, None);
+    assert_eq!(style_of(12).width, None);
+    assert_eq!(styler.warnings().0.len(), 2);
+}
+
+#[test]
+fn test_important_properties() {
+    let geodata = GeodataBuilder::default()
+        .with_node(1, 55.75, 37.61, &[])
+        .with_node(2, 55.76, 37.62, &[])
+        .with_way(10, &[1, 2], &[("highway", "primary")])
+        .with_way(11, &[1, 2], &[("highway", "residential")]);
+    let map = TestMap::new(
+        "important",
+        &geodata,
+        "way[highway] { width: 3 !important; color: #ff0000 !important; opacity: 0.5; }\n\
+         way[highway]::* { z-index: 5 !important; }\n\
+         way[highway=primary] { width: 5; color: #00ff00 !important; opacity: 0.7; }\n\
+         way[highway]::overlay { z-index: 1; casing-width: eval(prop(\"width\") +2) !important; }",
+    );
+    std::fs::write(map.dir.join("invalid.mapcss"), "way { width: 3 !importnt; }").unwrap();
+    assert!(parse_file(&map.dir, "invalid.mapcss").is_err());
+
+    let rules = parse_file(&map.dir, "style.mapcss").unwrap();
+    assert_eq!(rules[0].properties[0].to_string(), "width: 3 !important;");
+    assert_eq!(rules[0].properties[2].to_string(), "opacity: 0.5;");
+
+    let reader = map.reader();
+    let styler = Styler::new(rules, &StyleType::Josm, None);
+    let entities = reader.get_entities_in_bbox(55.7, 37.6, 55.8, 37.7, &None);
+
+    let styles = styler.style_entities(entities.ways.iter(), 16, false);
+    let styles_of = |id| {
+        styles
+            .iter()
+            .filter(|(w, _)| w.global_id() == id)
+            .map(|(_, style)| style)
+            .collect::<Vec<_>>()
+    };
+    for id in [10, 11] {
+        // The default layer and the overlay, which inherits only the z-index from `::*`.
+        let styles = styles_of(id);
+        assert_eq!(styles.len(), 2);
+        assert!(styles.iter().all(|style| style.z_index == 5.0));
+        assert_eq!(styles[0].width, Some(3.0));
+        assert!(styles[0].casing_width.is_none() && styles[1].casing_width.is_some());
+    }
+
+    let primary = styles_of(10)[0];
+    assert_eq!(primary.color, Some(Color { r: 0, g: 0xff, b: 0 }));
+    assert_eq!(primary.opacity, Some(0.7));
+    let residential = styles_of(11)[0];
+    assert_eq!(residential.color, Some(Color { r: 0xff, g: 0, b: 0 }));
+    assert_eq!(residential.opacity, Some(0.5));
+}
+
+#[test]
+fn test_pseudo_classes() {
+    let geodata = GeodataBuilder::default()
+        .with_node(1, 55.75, 37.61, &[])
+        .with_node(2, 55.76, 37.62, &[])
+        .with_node(3, 55.75, 37.62, &[])
+        .with_way(10, &[1, 2, 3, 1], &[("building", "yes")])
+        .with_way(11, &[1, 2, 3, 1], &[("highway", "pedestrian")])
+        .with_way(12, &[1, 2], &[("name", "Tagged")])
+        .with_way(13, &[2, 3], &[]);
+    let map = TestMap::new(
+        "pseudo_classes",
+        &geodata,
+        "way:closed { color: #ff0000; }\n\
+         way:unclosed { width: 2; }\n\
+         way:area { fill-color: #00ff00; }\n\
+         way!:tagged { opacity: 0.5; }\n\
+         way:selected { z-index: 7; }",
+    );
+    let rules = parse_file(&map.dir, "style.mapcss").unwrap();
+    assert_eq!(rules[3].selectors[0].to_string(), "way!:tagged");
+
+    let reader = map.reader();
+    let styler = Styler::new(rules, &StyleType::Josm, None);
+    let entities = reader.get_entities_in_bbox(55.7, 37.6, 55.8, 37.7, &None);
+
+    let styles = styler.style_entities(entities.ways.iter(), 16, false);
+    let style_of = |id| &styles.iter().find(|(w, _)| w.global_id() == id).unwrap().1;
+    let red = Some(Color { r: 0xff, g: 0, b: 0 });
+    let green = Some(Color { r: 0, g: 0xff, b: 0 });
+    assert_eq!(style_of(10).color, red);
+    assert_eq!(style_of(10).fill_color, green);
+    assert_eq!(style_of(11).color, red);
+    assert_eq!(style_of(11).fill_color, None);
+    assert_eq!((style_of(12).width, style_of(12).opacity), (Some(2.0), None));
+    assert_eq!((style_of(13).width, style_of(13).opacity), (Some(2.0), Some(0.5)));
+    // The pseudo-classes that only make sense in an editor don't affect matching.
+    assert!(styles.iter().all(|(_, style)| style.z_index == 7.0));
+}
+
+#[test]
+fn test_id_selectors() {
+    // All ways have the same tags, so only the ids tell them apart.
+    let path = [("highway", "path")];
+    let geodata = GeodataBuilder::default()
+        .with_node(1, 55.75, 37.61, &[])
+        .with_node(2, 55.76, 37.62, &[])
+        .with_node(3, 55.75, 37.62, &[])
+        .with_way(10, &[1, 2], &path)
+        .with_way(11, &[2, 3], &path)
+        .with_way(12, &[3, 1], &path);
+    let map = TestMap::new(
+        "id_selectors",
+        &geodata,
+        "way[highway] { color: #0000ff; }
+         way[osm_id=11] { color: #ff0000; }
+         way[osm_id!=11][highway] { width: 2; }
+         node[osm_id=3] { symbol-shape: circle; }",
+    );
+    let rules = parse_file(&map.dir, "style.mapcss").unwrap();
+    assert_eq!(rules[1].selectors[0].to_string(), "way[osm_id=11]");
+    assert_eq!(rules[2].selectors[0].to_string(), "way[osm_id!=11][highway]");
+    std::fs::write(map.dir.join("bad_id.mapcss"), "way[osm_id=abc] { color: #ff0000; }").unwrap();
+    assert!(parse_file(&map.dir, "bad_id.mapcss").is_err());
+
+    let reader = map.reader();
+    let styler = Styler::new(rules, &StyleType::Josm, None);
+    let entities = reader.get_entities_in_bbox(55.7, 37.6, 55.8, 37.7, &None);
+
+    let blue = Some(Color { r: 0, g: 0, b: 0xff });
+    let red = Some(Color { r: 0xff, g: 0, b: 0 });
+    // The second time the styles of the ways with the same tags come from the cache.
+    for _ in 0..2 {
+        let styles = styler.style_entities(entities.ways.iter(), 16, false);
+        let style_of = |id| &styles.iter().find(|(w, _)| w.global_id() == id).unwrap().1;
+        assert_eq!(
+            (style_of(10).color.clone(), style_of(10).width),
+            (blue.clone(), Some(2.0))
+        );
+        assert_eq!((style_of(11).color.clone(), style_of(11).width), (red.clone(), None));
+        assert_eq!(
+            (style_of(12).color.clone(), style_of(12).width),
+            (blue.clone(), Some(2.0))
+        );
+
+        let styled_nodes = styler.style_entities(entities.nodes.iter(), 16, false);
+        let styled_node_ids = styled_nodes.iter().map(|(n, _)| n.global_id()).collect::<Vec<_>>();
+        assert_eq!(styled_node_ids, vec![3]);
+    }
+}
+
+#[test]
+fn test_untagged_nodes() {
+    let geodata = GeodataBuilder::default()
+        .with_node(1, 55.75, 37.61, &[])
+        .with_node(2, 55.76, 37.62, &[])
+        .with_node(3, 55.76, 37.61, &[("amenity", "cafe")]);
+    let map = TestMap::new(
+        "untagged_nodes",
+        &geodata,
+        "node|z17- { z-index: 1; }\nnode[amenity] { z-index: 2; }",
+    );
+    let reader = map.reader();
+    let styler = map.styler();
+    let entities = reader.get_entities_in_bbox(55.7, 37.6, 55.8, 37.7, &None);
+
+    let styled_ids = |zoom| {
+        let mut ids = styler
+            .style_entities(entities.nodes.iter(), zoom, false)
+            .iter()
+            .map(|(node, _)| node.global_id())
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids
+    };
+    for _ in 0..2 {
+        assert_eq!(styled_ids(16), vec![3]);
+        assert_eq!(styled_ids(17), vec![1, 2, 3]);
+    }
+    // Once it's known that untagged nodes get no styles at a zoom level, they don't even go to the style cache.
+    let lookups = || {
+        let stats = styler.style_cache_stats();
+        stats.hits + stats.misses
+    };
+    let lookups_before = lookups();
+    styled_ids(16);
+    assert_eq!(lookups() - lookups_before, 1);
+}
+
+#[test]
+fn test_area_detection() {
+    let closed = [1, 2, 3, 1];
+    let geodata = GeodataBuilder::default()
+        .with_node(1, 55.75, 37.61, &[])
+        .with_node(2, 55.76, 37.62, &[])
+        .with_node(3, 55.76, 37.61, &[])
+        .with_way(10, &closed, &[("building", "yes")])
+        .with_way(11, &closed, &[("highway", "primary"), ("junction", "roundabout")])
+        .with_way(12, &closed, &[("highway", "pedestrian"), ("area", "yes")])
+        .with_way(
+            13,
+            &closed,
+            &[("barrier", "fence"), ("landuse", "grass"), ("area", "no")],
+        )
+        .with_way(14, &closed, &[("natural", "tree_row")])
+        .with_way(15, &closed, &[("railway", "platform")])
+        .with_way(16, &[1, 2, 3], &[("building", "yes")]);
+    let map = TestMap::new("area_detection", &geodata, "area { fill-color: #ff0000; }");
+    let reader = map.reader();
+    let styler = map.styler();
+    let entities = reader.get_entities_in_bbox(55.7, 37.6, 55.8, 37.7, &None);
+
+    let mut area_ids = styler
+        .style_entities(entities.ways.iter(), 16, false)
+        .iter()
+        .filter(|(_, style)| style.fill_color.is_some())
+        .map(|(way, _)| way.global_id())
+        .collect::<Vec<_>>();
+    area_ids.sort_unstable();
+    assert_eq!(area_ids, vec![10, 12, 15]);
+}
+
+#[test]
+fn test_label_languages() {
+    let geodata = GeodataBuilder::default()
+        .with_node(
+            1,
+            55.75,
+            37.61,
+            &[
+                ("place", "city"),
+                ("name", "Москва"),
+                ("name:en", "Moscow"),
+                ("name:de", "Moskau"),
+            ],
+        )
+        .with_node(
+            2,
+            55.76,
+            37.62,
+            &[("place", "town"), ("name", "Химки"), ("name:en", "Khimki")],
+        )
+        .with_node(3, 55.76, 37.61, &[("place", "village"), ("name", "Ромашково")])
+        .with_node(
+            4,
+            55.75,
+            37.62,
+            &[
+                ("amenity", "cafe"),
+                ("name", "Cafe"),
+                ("name:ru", "Кафе"),
+                ("name:en", "Coffee"),
+            ],
+        );
+    let map = TestMap::new(
+        "label_languages",
+        &geodata,
+        "node[place] { text: name; font-size: 10; }\nnode[amenity] { text: \"name:ru\"; font-size: 10; }",
+    );
+    let reader = map.reader();
+    let entities = reader.get_entities_in_bbox(55.7, 37.6, 55.8, 37.7, &None);
+
+    let labels = |languages: &[&str]| {
+        let mut styler = map.styler();
+        styler.set_label_languages(languages.iter().map(|language| language.to_string()).collect());
+        let mut labels = styler
+            .style_entities(entities.nodes.iter(), 16, false)
+            .iter()
+            .map(|(node, style)| {
+                let text_style = style.text_style.as_ref().unwrap();
+                (
+                    node.global_id(),
+                    text_style.resolve_text(&node.tags()).unwrap().to_string(),
+                )
+            })
+            .collect::<Vec<_>>();
+        labels.sort();
+        labels
+    };
+    let expected = |names: [&str; 4]| (1..=4).zip(names.map(String::from)).collect::<Vec<_>>();
+
+    assert_eq!(labels(&[]), expected(["Москва", "Химки", "Ромашково", "Кафе"]));
+    assert_eq!(
+        labels(&["de", "en"]),
+        expected(["Moskau", "Khimki", "Ромашково", "Кафе"])
+    );
+}
+
+#[test]
+fn test_text_fallbacks() {
+    let geodata = GeodataBuilder::default()
+        .with_node(
+            1,
+            55.75,
+            37.61,
+            &[("amenity", "cafe"), ("name", "Кафе"), ("name:en", "Cafe"), ("ref", "1")],
+        )
+        .with_node(
+            2,
+            55.76,
+            37.62,
+            &[("amenity", "atm"), ("ref", "42"), ("operator", "Bank")],
+        )
+        .with_node(
+            3,
+            55.76,
+            37.61,
+            &[("amenity", "atm"), ("operator", "Bank"), ("operator:en", "The Bank")],
+        )
+        .with_node(4, 55.75, 37.62, &[("amenity", "fuel"), ("name", "АЗС")]);
+    let map = TestMap::new(
+        "text_fallbacks",
+        &geodata,
+        "node[amenity] { text: name, ref, operator; }\nnode[amenity=fuel] { text: \"brand\"; }",
+    );
+    let reader = map.reader();
+    let entities = reader.get_entities_in_bbox(55.7, 37.6, 55.8, 37.7, &None);
+
+    let labels = |languages: &[&str]| {
+        let mut styler = map.styler();
+        styler.set_label_languages(languages.iter().map(|language| language.to_string()).collect());
+        let mut labels = styler
+            .style_entities(entities.nodes.iter(), 16, false)
+            .iter()
+            .map(|(node, style)| {
+                let text_style = style.text_style.as_ref().unwrap();
+                (
+                    node.global_id(),
+                    text_style.resolve_text(&node.tags()).map(String::from),
+                )
+            })
+            .collect::<Vec<_>>();
+        labels.sort();
+        labels
+    };
+    let expected = |names: [Option<&str>; 4]| {
+        (1..=4)
+            .zip(names.map(|name| name.map(String::from)))
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(labels(&[]), expected([Some("Кафе"), Some("42"), Some("Bank"), None]));
+    // The localized variants of every key come right before the key itself.
+    assert_eq!(
+        labels(&["en"]),
+        expected([Some("Cafe"), Some("42"), Some("The Bank"), None])
+    );
+}
+
+#[test]
+fn test_text_layout_properties() {
+    let geodata = GeodataBuilder::default()
+        .with_node(1, 55.75, 37.61, &[("place", "city")])
+        .with_node(2, 55.76, 37.62, &[("place", "town")])
+        .with_node(3, 55.76, 37.61, &[("place", "village")]);
+    let map = TestMap::new(
+        "text_layout",
+        &geodata,
+        "node[place] { text: name; text-transform: uppercase; text-max-width: 6; text-line-break: \"/;\"; }\n\
+         node[place=town] { text-transform: none; text-max-width: 0; font-size: 12; text-allow-overlap: true; }\n\
+         node[place=village] { text-transform: sideways; }",
+    );
+    let reader = map.reader();
+    let styler = map.styler();
+    let entities = reader.get_entities_in_bbox(55.7, 37.6, 55.8, 37.7, &None);
+    let styles = styler.style_entities(entities.nodes.iter(), 16, false);
+    let text_style_of = |id| {
+        styles
+            .iter()
+            .find(|(node, _)| node.global_id() == id)
+            .unwrap()
+            .1
+            .text_style
+            .as_ref()
+            .unwrap()
+    };
+
+    assert_eq!(text_style_of(1).text_transform, Some(TextTransform::Uppercase));
+    assert_eq!(text_style_of(1).max_width, Some(6.0));
+    assert_eq!(text_style_of(1).line_break_chars, vec!['/', ';']);
+    assert_eq!(text_style_of(2).text_transform, None);
+    assert_eq!(text_style_of(2).max_width, Some(0.0));
+    assert_eq!(text_style_of(1).font_size, 8.0);
+    assert_eq!(text_style_of(2).font_size, 12.0);
+    assert!(!text_style_of(1).allow_overlap);
+    assert!(text_style_of(2).allow_overlap);
+    assert_eq!(text_style_of(3).text_transform, None);
+    assert_eq!(styler.warnings().0.len(), 1);
+}
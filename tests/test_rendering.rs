@@ -1,10 +1,20 @@
 mod common;
 
+use common::TestMap;
+
+use renderer::draw::drawer::{DeadlineExceeded, DebugOverlays, DrawOptions};
+use renderer::draw::labeler::LabelLimits;
 use renderer::draw::png_writer::rgb_triples_to_png;
 use renderer::draw::tile_pixels::{RgbTriples, TilePixels};
+use renderer::geodata::fixture::GeodataBuilder;
+use renderer::geodata::prefilter::Prefilter;
+use renderer::geodata::reader::OsmEntity;
+use renderer::geodata::zoom_masks::ZoomMasks;
 use renderer::mapcss::parser::parse_file;
 use renderer::mapcss::styler::{StyleType, Styler};
 use renderer::perf_stats;
+use renderer::render::{extent_zoom, PixelRegion, RenderOptions, TileRenderer};
+use renderer::tile::{bbox_of_tile, LatLonBounds, Projection, Tile};
 use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::Write;
@@ -22,15 +32,42 @@ fn read_png(file_name: &str) -> (RgbTriples, png::OutputInfo) {
     (result, info)
 }
 
+// The rendered tiles are compared with the expected ones pixel by pixel. To make refactorings that change
+// anti-aliasing or rounding a bit easier, the comparison can be relaxed with environment variables:
+// RENDERING_TOLERANCE=N lets every channel of a pixel differ by up to N, and RENDERING_MAX_DIFFERENT_PIXELS=N
+// lets up to N pixels of a zoom level differ by more than that. With UPDATE_EXPECTED_TILES=1, the rendered
+// tiles replace the expected ones instead.
+fn env_setting(name: &str, default: u32) -> u32 {
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("{} should be a non-negative integer, got {}", name, value)),
+        Err(_) => default,
+    }
+}
+
+// With the `u16-pixels` feature, the dithering moves some channels by one.
+fn rendering_tolerance() -> u32 {
+    env_setting("RENDERING_TOLERANCE", if cfg!(feature = "u16-pixels") { 1 } else { 0 })
+}
+
+fn pixels_differ(expected: &(u8, u8, u8), actual: &(u8, u8, u8), tolerance: u32) -> bool {
+    let channel_differs = |e: u8, a: u8| u32::from(e.abs_diff(a)) > tolerance;
+    channel_differs(expected.0, actual.0)
+        || channel_differs(expected.1, actual.1)
+        || channel_differs(expected.2, actual.2)
+}
+
 fn compare_png_outputs(zoom: u8, suffix: &str) {
-    let (expected, expected_info) = read_png(&common::get_test_path(&[
-        "rendered",
-        &format!("{}{}_expected.png", zoom, suffix),
-    ]));
-    let (actual, actual_info) = read_png(&common::get_test_path(&[
-        "rendered",
-        &format!("{}{}.png", zoom, suffix),
-    ]));
+    let expected_path = common::get_test_path(&["rendered", &format!("{}{}_expected.png", zoom, suffix)]);
+    let actual_path = common::get_test_path(&["rendered", &format!("{}{}.png", zoom, suffix)]);
+    if env_setting("UPDATE_EXPECTED_TILES", 0) != 0 {
+        std::fs::copy(&actual_path, &expected_path).unwrap();
+        return;
+    }
+
+    let (expected, expected_info) = read_png(&expected_path);
+    let (actual, actual_info) = read_png(&actual_path);
 
     assert_eq!(
         expected_info.width, actual_info.width,
@@ -43,14 +80,22 @@ fn compare_png_outputs(zoom: u8, suffix: &str) {
         zoom
     );
 
+    let tolerance = rendering_tolerance();
+    let max_different_pixels = env_setting("RENDERING_MAX_DIFFERENT_PIXELS", 0) as usize;
     let diff = expected
         .iter()
         .zip(actual)
-        .map(|(e, a)| if *e != a { RED_PIXEL } else { Default::default() })
+        .map(|(e, a)| {
+            if pixels_differ(e, &a, tolerance) {
+                RED_PIXEL
+            } else {
+                Default::default()
+            }
+        })
         .collect::<Vec<_>>();
-    let has_diff = diff.contains(&RED_PIXEL);
+    let different_pixels = diff.iter().filter(|p| **p == RED_PIXEL).count();
 
-    if has_diff {
+    if different_pixels > max_different_pixels {
         let diff_output_path = common::get_test_path(&["rendered", &format!("{}{}_diff.png", zoom, suffix)]);
         let diff_output = File::create(&diff_output_path);
 
@@ -59,7 +104,8 @@ fn compare_png_outputs(zoom: u8, suffix: &str) {
             .write_all(&rgb_triples_to_png(&diff, actual_info.width as usize, actual_info.height as usize).unwrap())
             .unwrap();
         panic!(
-            "the tiles for zoom level {} differ from the expected ones; see {} for more details",
+            "{} pixels of the tiles for zoom level {} differ from the expected ones; see {} for more details",
+            different_pixels,
             zoom,
             std::fs::canonicalize(diff_output_path).unwrap().to_str().unwrap()
         );
@@ -88,13 +134,23 @@ fn test_rendering_zoom(zoom: u8, min_x: u32, max_x: u32, min_y: u32, max_y: u32,
             perf_stats::start_tile(zoom);
             let tile_to_draw = renderer::tile::Tile { zoom, x, y };
             let entities = reader.get_entities_in_tile_with_neighbors(&tile_to_draw, &None);
-            let rendered = drawer.draw_to_pixels(&entities, &tile_to_draw, &mut pixels, scale, &styler);
+            let rendered = drawer
+                .draw_to_pixels(
+                    &entities,
+                    &tile_to_draw,
+                    &mut pixels,
+                    scale,
+                    &styler,
+                    &[],
+                    &DrawOptions::default(),
+                )
+                .unwrap();
             perf_stats::finish_tile(&mut perf_stats);
             rendered_tiles
                 .entry(tile_to_draw.zoom)
-                .or_insert_with(Default::default)
+                .or_default()
                 .entry(tile_to_draw.y)
-                .or_insert_with(Default::default)
+                .or_default()
                 .insert(tile_to_draw.x, rendered.triples);
         }
     }
@@ -107,7 +163,7 @@ fn test_rendering_zoom(zoom: u8, min_x: u32, max_x: u32, min_y: u32, max_y: u32,
             for sub_y in 0..tile_dimension {
                 for rendered in x_rendered.values() {
                     if sub_y == 0 {
-                        rgb.extend(std::iter::repeat(RED_PIXEL).take(tile_dimension));
+                        rgb.extend(std::iter::repeat_n(RED_PIXEL, tile_dimension));
                     } else {
                         rgb.extend(&rendered[sub_y * tile_dimension..(sub_y + 1) * tile_dimension - 1]);
                         rgb.push(RED_PIXEL);
@@ -174,3 +230,1385 @@ fn test_zoom_18() {
 fn test_zoom_18_2x() {
     test_rendering_zoom(18, 158_457, 158_465, 81_946, 81_953, 2)
 }
+
+#[test]
+fn test_tile_renderer() {
+    let bin_file = common::get_test_path(&["osm", "nano_moscow_tile_renderer.bin"]);
+    renderer::geodata::importer::import(&common::get_test_path(&["osm", "nano_moscow.osm"]), &bin_file).unwrap();
+    let stylesheet = common::get_test_path(&["mapcss", "mapnik.mapcss"]);
+    let tile_renderer = TileRenderer::new(&bin_file, &stylesheet, &StyleType::Josm, None).unwrap();
+
+    // The top left tile of the expected image for zoom level 16.
+    let tile = renderer::tile::Tile {
+        zoom: 16,
+        x: 39_614,
+        y: 20_486,
+    };
+    let rendered = tile_renderer.render_rgba(&tile, 1).unwrap();
+    assert_eq!(rendered.dimension, 256);
+
+    let (expected, expected_info) = read_png(&common::get_test_path(&["rendered", "16_expected.png"]));
+    let expected_width = expected_info.width as usize;
+    // Skip the red tile borders.
+    let tolerance = rendering_tolerance();
+    for y in 1..rendered.dimension {
+        for x in 0..rendered.dimension - 1 {
+            let (actual, expected) = (common::pixel(&rendered, x, y), expected[y * expected_width + x]);
+            assert!(
+                !pixels_differ(&expected, &actual, tolerance),
+                "pixel ({}, {}) differs: expected {:?}, got {:?}",
+                x,
+                y,
+                expected,
+                actual
+            );
+        }
+    }
+
+    let png_bytes = tile_renderer.render_png(&tile, 1).unwrap();
+    assert!(png_bytes.starts_with(b"\x89PNG"));
+    let outside = renderer::tile::Tile { zoom: 2, x: 4, y: 1 };
+    assert!(tile_renderer.render_png(&outside, 1).is_err());
+    assert!(tile_renderer.render_rgba(&outside, 1).is_err());
+
+    let parallel_renderer = TileRenderer::new(&bin_file, &stylesheet, &StyleType::Josm, None)
+        .unwrap()
+        .with_drawing_threads(3);
+    assert!(
+        parallel_renderer.render_rgba(&tile, 2).unwrap().bytes == tile_renderer.render_rgba(&tile, 2).unwrap().bytes
+    );
+
+    let expired = RenderOptions {
+        deadline: Some(std::time::Instant::now()),
+        ..Default::default()
+    };
+    let err = tile_renderer.render_png_with_options(&tile, 1, &expired).unwrap_err();
+    assert!(err.is::<DeadlineExceeded>());
+}
+
+#[test]
+fn test_zoom_masks() {
+    let bin_file = common::get_test_path(&["osm", "nano_moscow_zoom_masks.bin"]);
+    renderer::geodata::importer::import(&common::get_test_path(&["osm", "nano_moscow.osm"]), &bin_file).unwrap();
+    let stylesheet = common::get_test_path(&["mapcss", "mapnik.mapcss"]);
+    let new_renderer = || TileRenderer::new(&bin_file, &stylesheet, &StyleType::Josm, None).unwrap();
+
+    let masks_file = common::get_test_path(&["osm", "nano_moscow_zoom_masks.masks"]);
+    let zoom_masks = new_renderer().bake_zoom_masks();
+    zoom_masks.save(&masks_file).unwrap();
+    let loaded = ZoomMasks::load(&masks_file).unwrap();
+    std::fs::remove_file(&masks_file).unwrap();
+    assert!(loaded == zoom_masks);
+    assert!(zoom_masks.nodes.contains(&0));
+    assert!(zoom_masks.ways.iter().any(|mask| *mask != 0));
+
+    let tile = renderer::tile::Tile {
+        zoom: 16,
+        x: 39_614,
+        y: 20_486,
+    };
+    let baked_renderer = new_renderer().with_zoom_masks(loaded).unwrap();
+    assert!(baked_renderer.render_rgba(&tile, 1).unwrap().bytes == new_renderer().render_rgba(&tile, 1).unwrap().bytes);
+
+    // The same rules are interpreted differently for Maps.ME.
+    let mapsme_renderer = TileRenderer::new(&bin_file, &stylesheet, &StyleType::MapsMe, None).unwrap();
+    assert!(mapsme_renderer.with_zoom_masks(zoom_masks).is_err());
+}
+
+#[test]
+fn test_render_region() {
+    let bin_file = common::get_test_path(&["osm", "nano_moscow_region.bin"]);
+    renderer::geodata::importer::import(&common::get_test_path(&["osm", "nano_moscow.osm"]), &bin_file).unwrap();
+    let stylesheet = common::get_test_path(&["mapcss", "mapnik.mapcss"]);
+    let tile_renderer = TileRenderer::new(&bin_file, &stylesheet, &StyleType::Josm, None).unwrap();
+
+    let tile = |x| renderer::tile::Tile { zoom: 16, x, y: 20_486 };
+    let (left_tile, right_tile) = (
+        tile_renderer.render_rgba(&tile(39_614), 2).unwrap(),
+        tile_renderer.render_rgba(&tile(39_615), 2).unwrap(),
+    );
+
+    // The right half of the first tile and the left half of the second one, without the top and bottom rows.
+    let region = PixelRegion {
+        zoom: 16,
+        left: 39_614 * 256 + 128,
+        top: 20_486 * 256 + 1,
+        width: 256,
+        height: 254,
+    };
+    let image = tile_renderer
+        .render_region(&region, 2, &RenderOptions::default())
+        .unwrap();
+    assert_eq!((image.width, image.height), (512, 508));
+
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let (src, src_x) = if x < 256 {
+                (&left_tile, x + 256)
+            } else {
+                (&right_tile, x - 256)
+            };
+            let idx = 4 * ((y + 2) * src.dimension + src_x);
+            let expected = (src.bytes[idx], src.bytes[idx + 1], src.bytes[idx + 2]);
+            assert_eq!(
+                image.triples[y * image.width + x],
+                expected,
+                "pixel ({}, {}) differs",
+                x,
+                y
+            );
+        }
+    }
+
+    let outside = PixelRegion {
+        zoom: 1,
+        left: 500,
+        top: 0,
+        width: 100,
+        height: 100,
+    };
+    assert!(tile_renderer
+        .render_region(&outside, 1, &RenderOptions::default())
+        .is_err());
+}
+
+#[test]
+fn test_render_plate_carree() {
+    let bin_file = common::get_test_path(&["osm", "nano_moscow_plate_carree.bin"]);
+    renderer::geodata::importer::import(&common::get_test_path(&["osm", "nano_moscow.osm"]), &bin_file).unwrap();
+    let stylesheet = common::get_test_path(&["mapcss", "mapnik.mapcss"]);
+    let tile_renderer = TileRenderer::new(&bin_file, &stylesheet, &StyleType::Josm, None).unwrap();
+
+    let options = RenderOptions {
+        projection: Projection::PlateCarree,
+        ..Default::default()
+    };
+    let (x, y) = Projection::PlateCarree.coords_to_xy(&(55.7526f64, 37.6065f64), 15);
+    let tile = Tile {
+        zoom: 15,
+        x: x as u32 / 256,
+        y: y as u32 / 256,
+    };
+    let image = tile_renderer.render_plate_carree(&tile, 1, &options).unwrap();
+    assert_eq!((image.width, image.height), (256, 256));
+
+    // Every row is the row of the Web Mercator tile one zoom level deeper that contains its latitude.
+    let mut mercator_tiles = BTreeMap::new();
+    for row in 0..image.height {
+        let (lat, lon) = Projection::PlateCarree.xy_to_coords(0.0, f64::from(tile.y * 256) + row as f64 + 0.5, 15);
+        let (_, mercator_y) = Projection::WebMercator.coords_to_xy(&(lat, lon), 16);
+        let mercator_y = mercator_y as u32;
+        let mercator_tile = mercator_tiles.entry(mercator_y / 256).or_insert_with(|| {
+            let mercator_tile = Tile {
+                zoom: 16,
+                x: tile.x,
+                y: mercator_y / 256,
+            };
+            tile_renderer.render_rgba(&mercator_tile, 1).unwrap()
+        });
+        for column in 0..image.width {
+            let idx = 4 * ((mercator_y % 256) as usize * 256 + column);
+            let bytes = &mercator_tile.bytes;
+            assert_eq!(
+                image.triples[row * image.width + column],
+                (bytes[idx], bytes[idx + 1], bytes[idx + 2]),
+                "pixel ({}, {}) differs",
+                column,
+                row
+            );
+        }
+    }
+    // A plate carrée tile covers more latitudes than a Web Mercator one this far from the equator.
+    assert!(mercator_tiles.len() > 1);
+
+    // Web Mercator doesn't reach the poles.
+    let world = tile_renderer
+        .render_plate_carree(&Tile { zoom: 0, x: 1, y: 0 }, 1, &options)
+        .unwrap();
+    let canvas_color = tile_renderer
+        .styler()
+        .canvas_fill_color(1)
+        .map_or((0, 0, 0), |c| (c.r, c.g, c.b));
+    assert!(world.triples[..256].iter().all(|&pixel| pixel == canvas_color));
+    assert!(world.triples[255 * 256..].iter().all(|&pixel| pixel == canvas_color));
+
+    let png = tile_renderer.render_png_with_options(&tile, 2, &options).unwrap();
+    let reader = png::Decoder::new(png.as_slice()).read_info().unwrap();
+    assert_eq!((reader.info().width, reader.info().height), (512, 512));
+    assert!(tile_renderer
+        .render_plate_carree(&Tile { zoom: 0, x: 2, y: 0 }, 1, &options)
+        .is_err());
+}
+
+#[test]
+fn test_outside_geodata() {
+    let bin_file = common::get_test_path(&["osm", "nano_moscow_outside.bin"]);
+    renderer::geodata::importer::import(&common::get_test_path(&["osm", "nano_moscow.osm"]), &bin_file).unwrap();
+    let stylesheet = common::get_test_path(&["mapcss", "mapnik.mapcss"]);
+    let tile_renderer = TileRenderer::new(&bin_file, &stylesheet, &StyleType::Josm, None).unwrap();
+
+    let readers = tile_renderer.readers();
+    let data_range = readers[0].tile_range().unwrap();
+    let tile = |x, y| Tile { zoom: 18, x, y };
+    assert!(!tile_renderer.is_outside_geodata(&tile(data_range.min_x, data_range.min_y)));
+    // The entities of the neighbors are drawn, too.
+    assert!(!tile_renderer.is_outside_geodata(&tile(data_range.min_x - 1, data_range.min_y - 1)));
+    assert!(tile_renderer.is_outside_geodata(&tile(data_range.min_x - 2, data_range.min_y)));
+    assert!(tile_renderer.is_outside_geodata(&tile(data_range.min_x, data_range.max_y + 2)));
+    assert!(!tile_renderer.is_outside_geodata(&Tile { zoom: 0, x: 0, y: 0 }));
+
+    // A tile that is declared empty is really empty.
+    let blank = tile_renderer
+        .render_rgba(&tile(data_range.max_x + 2, data_range.max_y), 1)
+        .unwrap();
+    assert!(blank.bytes.chunks_exact(4).all(|rgba| rgba == &blank.bytes[..4]));
+
+    // The PNG of the first blank tile is reused for the others.
+    let blank_png = tile_renderer
+        .render_png(&tile(data_range.max_x + 2, data_range.max_y), 1)
+        .unwrap();
+    let mut reader = png::Decoder::new(blank_png.as_slice()).read_info().unwrap();
+    let mut decoded = vec![0; reader.output_buffer_size()];
+    reader.next_frame(&mut decoded).unwrap();
+    assert!(decoded.chunks_exact(3).all(|rgb| rgb == &blank.bytes[..3]));
+    assert_eq!(tile_renderer.render_png(&tile(0, 0), 1).unwrap(), blank_png);
+
+    let geodata_extent = tile_renderer.geodata_extent().unwrap();
+    assert!(geodata_extent.min_lat < 55.7554 && 55.7554 < geodata_extent.max_lat);
+}
+
+#[test]
+fn test_antimeridian_lines() {
+    // A red road half a pixel east of the antimeridian and a blue one half a pixel west of it, both 8 pixels wide.
+    let zoom = 10;
+    let lon = 180.0 - 0.5 * 360.0 / f64::from(256u32 << zoom);
+    let geodata = GeodataBuilder::default()
+        .with_node(1, 65.40, lon, &[])
+        .with_node(2, 65.44, lon, &[])
+        .with_node(3, 65.46, -lon, &[])
+        .with_node(4, 65.50, -lon, &[])
+        .with_way(10, &[1, 2], &[("highway", "primary")])
+        .with_way(11, &[3, 4], &[("highway", "secondary")]);
+    let map = TestMap::new(
+        "antimeridian_lines",
+        &geodata,
+        "canvas { fill-color: #ffffff; }\n\
+         way[highway=primary] { color: #ff0000; width: 8; }\n\
+         way[highway=secondary] { color: #0000ff; width: 8; }",
+    );
+    let tile_renderer = map.renderer();
+
+    let west_tile = renderer::tile::tile_at(&(65.45, -180.0), zoom);
+    let east_tile = Tile {
+        x: (1 << zoom) - 1,
+        ..west_tile
+    };
+    let row = |lat| renderer::tile::coords_to_xy_tile_relative(&(lat, -180.0), &west_tile).1 as usize;
+    let (red_y, blue_y) = (row(65.42), row(65.48));
+    let east = tile_renderer.render_rgba(&east_tile, 1).unwrap();
+    let west = tile_renderer.render_rgba(&west_tile, 1).unwrap();
+    // Each road fully covers the two pixels next to the antimeridian on the other side of it, too.
+    for x in 0..2 {
+        assert_eq!(common::pixel(&east, 255 - x, red_y), (255, 0, 0));
+        assert_eq!(common::pixel(&west, x, red_y), (255, 0, 0));
+        assert_eq!(common::pixel(&west, x, blue_y), (0, 0, 255));
+        assert_eq!(common::pixel(&east, 255 - x, blue_y), (0, 0, 255));
+    }
+    assert_eq!(common::pixel(&west, 8, red_y), (255, 255, 255));
+    assert_eq!(common::pixel(&east, 247, blue_y), (255, 255, 255));
+}
+
+#[test]
+fn test_blank_tiles() {
+    // Two roads far from each other, and a bench without a style between them.
+    let (tile, lat, lon) = tile_centered_at(55.7491, 37.6071, 16);
+    let far_lon = lon + 0.1;
+    let geodata = GeodataBuilder::default()
+        .with_node(1, lat, lon - 0.001, &[])
+        .with_node(2, lat, lon + 0.001, &[])
+        .with_node(3, lat, far_lon - 0.001, &[])
+        .with_node(4, lat, far_lon + 0.001, &[])
+        .with_node(5, lat, lon + 0.05, &[("amenity", "bench")])
+        .with_way(1, &[1, 2], &[("highway", "primary")])
+        .with_way(2, &[3, 4], &[("highway", "primary")]);
+    let map = TestMap::new(
+        "blank_tiles",
+        &geodata,
+        "canvas { fill-color: #ffffff; }\nway[highway] { color: #ff0000; width: 2; }",
+    );
+    let tile_renderer = map.renderer();
+
+    assert!(!tile_renderer.is_blank(&tile));
+    let bench_tile = renderer::tile::tile_at(&(lat, lon + 0.05), tile.zoom);
+    assert!(!tile_renderer.is_outside_geodata(&bench_tile));
+    assert!(tile_renderer.is_blank(&bench_tile));
+    let blank = tile_renderer.render_rgba(&bench_tile, 1).unwrap();
+    assert!(blank.bytes.chunks_exact(4).all(|rgba| rgba == [255, 255, 255, 255]));
+    assert!(tile_renderer.is_blank(&Tile { zoom: 16, x: 0, y: 0 }));
+}
+
+#[test]
+fn test_query_point() {
+    // A forest with a path across it and a cafe inside, plus a shop that is styled but not drawn.
+    let geodata = GeodataBuilder::default()
+        .with_node(1, 55.750, 37.610, &[])
+        .with_node(2, 55.750, 37.614, &[])
+        .with_node(3, 55.752, 37.614, &[])
+        .with_node(4, 55.752, 37.610, &[])
+        .with_node(5, 55.751, 37.605, &[])
+        .with_node(6, 55.751, 37.625, &[])
+        .with_node(7, 55.7515, 37.612, &[("amenity", "cafe"), ("name", "Cafe")])
+        .with_node(8, 55.7505, 37.611, &[("shop", "bakery")])
+        .with_way(10, &[1, 2, 3, 4, 1], &[("landuse", "forest")])
+        .with_way(11, &[5, 6], &[("highway", "path")]);
+    let map = TestMap::new(
+        "query_point",
+        &geodata,
+        "way[highway] { color: #000000; width: 4; }\n\
+         area[landuse=forest] { fill-color: #00ff00; }\n\
+         node[amenity] { text: name; }\n\
+         node[shop] { z-index: 1; }",
+    );
+    let tile_renderer = map.renderer();
+
+    let query = |lat, lon| {
+        let entities = tile_renderer.query_point(lat, lon, 17).unwrap();
+        entities.iter().map(|e| (e.kind, e.id)).collect::<Vec<_>>()
+    };
+    assert_eq!(query(55.751, 37.612), [("way", 10), ("way", 11)]);
+    assert_eq!(query(55.7515, 37.612), [("node", 7), ("way", 10)]);
+    assert_eq!(query(55.7505, 37.611), [("way", 10)]);
+    assert_eq!(query(55.751, 37.607), [("way", 11)]);
+    assert!(query(55.749, 37.607).is_empty());
+    // The path is 4 pixels wide.
+    let (x, y) = renderer::tile::coords_to_xy(&(55.751, 37.607), 17);
+    let (lat, lon) = renderer::tile::xy_to_coords(x, y + 1.9, 17);
+    assert_eq!(query(lat, lon), [("way", 11)]);
+    let (lat, lon) = renderer::tile::xy_to_coords(x, y + 2.1, 17);
+    assert!(query(lat, lon).is_empty());
+
+    let cafe = tile_renderer.query_point(55.7515, 37.612, 17).unwrap().remove(0);
+    let expected_tags = [("amenity", "cafe"), ("name", "Cafe")];
+    assert_eq!(cafe.tags, expected_tags.map(|(k, v)| (k.to_string(), v.to_string())));
+    assert!(tile_renderer.query_point(55.751, 37.612, 19).is_err());
+
+    // The prefilter drops the entities before they are styled, so they are neither drawn nor found.
+    let prefiltered = |prefilter: Prefilter| {
+        let tile_renderer = map.renderer().with_prefilter(prefilter);
+        let entities = tile_renderer.query_point(55.751, 37.612, 17).unwrap();
+        entities.iter().map(|e| (e.kind, e.id)).collect::<Vec<_>>()
+    };
+    let only_highways = Prefilter::default().with_kept_tags("highway, *@18").unwrap();
+    assert_eq!(prefiltered(only_highways), [("way", 11)]);
+    // The path is about 1860 pixels long at zoom level 17, and the forest is about 1400 pixels around.
+    let long_ways = Prefilter::default().with_min_lengths("1600").unwrap();
+    assert_eq!(prefiltered(long_ways), [("way", 11)]);
+    let longer_ways = Prefilter::default().with_min_lengths("2000, 0@17").unwrap();
+    assert_eq!(prefiltered(longer_ways), [("way", 10), ("way", 11)]);
+    let longer_ways = Prefilter::default().with_min_lengths("2000").unwrap();
+    assert!(prefiltered(longer_ways).is_empty());
+}
+
+#[test]
+fn test_render_extent() {
+    let bin_file = common::get_test_path(&["osm", "nano_moscow_extent.bin"]);
+    renderer::geodata::importer::import(&common::get_test_path(&["osm", "nano_moscow.osm"]), &bin_file).unwrap();
+    let stylesheet = common::get_test_path(&["mapcss", "mapnik.mapcss"]);
+    let tile_renderer = TileRenderer::new(&bin_file, &stylesheet, &StyleType::Josm, None).unwrap();
+
+    // The extent of a tile is the tile itself, in both projections.
+    let mercator_tile = Tile {
+        zoom: 16,
+        x: 39_614,
+        y: 20_486,
+    };
+    let mercator_options = RenderOptions::default();
+    let image = tile_renderer
+        .render_extent(&bbox_of_tile(&mercator_tile), 256, 256, &mercator_options)
+        .unwrap();
+    let expected = tile_renderer.render_rgba(&mercator_tile, 1).unwrap();
+    assert_eq!((image.width, image.height), (256, 256));
+    assert!(image
+        .triples
+        .iter()
+        .zip(expected.bytes.chunks_exact(4))
+        .all(|(&(r, g, b), rgba)| [r, g, b] == rgba[..3]));
+
+    let plate_carree_tile = Tile {
+        zoom: 15,
+        x: 39_614,
+        y: 10_243,
+    };
+    let plate_carree_options = RenderOptions {
+        projection: Projection::PlateCarree,
+        ..Default::default()
+    };
+    let corner = |x: u32, y: u32| Projection::PlateCarree.xy_to_coords(f64::from(x * 256), f64::from(y * 256), 15);
+    let ((max_lat, min_lon), (min_lat, max_lon)) = (
+        corner(plate_carree_tile.x, plate_carree_tile.y),
+        corner(plate_carree_tile.x + 1, plate_carree_tile.y + 1),
+    );
+    let bounds = LatLonBounds {
+        min_lat,
+        min_lon,
+        max_lat,
+        max_lon,
+    };
+    let image = tile_renderer
+        .render_extent(&bounds, 256, 256, &plate_carree_options)
+        .unwrap();
+    let expected = tile_renderer
+        .render_plate_carree(&plate_carree_tile, 1, &plate_carree_options)
+        .unwrap();
+    assert!(image.triples == expected.triples);
+
+    // The images don't have to have the same aspect ratio as the extent.
+    let stretched = tile_renderer
+        .render_extent(&bounds, 128, 512, &plate_carree_options)
+        .unwrap();
+    assert_eq!(
+        (stretched.width, stretched.height, stretched.triples.len()),
+        (128, 512, 128 * 512)
+    );
+
+    let invalid = LatLonBounds {
+        min_lat: max_lat,
+        ..bounds
+    };
+    assert!(tile_renderer
+        .render_extent(&invalid, 256, 256, &plate_carree_options)
+        .is_err());
+
+    // A thin strip around the world needs the maximum zoom level for its height, but then it's far too wide.
+    let strip = LatLonBounds {
+        min_lat: 0.0,
+        min_lon: -180.0,
+        max_lat: 0.001,
+        max_lon: 180.0,
+    };
+    let err = extent_zoom(&strip, 2, 2048).unwrap_err();
+    assert!(
+        err.to_string().contains("is too stretched for a 2x2048 image"),
+        "{}",
+        err
+    );
+    assert!(tile_renderer
+        .render_extent(&strip, 2, 2048, &plate_carree_options)
+        .is_err());
+}
+
+#[test]
+fn test_debug_overlays() {
+    let bin_file = common::get_test_path(&["osm", "nano_moscow_debug.bin"]);
+    renderer::geodata::importer::import(&common::get_test_path(&["osm", "nano_moscow.osm"]), &bin_file).unwrap();
+    let stylesheet = common::get_test_path(&["mapcss", "mapnik.mapcss"]);
+    let tile_renderer = TileRenderer::new(&bin_file, &stylesheet, &StyleType::Josm, None).unwrap();
+
+    let region = PixelRegion {
+        zoom: 16,
+        left: 39_614 * 256,
+        top: 20_486 * 256,
+        width: 256,
+        height: 256,
+    };
+    let render = |debug| {
+        let options = RenderOptions {
+            debug,
+            ..Default::default()
+        };
+        tile_renderer.render_region(&region, 1, &options).unwrap().triples
+    };
+    let count_color = |triples: &RgbTriples, color| triples.iter().filter(|t| **t == color).count();
+    let (grid_color, placed_label_color) = ((0xc0, 0x00, 0xc0), (0x20, 0x60, 0xff));
+
+    let plain = render(DebugOverlays::default());
+    assert_eq!(count_color(&plain, grid_color), 0);
+    assert_eq!(count_color(&plain, placed_label_color), 0);
+
+    let grid = render(DebugOverlays {
+        grid: true,
+        ..Default::default()
+    });
+    for (x, y) in [(0, 0), (255, 0), (0, 128), (255, 255)] {
+        assert_eq!(grid[y * 256 + x], grid_color);
+    }
+    assert!(grid[128 * 256 + 128] == plain[128 * 256 + 128]);
+
+    let labels = render(DebugOverlays {
+        labels: true,
+        ..Default::default()
+    });
+    assert!(count_color(&labels, placed_label_color) > 100);
+
+    let ids = render(DebugOverlays {
+        ids: true,
+        ..Default::default()
+    });
+    assert!(ids != plain);
+    assert_eq!(count_color(&ids, placed_label_color), 0);
+}
+
+#[test]
+fn test_deterministic_labels() {
+    // A grid of named nodes that are much closer to each other than the size of their labels,
+    // on top of several overlapping squares with identical styles (and hence identical z-indices).
+    let (lat, lon) = (55.7491, 37.6071);
+    let place_names = (1..=100).map(|i| format!("Place {}", i)).collect::<Vec<_>>();
+    let square_names = (1..=5).map(|i| format!("Square {}", i)).collect::<Vec<_>>();
+    let mut nodes = Vec::new();
+    for (i, name) in (0..100u32).zip(&place_names) {
+        let (row, col) = (f64::from(i / 10), f64::from(i % 10));
+        let tags = vec![("place", "hamlet"), ("name", name.as_str())];
+        nodes.push((u64::from(i) + 1, lat + row * 0.00015, lon + col * 0.00025, tags));
+    }
+    let mut ways = Vec::new();
+    for (i, name) in (0..5u32).zip(&square_names) {
+        let offset = f64::from(i) * 0.0002;
+        let first_node_id = 1000 + 4 * u64::from(i);
+        let corners = [(0.0, 0.0), (0.0, 0.002), (0.0012, 0.002), (0.0012, 0.0)];
+        for (node_id, (d_lat, d_lon)) in (first_node_id..).zip(corners) {
+            nodes.push((node_id, lat + offset + d_lat, lon + offset + d_lon, Vec::new()));
+        }
+        let node_ids = (0..5).map(|idx| first_node_id + idx % 4).collect::<Vec<_>>();
+        ways.push((
+            u64::from(i) + 1,
+            node_ids,
+            vec![("landuse", "grass"), ("name", name.as_str())],
+        ));
+    }
+
+    // The rendering must not depend on the order in which the entities are stored, either.
+    let new_renderer = |test_name: &str, reversed: bool| {
+        let (mut nodes, mut ways) = (nodes.iter().collect::<Vec<_>>(), ways.iter().collect::<Vec<_>>());
+        if reversed {
+            nodes.reverse();
+            ways.reverse();
+        }
+        let mut geodata = GeodataBuilder::default();
+        for (id, node_lat, node_lon, tags) in nodes {
+            geodata = geodata.with_node(*id, *node_lat, *node_lon, tags);
+        }
+        for (id, node_ids, tags) in ways {
+            geodata = geodata.with_way(*id, node_ids, tags);
+        }
+        let stylesheet = "canvas { fill-color: #ffffff; }\n\
+             node[place] { text: name; font-size: 12; text-color: #000000; text-halo-radius: 1; text-halo-color: #ffffff; }\n\
+             area[landuse] { fill-color: #d0e0d0; text: name; font-size: 14; text-color: #306030; }";
+        TestMap::new(test_name, &geodata, stylesheet).renderer()
+    };
+    let forward_renderer = new_renderer("labels_forward", false);
+    let reversed_renderer = new_renderer("labels_reversed", true).with_drawing_threads(3);
+
+    let max_zoom_tile = renderer::tile::coords_to_max_zoom_tile(&(lat, lon));
+    let tile = renderer::tile::Tile {
+        zoom: 16,
+        x: max_zoom_tile.x >> 2,
+        y: max_zoom_tile.y >> 2,
+    };
+    for scale in 1..=2 {
+        let expected = forward_renderer.render_rgba(&tile, scale).unwrap().bytes;
+        assert!(expected.chunks(4).any(|pixel| pixel[..3].iter().all(|c| *c < 64)));
+        for _ in 0..3 {
+            assert!(forward_renderer.render_rgba(&tile, scale).unwrap().bytes == expected);
+            assert!(reversed_renderer.render_rgba(&tile, scale).unwrap().bytes == expected);
+        }
+    }
+}
+
+// Returns a tile near the given point, together with the coordinates of the center of the tile.
+fn tile_centered_at(lat: f64, lon: f64, zoom: u8) -> (renderer::tile::Tile, f64, f64) {
+    let (x, y) = renderer::tile::coords_to_xy(&(lat, lon), zoom);
+    let tile_size = f64::from(renderer::tile::TILE_SIZE);
+    let tile = renderer::tile::Tile {
+        zoom,
+        x: (x / tile_size) as u32,
+        y: (y / tile_size) as u32,
+    };
+    let degrees_per_pixel = 360.0 / (tile_size * f64::from(1 << zoom));
+    let center_lon = lon + ((f64::from(tile.x) + 0.5) * tile_size - x) * degrees_per_pixel;
+    let center_lat = lat - ((f64::from(tile.y) + 0.5) * tile_size - y) * degrees_per_pixel * lat.to_radians().cos();
+    (tile, center_lat, center_lon)
+}
+
+#[test]
+fn test_curved_labels() {
+    // A big square with a name, centered in a tile at zoom level 16.
+    let (tile, lat, lon) = tile_centered_at(55.7491, 37.6071, 16);
+    let size = 0.003;
+    let tags = [("landuse", "forest"), ("name", "Very Large Forest")];
+    let geodata = GeodataBuilder::default()
+        .with_node(1, lat - size, lon - size, &[])
+        .with_node(2, lat - size, lon + size, &[])
+        .with_node(3, lat + size, lon + size, &[])
+        .with_node(4, lat + size, lon - size, &[])
+        .with_way(1, &[1, 2, 3, 4, 1], &tags);
+    let map = TestMap::new("curved_labels", &geodata, "");
+
+    // Returns the size of the label, and the lowest row of its dark pixels in the middle and at the left end of it.
+    let render = |text_properties: &str| {
+        map.set_stylesheet(&format!(
+            "canvas {{ fill-color: #ffffff; }}\n\
+             area[landuse] {{ fill-color: #e0f0e0; text: name; font-size: 14; text-color: #000000; {} }}",
+            text_properties
+        ));
+        let image = map.renderer().render_rgba(&tile, 1).unwrap();
+        let dark_pixels = image
+            .bytes
+            .chunks(4)
+            .enumerate()
+            .filter(|(_, pixel)| pixel[..3].iter().all(|c| *c < 100))
+            .map(|(idx, _)| (idx % image.dimension, idx / image.dimension))
+            .collect::<Vec<_>>();
+        let min_x = dark_pixels.iter().map(|p| p.0).min().unwrap();
+        let max_x = dark_pixels.iter().map(|p| p.0).max().unwrap();
+        let min_y = dark_pixels.iter().map(|p| p.1).min().unwrap();
+        let max_y = dark_pixels.iter().map(|p| p.1).max().unwrap();
+        let lowest_in_columns = |from: usize, to: usize| {
+            dark_pixels
+                .iter()
+                .filter(|p| p.0 >= from && p.0 < to)
+                .map(|p| p.1)
+                .max()
+                .unwrap()
+        };
+        let width = max_x - min_x;
+        let middle = min_x + width / 2;
+        (
+            (max_x - min_x, max_y - min_y),
+            lowest_in_columns(middle - width / 10, middle + width / 10),
+            lowest_in_columns(min_x, min_x + width / 10),
+        )
+    };
+
+    let (centered_size, centered_middle, centered_left) = render("text-position: center; text-max-width: 0;");
+    assert!(centered_size.1 < 20);
+    assert!(centered_left.abs_diff(centered_middle) <= 4);
+
+    let (spaced_size, _, _) = render("text-position: center; text-max-width: 0; text-letter-spacing: 3;");
+    assert_eq!(spaced_size.0, centered_size.0 + 3 * ("Very Large Forest".len() - 1));
+
+    let (curved_size, curved_middle, curved_left) = render("text-position: curved;");
+    assert!(curved_size.1 >= centered_size.1 + 3);
+    assert!(curved_left >= curved_middle + 3);
+}
+
+#[test]
+fn test_icon_with_text() {
+    let (tile, lat, lon) = tile_centered_at(55.7491, 37.6071, 16);
+    let geodata = GeodataBuilder::default().with_node(1, lat, lon, &[("amenity", "fuel"), ("name", "Fuel")]);
+    let map = TestMap::new("icon_with_text", &geodata, "");
+    std::fs::copy("tests/mapcss/symbols/fuel.p.16.png", map.dir.join("icon.png")).unwrap();
+
+    // Returns the bounding boxes of the icon (if it's drawn) and of the bright green text as (min_x, min_y, max_x, max_y).
+    let render = |properties: &str| {
+        map.set_stylesheet(&format!(
+            "canvas {{ fill-color: #ffffff; }}\n\
+             node[amenity] {{ icon-image: \"icon.png\"; text: name; font-size: 12; text-color: #00ff00; {} }}",
+            properties
+        ));
+        let image = map.renderer().render_rgba(&tile, 1).unwrap();
+        let pixels_where = |predicate: &dyn Fn(&[u8]) -> bool| {
+            let coords = image
+                .bytes
+                .chunks(4)
+                .enumerate()
+                .filter(|(_, pixel)| predicate(pixel))
+                .map(|(idx, _)| (idx % image.dimension, idx / image.dimension))
+                .collect::<Vec<_>>();
+            Some((
+                coords.iter().map(|p| p.0).min()?,
+                coords.iter().map(|p| p.1).min()?,
+                coords.iter().map(|p| p.0).max()?,
+                coords.iter().map(|p| p.1).max()?,
+            ))
+        };
+        let is_text = |pixel: &[u8]| pixel[1] > 200 && pixel[0] < 100 && pixel[2] < 100;
+        let is_icon = |pixel: &[u8]| !is_text(pixel) && pixel[..3].iter().any(|c| *c < 200) && pixel[1] < 200;
+        (pixels_where(&is_icon), pixels_where(&is_text).unwrap())
+    };
+
+    let center = 128;
+    let (centered_icon, text) = render("");
+    let centered_icon = centered_icon.unwrap();
+    assert!(centered_icon.0 < center && centered_icon.2 > center);
+    assert!(centered_icon.1 < center && centered_icon.3 > center);
+    assert!(text.1 > centered_icon.3);
+    assert!((text.0 + text.2).abs_diff(centered_icon.0 + centered_icon.2) <= 2);
+
+    let (icon, text) = render("text-anchor: right; text-offset: 5;");
+    let icon = icon.unwrap();
+    assert!(text.0 >= icon.2 + 5);
+    assert!(text.1 > icon.1 && text.3 < icon.3);
+
+    let (icon, text) = render("text-anchor: above; icon-anchor: bottom;");
+    let icon = icon.unwrap();
+    // The icon is 16 pixels high.
+    assert_eq!(icon.3 + 8, centered_icon.3);
+    assert!(text.3 < icon.1);
+
+    // Without an icon, the text is anchored to the node itself.
+    let (icon, text) = render("icon-image: none.png; text-anchor: left;");
+    assert!(icon.is_none());
+    assert!(text.2 < center && text.2 + 6 >= center);
+    assert!(text.1 < center && text.3 > center);
+}
+
+#[test]
+fn test_label_limits() {
+    // A grid of villages and towns that are far enough from each other for all labels to fit.
+    let (tile, lat, lon) = tile_centered_at(55.7491, 37.6071, 16);
+    let names = (1..=16).map(|i| format!("P{}", i)).collect::<Vec<_>>();
+    let mut geodata = GeodataBuilder::default();
+    for (i, name) in (0..16u32).zip(&names) {
+        let (row, col) = (f64::from(i / 4) - 1.5, f64::from(i % 4) - 1.5);
+        let place = if i % 2 == 0 { "village" } else { "town" };
+        let tags = [("place", place), ("name", name.as_str())];
+        geodata = geodata.with_node(u64::from(i) + 1, lat + row * 0.0006, lon + col * 0.001, &tags);
+    }
+    let map = TestMap::new(
+        "label_limits",
+        &geodata,
+        "canvas { fill-color: #ffffff; }\n\
+         node[place=village] { text: name; font-size: 12; text-color: #0000ff; z-index: 1; }\n\
+         node[place=town] { text: name; font-size: 12; text-color: #ff0000; z-index: 2; }",
+    );
+
+    // Returns whether there are red (town) and blue (village) labels on the tile.
+    let render = |limits: &str| {
+        let tile_renderer = map.renderer().with_label_limits(LabelLimits::parse(limits).unwrap());
+        perf_stats::start_tile(tile.zoom);
+        let image = tile_renderer.render_rgba(&tile, 1).unwrap();
+        let stats = perf_stats::finish_tile_stats().unwrap().to_text();
+        let has_pixels = |channel: usize| {
+            image
+                .bytes
+                .chunks(4)
+                .any(|pixel| (0..3).all(|c| if c == channel { pixel[c] > 200 } else { pixel[c] < 100 }))
+        };
+        (has_pixels(0), has_pixels(2), stats)
+    };
+
+    let (towns, villages, stats) = render("100");
+    assert!(towns && villages);
+    assert!(!stats.contains("Dropped labels"));
+
+    // The towns have higher z-indices, so they are placed first.
+    let (towns, villages, stats) = render("4@12, 8@16");
+    assert!(towns && !villages);
+    assert!(stats.ends_with("\nDropped labels: 8\n"));
+
+    // No limit for the zoom levels below the first one.
+    let (towns, villages, _) = render("4@17");
+    assert!(towns && villages);
+
+    assert!(LabelLimits::parse("8@16, 4@12").is_err());
+    assert!(LabelLimits::parse("many").is_err());
+}
+
+#[test]
+fn test_text_allow_overlap() {
+    // A town and a village at the same place, labeled without icons (and the town without a font size).
+    let (tile, lat, lon) = tile_centered_at(55.7491, 37.6071, 16);
+    let geodata = GeodataBuilder::default()
+        .with_node(1, lat, lon, &[("place", "town"), ("name", "Long Town Name")])
+        .with_node(2, lat, lon, &[("place", "village"), ("name", "V")]);
+    let map = TestMap::new("text_allow_overlap", &geodata, "");
+
+    // Returns whether there are red (town) and blue (village) labels on the tile.
+    let render = |village_props: &str| {
+        map.set_stylesheet(&format!(
+            "canvas {{ fill-color: #ffffff; }}\n\
+             node[place=town] {{ text: name; text-color: #ff0000; z-index: 1; }}\n\
+             node[place=village] {{ text: name; text-color: #0000ff; font-size: 20; z-index: 2; {} }}",
+            village_props
+        ));
+        let image = map.renderer().render_rgba(&tile, 1).unwrap();
+        let has_pixels = |channel: usize| {
+            image
+                .bytes
+                .chunks(4)
+                .any(|pixel| (0..3).all(|c| if c == channel { pixel[c] > 200 } else { pixel[c] < 100 }))
+        };
+        (has_pixels(0), has_pixels(2))
+    };
+
+    // The town is placed first, so the village label collides with it.
+    assert_eq!(render(""), (true, false));
+    assert_eq!(render("text-allow-overlap: true;"), (true, true));
+}
+
+#[test]
+fn test_casing_layers() {
+    // A bridge crossing a road, and another road of the same layer joining the first one from below.
+    let (tile, lat, lon) = tile_centered_at(55.7491, 37.6071, 16);
+    let (d_lat, d_lon) = (0.0008, 0.0014);
+    let road = [("highway", "primary")];
+    let geodata = GeodataBuilder::default()
+        .with_node(1, lat, lon - d_lon, &[])
+        .with_node(2, lat, lon + d_lon, &[])
+        .with_node(3, lat + d_lat, lon, &[])
+        .with_node(4, lat - d_lat, lon, &[])
+        .with_node(5, lat - d_lat, lon - d_lon / 2.0, &[])
+        .with_node(6, lat, lon - d_lon / 2.0, &[])
+        .with_way(1, &[1, 6, 2], &road)
+        .with_way(2, &[3, 4], &[("highway", "primary"), ("bridge", "yes"), ("layer", "1")])
+        .with_way(3, &[5, 6], &road);
+    let map = TestMap::new(
+        "casing_layers",
+        &geodata,
+        "canvas { fill-color: #ffffff; }\n\
+         way[highway] { color: #ff0000; width: 6; casing-color: #000000; casing-width: 3; }",
+    );
+    let image = map.renderer().render_rgba(&tile, 1).unwrap();
+    let pixel = |x: usize, y: usize| common::pixel(&image, x, y);
+    let (center, black) = (image.dimension / 2, (0, 0, 0));
+
+    // The casing of the bridge covers the road below it...
+    let road_at_bridge_casing = (center + 5..center + 8).map(|x| pixel(x, center)).collect::<Vec<_>>();
+    assert!(road_at_bridge_casing.contains(&black));
+    // ...but the roads of the same layer are connected without the casing in between.
+    let junction_x = (0..image.dimension)
+        .filter(|x| pixel(*x, center + 20) == RED_PIXEL)
+        .min()
+        .unwrap();
+    assert!((center - 2..center + 10).all(|y| pixel(junction_x + 2, y) == RED_PIXEL));
+}
+
+#[test]
+fn test_continuous_casings() {
+    // Two parts of the same street meeting at a right angle in the center, and a path ending at the same node.
+    let (tile, lat, lon) = tile_centered_at(55.7491, 37.6071, 16);
+    let (d_lat, d_lon) = (0.0008, 0.0014);
+    let geodata = GeodataBuilder::default()
+        .with_node(1, lat, lon - d_lon, &[])
+        .with_node(2, lat, lon, &[])
+        .with_node(3, lat + d_lat, lon, &[])
+        .with_node(4, lat - d_lat, lon - d_lon, &[])
+        .with_way(1, &[1, 2], &[("highway", "primary")])
+        .with_way(2, &[3, 2], &[("highway", "primary")])
+        .with_way(3, &[4, 1], &[("highway", "path")]);
+    let map = TestMap::new(
+        "continuous_casings",
+        &geodata,
+        "canvas { fill-color: #ffffff; }\n\
+         way[highway] { color: #ff0000; width: 6; casing-color: #000000; casing-width: 3; casing-linejoin: miter; }\n\
+         way[highway=path] { casing-color: #0000ff; }",
+    );
+    let image = map.renderer().render_rgba(&tile, 1).unwrap();
+    let pixel = |x: usize, y: usize| common::pixel(&image, x, y);
+    let (center, black, white) = (image.dimension / 2, (0, 0, 0), (255, 255, 255));
+
+    // The casing of the street goes around the outer corner instead of leaving a gap between the butt ends...
+    assert_eq!(pixel(center + 5, center + 5), black);
+    // ...but the path with another casing doesn't continue the street at its western end.
+    let west = center - (d_lon * f64::from(renderer::tile::TILE_SIZE << tile.zoom) / 360.0).round() as usize;
+    assert_eq!(pixel(west - 5, center - 5), white);
+}
+
+#[test]
+fn test_stacked_bridges() {
+    let (tile, lat, lon) = tile_centered_at(55.7491, 37.6071, 16);
+    let (d_lat, d_lon) = (0.0005, 0.0009);
+    let square = |center_lat: f64, center_lon: f64| {
+        let (h_lat, h_lon) = (d_lat / 3.0, d_lon / 3.0);
+        vec![
+            (center_lat - h_lat, center_lon - h_lon),
+            (center_lat - h_lat, center_lon + h_lon),
+            (center_lat + h_lat, center_lon + h_lon),
+            (center_lat + h_lat, center_lon - h_lon),
+            (center_lat - h_lat, center_lon - h_lon),
+        ]
+    };
+    // A road crossed by a bridge and by a tunnel, a higher bridge crossing the first one, and two areas:
+    // a park above the tunnel and a bridge outline that is filled above all lines.
+    let ways = [
+        (
+            vec![(lat, lon - 2.0 * d_lon), (lat, lon + 2.0 * d_lon)],
+            "#ff0000",
+            None,
+        ),
+        (
+            vec![(lat - 2.0 * d_lat, lon - d_lon), (lat + 2.0 * d_lat, lon - d_lon)],
+            "#0000ff",
+            Some("1"),
+        ),
+        (
+            vec![(lat - 2.0 * d_lat, lon + d_lon), (lat + 2.0 * d_lat, lon + d_lon)],
+            "#00ff00",
+            Some("-1"),
+        ),
+        (
+            vec![(lat + d_lat, lon - 2.0 * d_lon), (lat + d_lat, lon - d_lon / 2.0)],
+            "#ffff00",
+            Some("2"),
+        ),
+    ];
+    let mut geodata = GeodataBuilder::default();
+    let mut next_node_id = 1;
+    let mut add_way = |geodata: GeodataBuilder, way_id: u64, points: &[(f64, f64)], tags: &[(&str, &str)]| {
+        let node_ids = (next_node_id..).take(points.len()).collect::<Vec<_>>();
+        next_node_id += points.len() as u64;
+        let geodata = node_ids
+            .iter()
+            .zip(points)
+            .fold(geodata, |geodata, (id, (node_lat, node_lon))| {
+                geodata.with_node(*id, *node_lat, *node_lon, &[])
+            });
+        geodata.with_way(way_id, &node_ids, tags)
+    };
+    for (way_id, (points, colour, layer)) in (1..).zip(&ways) {
+        let mut tags = vec![("highway", "primary"), ("colour", *colour)];
+        tags.extend(layer.map(|layer| ("layer", layer)));
+        geodata = add_way(geodata, way_id, points, &tags);
+    }
+    geodata = add_way(geodata, 10, &square(lat - d_lat, lon + d_lon), &[("leisure", "park")]);
+    geodata = add_way(
+        geodata,
+        11,
+        &square(lat - d_lat, lon - d_lon),
+        &[("man_made", "bridge")],
+    );
+    let map = TestMap::new(
+        "stacked_bridges",
+        &geodata,
+        "canvas { fill-color: #ffffff; }\n\
+         way[highway] { color: tag(\"colour\"); width: 6; casing-color: #000000; casing-width: 2; }\n\
+         area[leisure=park] { fill-color: #808080; }\n\
+         area[man_made=bridge] { fill-color: #ff00ff; major-z-index: 4; }",
+    );
+    let image = map.renderer().render_rgba(&tile, 1).unwrap();
+    let pixel_at = |point_lat: f64, point_lon: f64| {
+        let (x, y) = renderer::tile::coords_to_xy(&(point_lat, point_lon), tile.zoom);
+        let tile_size = f64::from(renderer::tile::TILE_SIZE);
+        let x = (x - f64::from(tile.x) * tile_size) as usize;
+        let y = (y - f64::from(tile.y) * tile_size) as usize;
+        common::pixel(&image, x, y)
+    };
+
+    // The bridge is above the road, and the higher bridge is above the first one.
+    assert_eq!(pixel_at(lat, lon - d_lon), (0, 0, 255));
+    assert_eq!(pixel_at(lat + d_lat, lon - d_lon), (255, 255, 0));
+    // The tunnel is below the road, but still above the fill of the park.
+    assert_eq!(pixel_at(lat, lon + d_lon), RED_PIXEL);
+    assert_eq!(pixel_at(lat - d_lat, lon + d_lon), (0, 255, 0));
+    // Unless the major z-index says otherwise.
+    assert_eq!(pixel_at(lat - d_lat, lon - d_lon), (255, 0, 255));
+}
+
+#[test]
+fn test_blend_mode() {
+    // Three parallel roads across a park: a tunnel, a road explicitly tagged as not a tunnel, and a usual road.
+    let (tile, lat, lon) = tile_centered_at(55.7491, 37.6071, 16);
+    let (d_lat, d_lon) = (0.0005, 0.0009);
+    let mut geodata = GeodataBuilder::default();
+    let park_corners = [(-2.0, -2.0), (-2.0, 2.0), (2.0, 2.0), (2.0, -2.0)];
+    for (id, (row, col)) in (1..).zip(park_corners) {
+        geodata = geodata.with_node(id, lat + row * d_lat, lon + col * d_lon, &[]);
+    }
+    geodata = geodata.with_way(1, &[1, 2, 3, 4, 1], &[("leisure", "park")]);
+    for (idx, tunnel) in (0u32..).zip(["yes", "no", ""]) {
+        let row = f64::from(idx) - 1.0;
+        let first_node_id = 10 + 2 * u64::from(idx);
+        for (node_id, col) in (first_node_id..).zip([-1.0, 1.0]) {
+            geodata = geodata.with_node(node_id, lat + row * d_lat, lon + col * d_lon, &[]);
+        }
+        let mut tags = vec![("highway", "primary")];
+        if !tunnel.is_empty() {
+            tags.push(("tunnel", tunnel));
+        }
+        geodata = geodata.with_way(u64::from(idx) + 2, &[first_node_id, first_node_id + 1], &tags);
+    }
+    let map = TestMap::new(
+        "blend_mode",
+        &geodata,
+        "canvas { fill-color: #ffffff; }\n\
+         area[leisure=park] { fill-color: #808080; }\n\
+         way[highway] { color: #ff8080; width: 6; }\n\
+         way[highway][tunnel?] { blend-mode: multiply; }\n\
+         way[highway][tunnel?]::casing { color: #000000; width: 10; dashes: 4, 4; z-index: -1; }",
+    );
+    let image = map.renderer().render_rgba(&tile, 1).unwrap();
+    // Returns the pixels of a horizontal segment `dy` pixels below the given road.
+    let pixels_along = |row: f64, dy: usize| {
+        let (x, y) = renderer::tile::coords_to_xy(&(lat + row * d_lat, lon), tile.zoom);
+        let tile_size = f64::from(renderer::tile::TILE_SIZE);
+        let (x, y) = (
+            (x - f64::from(tile.x) * tile_size) as usize,
+            (y - f64::from(tile.y) * tile_size) as usize + dy,
+        );
+        (x - 10..x + 10)
+            .map(|x| common::pixel(&image, x, y))
+            .collect::<Vec<_>>()
+    };
+
+    // The tunnel is multiplied with the park (and with the dashes of its casing below it).
+    let black = (0, 0, 0);
+    let tunnel = pixels_along(-1.0, 0);
+    assert!(tunnel.contains(&(128, 64, 64)) && tunnel.contains(&black));
+    assert!(!tunnel.contains(&(255, 128, 128)));
+    let tunnel_casing = pixels_along(-1.0, 4);
+    assert!(tunnel_casing.contains(&(128, 128, 128)) && tunnel_casing.contains(&black));
+    for other_road in [pixels_along(0.0, 0), pixels_along(1.0, 0)] {
+        assert!(other_road.iter().all(|pixel| *pixel == (255, 128, 128)));
+    }
+}
+
+#[test]
+fn test_canvas_image_and_antialiasing() {
+    // A slanted road, so that its edges fall between the pixels.
+    let (tile, lat, lon) = tile_centered_at(55.7491, 37.6071, 16);
+    let geodata = GeodataBuilder::default()
+        .with_node(1, lat - 0.0005, lon - 0.0013, &[])
+        .with_node(2, lat + 0.0005, lon + 0.0007, &[])
+        .with_way(1, &[1, 2], &[("highway", "primary")]);
+    let map = TestMap::new("canvas_image", &geodata, "");
+
+    // A 2x2 checkerboard of white and gray pixels.
+    let (white, gray, black) = ((255, 255, 255), (200, 200, 200), (0, 0, 0));
+    let checkerboard = rgb_triples_to_png(&[white, gray, gray, white], 2, 2).unwrap();
+    std::fs::write(map.dir.join("checkerboard.png"), checkerboard).unwrap();
+
+    let render = |antialiasing: &str| {
+        map.set_stylesheet(&format!(
+            "canvas {{ fill-color: #ff0000; fill-image: \"checkerboard.png\"; {} }}\n\
+             way[highway] {{ color: #000000; width: 3; }}",
+            antialiasing
+        ));
+        let image = map.renderer().render_rgba(&tile, 1).unwrap();
+        image
+            .bytes
+            .chunks(4)
+            .map(|pixel| (pixel[0], pixel[1], pixel[2]))
+            .collect::<Vec<_>>()
+    };
+
+    let smooth = render("");
+    let dimension = renderer::tile::TILE_SIZE as usize;
+    assert_eq!(&smooth[..4], &[white, gray, white, gray]);
+    assert_eq!(&smooth[dimension..dimension + 4], &[gray, white, gray, white]);
+    assert!(smooth.contains(&black));
+    let is_aliased = |pixel: &(u8, u8, u8)| [white, gray, black].contains(pixel);
+    assert!(!smooth.iter().all(is_aliased));
+
+    let sharp = render("antialiasing: none;");
+    assert_eq!(&sharp[..4], &smooth[..4]);
+    assert!(sharp.contains(&black));
+    assert!(sharp.iter().all(is_aliased));
+}
+
+#[test]
+fn test_min_area() {
+    // A small building, a big one, and a multipolygon building with a big hole, at 60 pixels from each other.
+    let (tile, lat, lon) = tile_centered_at(55.7491, 37.6071, 16);
+    let degrees_per_pixel = 360.0 / (f64::from(renderer::tile::TILE_SIZE) * f64::from(1 << tile.zoom));
+    let square = |first_node_id: u64, center_x: f64, size: f64| {
+        let corners = [(-1.0, -1.0), (-1.0, 1.0), (1.0, 1.0), (1.0, -1.0)];
+        corners.into_iter().zip(first_node_id..).map(move |((dx, dy), id)| {
+            let node_lat = lat - dy * size / 2.0 * degrees_per_pixel * lat.to_radians().cos();
+            let node_lon = lon + (center_x + dx * size / 2.0) * degrees_per_pixel;
+            (id, node_lat, node_lon)
+        })
+    };
+    let mut builder = GeodataBuilder::default();
+    for (id, node_lat, node_lon) in [square(1, -60.0, 10.0), square(5, 0.0, 30.0), square(9, 60.0, 30.0)]
+        .into_iter()
+        .flatten()
+        .chain(square(13, 60.0, 26.0))
+    {
+        builder = builder.with_node(id, node_lat, node_lon, &[]);
+    }
+    let building = [("building", "yes")];
+    let geodata = builder
+        .with_way(1, &[1, 2, 3, 4, 1], &building)
+        .with_way(2, &[5, 6, 7, 8, 5], &building)
+        .with_multipolygon(3, &[&[9, 10, 11, 12], &[13, 14, 15, 16]], &building);
+    let map = TestMap::new("min_area", &geodata, "");
+
+    let red_pixels_around = |min_area: &str, center_x: usize| {
+        map.set_stylesheet(&format!(
+            "canvas {{ fill-color: #ffffff; }}\narea[building] {{ fill-color: #ff0000; {} }}",
+            min_area
+        ));
+        let image = map.renderer().render_rgba(&tile, 1).unwrap();
+        let center_y = image.dimension / 2;
+        let mut count = 0;
+        for y in center_y - 20..center_y + 20 {
+            for x in center_x - 20..center_x + 20 {
+                if common::pixel(&image, x, y) == RED_PIXEL {
+                    count += 1;
+                }
+            }
+        }
+        count
+    };
+
+    let center = renderer::tile::TILE_SIZE as usize / 2;
+    let (small, big, with_hole) = (center - 60, center, center + 60);
+    for building in [small, big, with_hole] {
+        assert!(red_pixels_around("", building) > 0);
+    }
+    // Only the area without the hole counts, which is 30 * 30 - 26 * 26 = 224 pixels.
+    let min_area = "min-area: 300;";
+    assert_eq!(red_pixels_around(min_area, small), 0);
+    assert!(red_pixels_around(min_area, big) > 0);
+    assert_eq!(red_pixels_around(min_area, with_hole), 0);
+}
+
+#[test]
+fn test_several_geodata_files() {
+    // Both files contain the same square in the middle of the tile, and each of them has another one to the side.
+    let (tile, lat, lon) = tile_centered_at(55.7491, 37.6071, 16);
+    let with_square = |geodata: GeodataBuilder, way_id: u64, d_lon: f64| {
+        let size = 0.0005;
+        let corners = [(-size, -size), (-size, size), (size, size), (size, -size)];
+        let node_ids = (10 * way_id..).take(corners.len()).collect::<Vec<_>>();
+        let geodata = node_ids
+            .iter()
+            .zip(corners)
+            .fold(geodata, |geodata, (id, (lat_delta, lon_delta))| {
+                geodata.with_node(*id, lat + lat_delta, lon + d_lon + lon_delta, &[])
+            });
+        let ring = node_ids.iter().chain(&node_ids[..1]).copied().collect::<Vec<_>>();
+        geodata.with_way(way_id, &ring, &[("landuse", "forest")])
+    };
+    let common_square = with_square(GeodataBuilder::default(), 1, 0.0);
+    let west = TestMap::new(
+        "several_geodata_files",
+        &with_square(common_square.clone(), 2, -0.0015),
+        "canvas { fill-color: #ffffff; }\narea[landuse] { fill-color: #008000; fill-opacity: 0.5; }",
+    );
+    let east_file = west.dir.join("east.bin").to_string_lossy().into_owned();
+    with_square(common_square, 3, 0.0015).save(&east_file).unwrap();
+
+    let white = (255, 255, 255);
+    let west_only = west.renderer().render_rgba(&tile, 1).unwrap();
+    assert_ne!(common::pixel(&west_only, 58, 128), white);
+    assert_eq!(common::pixel(&west_only, 198, 128), white);
+
+    let both = west
+        .renderer()
+        .with_geodata_file(&east_file, None)
+        .unwrap()
+        .render_rgba(&tile, 1)
+        .unwrap();
+    assert_eq!(common::pixel(&both, 58, 128), common::pixel(&west_only, 58, 128));
+    assert_eq!(common::pixel(&both, 198, 128), common::pixel(&west_only, 58, 128));
+    // The common square is drawn only once, otherwise it would be darker than the other ones.
+    assert_eq!(common::pixel(&both, 128, 128), common::pixel(&west_only, 128, 128));
+
+    assert!(west.renderer().with_geodata_file("no_such_file.bin", None).is_err());
+}
+
+#[test]
+fn test_several_coastal_files() {
+    // Two neighboring extracts with the same coastline along the middle of the tile, land to the north.
+    let (tile, lat, lon) = tile_centered_at(55.7491, 37.6071, 16);
+    let dir = common::temp_test_dir("several_coastal_files");
+    let import = |name: &str, min_lon: f64, max_lon: f64, first_node_id: u64| {
+        let osm_file = dir.join(format!("{}.osm", name));
+        std::fs::write(
+            &osm_file,
+            format!(
+                "<osm><bounds minlat=\"{}\" minlon=\"{}\" maxlat=\"{}\" maxlon=\"{}\"/>\
+                 <node id=\"{}\" lat=\"{}\" lon=\"{}\"/><node id=\"{}\" lat=\"{}\" lon=\"{}\"/>\
+                 <way id=\"{}\"><nd ref=\"{}\"/><nd ref=\"{}\"/><tag k=\"natural\" v=\"coastline\"/></way></osm>",
+                lat - 0.002,
+                min_lon,
+                lat + 0.002,
+                max_lon,
+                first_node_id,
+                lat,
+                min_lon - 0.001,
+                first_node_id + 1,
+                lat,
+                max_lon + 0.001,
+                first_node_id,
+                first_node_id,
+                first_node_id + 1,
+            ),
+        )
+        .unwrap();
+        let bin_file = dir.join(format!("{}.bin", name)).to_string_lossy().into_owned();
+        renderer::geodata::importer::import(osm_file.to_string_lossy().as_ref(), &bin_file).unwrap();
+        bin_file
+    };
+    let west = import("west", lon - 0.002, lon, 1);
+    let east = import("east", lon, lon + 0.002, 3);
+
+    let map = TestMap::with_geodata_file(
+        "several_coastal_files",
+        &west,
+        "canvas { fill-color: #ffffff; }\n\
+         area[natural=land] { fill-color: #00ff00; }\n\
+         area[natural=sea] { fill-color: #0000ff; }",
+    );
+    let image = map
+        .renderer()
+        .with_geodata_file(&east, None)
+        .unwrap()
+        .render_rgba(&tile, 1)
+        .unwrap();
+    let (land, sea) = ((0, 255, 0), (0, 0, 255));
+    for x in [78, 178] {
+        assert_eq!(common::pixel(&image, x, 100), land);
+        assert_eq!(common::pixel(&image, x, 156), sea);
+    }
+}
+
+#[test]
+fn test_reload_geodata() {
+    let (tile, lat, lon) = tile_centered_at(55.7491, 37.6071, 16);
+    let size = 0.001;
+    let square = |landuse: &str| {
+        GeodataBuilder::default()
+            .with_node(1, lat - size, lon - size, &[])
+            .with_node(2, lat - size, lon + size, &[])
+            .with_node(3, lat + size, lon + size, &[])
+            .with_node(4, lat + size, lon - size, &[])
+            .with_way(1, &[1, 2, 3, 4, 1], &[("landuse", landuse)])
+    };
+    let map = TestMap::new(
+        "reload_geodata",
+        &square("grass"),
+        "canvas { fill-color: #ffffff; }\narea[landuse=forest] { fill-color: #008000; }",
+    );
+    let center_pixel =
+        |tile_renderer: &TileRenderer| common::pixel(&tile_renderer.render_rgba(&tile, 1).unwrap(), 128, 128);
+
+    let tile_renderer = map.renderer();
+    assert_eq!(center_pixel(&tile_renderer), (255, 255, 255));
+
+    let old_readers = tile_renderer.readers();
+    // Like the importer, writes the new file next to the old one and then replaces it.
+    let tmp_file = map.dir.join("geodata.tmp");
+    square("forest").save(&tmp_file).unwrap();
+    std::fs::rename(&tmp_file, &map.geodata_file).unwrap();
+    tile_renderer.reload_geodata().unwrap();
+    assert_eq!(center_pixel(&tile_renderer), (0, 128, 0));
+    // The readers that were taken before the reload still work with the old data.
+    let old_way = &old_readers[0].all_entities().ways[0];
+    assert_eq!(old_way.tags().get_by_key("landuse"), Some("grass"));
+
+    std::fs::remove_file(&map.geodata_file).unwrap();
+    assert!(tile_renderer.reload_geodata().is_err());
+    assert_eq!(center_pixel(&tile_renderer), (0, 128, 0));
+}
+
+#[test]
+fn test_render_svg() {
+    // A park, a road across it, and a labeled node.
+    let (tile, lat, lon) = tile_centered_at(55.7491, 37.6071, 16);
+    let d = 0.0005;
+    let geodata = GeodataBuilder::default()
+        .with_node(1, lat - d, lon - d, &[])
+        .with_node(2, lat - d, lon + d, &[])
+        .with_node(3, lat + d, lon + d, &[])
+        .with_node(4, lat + d, lon - d, &[])
+        .with_node(5, lat, lon - 2.0 * d, &[])
+        .with_node(6, lat, lon + 2.0 * d, &[])
+        .with_node(7, lat + d / 2.0, lon, &[("place", "square"), ("name", "Square")])
+        .with_way(1, &[1, 2, 3, 4, 1], &[("leisure", "park")])
+        .with_way(2, &[5, 6], &[("highway", "primary")]);
+    let map = TestMap::new(
+        "render_svg",
+        &geodata,
+        "canvas { fill-color: #ffffff; }\n\
+         area[leisure=park] { fill-color: #00ff00; fill-opacity: 0.5; }\n\
+         way[highway] { color: #ff0000; width: 6; casing-color: #000000; casing-width: 1; dashes: 4,2; }\n\
+         node[place] { text: name; text-color: #0000ff; font-size: 12; }",
+    );
+    let svg = map.renderer().render_svg(&tile, 2, &RenderOptions::default()).unwrap();
+
+    assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"512\" height=\"512\""));
+    assert!(svg.ends_with("</svg>\n"));
+    // The park is filled first, then the casing of the road is drawn, and the road itself goes on top of it.
+    let park = svg
+        .find("fill=\"#00ff00\" fill-rule=\"evenodd\" fill-opacity=\"0.5\"")
+        .unwrap();
+    let casing = svg.find("stroke=\"#000000\" stroke-width=\"16\"").unwrap();
+    let road = svg.find("stroke=\"#ff0000\" stroke-width=\"12\"").unwrap();
+    assert!(park < casing && casing < road);
+    assert!(svg[road..].contains("stroke-dasharray=\"8 4\""));
+    // The label is drawn as the outlines of its glyphs, after all the figures.
+    let label = svg.find("fill=\"#0000ff\"").unwrap();
+    assert!(label > road);
+    assert!(svg[..label].rfind("<path d=\"M").is_some());
+}
+
+#[test]
+fn test_render_pdf() {
+    let region = PixelRegion {
+        zoom: 16,
+        left: 1000,
+        top: 2000,
+        width: 1000,
+        height: 500,
+    };
+    let pages = region.split_into_pages(600, 400, 50).unwrap();
+    let pages = pages
+        .iter()
+        .map(|page| (page.left, page.top, page.width, page.height))
+        .collect::<Vec<_>>();
+    // The last page of every row and column is moved back, so it overlaps the previous one by more than 50 pixels.
+    assert_eq!(
+        pages,
+        vec![
+            (1000, 2000, 600, 400),
+            (1400, 2000, 600, 400),
+            (1000, 2100, 600, 400),
+            (1400, 2100, 600, 400),
+        ]
+    );
+    assert_eq!(region.split_into_pages(2000, 2000, 50).unwrap(), vec![region]);
+    assert!(PixelRegion {
+        zoom: 16,
+        left: 0,
+        top: 0,
+        width: 1000,
+        height: 400
+    }
+    .split_into_pages(600, 400, 400)
+    .is_err());
+
+    let bin_file = common::get_test_path(&["osm", "nano_moscow_pdf.bin"]);
+    renderer::geodata::importer::import(&common::get_test_path(&["osm", "nano_moscow.osm"]), &bin_file).unwrap();
+    let stylesheet = common::get_test_path(&["mapcss", "mapnik.mapcss"]);
+    let tile_renderer = TileRenderer::new(&bin_file, &stylesheet, &StyleType::Josm, None).unwrap();
+
+    // Two tiles side by side, split into two pages that both cross the boundary between the tiles.
+    let region = PixelRegion {
+        zoom: 16,
+        left: 39_614 * 256,
+        top: 20_486 * 256,
+        width: 512,
+        height: 256,
+    };
+    let pages = region.split_into_pages(300, 256, 88).unwrap();
+    assert_eq!(pages.len(), 2);
+    let pdf = tile_renderer.render_pdf(&pages, 2, &RenderOptions::default()).unwrap();
+    let find_all = |needle: &[u8]| {
+        pdf.windows(needle.len())
+            .enumerate()
+            .filter(|(_, window)| *window == needle)
+            .map(|(idx, _)| idx)
+            .collect::<Vec<_>>()
+    };
+    assert!(pdf.starts_with(b"%PDF-1.4\n"));
+    assert_eq!(find_all(b"/Count 2 ").len(), 1);
+    assert_eq!(find_all(b"/MediaBox [0 0 600 512]").len(), 2);
+
+    let (starts, ends) = (find_all(b">>\nstream\n"), find_all(b"\nendstream"));
+    assert_eq!(starts.len(), 2);
+    for (start, end) in starts.into_iter().zip(ends) {
+        let mut content = String::new();
+        std::io::Read::read_to_string(
+            &mut flate2::read::ZlibDecoder::new(&pdf[start + ">>\nstream\n".len()..end]),
+            &mut content,
+        )
+        .unwrap();
+        // Every page shows a part of each tile, clipped to the tile.
+        assert_eq!(content.matches(" re W n ").count(), 2);
+        assert!(content.contains(" f* Q\n"));
+        assert!(content.contains(" S Q\n"));
+    }
+}